@@ -0,0 +1,7558 @@
+//! Pushes changed mark files of a wallet repository upstream.
+//!
+//! The changes are committed in a copy of the repository, so that a manual
+//! change ongoing in the original is never disturbed. See [`PushRequest`].
+
+use std::cell::Cell;
+use std::collections::BTreeMap;
+use std::collections::HashMap;
+use std::collections::HashSet;
+use std::env;
+use std::ffi::OsStr;
+use std::fmt;
+use std::fs;
+use std::fs::File;
+use std::fs::TryLockError;
+use std::io;
+use std::io::Write;
+use std::path::Component;
+use std::path::Path;
+use std::path::PathBuf;
+use std::process::Command;
+use std::process::Stdio;
+use std::sync::atomic::AtomicUsize;
+use std::sync::atomic::Ordering;
+use std::sync::mpsc;
+use std::sync::mpsc::RecvTimeoutError;
+use std::sync::Arc;
+use std::sync::Mutex;
+use std::sync::PoisonError;
+use std::thread;
+use std::time::Duration;
+use std::time::Instant;
+use std::time::SystemTime;
+
+use clap::ValueEnum;
+use git2::build::CheckoutBuilder;
+use git2::AttrCheckFlags;
+use git2::AutotagOption;
+use git2::BranchType;
+use git2::Commit;
+use git2::Config;
+use git2::ConfigLevel;
+use git2::Cred;
+use git2::CredentialType;
+use git2::Delta;
+use git2::Diff;
+use git2::DiffFindOptions;
+use git2::DiffFormat;
+use git2::DiffOptions;
+use git2::Direction;
+use git2::ErrorClass;
+use git2::ErrorCode;
+use git2::FetchOptions;
+use git2::Index;
+use git2::IndexEntry;
+use git2::IndexTime;
+use git2::ObjectType;
+use git2::Oid;
+use git2::PushOptions;
+use git2::RebaseOptions;
+use git2::Reference;
+use git2::RemoteCallbacks;
+use git2::Repository;
+use git2::RepositoryState;
+use git2::ResetType;
+use git2::Signature;
+use git2::Status;
+use git2::StatusEntry;
+use git2::StatusOptions;
+use git2::StatusShow;
+use git2::Statuses;
+use git2::Time;
+use git2::Tree;
+use git2::WorktreeAddOptions;
+use git2::WorktreePruneOptions;
+use glob::MatchOptions;
+use glob::Pattern;
+use log::debug;
+use log::info;
+use log::warn;
+use notify::Event;
+use notify::RecommendedWatcher;
+use notify::RecursiveMode;
+use notify::Watcher;
+use regex::Regex;
+use serde::Deserialize;
+use tempfile::tempdir;
+use tempfile::tempdir_in;
+use tempfile::NamedTempFile;
+use unicode_normalization::UnicodeNormalization;
+
+/// The summary line of auto commits.
+const COMMIT_SUMMARY: &str = "Update marks";
+
+/// The trailer that identifies commits made by this tool.
+const AUTO_COMMIT_TRAILER: &str = "Auto-Commit: push-wallet-marks";
+
+/// The key of the trailer that names the machine that made an auto commit.
+const HOST_TRAILER_KEY: &str = "Auto-Commit-Host";
+
+/// The prefix of the references that snapshot HEAD before auto commits, see
+/// [`PushRequest::snapshots`].
+pub const SNAPSHOT_REFS: &str = "refs/pwm/snapshots/";
+
+/// The placeholders of commit message and tag templates and what they stand
+/// for. `{{` and `}}` stand for literal braces.
+pub const COMMIT_MESSAGE_PLACEHOLDERS: [(&str, &str); 6] = [
+    ("files", "the committed files, separated by commas"),
+    ("count", "the number of committed files"),
+    ("date", "the commit date as YYYY-MM-DD"),
+    (
+        "local-time",
+        "the commit time in this machine's time zone, e.g., 2024-01-05T13:45:00+01:00",
+    ),
+    (
+        "utc-time",
+        "the commit time in UTC, e.g., 2024-01-05T12:45:00Z",
+    ),
+    ("hostname", "the name of this machine"),
+];
+
+/// The name of the file in the Git directory that stores the last run's state.
+const RUN_STATE_FILE: &str = "push-wallet-marks-state";
+
+/// The version of the run state's format. A state of another version never
+/// matches, so bump it whenever the format changes.
+const RUN_STATE_VERSION: u32 = 1;
+
+/// The name of the file in the Git directory that stores the committer time of
+/// the last auto commit, in seconds since the epoch.
+const COMMIT_TIME_FILE: &str = "push-wallet-marks-commit-time";
+
+/// The name of the file in the Git directory that counts the auto commits
+/// since the last maintenance, see [`PushRequest::maintenance_every`].
+const COMMIT_COUNT_FILE: &str = "push-wallet-marks-commit-count";
+
+/// The name of the file in the Git directory that logs the runs that tried to
+/// push, one `<seconds since the epoch> <pushed|committed|failed>` line each,
+/// see [`PushRequest::stats`].
+const RUN_LOG_FILE: &str = "push-wallet-marks-runs";
+
+/// How many runs [`RUN_LOG_FILE`] keeps.
+const RUN_LOG_LIMIT: usize = 1000;
+
+/// The name of the file in the Git directory that stores when the pending
+/// batch of changes started, in seconds since the epoch, see
+/// [`PushRequest::batch_window`].
+const BATCH_FILE: &str = "push-wallet-marks-batch";
+
+/// The name of the file in the Git directory that is locked during a run.
+const RUN_LOCK_FILE: &str = "push-wallet-marks.lock";
+
+/// How often to check whether another Git process has released the index.
+const INDEX_LOCK_POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// The private keys in `~/.ssh` that are tried, in order, after ssh-agent.
+const SSH_KEY_NAMES: [&str; 3] = ["id_ed25519", "id_ecdsa", "id_rsa"];
+
+/// The name of the temporary linked worktree and of its branch.
+const WORKTREE_NAME: &str = "push-wallet-marks";
+
+/// The index mode of submodule entries.
+const GITLINK_MODE: u32 = 0o160000;
+
+/// The maximum number of threads used to copy a repository.
+const MAX_COPY_WORKERS: usize = 8;
+
+/// The entries of a common Git directory that its linked worktrees share. The
+/// others, e.g., `HEAD` and `index`, belong to the main worktree.
+const SHARED_GIT_DIR_ENTRIES: [&str; 6] =
+    ["config", "hooks", "info", "packed-refs", "refs", "shallow"];
+
+/// A request to push the changed mark files of a wallet repository.
+///
+/// # Example
+///
+/// ```no_run
+/// use git_auto_commit::PushRequest;
+///
+/// let outcome = PushRequest::new("/home/me/wallet")
+///     .auto_file("marks/checking.journal")
+///     .ignore_mode_changes(true)
+///     .push()?;
+/// println!("{:?}", outcome);
+/// # Ok::<(), String>(())
+/// ```
+#[derive(Clone, Debug)]
+pub struct PushRequest {
+    repo: PathBuf,
+    marks_repo: Option<PathBuf>,
+    auto_files: Vec<PathBuf>,
+    excludes: Vec<String>,
+    opaque: Vec<String>,
+    changes: ChangeFilter,
+    require_diff_regex: Option<String>,
+    allow_non_default_branch: bool,
+    on_sync_conflict: SyncConflictPolicy,
+    on_diverged: DivergencePolicy,
+    workspace: Workspace,
+    temp_dir: Option<PathBuf>,
+    keep_temp: bool,
+    ignore_state: bool,
+    lock_timeout: Duration,
+    batch_window: Duration,
+    push_target: PushTarget,
+    mirrors: Vec<String>,
+    retry: RetryPolicy,
+    author: Identity,
+    message: Option<String>,
+    tag_template: Option<String>,
+    snapshots: usize,
+    maintenance_every: usize,
+    validate_cmd: Option<String>,
+    run_hooks: bool,
+    commit_strategy: CommitStrategy,
+    show_diff: bool,
+    amend: bool,
+    follow_peers: bool,
+    force_with_lease: bool,
+    sign: Option<bool>,
+    signer: Option<Arc<dyn CommitSigner>>,
+    confirmer: Option<Arc<dyn Confirmer>>,
+    dry_run: bool,
+}
+
+impl PushRequest {
+    /// Creates a request for the repository at `repo` without any auto files.
+    pub fn new<P: Into<PathBuf>>(repo: P) -> Self {
+        PushRequest {
+            repo: repo.into(),
+            marks_repo: None,
+            auto_files: Vec::new(),
+            excludes: Vec::new(),
+            opaque: Vec::new(),
+            changes: ChangeFilter::default(),
+            require_diff_regex: None,
+            allow_non_default_branch: false,
+            on_sync_conflict: SyncConflictPolicy::Abort,
+            on_diverged: DivergencePolicy::Rebase,
+            workspace: Workspace::Copy,
+            temp_dir: None,
+            keep_temp: false,
+            ignore_state: false,
+            lock_timeout: Duration::ZERO,
+            batch_window: Duration::ZERO,
+            push_target: PushTarget::default(),
+            mirrors: Vec::new(),
+            retry: RetryPolicy::default(),
+            author: Identity::default(),
+            message: None,
+            tag_template: None,
+            snapshots: 0,
+            maintenance_every: 0,
+            validate_cmd: None,
+            run_hooks: false,
+            commit_strategy: CommitStrategy::Combined,
+            show_diff: false,
+            amend: false,
+            follow_peers: false,
+            force_with_lease: false,
+            sign: None,
+            signer: None,
+            confirmer: None,
+            dry_run: false,
+        }
+    }
+
+    /// A separate repository to commit and push the marks in, e.g., a shared
+    /// one while the wallet repository stays private. Each run copies the
+    /// auto files from the wallet's working tree into the marks repository's
+    /// at the same paths, and then commits and pushes them there. A missing
+    /// auto file is only deleted with [`PushRequest::allow_delete`].
+    pub fn marks_repo<P: Into<PathBuf>>(mut self, marks_repo: P) -> Self {
+        self.marks_repo = Some(marks_repo.into());
+        self
+    }
+
+    /// Adds a file to push if it changed. Glob patterns, e.g.,
+    /// `marks/**/*.journal`, are matched against tracked files.
+    pub fn auto_file<P: Into<PathBuf>>(mut self, auto_file: P) -> Self {
+        self.auto_files.push(auto_file.into());
+        self
+    }
+
+    /// Adds files to push if they changed. See [`PushRequest::auto_file`].
+    pub fn auto_files<I>(mut self, auto_files: I) -> Self
+    where
+        I: IntoIterator,
+        I::Item: Into<PathBuf>,
+    {
+        self.auto_files
+            .extend(auto_files.into_iter().map(Into::into));
+        self
+    }
+
+    /// Adds a glob pattern of auto files to treat as opaque blobs, e.g.,
+    /// `marks/*.gpg` for encrypted ones. Their diffs mean nothing, so the
+    /// diff-based checks and validations, i.e., the changed line limit,
+    /// [`PushRequest::marks_only`], [`PushRequest::require_diff_regex`], and
+    /// [`PushRequest::validate_cmd`], skip them, and the printed diff leaves
+    /// them out. Changes are still detected by their content's hash.
+    pub fn opaque<S: Into<String>>(mut self, opaque: S) -> Self {
+        self.opaque.push(opaque.into());
+        self
+    }
+
+    /// Adds glob patterns of auto files to treat as opaque blobs. See
+    /// [`PushRequest::opaque`].
+    pub fn opaques<I>(mut self, opaque: I) -> Self
+    where
+        I: IntoIterator,
+        I::Item: Into<String>,
+    {
+        self.opaque.extend(opaque.into_iter().map(Into::into));
+        self
+    }
+
+    /// Adds a glob pattern of files that are never pushed, even if an auto
+    /// file or pattern covers them. A pattern that matches a directory, e.g.,
+    /// `journals/drafts`, excludes everything in it.
+    pub fn exclude<S: Into<String>>(mut self, exclude: S) -> Self {
+        self.excludes.push(exclude.into());
+        self
+    }
+
+    /// Adds patterns of files that are never pushed. See
+    /// [`PushRequest::exclude`].
+    pub fn excludes<I>(mut self, excludes: I) -> Self
+    where
+        I: IntoIterator,
+        I::Item: Into<String>,
+    {
+        self.excludes.extend(excludes.into_iter().map(Into::into));
+        self
+    }
+
+    /// Whether to skip changes that only flip a file's mode and keep the
+    /// committed mode when staging content changes.
+    pub fn ignore_mode_changes(mut self, ignore_mode_changes: bool) -> Self {
+        self.changes.ignore_mode_changes = ignore_mode_changes;
+        self
+    }
+
+    /// Whether to push auto files that Git doesn't track yet. Glob patterns
+    /// then match untracked files too.
+    pub fn allow_new(mut self, allow_new: bool) -> Self {
+        self.changes.allow_new = allow_new;
+        self
+    }
+
+    /// Whether to push deletions of auto files.
+    pub fn allow_delete(mut self, allow_delete: bool) -> Self {
+        self.changes.allow_delete = allow_delete;
+        self
+    }
+
+    /// Whether to refuse to push changes that do more than flip the status
+    /// marks of transactions and postings, e.g., `!` to `*` in hledger
+    /// journals.
+    pub fn marks_only(mut self, marks_only: bool) -> Self {
+        self.changes.marks_only = marks_only;
+        self
+    }
+
+    /// The most mark files that a push may change. A larger change fails the
+    /// push, so that e.g. a journal reformatted by accident gets a human's
+    /// attention.
+    pub fn max_files(mut self, max_files: usize) -> Self {
+        self.changes.max_files = Some(max_files);
+        self
+    }
+
+    /// The most lines that a push may add and remove in total. See
+    /// [`PushRequest::max_files`].
+    pub fn max_changed_lines(mut self, max_changed_lines: usize) -> Self {
+        self.changes.max_changed_lines = Some(max_changed_lines);
+        self
+    }
+
+    /// A regular expression that every changed hunk of a push must match as a
+    /// whole. A hunk is matched as its removed and added lines, each with its
+    /// `-` or `+` prefix and trailing newline, e.g.,
+    /// `([-+]\d{4}-\d\d-\d\d [*!] .*\n)+` for hledger transactions whose only
+    /// change is their status mark.
+    pub fn require_diff_regex<S: Into<String>>(mut self, require_diff_regex: S) -> Self {
+        self.require_diff_regex = Some(require_diff_regex.into());
+        self
+    }
+
+    /// Whether to push even if HEAD isn't on the remote's default branch.
+    pub fn allow_non_default_branch(mut self, allow_non_default_branch: bool) -> Self {
+        self.allow_non_default_branch = allow_non_default_branch;
+        self
+    }
+
+    /// What to do about Syncthing conflict copies of auto files.
+    pub fn on_sync_conflict(mut self, on_sync_conflict: SyncConflictPolicy) -> Self {
+        self.on_sync_conflict = on_sync_conflict;
+        self
+    }
+
+    /// What to do if HEAD's branch and its upstream have diverged before the
+    /// auto commit is made. It doesn't apply with
+    /// [`PushRequest::force_with_lease`].
+    pub fn on_diverged(mut self, on_diverged: DivergencePolicy) -> Self {
+        self.on_diverged = on_diverged;
+        self
+    }
+
+    /// Where to make the auto commit.
+    pub fn workspace(mut self, workspace: Workspace) -> Self {
+        self.workspace = workspace;
+        self
+    }
+
+    /// The directory to create temporary copies and worktrees in instead of
+    /// the system's temporary directory, e.g., because that's a small tmpfs.
+    pub fn temp_dir<P: Into<PathBuf>>(mut self, temp_dir: P) -> Self {
+        self.temp_dir = Some(temp_dir.into());
+        self
+    }
+
+    /// Whether to keep the temporary copy if the run fails, so that it can
+    /// be inspected.
+    pub fn keep_temp(mut self, keep_temp: bool) -> Self {
+        self.keep_temp = keep_temp;
+        self
+    }
+
+    /// Whether to run even if HEAD and the auto files are unchanged since the
+    /// last run.
+    pub fn ignore_state(mut self, ignore_state: bool) -> Self {
+        self.ignore_state = ignore_state;
+        self
+    }
+
+    /// How long to wait for another Git process to release the index.
+    pub fn lock_timeout(mut self, lock_timeout: Duration) -> Self {
+        self.lock_timeout = lock_timeout;
+        self
+    }
+
+    /// Collects the changes of the runs within this long of a first one in a
+    /// single auto commit, which each run amends, and pushes it once the
+    /// window has passed, e.g., for exporters that change the marks every
+    /// minute. The first run after the window pushes the batch even without
+    /// changes of its own. Zero, the default, pushes every run's changes.
+    pub fn batch_window(mut self, batch_window: Duration) -> Self {
+        self.batch_window = batch_window;
+        self
+    }
+
+    /// How many times to retry a fetch or push that failed because of the
+    /// network.
+    pub fn retries(mut self, retries: u32) -> Self {
+        self.retry.retries = retries;
+        self
+    }
+
+    /// How long to wait before the first retry. Each further retry waits
+    /// twice as long as the previous one.
+    pub fn retry_delay(mut self, retry_delay: Duration) -> Self {
+        self.retry.delay = retry_delay;
+        self
+    }
+
+    /// The remote to push to instead of the remote of HEAD's upstream.
+    pub fn remote<S: Into<String>>(mut self, remote: S) -> Self {
+        self.push_target.remote = Some(remote.into());
+        self
+    }
+
+    /// The branch on the remote to push to instead of HEAD's upstream branch.
+    pub fn branch<S: Into<String>>(mut self, branch: S) -> Self {
+        self.push_target.branch = Some(branch.into());
+        self
+    }
+
+    /// Adds a remote to push the auto commit to after the upstream, e.g., a
+    /// mirror on another host. It gets the same branch. A failed push to it
+    /// is only reported, see [`PushOutcome::Pushed`].
+    pub fn mirror<S: Into<String>>(mut self, mirror: S) -> Self {
+        self.mirrors.push(mirror.into());
+        self
+    }
+
+    /// Adds remotes to push to. See [`PushRequest::mirror`].
+    pub fn mirrors<I>(mut self, mirrors: I) -> Self
+    where
+        I: IntoIterator,
+        I::Item: Into<String>,
+    {
+        self.mirrors.extend(mirrors.into_iter().map(Into::into));
+        self
+    }
+
+    /// The author name of auto commits instead of `user.name`, e.g., to tell
+    /// them apart from your own commits.
+    pub fn author_name<S: Into<String>>(mut self, author_name: S) -> Self {
+        self.author.name = Some(author_name.into());
+        self
+    }
+
+    /// The author email of auto commits instead of `user.email`.
+    pub fn author_email<S: Into<String>>(mut self, author_email: S) -> Self {
+        self.author.email = Some(author_email.into());
+        self
+    }
+
+    /// The commit message template. See [`COMMIT_MESSAGE_PLACEHOLDERS`].
+    /// Without it, the message is "Update marks" followed by a list of the
+    /// committed files.
+    pub fn message<S: Into<String>>(mut self, message: S) -> Self {
+        self.message = Some(message.into());
+        self
+    }
+
+    /// The template of a lightweight tag to create on the pushed auto commit
+    /// and push along with it, e.g., `marks/{date}`. See
+    /// [`COMMIT_MESSAGE_PLACEHOLDERS`]. An existing tag isn't moved.
+    pub fn tag_template<S: Into<String>>(mut self, tag_template: S) -> Self {
+        self.tag_template = Some(tag_template.into());
+        self
+    }
+
+    /// How many snapshots of HEAD to keep, which are taken before auto
+    /// commits as [`SNAPSHOT_REFS`]`<seconds since the epoch>`. They keep the
+    /// earlier mark files even if the branch is reset or its upstream
+    /// rewritten. The oldest snapshots are deleted beyond the count, and 0,
+    /// the default, takes none.
+    pub fn snapshots(mut self, snapshots: usize) -> Self {
+        self.snapshots = snapshots;
+        self
+    }
+
+    /// Runs `git maintenance run` in the original repository after every
+    /// this many auto commits, so that years of small commits don't slow it
+    /// down. A failing maintenance is only reported, and 0, the default,
+    /// never runs it.
+    pub fn maintenance_every(mut self, maintenance_every: usize) -> Self {
+        self.maintenance_every = maintenance_every;
+        self
+    }
+
+    /// A shell command that validates each staged mark file, e.g.,
+    /// `hledger check -f`. It runs in the repository with the file's path as
+    /// its last argument, and a failure aborts the push.
+    pub fn validate_cmd<S: Into<String>>(mut self, validate_cmd: S) -> Self {
+        self.validate_cmd = Some(validate_cmd.into());
+        self
+    }
+
+    /// Whether to run the repository's `pre-commit` and `post-commit` hooks
+    /// around each auto commit, as `git commit` would. A failing `pre-commit`
+    /// hook aborts the push.
+    pub fn run_hooks(mut self, run_hooks: bool) -> Self {
+        self.run_hooks = run_hooks;
+        self
+    }
+
+    /// How to split the mark files into commits.
+    pub fn commit_strategy(mut self, commit_strategy: CommitStrategy) -> Self {
+        self.commit_strategy = commit_strategy;
+        self
+    }
+
+    /// Whether to print the diff of the mark files before committing them and
+    /// to include it in the outcome.
+    pub fn show_diff(mut self, show_diff: bool) -> Self {
+        self.show_diff = show_diff;
+        self
+    }
+
+    /// Whether to amend HEAD instead of adding a commit if HEAD is an auto
+    /// commit that hasn't been pushed yet. A failed push then keeps the auto
+    /// commit in the repository, so that the next run amends it.
+    pub fn amend(mut self, amend: bool) -> Self {
+        self.amend = amend;
+        self
+    }
+
+    /// Whether a run without changes fetches the upstream and fast-forwards
+    /// over auto commits that other machines pushed, so that several machines
+    /// updating the same wallet converge. The branch is only fast-forwarded
+    /// if the upstream contains it and adds nothing but auto commits.
+    pub fn follow_peers(mut self, follow_peers: bool) -> Self {
+        self.follow_peers = follow_peers;
+        self
+    }
+
+    /// Whether to force the push instead of rebasing onto the upstream, as
+    /// long as the remote branch is still where it was last fetched from.
+    /// This replaces an upstream that was rewritten with the local branch.
+    pub fn force_with_lease(mut self, force_with_lease: bool) -> Self {
+        self.force_with_lease = force_with_lease;
+        self
+    }
+
+    /// Whether to sign the auto commit. Without it, `commit.gpgsign` decides.
+    pub fn sign(mut self, sign: bool) -> Self {
+        self.sign = Some(sign);
+        self
+    }
+
+    /// Signs auto commits with `signer` instead of gpg. It implies signing
+    /// unless it's disabled with [`PushRequest::sign`].
+    pub fn signer<S: CommitSigner + 'static>(mut self, signer: S) -> Self {
+        self.signer = Some(Arc::new(signer));
+        self
+    }
+
+    /// Asks `confirmer` before committing and pushing, e.g., to let a user
+    /// review the changes.
+    pub fn confirmer<C: Confirmer + 'static>(mut self, confirmer: C) -> Self {
+        self.confirmer = Some(Arc::new(confirmer));
+        self
+    }
+
+    /// Whether to only stage the mark files in the copy and report what would
+    /// be pushed.
+    pub fn dry_run(mut self, dry_run: bool) -> Self {
+        self.dry_run = dry_run;
+        self
+    }
+
+    /// Returns the repository path.
+    pub fn repo(&self) -> &Path {
+        &self.repo
+    }
+
+    /// Returns whether this is a dry run.
+    pub fn is_dry_run(&self) -> bool {
+        self.dry_run
+    }
+
+    /// Returns the absolute paths of the auto files, with glob patterns
+    /// expanded.
+    pub fn auto_file_paths(&self) -> Result<Vec<PathBuf>, String> {
+        let repo: Repository = open_repository(&self.repo)?;
+        let workdir: PathBuf = repo
+            .workdir()
+            .ok_or("The repository has no working directory.")?
+            .canonicalize()
+            .map_err(|e| format!("Could not resolve the repository path: {}", e))?;
+        Ok(
+            expand_auto_files(&repo, &self.auto_files, self.changes.allow_new)?
+                .iter()
+                .map(|auto_file| workdir.join(auto_file))
+                .collect(),
+        )
+    }
+
+    /// Reports the changed auto files and what a push would do with them,
+    /// without changing anything.
+    pub fn status(&self) -> Result<PushStatus, String> {
+        push_status(self)
+    }
+
+    /// Checks whether a push could succeed, e.g., whether the remote is
+    /// reachable, without changing anything.
+    pub fn doctor(&self) -> Vec<Check> {
+        doctor(self)
+    }
+
+    /// Compares the auto files with the tip of the upstream branch on the
+    /// remote, e.g., for monitoring that the wallet is in sync. The remote is
+    /// fetched into a temporary repository, so nothing changes, not even the
+    /// remote-tracking branch.
+    pub fn verify(&self) -> Result<Verification, String> {
+        verify(self)
+    }
+
+    /// Summarizes how the auto files differ from the tip of the upstream
+    /// branch on the remote, fetched like by [`PushRequest::verify`].
+    ///
+    /// # Returns
+    ///
+    /// The summary, or `None` if the remote doesn't have the upstream branch.
+    pub fn diff(&self) -> Result<Option<MarksDiff>, String> {
+        diff(self)
+    }
+
+    /// Pushes the changed mark files.
+    pub fn push(&self) -> Result<PushOutcome, String> {
+        self.push_timed(&mut Timings::default())
+    }
+
+    /// Pushes the changed mark files and records how long each phase took.
+    pub fn push_timed(&self, timings: &mut Timings) -> Result<PushOutcome, String> {
+        let outcome: Result<PushOutcome, String> = run(self, timings);
+        if !self.dry_run {
+            log_run(&self.repo, &outcome);
+        }
+        outcome
+    }
+
+    /// Analyzes the history of auto commits and the runs that tried to push,
+    /// e.g., for tuning how often to push.
+    pub fn stats(&self) -> Result<HistoryStats, String> {
+        stats(self)
+    }
+
+    /// Squashes the history of a branch that only has auto commits, e.g., of
+    /// the marks repository, into one snapshot commit where it's older than
+    /// `keep`, and force-pushes the branch, so that clones stay small.
+    ///
+    /// The branch must be in sync with its upstream. The old history is
+    /// snapshotted under [`SNAPSHOT_REFS`] first.
+    pub fn compact(&self, keep: Duration) -> Result<Compaction, String> {
+        compact(self, keep)
+    }
+}
+
+/// The result of a [`PushRequest`] that didn't fail.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum PushOutcome {
+    /// Nothing was pushed.
+    Skipped(SkipReason),
+    /// A dry run staged the mark files in the copy.
+    DryRun {
+        /// The mark files that would be committed.
+        staged_files: Vec<PathBuf>,
+        /// The diff of the mark files, if requested.
+        diff: Option<String>,
+        /// The remote that would be pushed to.
+        remote: String,
+        /// The full name of the branch that would be pushed to.
+        branch: String,
+    },
+    /// The auto commit was pushed.
+    Pushed {
+        /// The committed mark files.
+        staged_files: Vec<PathBuf>,
+        /// The diff of the mark files, if requested.
+        diff: Option<String>,
+        /// The ID of the auto commit.
+        commit: Oid,
+        /// Whether the auto commit was rebased onto a moved upstream.
+        rebased: bool,
+        /// Whether the auto commit replaced an unpushed one.
+        amended: bool,
+        /// Whether the auto commit was made on the upstream instead of local
+        /// commits that diverged from it, see [`DivergencePolicy::ResetToRemote`].
+        reset: bool,
+        /// The remote that was pushed to.
+        remote: String,
+        /// The full name of the branch that was pushed to, e.g.,
+        /// `refs/heads/main`.
+        branch: String,
+        /// The pushes to the mirrors, which happen after the push to the
+        /// upstream succeeded.
+        mirrors: Vec<MirrorPush>,
+        /// The name of the tag that was pushed with the auto commit, e.g.,
+        /// `marks/2024-01-05`.
+        tag: Option<String>,
+        /// Why the original repository couldn't be brought up to date with
+        /// the pushed auto commit, if it couldn't. Pulling fixes it.
+        sync_error: Option<String>,
+    },
+    /// The auto commit was kept in the repository without being pushed, so
+    /// that the next run amends it, because the push failed or the batch
+    /// window hasn't passed yet.
+    Committed {
+        /// The committed mark files.
+        staged_files: Vec<PathBuf>,
+        /// The diff of the mark files, if requested.
+        diff: Option<String>,
+        /// The ID of the auto commit.
+        commit: Oid,
+        /// Whether the auto commit was rebased onto a moved upstream.
+        rebased: bool,
+        /// Whether the auto commit replaced an unpushed one.
+        amended: bool,
+        /// Whether the auto commit was made on the upstream instead of local
+        /// commits that diverged from it, see [`DivergencePolicy::ResetToRemote`].
+        reset: bool,
+    },
+}
+
+/// Why a run didn't push anything.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SkipReason {
+    /// HEAD and the auto files are unchanged since the last run.
+    Unchanged,
+    /// No mark file has changes to commit.
+    NoChanges,
+    /// The repository is in the middle of an operation, e.g., `rebase`.
+    OngoingOperation(&'static str),
+    /// The index has staged changes of other files.
+    IndexNotEmpty,
+    /// A mark file has staged changes.
+    StagedMarkFiles,
+    /// Another run on the same repository is in progress.
+    AnotherRunInProgress,
+    /// Files other than the auto files have uncommitted changes, which rules
+    /// out committing in place.
+    DirtyWorktree,
+    /// The [`Confirmer`] declined the push.
+    Declined,
+    /// HEAD's branch and its upstream have diverged, see
+    /// [`DivergencePolicy::Abort`].
+    Diverged,
+}
+
+impl SkipReason {
+    /// Returns a short code, e.g., `no-changes` or, for ongoing operations,
+    /// the operation's name.
+    pub fn code(&self) -> &'static str {
+        match self {
+            SkipReason::Unchanged => "unchanged",
+            SkipReason::NoChanges => "no-changes",
+            SkipReason::OngoingOperation(operation) => operation,
+            SkipReason::IndexNotEmpty => "index-not-empty",
+            SkipReason::StagedMarkFiles => "staged-mark-files",
+            SkipReason::AnotherRunInProgress => "another-run-in-progress",
+            SkipReason::DirtyWorktree => "dirty-worktree",
+            SkipReason::Declined => "declined",
+            SkipReason::Diverged => "diverged",
+        }
+    }
+}
+
+/// What a push would do, as reported by [`PushRequest::status`].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct PushStatus {
+    /// The auto files with uncommitted changes.
+    pub files: Vec<AutoFileStatus>,
+    /// Why some auto files can never be committed, e.g., because they don't
+    /// exist.
+    pub problems: Vec<String>,
+    /// Why a push would skip the repository, if it would.
+    pub skip_reason: Option<SkipReason>,
+}
+
+/// A changed auto file in a [`PushStatus`].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct AutoFileStatus {
+    /// The path relative to the working directory.
+    pub path: PathBuf,
+    /// The file's Git status.
+    pub status: Status,
+    /// What a push would do with the file.
+    pub action: FileAction,
+}
+
+/// What a push would do with a changed auto file.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum FileAction {
+    /// Commit the change.
+    Commit,
+    /// Leave the change alone, for the given reason.
+    Ignore(String),
+    /// Fail, for the given reason.
+    Abort(String),
+}
+
+/// The push of an auto commit to a mirror, see [`PushRequest::mirror`].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct MirrorPush {
+    /// The name of the mirror's remote.
+    pub remote: String,
+    /// Why the push failed, if it did.
+    pub result: Result<(), String>,
+}
+
+/// The result of a check of [`PushRequest::doctor`].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Check {
+    /// What was checked, e.g., `upstream`.
+    pub name: &'static str,
+    /// What was found, or why the check failed.
+    pub result: Result<String, String>,
+}
+
+/// The result of [`PushRequest::verify`].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Verification {
+    /// The auto files match the remote tip.
+    InSync {
+        /// The remote tip.
+        commit: Oid,
+    },
+    /// Some auto files differ from the remote tip.
+    OutOfSync {
+        /// The remote tip.
+        commit: Oid,
+        /// The differing auto files, relative to the working directory.
+        files: Vec<PathBuf>,
+    },
+    /// The remote doesn't have the upstream branch.
+    NoRemoteBranch,
+}
+
+/// The result of [`PushRequest::stats`].
+#[derive(Clone, Debug, PartialEq)]
+pub struct HistoryStats {
+    /// How many auto commits HEAD contains.
+    pub commits: usize,
+    /// How many auto commits were made per day, by the date of each commit in
+    /// its own time zone as YYYY-MM-DD, oldest first.
+    pub commits_per_day: Vec<(String, usize)>,
+    /// How many files an auto commit changes on average, or 0 without any.
+    pub average_files: f64,
+    /// The most runs that failed in a row among the logged ones.
+    pub longest_failure_streak: usize,
+    /// How many of the latest runs failed in a row.
+    pub current_failure_streak: usize,
+}
+
+/// The result of [`PushRequest::compact`].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Compaction {
+    /// No commit but the root is older than the retention window.
+    NothingToCompact,
+    /// The old history was squashed and the branch pushed.
+    Compacted {
+        /// How many commits were squashed into the snapshot commit.
+        squashed: usize,
+        /// The new tip of the branch.
+        commit: Oid,
+    },
+}
+
+/// The result of [`PushRequest::diff`].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct MarksDiff {
+    /// The remote tip that the auto files were compared with.
+    pub commit: Oid,
+    /// The auto files that differ from the remote tip.
+    pub files: Vec<FileDiff>,
+}
+
+/// How an auto file differs from the remote tip.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct FileDiff {
+    /// The path, relative to the working directory.
+    pub path: PathBuf,
+    /// How many lines the working directory adds.
+    pub added: usize,
+    /// How many lines the working directory removes.
+    pub removed: usize,
+    /// When the remote history last changed the file, as an RFC 3339
+    /// timestamp, or `None` if the remote tip lacks it.
+    pub last_pushed: Option<String>,
+}
+
+impl Verification {
+    /// Returns a short code, e.g., `in-sync`.
+    pub fn code(&self) -> &'static str {
+        match self {
+            Verification::InSync { .. } => "in-sync",
+            Verification::OutOfSync { .. } => "out-of-sync",
+            Verification::NoRemoteBranch => "no-remote-branch",
+        }
+    }
+}
+
+/// Which changes of mark files are pushed.
+#[derive(Clone, Copy, Debug, Default)]
+struct ChangeFilter {
+    /// Whether to skip mode-only changes and keep the committed modes.
+    ignore_mode_changes: bool,
+    /// Whether to push untracked mark files.
+    allow_new: bool,
+    /// Whether to push deletions of mark files.
+    allow_delete: bool,
+    /// Whether to refuse changes other than flipped transaction marks.
+    marks_only: bool,
+    /// The most mark files that may change.
+    max_files: Option<usize>,
+    /// The most lines that may be added and removed in total.
+    max_changed_lines: Option<usize>,
+}
+
+/// How to retry network operations that failed because of the network.
+#[derive(Clone, Copy, Debug)]
+struct RetryPolicy {
+    /// The number of retries.
+    retries: u32,
+    /// The wait before the first retry, which doubles with each retry.
+    delay: Duration,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        RetryPolicy {
+            retries: 0,
+            delay: Duration::from_secs(5),
+        }
+    }
+}
+
+/// Who auto commits are attributed to. Unset parts default to the Git config.
+#[derive(Clone, Debug, Default)]
+struct Identity {
+    name: Option<String>,
+    email: Option<String>,
+}
+
+/// Where to push the auto commit. Unset parts default to HEAD's upstream.
+#[derive(Clone, Debug, Default)]
+struct PushTarget {
+    /// The name of the remote, e.g., `origin`.
+    remote: Option<String>,
+    /// The name of the branch on the remote, e.g., `main`.
+    branch: Option<String>,
+}
+
+/// What to do about Syncthing conflict copies (`*.sync-conflict-*`) of auto files.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize, ValueEnum)]
+#[serde(rename_all = "kebab-case")]
+pub enum SyncConflictPolicy {
+    /// Report the conflict copies and stop.
+    Abort,
+    /// Keep whichever of the auto file and its copies was modified last.
+    PreferNewest,
+    /// Merge the copies into the auto file, using HEAD's version as the base.
+    Merge,
+}
+
+/// What to do if HEAD's branch and its upstream have diverged, i.e., each has
+/// commits that the other lacks, before the auto commit is made.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize, ValueEnum)]
+#[serde(rename_all = "kebab-case")]
+pub enum DivergencePolicy {
+    /// Skip the run.
+    Abort,
+    /// Commit on HEAD and rebase the local commits and the auto commit onto
+    /// the upstream before pushing.
+    Rebase,
+    /// Drop the local commits, make the auto commit on the upstream, and move
+    /// the branch of the original repository there. The dropped commits stay
+    /// in the reflog.
+    ResetToRemote,
+}
+
+/// Where the auto commit is made, so that a manual change ongoing in the
+/// original repository is never disturbed.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize, ValueEnum)]
+#[serde(rename_all = "kebab-case")]
+pub enum Workspace {
+    /// A temporary copy of the repository that shares its object store.
+    Copy,
+    /// A temporary linked worktree, which shares the refs too and is removed
+    /// afterwards.
+    Worktree,
+    /// The original repository itself. It's only used if no tracked file but
+    /// the auto files has uncommitted changes.
+    InPlace,
+}
+
+/// How the changed mark files are split into auto commits.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize, ValueEnum)]
+#[serde(rename_all = "kebab-case")]
+pub enum CommitStrategy {
+    /// A single commit of all mark files.
+    Combined,
+    /// A commit per mark file, with the file's path in its message.
+    PerFile,
+}
+
+/// Wall-clock durations of the phases of a run.
+#[derive(Clone, Debug, Default)]
+pub struct Timings {
+    phases: Vec<(&'static str, Duration)>,
+}
+
+impl Timings {
+    /// Runs `f` and adds how long it took to the given phase, which is
+    /// recorded if it wasn't yet.
+    fn measure<T, F>(&mut self, phase: &'static str, f: F) -> T
+    where
+        F: FnOnce() -> T,
+    {
+        let start = Instant::now();
+        let result = f();
+        match self.phases.iter_mut().find(|(name, _)| *name == phase) {
+            Some((_, duration)) => *duration += start.elapsed(),
+            None => self.phases.push((phase, start.elapsed())),
+        }
+        result
+    }
+}
+
+/// Formats the timings as a table with a line per phase, in the order the
+/// phases first ran.
+impl fmt::Display for Timings {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Timings:")?;
+        for (phase, duration) in &self.phases {
+            write!(f, "\n  {:<10} {:>10.3?}", phase, duration)?;
+        }
+        Ok(())
+    }
+}
+
+/// How long a [`SyncService`] waits for quiet after a change by default.
+pub const DEFAULT_DEBOUNCE: Duration = Duration::from_secs(2);
+
+/// What a [`SyncService`] reports to its subscribers.
+#[derive(Clone, Debug)]
+pub enum SyncEvent {
+    /// A request ran, either when the service started or after its auto
+    /// files changed.
+    Ran {
+        /// The index of the request in the service's requests.
+        request: usize,
+        /// The outcome of the run.
+        outcome: Result<PushOutcome, String>,
+        /// How long the phases of the run took.
+        timings: Timings,
+    },
+    /// Watching the auto files failed, which stopped the service.
+    Failed(String),
+}
+
+/// Watches the auto files of requests and pushes whenever they change, in a
+/// background thread, e.g., for an application that hosts the sync.
+///
+/// ```no_run
+/// use git_auto_commit::{PushRequest, SyncEvent, SyncService};
+///
+/// let mut service = SyncService::new(vec![
+///     PushRequest::new("/home/me/wallet").auto_file("marks/checking.journal"),
+/// ]);
+/// let events = service.subscribe();
+/// service.start()?;
+/// for event in events {
+///     if let SyncEvent::Ran { outcome, .. } = event {
+///         println!("{:?}", outcome);
+///     }
+/// }
+/// # Ok::<(), String>(())
+/// ```
+#[derive(Debug)]
+pub struct SyncService {
+    requests: Vec<PushRequest>,
+    debounce: Duration,
+    subscribers: Arc<Mutex<Vec<mpsc::Sender<SyncEvent>>>>,
+    worker: Option<SyncWorker>,
+}
+
+/// The running part of a [`SyncService`].
+#[derive(Debug)]
+struct SyncWorker {
+    /// Keeps the auto files watched.
+    _watcher: RecommendedWatcher,
+    /// Wakes the thread up to stop it.
+    sender: mpsc::Sender<WatchMessage>,
+    thread: thread::JoinHandle<()>,
+}
+
+/// What wakes up the thread of a [`SyncService`].
+#[derive(Debug)]
+enum WatchMessage {
+    /// A file system event in a watched directory.
+    Changed(notify::Result<Event>),
+    /// [`SyncService::stop`] was called.
+    Stop,
+}
+
+impl SyncService {
+    /// Creates a stopped service for the requests.
+    pub fn new(requests: Vec<PushRequest>) -> Self {
+        SyncService {
+            requests,
+            debounce: DEFAULT_DEBOUNCE,
+            subscribers: Arc::default(),
+            worker: None,
+        }
+    }
+
+    /// Sets how long to wait for quiet after a change before pushing, so that
+    /// a burst of writes results in one commit [default: 2 seconds].
+    pub fn debounce(mut self, debounce: Duration) -> Self {
+        self.debounce = debounce;
+        self
+    }
+
+    /// Returns the requests that the service runs.
+    pub fn requests(&self) -> &[PushRequest] {
+        &self.requests
+    }
+
+    /// Returns a receiver of the events from now on. Subscribe before
+    /// starting to receive the runs that catch up with earlier changes.
+    pub fn subscribe(&self) -> mpsc::Receiver<SyncEvent> {
+        let (sender, receiver) = mpsc::channel();
+        self.subscribers
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .push(sender);
+        receiver
+    }
+
+    /// Starts watching the auto files and runs every request once to catch up
+    /// with changes made while nothing was watching.
+    ///
+    /// The directories that contain the auto files are watched, because
+    /// editors often replace a file instead of writing to it. Only the
+    /// requests with changed auto files run. Glob patterns of the auto files
+    /// are expanded now, so restart the service to watch new matching files.
+    pub fn start(&mut self) -> Result<(), String> {
+        if self.worker.is_some() {
+            return Err("The sync service is running already.".to_string());
+        }
+        let mut watched_files: HashMap<PathBuf, usize> = HashMap::new();
+        for (index, request) in self.requests.iter().enumerate() {
+            let auto_files: Vec<PathBuf> = request.auto_file_paths()?;
+            info!(
+                "Watching {} auto file(s) in {}.",
+                auto_files.len(),
+                request.repo().display()
+            );
+            watched_files.extend(auto_files.into_iter().map(|path| (path, index)));
+        }
+        let watched_dirs: HashSet<&Path> = watched_files
+            .keys()
+            .filter_map(|path| path.parent())
+            .collect();
+
+        let (sender, receiver) = mpsc::channel::<WatchMessage>();
+        let event_sender = sender.clone();
+        let mut watcher = notify::recommended_watcher(move |event| {
+            // The receiver is gone once the service stopped.
+            let _ = event_sender.send(WatchMessage::Changed(event));
+        })
+        .map_err(|e| format!("Could not start watching files: {}", e))?;
+        for dir in &watched_dirs {
+            watcher
+                .watch(dir, RecursiveMode::NonRecursive)
+                .map_err(|e| format!("Could not watch {}: {}", dir.display(), e))?;
+        }
+
+        let requests: Vec<PushRequest> = self.requests.clone();
+        let debounce: Duration = self.debounce;
+        let subscribers = Arc::clone(&self.subscribers);
+        let thread = thread::spawn(move || {
+            let emit = |event: SyncEvent| {
+                subscribers
+                    .lock()
+                    .unwrap_or_else(PoisonError::into_inner)
+                    .retain(|subscriber| subscriber.send(event.clone()).is_ok());
+            };
+            if let Err(e) = watch_auto_files(&requests, debounce, &watched_files, &receiver, &emit)
+            {
+                emit(SyncEvent::Failed(e));
+            }
+        });
+        self.worker = Some(SyncWorker {
+            _watcher: watcher,
+            sender,
+            thread,
+        });
+        Ok(())
+    }
+
+    /// Stops watching and waits for a run in progress to finish. Dropping the
+    /// service stops it, too.
+    pub fn stop(&mut self) {
+        if let Some(worker) = self.worker.take() {
+            // The thread is gone already if watching failed.
+            let _ = worker.sender.send(WatchMessage::Stop);
+            if worker.thread.join().is_err() {
+                warn!("The sync service's thread panicked.");
+            }
+        }
+    }
+
+    /// Returns whether the service was started and hasn't stopped since.
+    pub fn is_running(&self) -> bool {
+        self.worker
+            .as_ref()
+            .is_some_and(|worker| !worker.thread.is_finished())
+    }
+}
+
+impl Drop for SyncService {
+    fn drop(&mut self) {
+        self.stop();
+    }
+}
+
+/// The modification times and sizes of files, or `None` for a file that
+/// doesn't exist.
+type Fingerprint = Vec<Option<(SystemTime, u64)>>;
+
+/// Reads the fingerprint of files without involving Git.
+fn fingerprint(paths: &[PathBuf]) -> Fingerprint {
+    paths
+        .iter()
+        .map(|path| {
+            let metadata = fs::metadata(path).ok()?;
+            Some((metadata.modified().ok()?, metadata.len()))
+        })
+        .collect()
+}
+
+/// Runs the requests whenever their auto files change, until it's told to
+/// stop.
+///
+/// Events don't always mean changes, e.g., when an auto file is only read. A
+/// request doesn't run again if the fingerprint of its auto files and their
+/// directories is the same as at its last successful run. A directory's
+/// modification time changes when a file appears in it, e.g., a Syncthing
+/// conflict copy. A pending batch runs again at the end of its window, see
+/// [`PushRequest::batch_window`].
+///
+/// # Arguments
+///
+/// * `requests` - The requests to run.
+/// * `debounce` - How long to wait for quiet after a change.
+/// * `watched_files` - The absolute paths of auto files, mapped to the index
+///   of their request.
+/// * `receiver` - The file system events and the stop message.
+/// * `emit` - Reports the runs.
+fn watch_auto_files(
+    requests: &[PushRequest],
+    debounce: Duration,
+    watched_files: &HashMap<PathBuf, usize>,
+    receiver: &mpsc::Receiver<WatchMessage>,
+    emit: &dyn Fn(SyncEvent),
+) -> Result<(), String> {
+    let watch_error = |e: notify::Error| format!("Watching files failed: {}", e);
+    let disconnected = || "The file watcher stopped unexpectedly.".to_string();
+    let fingerprinted: Vec<Vec<PathBuf>> = (0..requests.len())
+        .map(|index| {
+            let mut paths: Vec<PathBuf> = watched_files
+                .iter()
+                .filter(|(_, &request)| request == index)
+                .flat_map(|(path, _)| {
+                    path.parent()
+                        .map(Path::to_path_buf)
+                        .into_iter()
+                        .chain([path.clone()])
+                })
+                .collect();
+            paths.sort();
+            paths.dedup();
+            paths
+        })
+        .collect();
+    let mut last_runs: Vec<Option<Fingerprint>> = vec![None; requests.len()];
+    let deadlines: Vec<Cell<Option<SystemTime>>> =
+        requests.iter().map(|_| Cell::new(None)).collect();
+    let mut run = |index: usize, force: bool| {
+        let before: Fingerprint = fingerprint(&fingerprinted[index]);
+        if !force && last_runs[index].as_ref() == Some(&before) {
+            debug!(
+                "The auto files of {} are unchanged since the last run.",
+                requests[index].repo().display()
+            );
+            return;
+        }
+        let mut timings = Timings::default();
+        let outcome: Result<PushOutcome, String> = requests[index].push_timed(&mut timings);
+        // A failed run is tried again on the next event, and so is one that
+        // another run kept from looking at the changes.
+        let done: bool = !matches!(
+            outcome,
+            Err(_) | Ok(PushOutcome::Skipped(SkipReason::AnotherRunInProgress))
+        );
+        last_runs[index] = done.then_some(before);
+        emit(SyncEvent::Ran {
+            request: index,
+            outcome,
+            timings,
+        });
+        // A batch that is due already, e.g., because its push failed, waits
+        // for the next change instead.
+        let deadline: Option<SystemTime> = open_repository(&requests[index].repo)
+            .and_then(|repo| batch_deadline(&repo, &requests[index]))
+            .unwrap_or(None)
+            .filter(|deadline| *deadline > SystemTime::now());
+        deadlines[index].set(deadline);
+    };
+
+    for index in 0..requests.len() {
+        run(index, false);
+    }
+    loop {
+        let next_deadline: Option<SystemTime> = deadlines.iter().filter_map(Cell::get).min();
+        let message: WatchMessage = match next_deadline {
+            Some(deadline) => {
+                let timeout: Duration = deadline
+                    .duration_since(SystemTime::now())
+                    .unwrap_or(Duration::ZERO);
+                match receiver.recv_timeout(timeout) {
+                    Ok(message) => message,
+                    Err(RecvTimeoutError::Timeout) => {
+                        let now: SystemTime = SystemTime::now();
+                        let due: Vec<usize> = (0..requests.len())
+                            .filter(|index| {
+                                deadlines[*index]
+                                    .get()
+                                    .is_some_and(|deadline| deadline <= now)
+                            })
+                            .collect();
+                        for index in due {
+                            run(index, true);
+                        }
+                        continue;
+                    }
+                    Err(RecvTimeoutError::Disconnected) => return Err(disconnected()),
+                }
+            }
+            None => receiver.recv().map_err(|_| disconnected())?,
+        };
+        let event: Event = match message {
+            WatchMessage::Changed(event) => event.map_err(watch_error)?,
+            WatchMessage::Stop => return Ok(()),
+        };
+        let mut pending: HashSet<usize> = affected_requests(&event, watched_files);
+        if pending.is_empty() {
+            continue;
+        }
+        loop {
+            match receiver.recv_timeout(debounce) {
+                Ok(WatchMessage::Changed(event)) => {
+                    pending.extend(affected_requests(
+                        &event.map_err(watch_error)?,
+                        watched_files,
+                    ));
+                }
+                Ok(WatchMessage::Stop) => return Ok(()),
+                Err(RecvTimeoutError::Timeout) => break,
+                Err(RecvTimeoutError::Disconnected) => return Err(disconnected()),
+            }
+        }
+        let mut pending: Vec<usize> = pending.into_iter().collect();
+        pending.sort_unstable();
+        for index in pending {
+            run(index, false);
+        }
+    }
+}
+
+/// Finds the requests whose auto files a file system event concerns.
+///
+/// Syncthing conflict copies count too, so that they're resolved right away.
+///
+/// # Arguments
+///
+/// * `event` - The file system event.
+/// * `watched_files` - The absolute paths of auto files, mapped to the index
+///   of their request.
+fn affected_requests(event: &Event, watched_files: &HashMap<PathBuf, usize>) -> HashSet<usize> {
+    let mut affected: HashSet<usize> = HashSet::new();
+    for path in &event.paths {
+        if let Some(&request) = watched_files.get(path) {
+            affected.insert(request);
+            continue;
+        }
+        let is_sync_conflict: bool = path
+            .file_name()
+            .and_then(|name| name.to_str())
+            .is_some_and(|name| name.contains(".sync-conflict-"));
+        if is_sync_conflict {
+            affected.extend(
+                watched_files
+                    .iter()
+                    .filter(|(auto_file, _)| auto_file.parent() == path.parent())
+                    .map(|(_, &request)| request),
+            );
+        }
+    }
+    affected
+}
+
+/// A modification of git2::StatusEntry that owns its path.
+///
+/// Owning the path gives us a saner interface for working with the path without
+/// checking the Option every time.
+#[derive(Clone)]
+struct StatusEntryBetter {
+    pub path: PathBuf,
+    pub status: Status,
+    /// The path that a renamed file had in the index.
+    pub old_path: Option<PathBuf>,
+}
+
+impl StatusEntryBetter {
+    fn from_status_entry(status_entry: &StatusEntry) -> Option<Self> {
+        let path: &str = status_entry.path()?;
+        let status: Status = status_entry.status();
+        // Git reports a rename under its old path.
+        if status.contains(Status::WT_RENAMED) {
+            let new_path: &Path = status_entry.index_to_workdir()?.new_file().path()?;
+            return Some(StatusEntryBetter {
+                path: new_path.to_path_buf(),
+                status,
+                old_path: Some(PathBuf::from(path)),
+            });
+        }
+        Some(StatusEntryBetter {
+            path: PathBuf::from(path),
+            status,
+            old_path: None,
+        })
+    }
+}
+
+/// Adds the offending path to an I/O error.
+fn with_path(err: io::Error, path: &Path) -> io::Error {
+    io::Error::new(err.kind(), format!("{}: {}", path.display(), err))
+}
+
+/// Recreates the directory tree of `from` under `to` and lists files to copy.
+///
+/// # Arguments
+///
+/// * `from` - The source directory.
+/// * `to` - The target directory. It must already exist.
+/// * `skip` - Source paths to leave out.
+/// * `files` - Where to put (source, target) pairs of files to copy.
+/// * `unreadable` - Where to put source directories we may not list.
+fn prepare_copy(
+    from: &Path,
+    to: &Path,
+    skip: &[PathBuf],
+    files: &mut Vec<(PathBuf, PathBuf)>,
+    unreadable: &mut Vec<PathBuf>,
+) -> io::Result<()> {
+    for entry in fs::read_dir(from).map_err(|e| with_path(e, from))? {
+        let entry = entry.map_err(|e| with_path(e, from))?;
+        let source = entry.path();
+        if skip.contains(&source) {
+            continue;
+        }
+        let target = to.join(entry.file_name());
+        let file_type = entry.file_type().map_err(|e| with_path(e, &source))?;
+        if file_type.is_dir() {
+            fs::create_dir(&target).map_err(|e| with_path(e, &target))?;
+            match prepare_copy(&source, &target, skip, files, unreadable) {
+                Err(e) if e.kind() == io::ErrorKind::PermissionDenied => unreadable.push(source),
+                result => result?,
+            }
+        } else if file_type.is_symlink() {
+            copy_symlink(&source, &target)?;
+        } else {
+            files.push((source, target));
+        }
+    }
+    Ok(())
+}
+
+#[cfg(unix)]
+fn copy_symlink(from: &Path, to: &Path) -> io::Result<()> {
+    let link = fs::read_link(from).map_err(|e| with_path(e, from))?;
+    std::os::unix::fs::symlink(link, to).map_err(|e| with_path(e, to))
+}
+
+#[cfg(not(unix))]
+fn copy_symlink(from: &Path, to: &Path) -> io::Result<()> {
+    fs::copy(from, to)
+        .map(|_| ())
+        .map_err(|e| with_path(e, from))
+}
+
+/// Copies the content of one directory to another.
+///
+/// The directory tree is created first and the files are then copied by a
+/// small pool of worker threads. Wallet repositories consist mostly of many
+/// small object files, so copying them one at a time leaves the disk idle.
+///
+/// # Arguments
+///
+/// * `from` - The source directory
+/// * `to` - The target directory.
+/// * `skip` - Source paths to leave out.
+///
+/// # Returns
+///
+/// The source files and directories that we weren't permitted to read. It's up
+/// to the caller to decide whether the copy is usable without them.
+fn copy_content<P, Q>(from: P, to: Q, skip: &[PathBuf]) -> io::Result<Vec<PathBuf>>
+where
+    P: AsRef<Path>,
+    Q: AsRef<Path>,
+{
+    let mut files: Vec<(PathBuf, PathBuf)> = Vec::new();
+    let mut unreadable: Vec<PathBuf> = Vec::new();
+    prepare_copy(
+        from.as_ref(),
+        to.as_ref(),
+        skip,
+        &mut files,
+        &mut unreadable,
+    )?;
+
+    let workers: usize = thread::available_parallelism()
+        .map_or(1, |n| n.get())
+        .min(MAX_COPY_WORKERS);
+    debug!(
+        "Copying {} file(s) with {} thread(s).",
+        files.len(),
+        workers
+    );
+    let next_file = AtomicUsize::new(0);
+    let unreadable = Mutex::new(unreadable);
+    thread::scope(|scope| {
+        let handles: Vec<_> = (0..workers)
+            .map(|_| {
+                scope.spawn(|| -> io::Result<()> {
+                    // fs::copy uses the kernel's copy facilities where they
+                    // exist, so there's no user-space buffer to tune.
+                    while let Some((source, target)) =
+                        files.get(next_file.fetch_add(1, Ordering::Relaxed))
+                    {
+                        match fs::copy(source, target) {
+                            Ok(_) => {}
+                            Err(e) if e.kind() == io::ErrorKind::PermissionDenied => unreadable
+                                .lock()
+                                .expect("A copy worker panicked.")
+                                .push(source.clone()),
+                            Err(e) => return Err(with_path(e, source)),
+                        }
+                    }
+                    Ok(())
+                })
+            })
+            .collect();
+        handles
+            .into_iter()
+            .try_for_each(|handle| handle.join().expect("A copy worker panicked."))
+    })?;
+    Ok(unreadable.into_inner().expect("A copy worker panicked."))
+}
+
+/// Returns the common Git directory of a linked worktree's Git directory, or
+/// the Git directory itself if it isn't a linked worktree's.
+fn common_dir(git_dir: &Path) -> PathBuf {
+    match fs::read_to_string(git_dir.join("commondir")) {
+        Ok(common_dir) => git_dir.join(common_dir.trim()),
+        Err(_) => git_dir.to_path_buf(),
+    }
+}
+
+/// Copies a Git directory except for its object store, which the copy shares
+/// with the original through `objects/info/alternates`, like
+/// `git clone --shared`.
+///
+/// Wallet repositories accumulate years of history, so copying the objects
+/// dominated the run time. New objects are written into the copy only.
+///
+/// The Git directory of a linked worktree only has the worktree's own files,
+/// e.g., `HEAD` and `index`, and points to the common Git directory for the
+/// rest. The copy combines both into a standalone Git directory. Linked
+/// worktrees aren't copied, as the copy has no use for them.
+///
+/// # Arguments
+///
+/// * `git_dir` - The original Git directory.
+/// * `to` - The Git directory of the copy. It must not exist yet.
+///
+/// # Returns
+///
+/// The source files and directories that we weren't permitted to read.
+fn copy_git_dir(git_dir: &Path, to: &Path) -> io::Result<Vec<PathBuf>> {
+    let common_dir: PathBuf = common_dir(git_dir);
+    let objects: PathBuf = common_dir.join("objects");
+    // The submodules' repositories aren't needed, as their working trees
+    // aren't copied either.
+    let modules: PathBuf = common_dir.join("modules");
+    let worktrees: PathBuf = common_dir.join("worktrees");
+    fs::create_dir(to).map_err(|e| with_path(e, to))?;
+    let unreadable: Vec<PathBuf> = if common_dir == git_dir {
+        copy_content(git_dir, to, &[objects.clone(), modules, worktrees])?
+    } else {
+        let mut unshared: Vec<PathBuf> = Vec::new();
+        for entry in fs::read_dir(&common_dir).map_err(|e| with_path(e, &common_dir))? {
+            let path: PathBuf = entry.map_err(|e| with_path(e, &common_dir))?.path();
+            let shared: bool = path
+                .file_name()
+                .is_some_and(|name| SHARED_GIT_DIR_ENTRIES.iter().any(|shared| name == *shared));
+            if !shared {
+                unshared.push(path);
+            }
+        }
+        let mut unreadable: Vec<PathBuf> = copy_content(&common_dir, to, &unshared)?;
+        // Leave out the links between the worktree and the common directory,
+        // and the worktree's own reflogs and refs, e.g., of a bisection.
+        let links: Vec<PathBuf> = ["commondir", "gitdir", "locked", "logs", "refs"]
+            .iter()
+            .map(|name| git_dir.join(name))
+            .collect();
+        unreadable.extend(copy_content(git_dir, to, &links)?);
+        unreadable
+    };
+    let info: PathBuf = to.join("objects").join("info");
+    fs::create_dir_all(&info).map_err(|e| with_path(e, &info))?;
+    let pack: PathBuf = to.join("objects").join("pack");
+    fs::create_dir(&pack).map_err(|e| with_path(e, &pack))?;
+    let objects: PathBuf = objects.canonicalize().map_err(|e| with_path(e, &objects))?;
+    let alternates: PathBuf = info.join("alternates");
+    fs::write(&alternates, format!("{}\n", objects.display()))
+        .map_err(|e| with_path(e, &alternates))?;
+    Ok(unreadable)
+}
+
+/// Copies the given files of a working tree. Files missing from the original
+/// are removed from the copy, as they may be deleted auto files.
+///
+/// # Arguments
+///
+/// * `workdir` - The original working directory.
+/// * `to` - The working directory of the copy.
+/// * `paths` - The files, relative to the working directory.
+fn copy_worktree_files(workdir: &Path, to: &Path, paths: &[PathBuf]) -> io::Result<()> {
+    for path in paths {
+        let source: PathBuf = workdir.join(path);
+        let target: PathBuf = to.join(path);
+        let metadata = match fs::symlink_metadata(&source) {
+            Ok(metadata) => metadata,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => match fs::remove_file(&target) {
+                Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(with_path(e, &target)),
+                _ => continue,
+            },
+            Err(e) => return Err(with_path(e, &source)),
+        };
+        if let Some(parent) = target.parent() {
+            fs::create_dir_all(parent).map_err(|e| with_path(e, parent))?;
+        }
+        if metadata.file_type().is_symlink() {
+            copy_symlink(&source, &target)?;
+        } else {
+            fs::copy(&source, &target).map_err(|e| with_path(e, &source))?;
+        }
+    }
+    Ok(())
+}
+
+/// Returns the tracked `.gitattributes` and `.gitignore` files, which affect
+/// how Git stages files and which untracked files it reports.
+fn git_rule_files(repo: &Repository) -> Result<Vec<PathBuf>, String> {
+    let index: Index = repo
+        .index()
+        .map_err(|e| format!("Could not fetch the index: {}", e))?;
+    Ok(index
+        .iter()
+        .map(|entry| PathBuf::from(String::from_utf8_lossy(&entry.path).into_owned()))
+        .filter(|path| {
+            path.file_name()
+                .is_some_and(|name| name == ".gitattributes" || name == ".gitignore")
+        })
+        .collect())
+}
+
+/// Copies a repository into a new temporary directory inside `parent`.
+///
+/// Only the Git directory and the files that matter for committing the auto
+/// files are copied; the rest of the working tree is left out.
+///
+/// # Arguments
+///
+/// * `repo` - The original repository.
+/// * `paths` - The working tree files to copy, relative to its root.
+/// * `parent` - The directory in which to create the temporary directory.
+///
+/// # Returns
+///
+/// The temporary directory and the source paths that couldn't be read.
+fn copy_repository_into(
+    repo: &Repository,
+    paths: &[PathBuf],
+    parent: &Path,
+) -> Result<(tempfile::TempDir, Vec<PathBuf>), String> {
+    let workdir: &Path = repo
+        .workdir()
+        .ok_or("The repository has no working directory.")?;
+    let temp_dir: tempfile::TempDir = tempdir_in(parent).map_err(|io_err| {
+        format!(
+            "Could not create a temporary directory in {}:\n{}",
+            parent.display(),
+            io_err
+        )
+    })?;
+    debug!(
+        "Created a temporary directory at {}.",
+        temp_dir.path().display()
+    );
+    let copy_error = |fs_err: io::Error| {
+        format!(
+            "Could not copy the repository {} to {}:\n{}",
+            workdir.display(),
+            temp_dir.path().display(),
+            fs_err
+        )
+    };
+    let unreadable: Vec<PathBuf> =
+        copy_git_dir(repo.path(), &temp_dir.path().join(".git")).map_err(copy_error)?;
+    copy_worktree_files(workdir, temp_dir.path(), paths).map_err(copy_error)?;
+    Ok((temp_dir, unreadable))
+}
+
+/// Reads the effective configuration of a repository.
+///
+/// Multi-valued variables are reduced to their last value, which is the one Git
+/// uses for single-valued lookups.
+fn effective_config(repo: &Repository) -> Result<HashMap<String, String>, String> {
+    let config: Config = repo
+        .config()
+        .map_err(|e| format!("Could not read the repository configuration: {}", e))?;
+    let mut entries = config
+        .entries(None)
+        .map_err(|e| format!("Could not list the repository configuration: {}", e))?;
+    let mut values: HashMap<String, String> = HashMap::new();
+    while let Some(entry) = entries.next() {
+        let entry = entry.map_err(|e| format!("Could not read a configuration entry: {}", e))?;
+        if let (Some(name), Some(value)) = (entry.name(), entry.value()) {
+            values.insert(name.to_string(), value.to_string());
+        }
+    }
+    Ok(values)
+}
+
+/// Pins the original repository's effective configuration in its copy.
+///
+/// Conditional includes, e.g., `includeIf.gitdir:` identities, depend on the
+/// repository's location, so they stop applying once the repository is
+/// copied. Every value the copy sees differently is written into the copy's
+/// local configuration.
+///
+/// # Arguments
+///
+/// * `original` - The original repository.
+/// * `copy` - The copied repository.
+fn pin_effective_config(original: &Repository, copy: &Repository) -> Result<(), String> {
+    let original_values: HashMap<String, String> = effective_config(original)?;
+    let copy_values: HashMap<String, String> = effective_config(copy)?;
+    let mut local_config: Config = copy
+        .config()
+        .and_then(|config| config.open_level(ConfigLevel::Local))
+        .map_err(|e| format!("Could not open the copy's local configuration: {}", e))?;
+    for (name, value) in &original_values {
+        if name.starts_with("include.") || name.starts_with("includeif.") {
+            continue;
+        }
+        if copy_values.get(name) != Some(value) {
+            local_config
+                .set_str(name, value)
+                .map_err(|e| format!("Could not set {} in the copy: {}", name, e))?;
+        }
+    }
+    Ok(())
+}
+
+/// Makes a remote URL independent of the repository's location.
+///
+/// Relative local paths are resolved against `base`. Anything that looks like
+/// a URL or an scp-like `host:path` address is returned unchanged.
+fn resolve_remote_url(url: &str, base: &Path) -> Result<String, String> {
+    let is_url = url.contains("://");
+    let is_scp_like = url
+        .find(':')
+        .is_some_and(|colon| !url[..colon].contains('/'));
+    let path = Path::new(url);
+    if is_url || is_scp_like || path.is_absolute() {
+        return Ok(url.to_string());
+    }
+    let resolved: PathBuf = base.join(path);
+    resolved.to_str().map(str::to_string).ok_or_else(|| {
+        format!(
+            "Could not resolve the remote URL {}, because {} isn’t valid Unicode.",
+            url,
+            resolved.display()
+        )
+    })
+}
+
+/// Pins the original repository's remote URLs in its copy.
+///
+/// libgit2 applies `url.<base>.insteadOf` rewrites when it loads a remote, and
+/// relative paths would be resolved against the copy. Both are resolved in the
+/// context of the original repository and stored in the copy.
+///
+/// # Arguments
+///
+/// * `original` - The original repository.
+/// * `copy` - The copied repository.
+fn pin_remote_urls(original: &Repository, copy: &Repository) -> Result<(), String> {
+    let workdir: &Path = original
+        .workdir()
+        .ok_or("The repository has no working directory.")?;
+    let remote_names = original
+        .remotes()
+        .map_err(|e| format!("Could not list remotes: {}", e))?;
+    for name in remote_names.iter().flatten() {
+        let remote = original
+            .find_remote(name)
+            .map_err(|e| format!("Could not load the remote {}: {}", name, e))?;
+        if let Some(url) = remote.url() {
+            copy.remote_set_url(name, &resolve_remote_url(url, workdir)?)
+                .map_err(|e| format!("Could not set the URL of {} in the copy: {}", name, e))?;
+        }
+        if let Some(pushurl) = remote.pushurl() {
+            copy.remote_set_pushurl(name, Some(&resolve_remote_url(pushurl, workdir)?))
+                .map_err(|e| {
+                    format!("Could not set the push URL of {} in the copy: {}", name, e)
+                })?;
+        }
+    }
+    Ok(())
+}
+
+/// Copies a repository from the given path to a temporary directory.
+///
+/// The copy goes to the system's temporary directory first. If that fails, e.g.,
+/// because it's a small tmpfs or a restricted mount, the copy is retried in
+/// the directory that contains the repository.
+///
+/// # Arguments
+///
+/// * `repo_path` — The original repository path.
+/// * `auto_files` — The auto files, the only working tree files that are
+///   copied besides Git's attribute and ignore files.
+/// * `temp_dir` — The directory to copy to instead, without a retry.
+///
+/// # Returns
+///
+/// A temporary directory with the copied repository.
+fn copy_repository<P>(
+    repo_path: P,
+    auto_files: &[PathBuf],
+    temp_dir: Option<&Path>,
+) -> Result<tempfile::TempDir, String>
+where
+    P: AsRef<Path>,
+{
+    let repo = Repository::open(repo_path.as_ref()).map_err(|e| {
+        format!(
+            "Failed to open a repository, {}: {}",
+            repo_path.as_ref().display(),
+            e
+        )
+    })?;
+    let workdir: &Path = repo
+        .workdir()
+        .ok_or("The repository has no working directory.")?;
+    let mut paths: Vec<PathBuf> = git_rule_files(&repo)?;
+    paths.extend_from_slice(auto_files);
+    let (temp_dir, unreadable) = match temp_dir {
+        Some(temp_dir) => copy_repository_into(&repo, &paths, temp_dir)?,
+        None => match copy_repository_into(&repo, &paths, &env::temp_dir()) {
+            Ok(copy) => copy,
+            Err(temp_err) => {
+                let parent: &Path = workdir.parent().ok_or(temp_err.clone())?;
+                warn!("{}\nRetrying the copy in {}.", temp_err, parent.display());
+                copy_repository_into(&repo, &paths, parent)?
+            }
+        },
+    };
+    if let Some(path) = unreadable.first() {
+        return Err(format!(
+            "Could not copy the repository {}, because {} is unreadable.",
+            workdir.display(),
+            path.display()
+        ));
+    }
+    let copy = Repository::open(temp_dir.path()).map_err(|e| {
+        format!(
+            "Failed to open the copied repository, {}: {}",
+            temp_dir.path().display(),
+            e
+        )
+    })?;
+    // Another Git process may have taken the lock while we were copying. The
+    // copy is ours alone, so its stale lock can go.
+    let copied_lock: PathBuf = copy.path().join("index.lock");
+    if copied_lock.exists() {
+        fs::remove_file(&copied_lock).map_err(|e| {
+            format!(
+                "Could not remove the copied index lock {}: {}",
+                copied_lock.display(),
+                e
+            )
+        })?;
+    }
+    pin_effective_config(&repo, &copy)?;
+    pin_remote_urls(&repo, &copy)?;
+    debug!(
+        "Copied the repo at {} to the temporary directory.",
+        repo_path.as_ref().display()
+    );
+    Ok(temp_dir)
+}
+
+/// A temporary linked worktree of the original repository with its own branch.
+/// Both are removed on drop.
+struct TemporaryWorktree {
+    /// The Git directory of the original repository.
+    git_dir: PathBuf,
+    /// The directory that contains the worktree.
+    dir: tempfile::TempDir,
+}
+
+impl TemporaryWorktree {
+    /// Returns the working directory of the worktree.
+    fn path(&self) -> PathBuf {
+        self.dir.path().join(WORKTREE_NAME)
+    }
+}
+
+impl Drop for TemporaryWorktree {
+    fn drop(&mut self) {
+        let result: Result<(), String> = Repository::open(&self.git_dir)
+            .map_err(|e| format!("Could not open {}: {}", self.git_dir.display(), e))
+            .and_then(|repo| remove_temporary_worktree(&repo));
+        if let Err(e) = result {
+            warn!("Could not remove the temporary worktree: {}", e);
+        }
+    }
+}
+
+/// Removes the temporary worktree and its branch if they exist, e.g., after a
+/// crashed run.
+fn remove_temporary_worktree(repo: &Repository) -> Result<(), String> {
+    if let Ok(worktree) = repo.find_worktree(WORKTREE_NAME) {
+        worktree
+            .prune(Some(
+                WorktreePruneOptions::new()
+                    .valid(true)
+                    .locked(true)
+                    .working_tree(true),
+            ))
+            .map_err(|e| format!("Could not prune the worktree {}: {}", WORKTREE_NAME, e))?;
+    }
+    if let Ok(mut branch) = repo.find_branch(WORKTREE_NAME, BranchType::Local) {
+        branch
+            .delete()
+            .map_err(|e| format!("Could not delete the branch {}: {}", WORKTREE_NAME, e))?;
+    }
+    Ok(())
+}
+
+/// Adds a temporary linked worktree on a new branch at HEAD and copies the
+/// auto files into it.
+///
+/// The worktree shares the object store and the refs with the original, so
+/// nothing but the checkout needs to be written.
+///
+/// # Arguments
+///
+/// * `repo` - The original repository.
+/// * `auto_files` - The auto files, relative to the working directory.
+/// * `temp_dir` - The directory to add the worktree in instead of the
+///   system's temporary directory.
+fn add_temporary_worktree(
+    repo: &Repository,
+    auto_files: &[PathBuf],
+    temp_dir: Option<&Path>,
+) -> Result<TemporaryWorktree, String> {
+    let workdir: &Path = repo
+        .workdir()
+        .ok_or("The repository has no working directory.")?;
+    remove_temporary_worktree(repo)?;
+    let head = head_commit(repo)?.ok_or(
+        "HEAD has no commit yet, so there is nothing to add a worktree at. Use another workspace for the first commit.",
+    )?;
+    let branch = repo
+        .branch(WORKTREE_NAME, &head, false)
+        .map_err(|e| format!("Could not create the branch {}: {}", WORKTREE_NAME, e))?;
+    let dir: tempfile::TempDir = match temp_dir {
+        Some(temp_dir) => tempdir_in(temp_dir),
+        None => tempdir(),
+    }
+    .map_err(|e| format!("Could not create a temporary directory: {}", e))?;
+    let worktree = TemporaryWorktree {
+        git_dir: repo.path().to_path_buf(),
+        dir,
+    };
+    let mut options = WorktreeAddOptions::new();
+    options.reference(Some(branch.get()));
+    repo.worktree(WORKTREE_NAME, &worktree.path(), Some(&options))
+        .map_err(|e| format!("Could not add a temporary worktree: {}", e))?;
+    copy_worktree_files(workdir, &worktree.path(), auto_files).map_err(|e| {
+        format!(
+            "Could not copy the auto files to {}: {}",
+            worktree.path().display(),
+            e
+        )
+    })?;
+    debug!(
+        "Added a temporary worktree at {}.",
+        worktree.path().display()
+    );
+    Ok(worktree)
+}
+
+/// Checks that no tracked file but the auto files has uncommitted changes, so
+/// that a commit in the original repository can't interfere with a manual
+/// change.
+///
+/// # Arguments
+///
+/// * `repo` - The wallet repository.
+/// * `auto_files` - The auto files, relative to the working directory.
+fn check_clean_worktree(
+    repo: &Repository,
+    auto_files: &[PathBuf],
+) -> Result<Result<(), SkipReason>, String> {
+    let mut options = StatusOptions::new();
+    options
+        .include_untracked(false)
+        .include_ignored(false)
+        .exclude_submodules(true);
+    let statuses: Statuses = repo
+        .statuses(Some(&mut options))
+        .map_err(|e| format!("Could not fetch file statuses: {}", e))?;
+    let dirty_files: Vec<PathBuf> = statuses
+        .iter()
+        .map(|entry| PathBuf::from(String::from_utf8_lossy(entry.path_bytes()).into_owned()))
+        .filter(|path| !auto_files.contains(path))
+        .collect();
+    if dirty_files.is_empty() {
+        return Ok(Ok(()));
+    }
+    for dirty_file in dirty_files {
+        warn!(
+            "{} has uncommitted changes, so we’re not committing in place.",
+            dirty_file.display()
+        );
+    }
+    Ok(Err(SkipReason::DirtyWorktree))
+}
+
+/// Checks whether the index differs from HEAD.
+///
+/// # Arguments
+///
+/// * `index_statuses` - Statuses collected with `index_status_options`. Every
+///   entry of such a list is an index change, so there's no need to walk it.
+fn is_index_empty(index_statuses: &Statuses) -> bool {
+    index_statuses.is_empty()
+}
+
+/// Normalizes a path to Unicode NFC.
+///
+/// macOS file systems store names decomposed (NFD), while users type them
+/// composed (NFC). Paths that aren't valid Unicode are returned as they are.
+fn nfc_path(path: &Path) -> PathBuf {
+    path.to_str().map_or_else(
+        || path.to_path_buf(),
+        |s| PathBuf::from(s.nfc().collect::<String>()),
+    )
+}
+
+/// Converts a relative path to Git's spelling, with `/` as the separator and
+/// without `.` components, e.g., `.\marks\a.journal` to `marks/a.journal` on
+/// Windows.
+fn git_path(path: &Path) -> PathBuf {
+    let components: Vec<&OsStr> = path
+        .components()
+        .filter(|component| !matches!(component, Component::CurDir))
+        .map(|component| component.as_os_str())
+        .collect();
+    PathBuf::from(components.join(OsStr::new("/")))
+}
+
+/// Returns whether the repository's file system ignores case, e.g., on
+/// Windows and macOS. Git records it in `core.ignorecase`.
+fn ignores_case(repo: &Repository) -> bool {
+    repo.config()
+        .and_then(|config| config.get_bool("core.ignorecase"))
+        .unwrap_or(false)
+}
+
+/// Returns the spelling of a path under which it's compared with others: in
+/// Unicode NFC and, if the file system ignores case, in lowercase.
+fn path_key(path: &Path, ignore_case: bool) -> PathBuf {
+    let path: PathBuf = nfc_path(path);
+    match path.to_str() {
+        Some(s) if ignore_case => PathBuf::from(s.to_lowercase()),
+        _ => path,
+    }
+}
+
+/// Returns the options of matching glob patterns against Git paths.
+fn glob_match_options(ignore_case: bool) -> MatchOptions {
+    MatchOptions {
+        case_sensitive: !ignore_case,
+        require_literal_separator: true,
+        ..MatchOptions::new()
+    }
+}
+
+/// Returns the NFC and NFD spellings of a path.
+fn normalization_variants(path: &Path) -> Vec<PathBuf> {
+    let Some(path_str) = path.to_str() else {
+        return vec![path.to_path_buf()];
+    };
+    let mut variants: Vec<PathBuf> = vec![
+        path.to_path_buf(),
+        PathBuf::from(path_str.nfc().collect::<String>()),
+        PathBuf::from(path_str.nfd().collect::<String>()),
+    ];
+    variants.sort();
+    variants.dedup();
+    variants
+}
+
+/// Compiles glob patterns of paths, e.g., exclude patterns. A trailing slash is
+/// optional.
+///
+/// # Arguments
+///
+/// * `kind` - What the patterns are for, e.g., "exclude".
+/// * `patterns` - The patterns.
+fn path_patterns(kind: &str, patterns: &[String]) -> Result<Vec<Pattern>, String> {
+    patterns
+        .iter()
+        .map(|pattern| {
+            let normalized: String = if cfg!(windows) {
+                pattern.replace('\\', "/")
+            } else {
+                pattern.clone()
+            };
+            Pattern::new(normalized.trim_end_matches('/'))
+                .map_err(|e| format!("The {} pattern {} is invalid: {}", kind, pattern, e))
+        })
+        .collect()
+}
+
+/// Checks whether a path or one of the directories it's in matches a
+/// pattern, e.g., an exclude pattern.
+fn matches_patterns(path: &Path, patterns: &[Pattern], ignore_case: bool) -> bool {
+    let match_options: MatchOptions = glob_match_options(ignore_case);
+    path.ancestors()
+        .filter(|ancestor| !ancestor.as_os_str().is_empty())
+        .any(|ancestor| {
+            patterns
+                .iter()
+                .any(|pattern| pattern.matches_path_with(ancestor, match_options))
+        })
+}
+
+/// Collects owned entries of the statuses whose paths are among `paths` and
+/// not excluded.
+///
+/// Paths are compared in Unicode NFC, so decomposed and composed spellings of
+/// the same name match, and with Git's `/` separators. On file systems that
+/// ignore case, so does the comparison.
+///
+/// # Arguments
+///
+/// * `statuses` - The statuses to filter.
+/// * `paths` - The relative paths to keep.
+/// * `excludes` - Patterns of paths to drop, even if they're among `paths`.
+/// * `ignore_case` - Whether to compare paths case-insensitively.
+fn filter_statuses_by_path<P>(
+    statuses: &Statuses,
+    paths: &[P],
+    excludes: &[Pattern],
+    ignore_case: bool,
+) -> Vec<StatusEntryBetter>
+where
+    P: AsRef<Path>,
+{
+    let paths: HashSet<PathBuf> = paths
+        .iter()
+        .map(|p| path_key(&git_path(p.as_ref()), ignore_case))
+        .collect();
+
+    statuses
+        .iter()
+        .filter_map(|status_entry| StatusEntryBetter::from_status_entry(&status_entry))
+        .filter(|status_entry| {
+            // Both sides of a rename must be mark files.
+            std::iter::once(&status_entry.path)
+                .chain(&status_entry.old_path)
+                .all(|path| {
+                    let path: PathBuf = path_key(path, ignore_case);
+                    paths.contains(&path) && !matches_patterns(&path, excludes, ignore_case)
+                })
+        })
+        .collect()
+}
+
+/// Creates status options that only look at the given files.
+///
+/// Restricting the status to pathspecs means that libgit2 doesn't walk the
+/// whole working tree, which matters for large wallet repositories. Ignored
+/// files are never committed, so they're not enumerated either, and
+/// untracked files only if new mark files are allowed. Submodules aren't mark
+/// files, and their working trees aren't in the workspace, so they're skipped.
+///
+/// # Arguments
+///
+/// * `paths` - The relative paths of files to inspect.
+/// * `include_untracked` - Whether to report untracked files.
+fn file_status_options<P>(paths: &[P], include_untracked: bool) -> StatusOptions
+where
+    P: AsRef<Path>,
+{
+    let mut options = StatusOptions::new();
+    options
+        .include_untracked(include_untracked)
+        .recurse_untracked_dirs(include_untracked)
+        .include_ignored(false)
+        .exclude_submodules(true)
+        .renames_index_to_workdir(true)
+        .disable_pathspec_match(true);
+    // The pathspecs are literal, so each spelling of a name needs its own.
+    for variant in paths
+        .iter()
+        .flat_map(|p| normalization_variants(p.as_ref()))
+    {
+        options.pathspec(variant);
+    }
+    options
+}
+
+/// Creates status options that only compare HEAD with the index.
+///
+/// This doesn't touch the working tree, so it stays cheap even in large
+/// repositories.
+fn index_status_options() -> StatusOptions {
+    let mut options = StatusOptions::new();
+    options.show(StatusShow::Index);
+    options
+}
+
+/// Checks whether a modified file has the same content as its index entry.
+///
+/// # Arguments
+///
+/// * `repo` - The wallet repository.
+/// * `status_entry` - The status of the file.
+fn is_mode_only_change(
+    repo: &Repository,
+    status_entry: &StatusEntryBetter,
+) -> Result<bool, String> {
+    if status_entry.status != Status::WT_MODIFIED {
+        return Ok(false);
+    }
+    let workdir: &Path = repo
+        .workdir()
+        .ok_or("The repository has no working directory.")?;
+    let index: Index = repo
+        .index()
+        .map_err(|e| format!("Could not fetch the index: {}", e))?;
+    let Some(index_entry) = index.get_path(&status_entry.path, 0) else {
+        return Ok(false);
+    };
+    let full_path: PathBuf = workdir.join(&status_entry.path);
+    let oid: Oid = Oid::hash_file(ObjectType::Blob, &full_path)
+        .map_err(|e| format!("Could not hash {}: {}", full_path.display(), e))?;
+    Ok(oid == index_entry.id)
+}
+
+/// Names the operation that a repository is in the middle of, if any.
+///
+/// Committing in the middle of, e.g., a bisect or a cherry-pick would mix auto
+/// commits into the user's session, so such states skip the run.
+fn ongoing_operation(state: RepositoryState) -> Option<&'static str> {
+    match state {
+        RepositoryState::Clean => None,
+        RepositoryState::Bisect => Some("bisect"),
+        RepositoryState::CherryPick | RepositoryState::CherryPickSequence => Some("cherry-pick"),
+        RepositoryState::Merge => Some("merge"),
+        RepositoryState::Revert | RepositoryState::RevertSequence => Some("revert"),
+        RepositoryState::Rebase
+        | RepositoryState::RebaseInteractive
+        | RepositoryState::RebaseMerge => Some("rebase"),
+        RepositoryState::ApplyMailbox | RepositoryState::ApplyMailboxOrRebase => Some("am"),
+    }
+}
+
+/// Checks whether the repository is in a state we shouldn't touch, i.e., in
+/// the middle of an operation or with a non-empty index.
+///
+/// # Arguments
+///
+/// * `repo` - The wallet repository.
+/// * `auto_files` - The mark files to potentially push.
+/// * `excludes` - Patterns of files never to push.
+///
+/// # Returns
+///
+/// The printed reason to skip the push, if any.
+fn untouchable_state<A>(
+    repo: &Repository,
+    auto_files: &[A],
+    excludes: &[Pattern],
+) -> Result<Option<SkipReason>, String>
+where
+    A: AsRef<Path>,
+{
+    if let Some(operation) = ongoing_operation(repo.state()) {
+        info!(
+            "Skipping the push (reason: {}): the repository is in the middle of a {}.",
+            operation, operation
+        );
+        return Ok(Some(SkipReason::OngoingOperation(operation)));
+    }
+
+    let ignore_case: bool = ignores_case(repo);
+    let index_statuses: Statuses = repo
+        .statuses(Some(&mut index_status_options()))
+        .map_err(|e| format!("Could not fetch index statuses: {}", e))?;
+
+    if !is_index_empty(&index_statuses) {
+        let staged_mark_files: Vec<StatusEntryBetter> =
+            filter_statuses_by_path(&index_statuses, auto_files, excludes, ignore_case);
+        let reason: SkipReason = if staged_mark_files.is_empty() {
+            SkipReason::IndexNotEmpty
+        } else {
+            SkipReason::StagedMarkFiles
+        };
+        if staged_mark_files.is_empty() {
+            warn!("The repository’s index is not empty. There’s possibly a manual change ongoing so we’re aborting the push.");
+        }
+        // A staged mark file may have further unstaged changes, and it's not
+        // ours to decide which of the two versions should be pushed.
+        for staged_mark_file in staged_mark_files {
+            warn!(
+                "The mark file {} has staged changes. Commit or unstage them; we’re aborting the push.",
+                staged_mark_file.path.display()
+            );
+        }
+        return Ok(Some(reason));
+    }
+    Ok(None)
+}
+
+/// Collects the statuses of mark files that should be committed.
+///
+/// Prints and returns the reason if the repository is in a state we shouldn't
+/// touch or if there's nothing to commit.
+///
+/// # Arguments
+///
+/// * `repo` - The wallet repository.
+/// * `auto_files` - The mark files to potentially push.
+/// * `excludes` - Patterns of files never to push.
+/// * `changes` - Which changes to collect.
+fn collect_mark_file_statuses<A>(
+    repo: &Repository,
+    auto_files: &[A],
+    excludes: &[Pattern],
+    changes: ChangeFilter,
+) -> Result<Result<Vec<StatusEntryBetter>, SkipReason>, String>
+where
+    A: AsRef<Path>,
+{
+    if let Some(reason) = untouchable_state(repo, auto_files, excludes)? {
+        return Ok(Err(reason));
+    }
+
+    let ignore_case: bool = ignores_case(repo);
+    // Renames are only detected with untracked files, so new files are
+    // dropped afterwards if they aren't allowed.
+    let statuses: Statuses = repo
+        .statuses(Some(&mut file_status_options(auto_files, true)))
+        .map_err(|e| format!("Could not fetch file statuses: {}", e))?;
+
+    let mut mark_file_statuses: Vec<StatusEntryBetter> =
+        filter_statuses_by_path(&statuses, auto_files, excludes, ignore_case);
+    mark_file_statuses
+        .retain(|mark_file_status| changes.allow_new || mark_file_status.status != Status::WT_NEW);
+    for mark_file_status in &mark_file_statuses {
+        debug!(
+            "{} has the status {:?}.",
+            mark_file_status.path.display(),
+            mark_file_status.status
+        );
+    }
+
+    if changes.ignore_mode_changes {
+        let mut content_changes: Vec<StatusEntryBetter> = Vec::new();
+        for mark_file_status in mark_file_statuses {
+            if is_mode_only_change(repo, &mark_file_status)? {
+                info!(
+                    "Ignoring the mode-only change of {}.",
+                    mark_file_status.path.display()
+                );
+            } else {
+                content_changes.push(mark_file_status);
+            }
+        }
+        mark_file_statuses = content_changes;
+    }
+
+    if mark_file_statuses.is_empty() {
+        info!("No mark files to push.");
+        return Ok(Err(SkipReason::NoChanges));
+    }
+    Ok(Ok(mark_file_statuses))
+}
+
+/// Removes the status mark (`*` or `!`) of a transaction or posting line in
+/// ledger-like journals, e.g., hledger's or beancount's.
+///
+/// Transactions start with a date, postings with whitespace. The mark follows
+/// either, so other lines are returned unchanged.
+fn without_status_mark(line: &str) -> String {
+    split_status_mark(line).map_or_else(|| line.to_string(), |(unmarked, _)| unmarked)
+}
+
+/// Splits the status mark off a line, see [`without_status_mark`].
+///
+/// # Returns
+///
+/// The line without the mark and the mark, or `None` if the line has no mark.
+fn split_status_mark(line: &str) -> Option<(String, char)> {
+    let (head, rest): (&str, &str) = if line.starts_with(|c: char| c.is_ascii_digit()) {
+        line.split_at(line.find(char::is_whitespace).unwrap_or(line.len()))
+    } else if line.starts_with([' ', '\t']) {
+        ("", line)
+    } else {
+        return None;
+    };
+    let indent: usize = rest.len() - rest.trim_start().len();
+    let (spacing, body): (&str, &str) = rest.split_at(indent);
+    let mark: char = body.chars().next().filter(|c| matches!(c, '*' | '!'))?;
+    let unmarked: &str = &body[1..];
+    (unmarked.is_empty() || unmarked.starts_with([' ', '\t'])).then(|| {
+        (
+            format!("{}{}{}", head, spacing, unmarked.trim_start()),
+            mark,
+        )
+    })
+}
+
+/// A transaction with its postings, or another line, of a mark file.
+struct JournalBlock<'a> {
+    /// The lines, with their line endings.
+    lines: Vec<&'a str>,
+    /// What identifies the block in other versions of the file: its lines
+    /// without status marks, and how many equal blocks come before it.
+    id: (String, usize),
+}
+
+/// Splits a journal into transactions, which start with a date and continue
+/// with indented postings, and other lines.
+fn journal_blocks(content: &str) -> Vec<JournalBlock<'_>> {
+    let mut blocks: Vec<Vec<&str>> = Vec::new();
+    let mut in_transaction: bool = false;
+    for line in content.split_inclusive('\n') {
+        let posting: bool = line.starts_with([' ', '\t']) && !line.trim().is_empty();
+        match blocks.last_mut() {
+            Some(block) if in_transaction && posting => block.push(line),
+            _ => {
+                in_transaction = line.starts_with(|c: char| c.is_ascii_digit());
+                blocks.push(vec![line]);
+            }
+        }
+    }
+    let mut counts: HashMap<String, usize> = HashMap::new();
+    blocks
+        .into_iter()
+        .map(|lines| {
+            let key: String = lines
+                .iter()
+                .map(|line| without_status_mark(line.trim_end()))
+                .collect::<Vec<String>>()
+                .join("\n");
+            let count: &mut usize = counts.entry(key.clone()).or_default();
+            *count += 1;
+            JournalBlock {
+                lines,
+                id: (key, *count),
+            }
+        })
+        .collect()
+}
+
+/// Merges three versions of a mark file, e.g., as Git's merge driver when two
+/// machines changed the same journal.
+///
+/// Transactions are matched by their lines without status marks. One that
+/// only one side added or removed is added or removed. For one whose marks
+/// both sides changed, each line gets the more settled mark, `*` over `!` over
+/// none. Other lines are merged the same way, so the merge never conflicts.
+///
+/// # Arguments
+///
+/// * `base` - The common ancestor.
+/// * `ours` - Our version, whose order of transactions is kept.
+/// * `theirs` - Their version.
+pub fn merge_mark_files(base: &str, ours: &str, theirs: &str) -> String {
+    let base: Vec<JournalBlock> = journal_blocks(base);
+    let ours: Vec<JournalBlock> = journal_blocks(ours);
+    let theirs: Vec<JournalBlock> = journal_blocks(theirs);
+    let find = |blocks: &'_ [JournalBlock<'_>], id: &(String, usize)| -> Option<usize> {
+        blocks.iter().position(|block| block.id == *id)
+    };
+    // A block that one side removed stays removed unless the other changed it.
+    let removed = |block: &JournalBlock| {
+        find(&base, &block.id).is_some_and(|position| base[position].lines == block.lines)
+    };
+
+    let mut merged: Vec<(&(String, usize), Vec<&str>)> = Vec::new();
+    for block in &ours {
+        match find(&theirs, &block.id) {
+            Some(position) => {
+                let base_block: Option<&JournalBlock> =
+                    find(&base, &block.id).map(|position| &base[position]);
+                let lines: Vec<&str> = block
+                    .lines
+                    .iter()
+                    .zip(&theirs[position].lines)
+                    .enumerate()
+                    .map(|(index, (&our_line, &their_line))| {
+                        let base_line: Option<&str> =
+                            base_block.map(|base_block| base_block.lines[index]);
+                        if our_line == their_line || base_line == Some(their_line) {
+                            our_line
+                        } else if base_line == Some(our_line) {
+                            their_line
+                        } else {
+                            let rank = |line: &str| match split_status_mark(line) {
+                                Some((_, '*')) => 2,
+                                Some(_) => 1,
+                                None => 0,
+                            };
+                            if rank(their_line) > rank(our_line) {
+                                their_line
+                            } else {
+                                our_line
+                            }
+                        }
+                    })
+                    .collect();
+                merged.push((&block.id, lines));
+            }
+            None if removed(block) => {}
+            None => merged.push((&block.id, block.lines.clone())),
+        }
+    }
+    for (position, block) in theirs.iter().enumerate() {
+        if find(&ours, &block.id).is_some() || removed(block) {
+            continue;
+        }
+        // Their new block follows the closest block before it that's merged.
+        let insert_at: usize = theirs[..position]
+            .iter()
+            .rev()
+            .find_map(|previous| merged.iter().position(|(id, _)| **id == previous.id))
+            .map_or(0, |previous| previous + 1);
+        merged.insert(insert_at, (&block.id, block.lines.clone()));
+    }
+
+    let mut content = String::new();
+    for line in merged.into_iter().flat_map(|(_, lines)| lines) {
+        // A last line without a line ending may not be last anymore.
+        if !content.is_empty() && !content.ends_with('\n') {
+            content.push('\n');
+        }
+        content.push_str(line);
+    }
+    content
+}
+
+/// Returns whether Git LFS stores a file, i.e., its `filter` attribute is
+/// `lfs`.
+///
+/// libgit2 doesn't run the LFS filters, so staging such a file would commit
+/// its content instead of an LFS pointer.
+fn is_lfs_tracked(repo: &Repository, path: &Path) -> bool {
+    repo.get_attr(path, "filter", AttrCheckFlags::FILE_THEN_INDEX)
+        .ok()
+        .flatten()
+        == Some("lfs")
+}
+
+/// Checks that no mark file whose content would be committed is stored in Git
+/// LFS, see [`is_lfs_tracked`]. Deletions don't involve the content.
+///
+/// # Arguments
+///
+/// * `repo` - The wallet repository.
+/// * `mark_file_statuses` - The mark files to commit.
+fn check_lfs(repo: &Repository, mark_file_statuses: &[StatusEntryBetter]) -> Result<(), String> {
+    for mark_file_status in mark_file_statuses {
+        if mark_file_status.status != Status::WT_DELETED
+            && is_lfs_tracked(repo, &mark_file_status.path)
+        {
+            return Err(format!(
+                "The mark file {} is stored in Git LFS, whose clean filter this tool can’t run, so its content would be committed instead of an LFS pointer. Commit it with Git or remove it from the auto files.",
+                mark_file_status.path.display()
+            ));
+        }
+    }
+    Ok(())
+}
+
+/// Checks that the changes of mark files only flip status marks.
+///
+/// The marks of a journal don't add or remove lines, so the old and new
+/// versions are compared line by line.
+///
+/// # Arguments
+///
+/// * `repo` - The wallet repository.
+/// * `mark_file_statuses` - The mark files to commit.
+fn check_marks_only(
+    repo: &Repository,
+    mark_file_statuses: &[StatusEntryBetter],
+) -> Result<(), String> {
+    let workdir: &Path = repo
+        .workdir()
+        .ok_or("The repository has no working directory.")?;
+    let head_tree: Option<Tree> = head_commit(repo)?
+        .map(|head| head.tree())
+        .transpose()
+        .map_err(|e| format!("Could not find the HEAD tree: {}", e))?;
+    for mark_file_status in mark_file_statuses {
+        let path: &Path = &mark_file_status.path;
+        let Some(head_tree) = head_tree
+            .as_ref()
+            .filter(|_| mark_file_status.status == Status::WT_MODIFIED)
+        else {
+            return Err(format!(
+                "{} is new, deleted, or renamed, which is more than a change of marks. Not pushing it.",
+                path.display()
+            ));
+        };
+        let old: Vec<u8> = head_tree
+            .get_path(path)
+            .and_then(|entry| entry.to_object(repo))
+            .and_then(|object| object.peel_to_blob())
+            .map(|blob| blob.content().to_vec())
+            .map_err(|e| format!("Could not read {} at HEAD: {}", path.display(), e))?;
+        let new: Vec<u8> = fs::read(workdir.join(path))
+            .map_err(|e| format!("Could not read {}: {}", path.display(), e))?;
+        let old: String = String::from_utf8_lossy(&old).into_owned();
+        let new: String = String::from_utf8_lossy(&new).into_owned();
+        let old_lines: Vec<&str> = old.split('\n').collect();
+        let new_lines: Vec<&str> = new.split('\n').collect();
+        if old_lines.len() != new_lines.len() {
+            return Err(format!(
+                "{} gained or lost lines, which is more than a change of marks. Not pushing it.",
+                path.display()
+            ));
+        }
+        let changed_line: Option<usize> =
+            old_lines.iter().zip(&new_lines).position(|(old, new)| {
+                old != new && without_status_mark(old) != without_status_mark(new)
+            });
+        if let Some(line) = changed_line {
+            return Err(format!(
+                "Line {} of {} changed more than its mark. Not pushing it.",
+                line + 1,
+                path.display()
+            ));
+        }
+    }
+    Ok(())
+}
+
+/// The environment variable that passes the path of the validated mark file to
+/// cmd on Windows.
+#[cfg(windows)]
+const VALIDATED_FILE_VAR: &str = "PUSH_WALLET_MARKS_FILE";
+
+/// Builds the command that validates a mark file, see [`validate_mark_files`].
+///
+/// The path is a positional parameter of the script, so the shell never
+/// parses it.
+#[cfg(not(windows))]
+fn validation_command(validate_cmd: &str, path: &Path) -> Command {
+    let mut command = Command::new("sh");
+    command
+        .arg("-c")
+        .arg(format!("{} \"$1\"", validate_cmd))
+        .arg("sh")
+        .arg(path);
+    command
+}
+
+/// Builds the command that validates a mark file, see [`validate_mark_files`].
+///
+/// cmd has no positional parameters, so the path comes in an environment
+/// variable. With delayed expansion, `!VAR!` is expanded after the line is
+/// parsed, so `&` or `^` in the path stay literal, and Windows paths can't
+/// contain quotes. `/S` strips just the outer quotes, which keeps a quoted
+/// program intact. The line is passed as is, since cmd doesn't understand the
+/// escaping of [`Command::arg`].
+#[cfg(windows)]
+fn validation_command(validate_cmd: &str, path: &Path) -> Command {
+    use std::os::windows::process::CommandExt;
+
+    let mut command = Command::new("cmd");
+    command
+        .args(["/V:ON", "/S", "/C"])
+        .raw_arg(format!("\"{} \"!{}!\"\"", validate_cmd, VALIDATED_FILE_VAR))
+        .env(VALIDATED_FILE_VAR, path);
+    command
+}
+
+/// Runs a validation command against each staged mark file that still exists.
+///
+/// The command runs in the original working directory, so that the files a
+/// journal includes are there. The mark files have the same content there as
+/// in the workspace.
+///
+/// # Arguments
+///
+/// * `validate_cmd` - The shell command. The file's path is appended.
+/// * `workdir` - The original working directory.
+/// * `mark_file_statuses` - The staged mark files.
+fn validate_mark_files(
+    validate_cmd: &str,
+    workdir: &Path,
+    mark_file_statuses: &[StatusEntryBetter],
+) -> Result<(), String> {
+    for mark_file_status in mark_file_statuses {
+        if mark_file_status.status.contains(Status::WT_DELETED) {
+            continue;
+        }
+        let path: &Path = &mark_file_status.path;
+        let output = validation_command(validate_cmd, path)
+            .current_dir(workdir)
+            .stdin(Stdio::null())
+            .output()
+            .map_err(|e| format!("Could not run the validation command: {}", e))?;
+        if !output.status.success() {
+            let mut details: String = String::from_utf8_lossy(&output.stdout).into_owned();
+            details.push_str(&String::from_utf8_lossy(&output.stderr));
+            let mut error: String = format!(
+                "The validation of {} failed ({}).",
+                path.display(),
+                output.status
+            );
+            if !details.trim().is_empty() {
+                error.push('\n');
+                error.push_str(details.trim_end());
+            }
+            return Err(error);
+        }
+        debug!("{} passed the validation.", path.display());
+    }
+    Ok(())
+}
+
+/// Runs a Git hook of the workspace if it exists.
+///
+/// Like the validation command, the hook runs in the original working
+/// directory, so that the scripts it calls are there. Git commands in the hook
+/// see the workspace's repository and index through `GIT_DIR` and
+/// `GIT_INDEX_FILE`, but the original working tree, so a pre-commit hook could
+/// stage unrelated changes of the user. [`check_hook_changes`] rejects those.
+///
+/// # Arguments
+///
+/// * `repo` - The workspace repository.
+/// * `workdir` - The original working directory.
+/// * `name` - The hook, e.g., `pre-commit`.
+fn run_hook(repo: &Repository, workdir: &Path, name: &str) -> Result<(), String> {
+    let config = repo
+        .config()
+        .map_err(|e| format!("Could not read the Git config: {}", e))?;
+    let hooks_dir: PathBuf = match config.get_path("core.hooksPath") {
+        Ok(hooks_path) => workdir.join(hooks_path),
+        Err(_) => common_dir(repo.path()).join("hooks"),
+    };
+    let hook: PathBuf = hooks_dir.join(name);
+    if !hook.is_file() {
+        return Ok(());
+    }
+    if cfg!(unix) && file_mode(&hook)? & 0o111 == 0 {
+        warn!(
+            "The {} hook was ignored because it's not executable.",
+            hook.display()
+        );
+        return Ok(());
+    }
+    // The index may be a private copy, see `use_private_index`.
+    let index_path: PathBuf = repo
+        .index()
+        .ok()
+        .and_then(|index| index.path().map(Path::to_path_buf))
+        .unwrap_or_else(|| repo.path().join("index"));
+    let mut command: Command = if cfg!(windows) {
+        let mut command = Command::new("sh");
+        command.arg(&hook);
+        command
+    } else {
+        Command::new(&hook)
+    };
+    let output = command
+        .current_dir(workdir)
+        .env("GIT_DIR", repo.path())
+        .env("GIT_INDEX_FILE", index_path)
+        .env("GIT_WORK_TREE", workdir)
+        .stdin(Stdio::null())
+        .output()
+        .map_err(|e| format!("Could not run the {} hook: {}", name, e))?;
+    if !output.status.success() {
+        let mut details: String = String::from_utf8_lossy(&output.stdout).into_owned();
+        details.push_str(&String::from_utf8_lossy(&output.stderr));
+        let mut error: String = format!("The {} hook failed ({}).", name, output.status);
+        if !details.trim().is_empty() {
+            error.push('\n');
+            error.push_str(details.trim_end());
+        }
+        return Err(error);
+    }
+    debug!("The {} hook passed.", name);
+    Ok(())
+}
+
+/// Checks that a hook changed nothing in the index but the staged mark files,
+/// e.g., by formatting them.
+///
+/// # Arguments
+///
+/// * `repo` - The workspace repository.
+/// * `index` - The index after the hook ran.
+/// * `staged_tree` - The tree of the index before the hook ran.
+/// * `mark_file_statuses` - The staged mark files.
+fn check_hook_changes(
+    repo: &Repository,
+    index: &mut Index,
+    staged_tree: Oid,
+    mark_file_statuses: &[StatusEntryBetter],
+) -> Result<(), String> {
+    let hook_tree: Oid = index
+        .write_tree()
+        .map_err(|e| format!("Could not write the index tree: {}", e))?;
+    let diff: Diff = repo
+        .find_tree(staged_tree)
+        .and_then(|staged_tree| repo.find_tree(hook_tree).map(|tree| (staged_tree, tree)))
+        .and_then(|(staged_tree, hook_tree)| {
+            repo.diff_tree_to_tree(Some(&staged_tree), Some(&hook_tree), None)
+        })
+        .map_err(|e| format!("Could not diff the index: {}", e))?;
+    let mut other_files: Vec<String> = diff
+        .deltas()
+        .flat_map(|delta| [delta.old_file().path(), delta.new_file().path()])
+        .flatten()
+        .filter(|path| {
+            !mark_file_statuses
+                .iter()
+                .any(|mark_file_status| mark_file_status.path == *path)
+        })
+        .map(|path| path.display().to_string())
+        .collect();
+    other_files.sort();
+    other_files.dedup();
+    if other_files.is_empty() {
+        Ok(())
+    } else {
+        Err(format!(
+            "The pre-commit hook staged files besides the mark files: {}.",
+            other_files.join(", ")
+        ))
+    }
+}
+
+/// Diffs the uncommitted changes of mark files.
+///
+/// # Arguments
+///
+/// * `repo` - The wallet repository.
+/// * `index` - The index, which doesn't have the changes staged yet.
+/// * `mark_file_statuses` - The changed mark files.
+fn diff_mark_files<'r>(
+    repo: &'r Repository,
+    index: &Index,
+    mark_file_statuses: &[StatusEntryBetter],
+) -> Result<Diff<'r>, String> {
+    let mut options = DiffOptions::new();
+    options
+        .include_untracked(true)
+        .recurse_untracked_dirs(true)
+        .show_untracked_content(true)
+        .disable_pathspec_match(true);
+    // Without a pathspec, the diff would cover every file.
+    if mark_file_statuses.is_empty() {
+        return repo
+            .diff_tree_to_tree(None, None, None)
+            .map_err(|e| format!("Could not diff the mark files: {}", e));
+    }
+    for mark_file_status in mark_file_statuses {
+        options.pathspec(&mark_file_status.path);
+        if let Some(old_path) = &mark_file_status.old_path {
+            options.pathspec(old_path);
+        }
+    }
+    let mut diff = repo
+        .diff_index_to_workdir(Some(index), Some(&mut options))
+        .map_err(|e| format!("Could not diff the mark files: {}", e))?;
+    diff.find_similar(Some(
+        DiffFindOptions::new().renames(true).for_untracked(true),
+    ))
+    .map_err(|e| format!("Could not detect renamed mark files: {}", e))?;
+    Ok(diff)
+}
+
+/// Renders the uncommitted changes of mark files as a unified diff. See
+/// [`diff_mark_files`].
+fn mark_file_diff(
+    repo: &Repository,
+    index: &Index,
+    mark_file_statuses: &[StatusEntryBetter],
+) -> Result<String, String> {
+    let diff: Diff = diff_mark_files(repo, index, mark_file_statuses)?;
+    let mut patch = String::new();
+    diff.print(DiffFormat::Patch, |_, _, line| {
+        if matches!(line.origin(), '+' | '-' | ' ') {
+            patch.push(line.origin());
+        }
+        patch.push_str(&String::from_utf8_lossy(line.content()));
+        true
+    })
+    .map_err(|e| format!("Could not print the diff of the mark files: {}", e))?;
+    Ok(patch)
+}
+
+/// Fails if the uncommitted changes of mark files exceed the limits.
+///
+/// # Arguments
+///
+/// * `repo` - The wallet repository.
+/// * `index` - The index, which doesn't have the changes staged yet.
+/// * `mark_file_statuses` - The changed mark files.
+/// * `diffed` - The changed mark files whose lines count, i.e., not the opaque
+///   ones.
+/// * `changes` - The limits.
+fn check_change_size(
+    repo: &Repository,
+    index: &Index,
+    mark_file_statuses: &[StatusEntryBetter],
+    diffed: &[StatusEntryBetter],
+    changes: ChangeFilter,
+) -> Result<(), String> {
+    if let Some(max_files) = changes.max_files {
+        if mark_file_statuses.len() > max_files {
+            return Err(format!(
+                "{} mark files changed, more than the limit of {}. Review and push them manually.",
+                mark_file_statuses.len(),
+                max_files
+            ));
+        }
+    }
+    if let Some(max_changed_lines) = changes.max_changed_lines {
+        let stats = diff_mark_files(repo, index, diffed)?
+            .stats()
+            .map_err(|e| format!("Could not count the changed lines: {}", e))?;
+        let changed_lines: usize = stats.insertions() + stats.deletions();
+        if changed_lines > max_changed_lines {
+            return Err(format!(
+                "{} lines of mark files changed ({} added, {} removed), more than the limit of {}. Review and push them manually.",
+                changed_lines,
+                stats.insertions(),
+                stats.deletions(),
+                max_changed_lines
+            ));
+        }
+    }
+    Ok(())
+}
+
+/// Fails unless every changed hunk of the mark files matches a pattern, see
+/// [`PushRequest::require_diff_regex`].
+///
+/// A changed file without any hunks, e.g., a binary file or a pure rename,
+/// fails too.
+///
+/// # Arguments
+///
+/// * `repo` - The wallet repository.
+/// * `index` - The index, which doesn't have the changes staged yet.
+/// * `mark_file_statuses` - The changed mark files.
+/// * `require_diff_regex` - The regular expression.
+fn check_diff_pattern(
+    repo: &Repository,
+    index: &Index,
+    mark_file_statuses: &[StatusEntryBetter],
+    require_diff_regex: &str,
+) -> Result<(), String> {
+    // The pattern is checked on its own, so that errors point into it.
+    let regex = Regex::new(require_diff_regex)
+        .and_then(|_| Regex::new(&format!(r"\A(?:{})\z", require_diff_regex)))
+        .map_err(|e| {
+            format!(
+                "The required diff pattern {} is invalid: {}",
+                require_diff_regex, e
+            )
+        })?;
+    let diff: Diff = diff_mark_files(repo, index, mark_file_statuses)?;
+    // The hunks by file, each with its header and changed lines.
+    let mut files: Vec<(PathBuf, Vec<(String, String)>)> = Vec::new();
+    diff.print(DiffFormat::Patch, |delta, hunk, line| {
+        match line.origin() {
+            'F' => files.push((
+                delta
+                    .new_file()
+                    .path()
+                    .or(delta.old_file().path())
+                    .unwrap_or(Path::new(""))
+                    .to_path_buf(),
+                Vec::new(),
+            )),
+            'H' => {
+                if let (Some((_, hunks)), Some(hunk)) = (files.last_mut(), hunk) {
+                    let header = String::from_utf8_lossy(hunk.header())
+                        .trim_end()
+                        .to_string();
+                    hunks.push((header, String::new()));
+                }
+            }
+            origin @ ('+' | '-') => {
+                if let Some((_, text)) = files.last_mut().and_then(|(_, hunks)| hunks.last_mut()) {
+                    text.push(origin);
+                    text.push_str(&String::from_utf8_lossy(line.content()));
+                }
+            }
+            _ => {}
+        }
+        true
+    })
+    .map_err(|e| format!("Could not diff the mark files: {}", e))?;
+    for (path, hunks) in &files {
+        if hunks.is_empty() {
+            return Err(format!(
+                "{} changed without any lines to match the required diff pattern. Review and push it manually.",
+                path.display()
+            ));
+        }
+        for (header, text) in hunks {
+            if !regex.is_match(text) {
+                return Err(format!(
+                    "The change of {} at {} doesn't match the required diff pattern. Review and push it manually.\n{}",
+                    path.display(),
+                    header,
+                    text.trim_end()
+                ));
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Adds modified mark files to the index and removes deleted ones.
+///
+/// # Arguments
+///
+/// * `index` - The repository index.
+/// * `mark_file_statuses` - The mark files to stage.
+/// * `changes` - Which changes may be staged.
+fn add_mark_files(
+    index: &mut Index,
+    mark_file_statuses: &[StatusEntryBetter],
+    changes: ChangeFilter,
+) -> Result<(), String> {
+    for mark_file_status in mark_file_statuses {
+        let path: &Path = mark_file_status.path.as_path();
+        if let Some(old_path) = &mark_file_status.old_path {
+            index.remove_path(old_path).map_err(|e| {
+                format!(
+                    "Could not remove {} from the index: {}",
+                    old_path.display(),
+                    e
+                )
+            })?;
+            index
+                .add_path(path)
+                .map_err(|e| format!("Could not add {} to the index: {}", path.display(), e))?;
+            continue;
+        }
+        // New files only show up in the statuses if they're allowed.
+        if mark_file_status.status == Status::WT_MODIFIED
+            || mark_file_status.status == Status::WT_NEW
+        {
+            let committed_mode: Option<u32> = index.get_path(path, 0).map(|entry| entry.mode);
+            index
+                .add_path(path)
+                .map_err(|e| format!("Could not add {} to the index: {}", path.display(), e))?;
+            if !changes.ignore_mode_changes {
+                continue;
+            }
+            if let (Some(mode), Some(mut entry)) = (committed_mode, index.get_path(path, 0)) {
+                if entry.mode != mode {
+                    entry.mode = mode;
+                    index.add(&entry).map_err(|e| {
+                        format!("Could not restore the mode of {}: {}", path.display(), e)
+                    })?;
+                }
+            }
+        } else if mark_file_status.status == Status::WT_DELETED {
+            if !changes.allow_delete {
+                return Err(format!(
+                    "The mark file {} was deleted. Pass --allow-delete to commit the deletion.",
+                    path.display()
+                ));
+            }
+            index.remove_path(path).map_err(|e| {
+                format!("Could not remove {} from the index: {}", path.display(), e)
+            })?;
+        } else {
+            return Err(format!(
+                "The mark file {} has an unexpected status: {:?}.",
+                mark_file_status.path.display(),
+                mark_file_status.status
+            ));
+        }
+    }
+    Ok(())
+}
+
+/// Checks that HEAD is on the branch that its remote considers the default.
+///
+/// The remote's default branch is read from `refs/remotes/<remote>/HEAD`, which
+/// `git clone` and `git remote set-head` maintain. If it's unknown, the check
+/// passes.
+///
+/// # Arguments
+///
+/// * `repo` - The wallet repository.
+/// * `remote` - The remote to push to, if not the upstream's.
+fn check_default_branch(repo: &Repository, remote: Option<&str>) -> Result<(), String> {
+    let Some(head_name) = head_branch(repo)? else {
+        return Err("HEAD is detached, so auto commits wouldn’t land on any branch.".to_string());
+    };
+    let head_name: &str = &head_name;
+    let branch_name: &str = head_name.strip_prefix("refs/heads/").unwrap_or(head_name);
+    let remote_name: String = match remote {
+        Some(remote) => remote.to_string(),
+        None => repo
+            .branch_upstream_remote(head_name)
+            .ok()
+            .and_then(|remote| remote.as_str().map(str::to_string))
+            .unwrap_or_else(|| "origin".to_string()),
+    };
+
+    let remote_head_name = format!("refs/remotes/{}/HEAD", remote_name);
+    let Ok(remote_head) = repo.find_reference(&remote_head_name) else {
+        return Ok(());
+    };
+    let Some(default_branch) = remote_head
+        .symbolic_target()
+        .and_then(|target| target.strip_prefix(&format!("refs/remotes/{}/", remote_name)))
+    else {
+        return Ok(());
+    };
+    if default_branch != branch_name {
+        return Err(format!(
+            "HEAD is on {}, but the default branch of {} is {}. Pass --allow-non-default-branch if that’s intended.",
+            branch_name, remote_name, default_branch
+        ));
+    }
+    Ok(())
+}
+
+/// Waits until no other Git process holds the repository's index lock.
+///
+/// # Arguments
+///
+/// * `repo` - The wallet repository.
+/// * `timeout` - How long to wait before reporting the repository as busy.
+fn wait_for_index_lock(repo: &Repository, timeout: Duration) -> Result<(), String> {
+    let lock_path: PathBuf = repo.path().join("index.lock");
+    let deadline: Instant = Instant::now() + timeout;
+    while lock_path.exists() {
+        if Instant::now() >= deadline {
+            return Err(format!(
+                "The repository is busy: {} exists. Another Git process is running or has crashed and left the lock behind.",
+                lock_path.display()
+            ));
+        }
+        thread::sleep(INDEX_LOCK_POLL_INTERVAL);
+    }
+    Ok(())
+}
+
+/// Checks whether an auto file is a glob pattern rather than a path.
+fn is_glob_pattern(auto_file: &Path) -> bool {
+    auto_file
+        .to_str()
+        .is_some_and(|s| s.contains(['*', '?', '[']))
+}
+
+/// Finds untracked files that auto files were renamed to, so that the rename
+/// is pushed if the auto file patterns cover the new path.
+///
+/// # Arguments
+///
+/// * `repo` - The wallet repository.
+/// * `auto_file_patterns` - The auto files as given, with glob patterns.
+/// * `auto_files` - The expanded auto files.
+fn rename_targets(
+    repo: &Repository,
+    auto_file_patterns: &[PathBuf],
+    auto_files: &[PathBuf],
+) -> Result<Vec<PathBuf>, String> {
+    let known: HashSet<&PathBuf> = auto_files.iter().collect();
+    let untracked: Vec<PathBuf> = expand_auto_files(repo, auto_file_patterns, true)?
+        .into_iter()
+        .filter(|path| !known.contains(path))
+        .collect();
+    if untracked.is_empty() {
+        return Ok(Vec::new());
+    }
+    let paths: Vec<&PathBuf> = auto_files.iter().chain(&untracked).collect();
+    let statuses: Statuses = repo
+        .statuses(Some(&mut file_status_options(&paths, true)))
+        .map_err(|e| format!("Could not fetch file statuses: {}", e))?;
+    Ok(statuses
+        .iter()
+        .filter_map(|status_entry| StatusEntryBetter::from_status_entry(&status_entry))
+        .filter(|status_entry| status_entry.old_path.is_some())
+        .map(|status_entry| status_entry.path)
+        .filter(|path| untracked.contains(path))
+        .collect())
+}
+
+/// Expands glob patterns among the auto files against the files in the
+/// repository.
+///
+/// `*` doesn't cross directory separators, while `**` does, e.g.,
+/// `marks/**/*.journal`. A directory stands for the files in it. Other auto
+/// files are kept as they are.
+///
+/// # Arguments
+///
+/// * `repo` - The wallet repository.
+/// * `auto_files` - The auto files and patterns.
+/// * `include_untracked` - Whether patterns also match untracked files.
+fn expand_auto_files(
+    repo: &Repository,
+    auto_files: &[PathBuf],
+    include_untracked: bool,
+) -> Result<Vec<PathBuf>, String> {
+    let workdir: &Path = repo
+        .workdir()
+        .ok_or("The repository has no working directory.")?;
+    let auto_files: Vec<PathBuf> = auto_files.iter().map(|path| git_path(path)).collect();
+    let is_dir = |auto_file: &Path| workdir.join(auto_file).is_dir();
+    if !auto_files
+        .iter()
+        .any(|auto_file| is_glob_pattern(auto_file) || is_dir(auto_file))
+    {
+        return Ok(auto_files);
+    }
+    let index: Index = repo
+        .index()
+        .map_err(|e| format!("Could not fetch the index: {}", e))?;
+    let mut candidates: Vec<PathBuf> = index
+        .iter()
+        // Submodules aren't files that could be committed.
+        .filter(|entry| entry.mode != GITLINK_MODE)
+        .filter_map(|entry| String::from_utf8(entry.path).ok())
+        .map(PathBuf::from)
+        .collect();
+    if include_untracked {
+        let mut options = StatusOptions::new();
+        options
+            .include_untracked(true)
+            .recurse_untracked_dirs(true)
+            .exclude_submodules(true)
+            .show(StatusShow::Workdir);
+        let statuses: Statuses = repo
+            .statuses(Some(&mut options))
+            .map_err(|e| format!("Could not fetch untracked files: {}", e))?;
+        candidates.extend(
+            statuses
+                .iter()
+                .filter(|entry| entry.status() == Status::WT_NEW)
+                .filter_map(|entry| entry.path().map(PathBuf::from)),
+        );
+    }
+    let ignore_case: bool = ignores_case(repo);
+    let match_options: MatchOptions = glob_match_options(ignore_case);
+
+    let mut expanded: Vec<PathBuf> = Vec::new();
+    for auto_file in &auto_files {
+        if is_dir(auto_file) {
+            let prefix: PathBuf = path_key(auto_file, ignore_case);
+            let files = candidates
+                .iter()
+                .filter(|path| path_key(path, ignore_case).starts_with(&prefix));
+            expanded.extend(files.cloned());
+            continue;
+        }
+        if !is_glob_pattern(auto_file) {
+            expanded.push(auto_file.clone());
+            continue;
+        }
+        let pattern_str: &str = auto_file.to_str().unwrap_or_default();
+        let pattern = Pattern::new(pattern_str)
+            .map_err(|e| format!("The auto file pattern {} is invalid: {}", pattern_str, e))?;
+        let matches: Vec<&PathBuf> = candidates
+            .iter()
+            .filter(|path| pattern.matches_path_with(path, match_options))
+            .collect();
+        if matches.is_empty() {
+            warn!(
+                "The auto file pattern {} matches no {}files.",
+                pattern_str,
+                if include_untracked { "" } else { "tracked " }
+            );
+        }
+        expanded.extend(matches.into_iter().cloned());
+    }
+    let mut seen: HashSet<PathBuf> = HashSet::new();
+    expanded.retain(|path| seen.insert(path.clone()));
+    Ok(expanded)
+}
+
+/// Finds the ignore rule that matches a path, as `source:line:pattern`.
+///
+/// libgit2 only tells whether a path is ignored, so this asks the Git CLI. If
+/// it isn't available, the rule stays unknown.
+fn find_ignore_rule(workdir: &Path, path: &Path) -> Option<String> {
+    let output = Command::new("git")
+        .arg("-C")
+        .arg(workdir)
+        .args(["check-ignore", "--verbose", "--"])
+        .arg(path)
+        .output()
+        .ok()?;
+    let output = String::from_utf8(output.stdout).ok()?;
+    let (rule, _path) = output.trim_end().split_once('\t')?;
+    Some(rule.to_string())
+}
+
+/// Explains why auto files can't be committed, e.g., because they don't
+/// exist or Git ignores them.
+///
+/// Statuses never report such files, so without an explanation the tool would
+/// silently never commit them.
+///
+/// # Arguments
+///
+/// * `repo` - The wallet repository.
+/// * `auto_file_patterns` - The auto files as given, with glob patterns.
+/// * `auto_files` - The expanded auto files.
+/// * `allow_new` - Whether untracked auto files may be committed.
+///
+/// # Returns
+///
+/// A message per auto file that can't be committed.
+fn auto_file_problems(
+    repo: &Repository,
+    auto_file_patterns: &[PathBuf],
+    auto_files: &[PathBuf],
+    allow_new: bool,
+) -> Result<Vec<String>, String> {
+    let workdir: &Path = repo
+        .workdir()
+        .ok_or("The repository has no working directory.")?;
+    let index: Index = repo
+        .index()
+        .map_err(|e| format!("Could not fetch the index: {}", e))?;
+    let mut problems: Vec<String> = Vec::new();
+    for pattern in auto_file_patterns {
+        let dir: PathBuf = git_path(pattern);
+        if workdir.join(&dir).is_dir() && !auto_files.iter().any(|path| path.starts_with(&dir)) {
+            problems.push(format!(
+                "The auto file directory {} contains no {}files.",
+                dir.display(),
+                if allow_new { "" } else { "tracked " }
+            ));
+        }
+    }
+    for auto_file in auto_files {
+        if is_lfs_tracked(repo, auto_file) {
+            problems.push(format!(
+                "The auto file {} is stored in Git LFS, whose filters this tool can’t run, so a change of it fails the push.",
+                auto_file.display()
+            ));
+            continue;
+        }
+        if index.get_path(auto_file, 0).is_some() {
+            continue;
+        }
+        if !workdir.join(auto_file).exists() {
+            problems.push(format!(
+                "The auto file {} doesn’t exist and isn’t tracked. Is the path right?",
+                auto_file.display()
+            ));
+        } else if repo.is_path_ignored(auto_file).unwrap_or(false) {
+            problems.push(match find_ignore_rule(workdir, auto_file) {
+                Some(rule) => format!(
+                    "The auto file {} is ignored by {}, so it’s never committed.",
+                    auto_file.display(),
+                    rule
+                ),
+                None => format!(
+                    "The auto file {} is ignored by Git, so it’s never committed.",
+                    auto_file.display()
+                ),
+            });
+        } else if !allow_new {
+            problems.push(format!(
+                "The auto file {} isn’t tracked, so it’s never committed. Commit it once or pass --allow-new.",
+                auto_file.display()
+            ));
+        }
+    }
+    Ok(problems)
+}
+
+/// Lists Syncthing conflict copies of a file.
+///
+/// Syncthing names them `<stem>.sync-conflict-<date>-<time>-<device>.<ext>`.
+///
+/// # Arguments
+///
+/// * `path` - The full path of the file.
+fn find_sync_conflicts(path: &Path) -> Result<Vec<PathBuf>, String> {
+    let (Some(dir), Some(stem)) = (path.parent(), path.file_stem().and_then(|s| s.to_str())) else {
+        return Ok(Vec::new());
+    };
+    let prefix = format!("{}.sync-conflict-", stem);
+    let suffix: String = path
+        .extension()
+        .and_then(|e| e.to_str())
+        .map_or_else(String::new, |e| format!(".{}", e));
+    let entries = match fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(format!("Could not list {}: {}", dir.display(), e)),
+    };
+    let mut conflicts: Vec<PathBuf> = entries
+        .filter_map(|entry| entry.ok())
+        .filter(|entry| {
+            entry
+                .file_name()
+                .to_str()
+                .is_some_and(|name| name.starts_with(&prefix) && name.ends_with(&suffix))
+        })
+        .map(|entry| entry.path())
+        .collect();
+    conflicts.sort();
+    Ok(conflicts)
+}
+
+/// Returns when a file was last modified.
+fn modified_time(path: &Path) -> Result<SystemTime, String> {
+    fs::metadata(path)
+        .and_then(|metadata| metadata.modified())
+        .map_err(|e| {
+            format!(
+                "Could not read the modification time of {}: {}",
+                path.display(),
+                e
+            )
+        })
+}
+
+/// Merges conflict copies into a file with `git merge-file`.
+///
+/// # Arguments
+///
+/// * `repo` - The wallet repository.
+/// * `auto_file` - The relative path of the file.
+/// * `conflicts` - The conflict copies to merge in.
+///
+/// # Returns
+///
+/// Whether all copies merged cleanly. If not, the file is left untouched.
+fn merge_sync_conflicts(
+    repo: &Repository,
+    auto_file: &Path,
+    conflicts: &[PathBuf],
+) -> Result<bool, String> {
+    let workdir: &Path = repo
+        .workdir()
+        .ok_or("The repository has no working directory.")?;
+    let base: Vec<u8> = repo
+        .head()
+        .and_then(|head| head.peel_to_tree())
+        .and_then(|tree| tree.get_path(auto_file))
+        .and_then(|entry| entry.to_object(repo))
+        .and_then(|object| object.peel_to_blob())
+        .map(|blob| blob.content().to_vec())
+        .unwrap_or_default();
+    let base_file = NamedTempFile::new()
+        .and_then(|mut file| file.write_all(&base).map(|_| file))
+        .map_err(|e| format!("Could not write the merge base: {}", e))?;
+    let merged_file = NamedTempFile::new()
+        .map_err(|e| format!("Could not create a file for the merge: {}", e))?;
+    fs::copy(workdir.join(auto_file), merged_file.path())
+        .map_err(|e| format!("Could not copy {}: {}", auto_file.display(), e))?;
+
+    for conflict in conflicts {
+        let status = Command::new("git")
+            .arg("merge-file")
+            .arg(merged_file.path())
+            .arg(base_file.path())
+            .arg(conflict)
+            .status()
+            .map_err(|e| format!("Could not run git merge-file: {}", e))?;
+        if !status.success() {
+            return Ok(false);
+        }
+    }
+    fs::copy(merged_file.path(), workdir.join(auto_file))
+        .map_err(|e| format!("Could not write the merged {}: {}", auto_file.display(), e))?;
+    Ok(true)
+}
+
+/// Resolves Syncthing conflict copies of auto files in the working tree.
+///
+/// # Arguments
+///
+/// * `repo` - The wallet repository.
+/// * `auto_files` - The mark files to potentially push.
+/// * `policy` - How to resolve the conflicts.
+fn resolve_sync_conflicts<A>(
+    repo: &Repository,
+    auto_files: &[A],
+    policy: SyncConflictPolicy,
+) -> Result<(), String>
+where
+    A: AsRef<Path>,
+{
+    let workdir: &Path = repo
+        .workdir()
+        .ok_or("The repository has no working directory.")?;
+    let mut unresolved: Vec<PathBuf> = Vec::new();
+    for auto_file in auto_files {
+        let auto_file: &Path = auto_file.as_ref();
+        let full_path: PathBuf = workdir.join(auto_file);
+        let conflicts: Vec<PathBuf> = find_sync_conflicts(&full_path)?;
+        if conflicts.is_empty() {
+            continue;
+        }
+        let resolved: bool = match policy {
+            SyncConflictPolicy::Abort => false,
+            SyncConflictPolicy::PreferNewest => {
+                let mut newest: &Path = &full_path;
+                let mut newest_time: SystemTime = modified_time(&full_path)?;
+                for conflict in &conflicts {
+                    let time: SystemTime = modified_time(conflict)?;
+                    if time > newest_time {
+                        newest = conflict;
+                        newest_time = time;
+                    }
+                }
+                if newest != full_path {
+                    info!(
+                        "Replacing {} with its newer conflict copy {}.",
+                        auto_file.display(),
+                        newest.display()
+                    );
+                    fs::copy(newest, &full_path)
+                        .map_err(|e| format!("Could not replace {}: {}", full_path.display(), e))?;
+                }
+                true
+            }
+            SyncConflictPolicy::Merge => {
+                let merged: bool = merge_sync_conflicts(repo, auto_file, &conflicts)?;
+                if merged {
+                    info!(
+                        "Merged {} conflict copies into {}.",
+                        conflicts.len(),
+                        auto_file.display()
+                    );
+                }
+                merged
+            }
+        };
+        if !resolved {
+            unresolved.extend(conflicts);
+            continue;
+        }
+        for conflict in &conflicts {
+            fs::remove_file(conflict)
+                .map_err(|e| format!("Could not remove {}: {}", conflict.display(), e))?;
+        }
+    }
+    if unresolved.is_empty() {
+        return Ok(());
+    }
+    Err(format!(
+        "Found unresolved Syncthing conflict copies of auto files:\n{}",
+        unresolved
+            .iter()
+            .map(|path| format!("  {}", path.display()))
+            .collect::<Vec<String>>()
+            .join("\n")
+    ))
+}
+
+/// Reads when the pending batch started from the [`BATCH_FILE`] at `path`.
+fn batch_started(path: &Path) -> Option<SystemTime> {
+    let seconds: u64 = fs::read_to_string(path).ok()?.trim().parse().ok()?;
+    Some(SystemTime::UNIX_EPOCH + Duration::from_secs(seconds))
+}
+
+/// Returns when the pending batch of a repository is due to be pushed, or
+/// `None` if there's none, i.e., HEAD isn't an unpushed auto commit.
+///
+/// # Arguments
+///
+/// * `repo` - The original repository.
+/// * `request` - The options of the run.
+fn batch_deadline(repo: &Repository, request: &PushRequest) -> Result<Option<SystemTime>, String> {
+    if request.batch_window.is_zero() {
+        return Ok(None);
+    }
+    let Some(started) = batch_started(&repo.path().join(BATCH_FILE)) else {
+        return Ok(None);
+    };
+    Ok(unpushed_auto_commit(repo, &request.push_target)?.map(|_| started + request.batch_window))
+}
+
+/// Returns whether the pending batch of a repository is due to be pushed, see
+/// [`batch_deadline`].
+fn batch_due(repo: &Repository, request: &PushRequest) -> Result<bool, String> {
+    Ok(batch_deadline(repo, request)?.is_some_and(|deadline| deadline <= SystemTime::now()))
+}
+
+/// Returns the path of the file that stores the state of the last run.
+fn run_state_path(repo: &Repository) -> PathBuf {
+    repo.path().join(RUN_STATE_FILE)
+}
+
+/// Locks the run lock file of the repository, so that runs on it don't race.
+///
+/// A Git directory that can't be written, e.g., on a read-only mount, gets its
+/// lock file in `$XDG_RUNTIME_DIR` or the temporary directory instead, named
+/// after a hash of the Git directory's path. A run on it can still skip an
+/// unchanged repository.
+///
+/// # Returns
+///
+/// The locked file, which holds the lock until it's closed, or `None` if
+/// another run holds the lock. The operating system releases the lock of a
+/// crashed run.
+fn lock_run(repo: &Repository) -> Result<Option<File>, String> {
+    let open = |lock_path: &Path| {
+        File::options()
+            .create(true)
+            .truncate(false)
+            .write(true)
+            .open(lock_path)
+    };
+    let mut lock_path: PathBuf = repo.path().join(RUN_LOCK_FILE);
+    let file: File = match open(&lock_path) {
+        Ok(file) => file,
+        Err(e)
+            if matches!(
+                e.kind(),
+                io::ErrorKind::PermissionDenied | io::ErrorKind::ReadOnlyFilesystem
+            ) =>
+        {
+            let fallback: PathBuf = fallback_lock_path(repo.path())?;
+            info!(
+                "Could not open {} ({}), so {} locks the run instead.",
+                lock_path.display(),
+                e,
+                fallback.display()
+            );
+            lock_path = fallback;
+            open(&lock_path)
+                .map_err(|e| format!("Could not open {}: {}", lock_path.display(), e))?
+        }
+        Err(e) => return Err(format!("Could not open {}: {}", lock_path.display(), e)),
+    };
+    match file.try_lock() {
+        Ok(()) => Ok(Some(file)),
+        Err(TryLockError::WouldBlock) => Ok(None),
+        Err(TryLockError::Error(e)) => {
+            Err(format!("Could not lock {}: {}", lock_path.display(), e))
+        }
+    }
+}
+
+/// Returns the path of the run lock file of a Git directory that can't be
+/// written, see [`lock_run`].
+fn fallback_lock_path(git_dir: &Path) -> Result<PathBuf, String> {
+    let git_dir: PathBuf = fs::canonicalize(git_dir).unwrap_or(git_dir.into());
+    let key: Oid = Oid::hash_object(ObjectType::Blob, git_dir.as_os_str().as_encoded_bytes())
+        .map_err(|e| format!("Could not hash {}: {}", git_dir.display(), e))?;
+    Ok(env::var_os("XDG_RUNTIME_DIR")
+        .map(PathBuf::from)
+        .filter(|dir| dir.is_dir())
+        .unwrap_or_else(env::temp_dir)
+        .join(format!("push-wallet-marks-{}.lock", key)))
+}
+
+/// Returns the permission bits of a file, which Git tracks as the file mode.
+#[cfg(unix)]
+fn file_mode(path: &Path) -> Result<u32, String> {
+    use std::os::unix::fs::PermissionsExt;
+    fs::metadata(path)
+        .map(|metadata| metadata.permissions().mode())
+        .map_err(|e| format!("Could not read the metadata of {}: {}", path.display(), e))
+}
+
+#[cfg(not(unix))]
+fn file_mode(_path: &Path) -> Result<u32, String> {
+    Ok(0)
+}
+
+/// Describes what a run's outcome depends on: HEAD, the mark files, and the
+/// options of the request.
+///
+/// Two runs with the same description reach the same conclusion, so the
+/// description is persisted and compared to skip repeated no-op runs. The
+/// options that decide which changes count are spelled out, and a hash of
+/// the whole request covers the others, so that changing any option runs
+/// again.
+///
+/// # Arguments
+///
+/// * `repo` - The wallet repository.
+/// * `request` - The options of the run.
+/// * `auto_files` - The mark files to potentially push.
+fn describe_run_state<A>(
+    repo: &Repository,
+    request: &PushRequest,
+    auto_files: &[A],
+) -> Result<String, String>
+where
+    A: AsRef<Path>,
+{
+    let workdir: &Path = repo
+        .workdir()
+        .ok_or("The repository has no working directory.")?;
+    let head: String = match repo.head() {
+        Ok(head) => head
+            .target()
+            .map_or_else(String::new, |oid| oid.to_string()),
+        Err(e) if e.code() == ErrorCode::UnbornBranch => "unborn".to_string(),
+        Err(e) => return Err(format!("Could not resolve HEAD: {}", e)),
+    };
+
+    // Whether to ignore the state doesn't change the outcome, and dry runs
+    // don't record one.
+    let options = PushRequest {
+        ignore_state: false,
+        dry_run: false,
+        ..request.clone()
+    };
+    let options: Oid = Oid::hash_object(ObjectType::Blob, format!("{:?}", options).as_bytes())
+        .map_err(|e| format!("Could not hash the options: {}", e))?;
+    let changes: ChangeFilter = request.changes;
+
+    let mut state = format!(
+        "version {}\nhead {}\noptions {}\n",
+        RUN_STATE_VERSION, head, options
+    );
+    // A mode-only change is skipped with the option, but pushed without it,
+    // and vice versa for an untracked file.
+    state.push_str(&format!(
+        "ignore-mode-changes {}\n",
+        changes.ignore_mode_changes
+    ));
+    state.push_str(&format!("allow-new {}\n", changes.allow_new));
+    // The files below are what the patterns resolved to, but an exclude
+    // pattern only applies when the statuses are filtered.
+    for pattern in &request.auto_files {
+        state.push_str(&format!("auto-file {}\n", pattern.display()));
+    }
+    for exclude in &request.excludes {
+        state.push_str(&format!("exclude {}\n", exclude));
+    }
+    for auto_file in auto_files {
+        let auto_file: &Path = auto_file.as_ref();
+        let full_path: PathBuf = workdir.join(auto_file);
+        let hash: String = if full_path.exists() {
+            let oid: Oid = Oid::hash_file(ObjectType::Blob, &full_path)
+                .map_err(|e| format!("Could not hash {}: {}", full_path.display(), e))?;
+            format!("{} {:o}", oid, file_mode(&full_path)?)
+        } else {
+            "missing".to_string()
+        };
+        state.push_str(&format!("{} {}\n", hash, auto_file.display()));
+    }
+    Ok(state)
+}
+
+/// Stages mark files all-or-nothing.
+///
+/// If any mark file can't be staged, the in-memory index is reloaded from disk,
+/// so no half-staged index can be written or committed later.
+///
+/// # Arguments
+///
+/// * `index` - The repository index.
+/// * `mark_file_statuses` - The mark files to stage.
+/// * `changes` - Which changes may be staged.
+fn stage_mark_files(
+    index: &mut Index,
+    mark_file_statuses: &[StatusEntryBetter],
+    changes: ChangeFilter,
+) -> Result<(), String> {
+    add_mark_files(index, mark_file_statuses, changes).or_else(|err| {
+        index.read(true).map_err(|e| {
+            format!(
+                "{}\nCould not roll back the partially staged index: {}",
+                err, e
+            )
+        })?;
+        Err(err)
+    })
+}
+
+/// Formats the date of a Git time in its own time zone as YYYY-MM-DD.
+fn format_date(time: git2::Time) -> String {
+    let seconds: i64 = time.seconds() + i64::from(time.offset_minutes()) * 60;
+    // Converts days since 1970-01-01 to a civil date, see
+    // https://howardhinnant.github.io/date_algorithms.html#civil_from_days.
+    let days: i64 = seconds.div_euclid(86_400) + 719_468;
+    let era: i64 = days.div_euclid(146_097);
+    let day_of_era: i64 = days - era * 146_097;
+    let year_of_era: i64 =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+    let day_of_year: i64 = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let shifted_month: i64 = (5 * day_of_year + 2) / 153;
+    let day: i64 = day_of_year - (153 * shifted_month + 2) / 5 + 1;
+    let month: i64 = if shifted_month < 10 {
+        shifted_month + 3
+    } else {
+        shifted_month - 9
+    };
+    let year: i64 = year_of_era + era * 400 + i64::from(month <= 2);
+    format!("{:04}-{:02}-{:02}", year, month, day)
+}
+
+/// Formats a commit time as an RFC 3339 timestamp in its own time zone, or
+/// with a `Z` for UTC.
+fn format_time(time: git2::Time) -> String {
+    let offset_minutes: i32 = time.offset_minutes();
+    let seconds_of_day: i64 = (time.seconds() + i64::from(offset_minutes) * 60).rem_euclid(86_400);
+    let zone: String = match offset_minutes {
+        0 => "Z".to_string(),
+        _ => format!(
+            "{}{:02}:{:02}",
+            if offset_minutes < 0 { '-' } else { '+' },
+            offset_minutes.abs() / 60,
+            offset_minutes.abs() % 60
+        ),
+    };
+    format!(
+        "{}T{:02}:{:02}:{:02}{}",
+        format_date(time),
+        seconds_of_day / 3600,
+        seconds_of_day / 60 % 60,
+        seconds_of_day % 60,
+        zone
+    )
+}
+
+/// Fills in the placeholders of a commit message or tag template.
+///
+/// # Arguments
+///
+/// * `kind` - What the template is for, e.g., "commit message".
+/// * `template` - The template, see [`COMMIT_MESSAGE_PLACEHOLDERS`].
+/// * `mark_file_statuses` - The committed mark files.
+/// * `time` - The commit time.
+fn render_template(
+    kind: &str,
+    template: &str,
+    mark_file_statuses: &[StatusEntryBetter],
+    time: git2::Time,
+) -> Result<String, String> {
+    let mut message = String::new();
+    let mut rest: &str = template;
+    while let Some(start) = rest.find(['{', '}']) {
+        message.push_str(&rest[..start]);
+        rest = &rest[start..];
+        if let Some(after) = rest.strip_prefix("{{") {
+            message.push('{');
+            rest = after;
+            continue;
+        }
+        if let Some(after) = rest.strip_prefix("}}") {
+            message.push('}');
+            rest = after;
+            continue;
+        }
+        let end: usize = match rest.find('}') {
+            Some(end) if rest.starts_with('{') => end,
+            _ => {
+                return Err(format!(
+                    "The {} template has an unmatched brace: {}",
+                    kind, template
+                ))
+            }
+        };
+        match &rest[1..end] {
+            "files" => message.push_str(
+                &mark_file_statuses
+                    .iter()
+                    .map(|mark_file_status| mark_file_status.path.display().to_string())
+                    .collect::<Vec<String>>()
+                    .join(", "),
+            ),
+            "count" => message.push_str(&mark_file_statuses.len().to_string()),
+            "date" => message.push_str(&format_date(time)),
+            "local-time" => message.push_str(&format_time(time)),
+            "utc-time" => message.push_str(&format_time(git2::Time::new(time.seconds(), 0))),
+            "hostname" => message.push_str(&gethostname::gethostname().to_string_lossy()),
+            placeholder => {
+                return Err(format!(
+                    "The {} template has an unknown placeholder {{{}}}. Known ones are: {}.",
+                    kind,
+                    placeholder,
+                    COMMIT_MESSAGE_PLACEHOLDERS
+                        .iter()
+                        .map(|(name, _)| format!("{{{}}}", name))
+                        .collect::<Vec<String>>()
+                        .join(", ")
+                ))
+            }
+        }
+        rest = &rest[end + 1..];
+    }
+    message.push_str(rest);
+    Ok(message)
+}
+
+/// Builds the message of an auto commit.
+///
+/// # Arguments
+///
+/// * `mark_file_statuses` - The committed mark files.
+/// * `template` - The message template, if not the default message.
+/// * `strategy` - How the mark files are split into commits.
+/// * `time` - The commit time.
+fn commit_message(
+    mark_file_statuses: &[StatusEntryBetter],
+    template: Option<&str>,
+    strategy: CommitStrategy,
+    time: git2::Time,
+) -> Result<String, String> {
+    let message: String = match (template, mark_file_statuses) {
+        (Some(template), _) => {
+            render_template("commit message", template, mark_file_statuses, time)?
+        }
+        (None, [mark_file_status]) if strategy == CommitStrategy::PerFile => {
+            format!("{} in {}", COMMIT_SUMMARY, mark_file_status.path.display())
+        }
+        (None, _) => {
+            let mut message = format!("{}\n\n", COMMIT_SUMMARY);
+            for mark_file_status in mark_file_statuses {
+                message.push_str(&format!("- {}\n", mark_file_status.path.display()));
+            }
+            message
+        }
+    };
+    Ok(format!(
+        "{}\n\n{}\n{}: {}\n",
+        message.trim_end(),
+        AUTO_COMMIT_TRAILER,
+        HOST_TRAILER_KEY,
+        gethostname::gethostname().to_string_lossy()
+    ))
+}
+
+/// Builds the name of the tag of an auto commit.
+///
+/// # Arguments
+///
+/// * `template` - The tag template, see [`COMMIT_MESSAGE_PLACEHOLDERS`].
+/// * `mark_file_statuses` - The committed mark files.
+/// * `time` - The commit time.
+///
+/// # Returns
+///
+/// The tag name without `refs/tags/`.
+fn tag_name(
+    template: &str,
+    mark_file_statuses: &[StatusEntryBetter],
+    time: git2::Time,
+) -> Result<String, String> {
+    let name: String = render_template("tag", template, mark_file_statuses, time)?;
+    if !Reference::is_valid_name(&format!("refs/tags/{}", name)) {
+        return Err(format!(
+            "The tag template {} gives {}, which isn't a valid tag name.",
+            template, name
+        ));
+    }
+    Ok(name)
+}
+
+/// Creates a lightweight tag on a commit unless it already exists there.
+///
+/// # Arguments
+///
+/// * `repo` - The repository to create the tag in.
+/// * `name` - The tag name without `refs/tags/`.
+/// * `commit_id` - The tagged commit.
+fn create_tag(repo: &Repository, name: &str, commit_id: Oid) -> Result<(), String> {
+    let tag_ref: String = format!("refs/tags/{}", name);
+    if let Ok(existing) = repo.find_reference(&tag_ref) {
+        return match existing.target() {
+            Some(target) if target == commit_id => Ok(()),
+            _ => Err(format!(
+                "The tag {} already exists on another commit, so it isn't moved.",
+                name
+            )),
+        };
+    }
+    repo.reference(
+        &tag_ref,
+        commit_id,
+        false,
+        "push-wallet-marks: tag the auto commit",
+    )
+    .map(|_| ())
+    .map_err(|e| format!("Could not create the tag {}: {}", name, e))
+}
+
+/// Snapshots HEAD under [`SNAPSHOT_REFS`] unless the latest snapshot is of
+/// the same commit, and deletes the oldest snapshots beyond a count.
+///
+/// # Arguments
+///
+/// * `repo` - The original repository.
+/// * `keep` - How many snapshots to keep.
+fn snapshot_head(repo: &Repository, keep: usize) -> Result<(), String> {
+    let Some(head) = head_commit(repo)? else {
+        return Ok(());
+    };
+    let mut snapshots: Vec<(u64, Reference)> = repo
+        .references_glob(&format!("{}*", SNAPSHOT_REFS))
+        .map_err(|e| format!("Could not list the snapshots: {}", e))?
+        .filter_map(Result::ok)
+        .filter_map(|reference| {
+            let seconds: u64 = reference
+                .name()?
+                .strip_prefix(SNAPSHOT_REFS)?
+                .parse()
+                .ok()?;
+            Some((seconds, reference))
+        })
+        .collect();
+    snapshots.sort_by_key(|(seconds, _)| *seconds);
+    match snapshots.last() {
+        Some((_, latest)) if latest.target() == Some(head.id()) => {
+            debug!("The latest snapshot is of HEAD already.");
+        }
+        latest => {
+            let now: u64 = SystemTime::now()
+                .duration_since(SystemTime::UNIX_EPOCH)
+                .map_or(0, |duration| duration.as_secs());
+            // Two runs within a second still get a snapshot each.
+            let seconds: u64 = latest.map_or(now, |(latest, _)| now.max(latest + 1));
+            let name: String = format!("{}{}", SNAPSHOT_REFS, seconds);
+            let snapshot: Reference = repo
+                .reference(&name, head.id(), false, "push-wallet-marks: snapshot")
+                .map_err(|e| format!("Could not create the snapshot {}: {}", name, e))?;
+            debug!("Snapshotted {} as {}.", head.id(), name);
+            snapshots.push((seconds, snapshot));
+        }
+    }
+    let excess: usize = snapshots.len().saturating_sub(keep);
+    for (_, mut snapshot) in snapshots.into_iter().take(excess) {
+        let name: String = snapshot.name().unwrap_or_default().to_string();
+        snapshot
+            .delete()
+            .map_err(|e| format!("Could not delete the snapshot {}: {}", name, e))?;
+        debug!("Deleted the snapshot {}.", name);
+    }
+    Ok(())
+}
+
+/// Tags a pushed auto commit and pushes the tag to the upstream's remote.
+///
+/// # Arguments
+///
+/// * `repo` - The repository that the auto commit was pushed from.
+/// * `upstream` - Where the auto commit was pushed.
+/// * `name` - The tag name without `refs/tags/`.
+/// * `commit_id` - The pushed auto commit.
+/// * `retry` - How to retry a push that failed because of the network.
+fn push_tag(
+    repo: &Repository,
+    upstream: &Upstream,
+    name: &str,
+    commit_id: Oid,
+    retry: RetryPolicy,
+) -> Result<(), String> {
+    create_tag(repo, name, commit_id)?;
+    let refspec = format!("refs/tags/{0}:refs/tags/{0}", name);
+    let mut remote = repo
+        .find_remote(&upstream.remote)
+        .map_err(|e| format!("Could not find the remote {}: {}", upstream.remote, e))?;
+    with_retries(retry, &format!("push to {}", upstream.remote), || {
+        remote.push(&[refspec.as_str()], Some(&mut push_options(repo)?))
+    })
+    .map_err(|e| remote_error("push to", &upstream.remote, e))?;
+    info!("Pushed the tag {} to {}.", name, upstream.remote);
+    Ok(())
+}
+
+/// Returns whether a commit was made by this tool, i.e., has its trailer.
+fn is_auto_commit(commit: &Commit) -> bool {
+    commit.message().is_some_and(|message| {
+        message
+            .lines()
+            .any(|line| line.trim_end() == AUTO_COMMIT_TRAILER)
+    })
+}
+
+/// Returns the machine that made an auto commit, from its trailer.
+fn auto_commit_host(commit: &Commit) -> Option<String> {
+    commit.message()?.lines().find_map(|line| {
+        let (key, value) = line.split_once(':')?;
+        (key == HOST_TRAILER_KEY).then(|| value.trim().to_string())
+    })
+}
+
+/// Finds an auto commit at HEAD that can be amended.
+///
+/// A commit counts as pushed if the remote-tracking branch contains it. The
+/// upstream isn't fetched, so that this works offline.
+///
+/// # Arguments
+///
+/// * `repo` - The wallet repository.
+/// * `target` - Where auto commits are pushed.
+///
+/// # Returns
+///
+/// The HEAD commit if it's an auto commit that hasn't been pushed yet.
+fn unpushed_auto_commit<'r>(
+    repo: &'r Repository,
+    target: &PushTarget,
+) -> Result<Option<Commit<'r>>, String> {
+    let Some(head) = head_commit(repo)? else {
+        return Ok(None);
+    };
+    if !is_auto_commit(&head) || head.parent_count() != 1 {
+        return Ok(None);
+    }
+    let upstream = Upstream::of_head(repo, target)?;
+    let pushed: bool = match repo.refname_to_id(&upstream.tracking_ref()) {
+        Ok(upstream_id) => {
+            upstream_id == head.id()
+                || repo
+                    .graph_descendant_of(upstream_id, head.id())
+                    .map_err(|e| {
+                        format!(
+                            "Could not compare HEAD with {}: {}",
+                            upstream.tracking_ref(),
+                            e
+                        )
+                    })?
+        }
+        Err(e) if e.code() == ErrorCode::NotFound => false,
+        Err(e) => {
+            return Err(format!(
+                "Could not resolve {}: {}",
+                upstream.tracking_ref(),
+                e
+            ))
+        }
+    };
+    Ok((!pushed).then_some(head))
+}
+
+/// Lists the files that a commit changes compared to its first parent, or all
+/// of its files if it's a root commit.
+fn changed_files(repo: &Repository, commit: &Commit) -> Result<Vec<PathBuf>, String> {
+    let tree = commit
+        .tree()
+        .map_err(|e| format!("Could not read the tree of {}: {}", commit.id(), e))?;
+    let parent_tree: Option<Tree> = match commit.parent_count() {
+        0 => None,
+        _ => Some(
+            commit
+                .parent(0)
+                .and_then(|parent| parent.tree())
+                .map_err(|e| format!("Could not read the parent of {}: {}", commit.id(), e))?,
+        ),
+    };
+    let diff = repo
+        .diff_tree_to_tree(parent_tree.as_ref(), Some(&tree), None)
+        .map_err(|e| format!("Could not diff {}: {}", commit.id(), e))?;
+    Ok(diff
+        .deltas()
+        .filter_map(|delta| delta.new_file().path().or(delta.old_file().path()))
+        .map(Path::to_path_buf)
+        .collect())
+}
+
+/// Checks that an auto commit changes nothing but auto files, so that a bug
+/// can't push other changes of the user.
+///
+/// # Arguments
+///
+/// * `repo` - The workspace repository.
+/// * `commit_id` - The auto commit.
+/// * `auto_files` - The auto files, relative to the working directory.
+fn check_commit_files<A>(repo: &Repository, commit_id: Oid, auto_files: &[A]) -> Result<(), String>
+where
+    A: AsRef<Path>,
+{
+    let commit: Commit = repo
+        .find_commit(commit_id)
+        .map_err(|e| format!("Could not find the auto commit {}: {}", commit_id, e))?;
+    let other_files: Vec<String> = changed_files(repo, &commit)?
+        .into_iter()
+        .filter(|path| {
+            !auto_files
+                .iter()
+                .any(|auto_file| auto_file.as_ref() == path)
+        })
+        .map(|path| path.display().to_string())
+        .collect();
+    if other_files.is_empty() {
+        Ok(())
+    } else {
+        Err(format!(
+            "The auto commit {} changes files besides the auto files, so it wasn't pushed: {}.",
+            commit_id,
+            other_files.join(", ")
+        ))
+    }
+}
+
+/// Lists the files that an amended auto commit changes, i.e., the ones of the
+/// replaced commit and the newly staged ones.
+///
+/// # Arguments
+///
+/// * `repo` - The wallet repository.
+/// * `amended` - The replaced auto commit.
+/// * `index` - The index with the staged mark files.
+fn amended_mark_files(
+    repo: &Repository,
+    amended: &Commit,
+    index: &Index,
+) -> Result<Vec<StatusEntryBetter>, String> {
+    let parent_tree = amended
+        .parent(0)
+        .and_then(|parent| parent.tree())
+        .map_err(|e| format!("Could not read the parent of {}: {}", amended.id(), e))?;
+    let diff = repo
+        .diff_tree_to_index(Some(&parent_tree), Some(index), None)
+        .map_err(|e| format!("Could not diff the amended commit: {}", e))?;
+    Ok(diff
+        .deltas()
+        .filter_map(|delta| {
+            let path: &Path = delta.new_file().path().or(delta.old_file().path())?;
+            let status: Status = match delta.status() {
+                Delta::Added => Status::INDEX_NEW,
+                Delta::Deleted => Status::INDEX_DELETED,
+                _ => Status::INDEX_MODIFIED,
+            };
+            Some(StatusEntryBetter {
+                path: path.to_path_buf(),
+                status,
+                old_path: None,
+            })
+        })
+        .collect())
+}
+
+/// Returns the signature of the user, as configured for the repository.
+fn committer(repo: &Repository) -> Result<Signature<'static>, String> {
+    repo.signature().map_err(|e| {
+        format!(
+            "Could not determine the committer, is user.name and user.email set?: {}",
+            e
+        )
+    })
+}
+
+/// Hands out committer times that increase across the runs on a repository.
+///
+/// Two runs within the same second, or a clock that was turned back, would
+/// otherwise give auto commits times that don't match their order. The last
+/// time is persisted in the original repository, whose runs are serialized by
+/// the run lock.
+struct CommitClock {
+    /// The file that stores the last time.
+    path: PathBuf,
+    /// Whether to persist the times, which dry runs don't.
+    record: bool,
+}
+
+impl CommitClock {
+    /// Creates the clock of a repository.
+    ///
+    /// # Arguments
+    ///
+    /// * `git_dir` - The Git directory of the original repository.
+    /// * `record` - Whether to persist the times.
+    fn new(git_dir: &Path, record: bool) -> Self {
+        CommitClock {
+            path: git_dir.join(COMMIT_TIME_FILE),
+            record,
+        }
+    }
+
+    /// Returns the committer at the current time, or a second after the last
+    /// time if that's later.
+    fn committer(&self, repo: &Repository) -> Result<Signature<'static>, String> {
+        let now: Signature = committer(repo)?;
+        let last: Option<i64> = fs::read_to_string(&self.path)
+            .ok()
+            .and_then(|content| content.trim().parse().ok());
+        let seconds: i64 = match last {
+            Some(last) if last >= now.when().seconds() => {
+                debug!(
+                    "The last auto commit is from {}, so the next one is a second later.",
+                    last
+                );
+                last + 1
+            }
+            _ => now.when().seconds(),
+        };
+        let signature: Signature = Signature::new(
+            &String::from_utf8_lossy(now.name_bytes()),
+            &String::from_utf8_lossy(now.email_bytes()),
+            &Time::new(seconds, now.when().offset_minutes()),
+        )
+        .map_err(|e| format!("Could not create the committer: {}", e))?;
+        // Like the run state, the time is only a nicety, so a failure to
+        // record it doesn't fail the run.
+        if self.record {
+            if let Err(e) = fs::write(&self.path, format!("{}\n", seconds)) {
+                warn!(
+                    "Could not record the commit time in {}: {}",
+                    self.path.display(),
+                    e
+                );
+            }
+        }
+        Ok(signature)
+    }
+}
+
+/// Counts auto commits towards the next maintenance in the
+/// [`COMMIT_COUNT_FILE`] of a repository.
+///
+/// # Arguments
+///
+/// * `repo` - The original repository.
+/// * `commits` - How many auto commits the run made.
+/// * `every` - After how many auto commits maintenance is due.
+///
+/// # Returns
+///
+/// Whether maintenance is due, which restarts the count.
+fn count_auto_commits(repo: &Repository, commits: usize, every: usize) -> Result<bool, String> {
+    let path: PathBuf = repo.path().join(COMMIT_COUNT_FILE);
+    let count: usize = fs::read_to_string(&path)
+        .ok()
+        .and_then(|content| content.trim().parse().ok())
+        .unwrap_or(0)
+        + commits;
+    let due: bool = count >= every;
+    fs::write(&path, format!("{}\n", if due { 0 } else { count })).map_err(|e| {
+        format!(
+            "Could not count the auto commits in {}: {}",
+            path.display(),
+            e
+        )
+    })?;
+    Ok(due)
+}
+
+/// Runs `git maintenance run`, which packs the objects of the repository, among
+/// others.
+///
+/// # Arguments
+///
+/// * `repo` - The original repository.
+fn run_maintenance(repo: &Repository) -> Result<(), String> {
+    let output = Command::new("git")
+        .arg("--git-dir")
+        .arg(repo.path())
+        .args(["maintenance", "run"])
+        .stdin(Stdio::null())
+        .output()
+        .map_err(|e| format!("Could not run git maintenance: {}", e))?;
+    if !output.status.success() {
+        return Err(format!(
+            "git maintenance failed ({}): {}",
+            output.status,
+            String::from_utf8_lossy(&output.stderr).trim_end()
+        ));
+    }
+    info!("Ran git maintenance in {}.", repo.path().display());
+    Ok(())
+}
+
+/// Returns the author of auto commits, which is the committer unless it's
+/// overridden.
+///
+/// The committer stays the user, so that their signing key still matches.
+fn author(repo: &Repository, overrides: &Identity) -> Result<Signature<'static>, String> {
+    let (name, email): (String, String) = match (&overrides.name, &overrides.email) {
+        (Some(name), Some(email)) => (name.clone(), email.clone()),
+        (name, email) => {
+            let committer: Signature = committer(repo)?;
+            (
+                name.clone().unwrap_or_else(|| {
+                    String::from_utf8_lossy(committer.name_bytes()).into_owned()
+                }),
+                email.clone().unwrap_or_else(|| {
+                    String::from_utf8_lossy(committer.email_bytes()).into_owned()
+                }),
+            )
+        }
+    };
+    Signature::now(&name, &email)
+        .map_err(|e| format!("Could not use {} <{}> as the author: {}", name, email, e))
+}
+
+/// Decides whether changed mark files are committed and pushed.
+pub trait Confirmer: fmt::Debug + Send + Sync {
+    /// Returns whether to commit and push the changes of `files`, which are
+    /// relative to the repository.
+    fn confirm(&self, files: &[PathBuf]) -> Result<bool, String>;
+}
+
+/// Signs commits.
+pub trait CommitSigner: fmt::Debug + Send + Sync {
+    /// Returns the signature of the raw commit `content`, to be stored in its
+    /// `gpgsig` header.
+    fn sign(&self, content: &str) -> Result<String, String>;
+}
+
+/// Signs commits with gpg, the way Git does.
+#[derive(Clone, Debug)]
+pub struct GpgSigner {
+    program: String,
+    key: String,
+}
+
+impl GpgSigner {
+    /// Creates a signer that runs `program` with the secret key `key`.
+    pub fn new<P: Into<String>, K: Into<String>>(program: P, key: K) -> Self {
+        GpgSigner {
+            program: program.into(),
+            key: key.into(),
+        }
+    }
+}
+
+impl CommitSigner for GpgSigner {
+    fn sign(&self, content: &str) -> Result<String, String> {
+        let mut child = Command::new(&self.program)
+            .args(["--status-fd=2", "-bsau", &self.key])
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()
+            .map_err(|e| format!("Could not run {}: {}", self.program, e))?;
+        child
+            .stdin
+            .take()
+            .expect("The stdin of the signer is piped.")
+            .write_all(content.as_bytes())
+            .map_err(|e| format!("Could not pass the commit to {}: {}", self.program, e))?;
+        let output = child
+            .wait_with_output()
+            .map_err(|e| format!("Could not run {}: {}", self.program, e))?;
+        let status: String = String::from_utf8_lossy(&output.stderr).into_owned();
+        if !output.status.success() || !status.contains("\n[GNUPG:] SIG_CREATED ") {
+            return Err(format!(
+                "{} failed to sign the commit: {}",
+                self.program,
+                status.trim()
+            ));
+        }
+        String::from_utf8(output.stdout)
+            .map_err(|_| format!("{} returned a signature that isn't UTF-8.", self.program))
+    }
+}
+
+/// Signs commits with an SSH key through `ssh-keygen -Y sign`, the way Git does
+/// with `gpg.format=ssh`.
+#[derive(Clone, Debug)]
+pub struct SshSigner {
+    program: String,
+    key: String,
+}
+
+impl SshSigner {
+    /// Creates a signer that runs `program` with `key`, which is either the
+    /// path of a key file or a literal public key whose private key is in
+    /// ssh-agent.
+    pub fn new<P: Into<String>, K: Into<String>>(program: P, key: K) -> Self {
+        SshSigner {
+            program: program.into(),
+            key: key.into(),
+        }
+    }
+}
+
+impl CommitSigner for SshSigner {
+    fn sign(&self, content: &str) -> Result<String, String> {
+        let dir =
+            tempdir().map_err(|e| format!("Could not create a directory to sign in: {}", e))?;
+        let content_file: PathBuf = dir.path().join("commit");
+        fs::write(&content_file, content)
+            .map_err(|e| format!("Could not write the commit to sign: {}", e))?;
+        let mut command = Command::new(&self.program);
+        command.args(["-Y", "sign", "-n", "git", "-f"]);
+        let literal_key: Option<&str> = self
+            .key
+            .strip_prefix("key::")
+            .or_else(|| self.key.starts_with("ssh-").then_some(self.key.as_str()));
+        if let Some(public_key) = literal_key {
+            let key_file: PathBuf = dir.path().join("key.pub");
+            fs::write(&key_file, public_key)
+                .map_err(|e| format!("Could not write the public signing key: {}", e))?;
+            command.arg(key_file).arg("-U");
+        } else {
+            let key_file: PathBuf = match (self.key.strip_prefix("~/"), env::var_os("HOME")) {
+                (Some(relative), Some(home)) => PathBuf::from(home).join(relative),
+                _ => PathBuf::from(&self.key),
+            };
+            command.arg(key_file);
+        }
+        let output = command
+            .arg(&content_file)
+            .stdin(Stdio::null())
+            .output()
+            .map_err(|e| format!("Could not run {}: {}", self.program, e))?;
+        if !output.status.success() {
+            return Err(format!(
+                "{} failed to sign the commit: {}",
+                self.program,
+                String::from_utf8_lossy(&output.stderr).trim()
+            ));
+        }
+        fs::read_to_string(dir.path().join("commit.sig"))
+            .map_err(|e| format!("Could not read the signature of {}: {}", self.program, e))
+    }
+}
+
+/// Returns the signer of auto commits, if they are to be signed.
+///
+/// Like Git, it follows `commit.gpgsign`, `gpg.format`, the `gpg.*.program`
+/// settings, and `user.signingkey`. OpenPGP falls back to the committer's
+/// identity as the key.
+fn commit_signer(
+    repo: &Repository,
+    request: &PushRequest,
+) -> Result<Option<Arc<dyn CommitSigner>>, String> {
+    let config: Config = repo
+        .config()
+        .map_err(|e| format!("Could not read the Git config: {}", e))?;
+    let sign: bool = match request.sign {
+        Some(sign) => sign,
+        None => request.signer.is_some() || config.get_bool("commit.gpgsign").unwrap_or(false),
+    };
+    if !sign {
+        return Ok(None);
+    }
+    if let Some(signer) = &request.signer {
+        return Ok(Some(Arc::clone(signer)));
+    }
+    let key: Option<String> = config.get_string("user.signingkey").ok();
+    let format: String = config
+        .get_string("gpg.format")
+        .unwrap_or_else(|_| "openpgp".to_string());
+    match format.as_str() {
+        "openpgp" | "x509" => {
+            let program: String = if format == "x509" {
+                config
+                    .get_string("gpg.x509.program")
+                    .unwrap_or_else(|_| "gpgsm".to_string())
+            } else {
+                config
+                    .get_string("gpg.openpgp.program")
+                    .or_else(|_| config.get_string("gpg.program"))
+                    .unwrap_or_else(|_| "gpg".to_string())
+            };
+            let key: String = match key {
+                Some(key) => key,
+                None => committer(repo)?.to_string(),
+            };
+            Ok(Some(Arc::new(GpgSigner::new(program, key))))
+        }
+        "ssh" => {
+            let program: String = config
+                .get_string("gpg.ssh.program")
+                .unwrap_or_else(|_| "ssh-keygen".to_string());
+            let key: String = key.ok_or(
+                "Signing with SSH needs user.signingkey set to a key file or a public key.",
+            )?;
+            Ok(Some(Arc::new(SshSigner::new(program, key))))
+        }
+        _ => Err(format!("Unsupported gpg.format `{}`.", format)),
+    }
+}
+
+/// Writes a commit object, signed if there is a signer, without updating any
+/// reference.
+fn write_commit(
+    repo: &Repository,
+    author: &Signature,
+    committer: &Signature,
+    message: &str,
+    tree: &Tree,
+    parents: &[&Commit],
+    signer: Option<&dyn CommitSigner>,
+) -> Result<Oid, String> {
+    let Some(signer) = signer else {
+        return repo
+            .commit(None, author, committer, message, tree, parents)
+            .map_err(|e| format!("Could not create the commit: {}", e));
+    };
+    let buffer = repo
+        .commit_create_buffer(author, committer, message, tree, parents)
+        .map_err(|e| format!("Could not create the commit: {}", e))?;
+    let content: &str = buffer
+        .as_str()
+        .ok_or("Could not create the commit: it isn't UTF-8.")?;
+    let signature: String = signer.sign(content)?;
+    repo.commit_signed(content, &signature, None)
+        .map_err(|e| format!("Could not create the signed commit: {}", e))
+}
+
+/// Returns the HEAD commit, or `None` if HEAD's branch is unborn, i.e., has
+/// no commit yet, as in a freshly initialized repository.
+fn head_commit<'r>(repo: &'r Repository) -> Result<Option<Commit<'r>>, String> {
+    match repo.head() {
+        Ok(head) => head
+            .peel_to_commit()
+            .map(Some)
+            .map_err(|e| format!("Could not find the HEAD commit: {}", e)),
+        Err(e) if e.code() == ErrorCode::UnbornBranch => Ok(None),
+        Err(e) => Err(format!("Could not resolve HEAD: {}", e)),
+    }
+}
+
+/// Returns the full name of the branch that HEAD points to, even if it's
+/// unborn, or `None` if HEAD is detached.
+fn head_branch(repo: &Repository) -> Result<Option<String>, String> {
+    let head = repo
+        .find_reference("HEAD")
+        .map_err(|e| format!("Could not resolve HEAD: {}", e))?;
+    Ok(head
+        .symbolic_target()
+        .filter(|name| name.starts_with("refs/heads/"))
+        .map(str::to_string))
+}
+
+/// Commits the staged mark files on top of HEAD, or as the root commit if
+/// HEAD's branch is unborn.
+///
+/// # Arguments
+///
+/// * `repo` - The wallet repository.
+/// * `index` - The index with the staged mark files.
+/// * `author` - The author of the commit.
+/// * `message` - The commit message.
+/// * `signer` - Signs the commit if given.
+/// * `amended` - The HEAD commit to replace instead of committing on top of it.
+/// * `clock` - Gives the commit its time.
+///
+/// # Returns
+///
+/// The ID of the new commit.
+fn commit_mark_files(
+    repo: &Repository,
+    index: &mut Index,
+    author: &Signature,
+    message: &str,
+    signer: Option<&dyn CommitSigner>,
+    amended: Option<&Commit>,
+    clock: &CommitClock,
+) -> Result<Oid, String> {
+    index
+        .write()
+        .map_err(|e| format!("Could not write the index: {}", e))?;
+    let tree_id: Oid = index
+        .write_tree()
+        .map_err(|e| format!("Could not write the tree: {}", e))?;
+    let tree = repo
+        .find_tree(tree_id)
+        .map_err(|e| format!("Could not find the written tree: {}", e))?;
+    let signature: Signature = clock.committer(repo)?;
+    let head_commit: Option<Commit> = head_commit(repo)?;
+    let parents: Vec<Commit> = match amended {
+        Some(amended) => amended.parents().collect(),
+        None => head_commit.iter().cloned().collect(),
+    };
+    let commit_id: Oid = write_commit(
+        repo,
+        author,
+        &signature,
+        message,
+        &tree,
+        &parents.iter().collect::<Vec<&Commit>>(),
+        signer,
+    )?;
+    let branch_ref: String =
+        head_branch(repo)?.ok_or("HEAD is detached, so there is no branch to commit on.")?;
+    let log_message: String = match (amended, &head_commit) {
+        (Some(_), _) => format!("commit (amend): {}", COMMIT_SUMMARY),
+        (None, Some(_)) => format!("commit: {}", COMMIT_SUMMARY),
+        (None, None) => format!("commit (initial): {}", COMMIT_SUMMARY),
+    };
+    match &head_commit {
+        Some(head_commit) => {
+            repo.reference_matching(&branch_ref, commit_id, true, head_commit.id(), &log_message)
+        }
+        // Fails if another process created the branch in the meantime.
+        None => repo.reference(&branch_ref, commit_id, false, &log_message),
+    }
+    .map_err(|e| format!("Could not update {}: {}", branch_ref, e))?;
+    Ok(commit_id)
+}
+
+/// The upstream of the current branch, i.e., where auto commits are pushed.
+struct Upstream {
+    /// The full name of the local branch, e.g., `refs/heads/main`.
+    pub branch_ref: String,
+    /// The name of the remote, e.g., `origin`.
+    pub remote: String,
+    /// The full name of the branch on the remote, e.g., `refs/heads/main`.
+    pub merge_ref: String,
+}
+
+impl Upstream {
+    /// Reads the upstream of the branch that HEAD points to.
+    ///
+    /// # Arguments
+    ///
+    /// * `repo` - The wallet repository.
+    /// * `target` - Overrides of the remote and the branch. If both are given,
+    ///   HEAD's branch doesn't need an upstream. If only the remote is given,
+    ///   the branch defaults to the upstream branch, or to HEAD's branch name
+    ///   without an upstream.
+    fn of_head(repo: &Repository, target: &PushTarget) -> Result<Self, String> {
+        let Some(branch_ref) = head_branch(repo)? else {
+            return Err("HEAD is detached, so there is no branch to push.".to_string());
+        };
+        let branch_ref: &str = &branch_ref;
+        let no_upstream = |_| {
+            format!(
+                "The branch {} has no upstream. Set one with `git branch --set-upstream-to` or pass --remote and --branch.",
+                branch_ref
+            )
+        };
+        let branch_name: &str = branch_ref.strip_prefix("refs/heads/").unwrap_or(branch_ref);
+        let remote: String = match &target.remote {
+            Some(remote) => remote.clone(),
+            None => repo
+                .branch_upstream_remote(branch_ref)
+                .map_err(no_upstream)?
+                .as_str()
+                .ok_or("The upstream remote's name isn’t valid UTF-8.")?
+                .to_string(),
+        };
+        let upstream_merge_ref = || {
+            repo.config()
+                .and_then(|config| config.get_string(&format!("branch.{}.merge", branch_name)))
+        };
+        let merge_ref: String = match (&target.branch, &target.remote) {
+            (Some(branch), _) if branch.starts_with("refs/") => branch.clone(),
+            (Some(branch), _) => format!("refs/heads/{}", branch),
+            (None, Some(_)) => upstream_merge_ref().unwrap_or_else(|_| branch_ref.to_string()),
+            (None, None) => upstream_merge_ref().map_err(no_upstream)?,
+        };
+        Ok(Upstream {
+            branch_ref: branch_ref.to_string(),
+            remote,
+            merge_ref,
+        })
+    }
+
+    /// Returns the remote-tracking reference of the upstream branch.
+    fn tracking_ref(&self) -> String {
+        let branch_name: &str = self
+            .merge_ref
+            .strip_prefix("refs/heads/")
+            .unwrap_or(&self.merge_ref);
+        format!("refs/remotes/{}/{}", self.remote, branch_name)
+    }
+}
+
+/// Prints the commits that a run would create and where it would be pushed.
+///
+/// # Arguments
+///
+/// * `repo` - The wallet repository.
+/// * `target` - Where to push.
+/// * `tag` - The name of the tag that would be created, if any.
+/// * `mirrors` - The remotes of the mirrors that would be pushed to as well.
+/// * `commits` - The mark files of each commit and its message.
+///
+/// # Returns
+///
+/// Where the commits would be pushed.
+fn report_dry_run(
+    repo: &Repository,
+    target: &PushTarget,
+    tag: Option<&str>,
+    mirrors: &[String],
+    commits: &[(&[StatusEntryBetter], String)],
+) -> Result<Upstream, String> {
+    let upstream = Upstream::of_head(repo, target)?;
+    for (mark_file_statuses, message) in commits {
+        info!(
+            "Would commit {} mark file(s) with the message:\n\n{}",
+            mark_file_statuses.len(),
+            message
+        );
+    }
+    info!(
+        "Would push {} to {} {}.",
+        upstream.branch_ref, upstream.remote, upstream.merge_ref
+    );
+    if let Some(tag) = tag {
+        info!("Would tag the auto commit as {}.", tag);
+    }
+    for mirror in mirrors {
+        info!(
+            "Would push {} to {} {}.",
+            upstream.branch_ref, mirror, upstream.merge_ref
+        );
+    }
+    Ok(upstream)
+}
+
+/// Lists the private SSH keys in their standard locations.
+fn default_ssh_keys() -> Vec<PathBuf> {
+    let Some(home) = env::var_os("HOME") else {
+        return Vec::new();
+    };
+    let ssh_dir: PathBuf = PathBuf::from(home).join(".ssh");
+    SSH_KEY_NAMES
+        .iter()
+        .map(|name| ssh_dir.join(name))
+        .filter(|path| path.is_file())
+        .collect()
+}
+
+/// Creates remote callbacks that authenticate like Git does.
+///
+/// SSH remotes get the keys in ssh-agent and then the keys in `~/.ssh`. HTTPS
+/// remotes get the credentials from the configured Git credential helpers.
+/// Each source is tried once, so a remote that rejects all of them fails
+/// instead of asking forever.
+///
+/// # Arguments
+///
+/// * `repo` - The wallet repository, whose configuration names the credential
+///   helpers.
+fn authenticating_callbacks<'a>(repo: &Repository) -> Result<RemoteCallbacks<'a>, String> {
+    let config: Config = repo
+        .config()
+        .map_err(|e| format!("Could not read the Git configuration: {}", e))?;
+    let mut tried_agent = false;
+    let mut ssh_keys = default_ssh_keys().into_iter();
+    let mut tried_helper = false;
+    let mut callbacks = RemoteCallbacks::new();
+    callbacks.credentials(move |url, username_from_url, allowed_types| {
+        let username: &str = username_from_url.unwrap_or("git");
+        if allowed_types.contains(CredentialType::USERNAME) {
+            return Cred::username(username);
+        }
+        if allowed_types.contains(CredentialType::SSH_KEY) {
+            if !tried_agent {
+                tried_agent = true;
+                if let Ok(cred) = Cred::ssh_key_from_agent(username) {
+                    return Ok(cred);
+                }
+            }
+            if let Some(key) = ssh_keys.next() {
+                return Cred::ssh_key(username, None, &key, None);
+            }
+        }
+        if allowed_types.contains(CredentialType::USER_PASS_PLAINTEXT) && !tried_helper {
+            tried_helper = true;
+            return Cred::credential_helper(&config, url, username_from_url);
+        }
+        Err(git2::Error::from_str("No more credentials to try"))
+    });
+    Ok(callbacks)
+}
+
+/// Describes a failed fetch or push, pointing out authentication problems.
+///
+/// # Arguments
+///
+/// * `action` - What failed, e.g., "push to".
+/// * `remote` - The name of the remote.
+/// * `error` - The error from libgit2.
+fn remote_error(action: &str, remote: &str, error: git2::Error) -> String {
+    if error.code() == ErrorCode::Auth {
+        format!(
+            "Could not authenticate to {} ({}). Tried ssh-agent, the keys {} in ~/.ssh, and the Git credential helpers.",
+            remote,
+            error,
+            SSH_KEY_NAMES.join(", ")
+        )
+    } else {
+        format!("Could not {} {}: {}", action, remote, error)
+    }
+}
+
+/// Returns whether a failed network operation might succeed if retried.
+///
+/// Authentication failures and refs rejected by the remote are permanent.
+fn is_transient(error: &git2::Error) -> bool {
+    error.code() != ErrorCode::Auth
+        && matches!(
+            error.class(),
+            ErrorClass::Net | ErrorClass::Os | ErrorClass::Ssh | ErrorClass::Ssl | ErrorClass::Http
+        )
+}
+
+/// Runs a network operation, retrying it with exponential backoff while it
+/// fails transiently.
+///
+/// # Arguments
+///
+/// * `retry` - How often and after what delay to retry.
+/// * `action` - What the operation does, e.g., "push to origin", for the log.
+/// * `operation` - The operation.
+fn with_retries<T, F>(retry: RetryPolicy, action: &str, mut operation: F) -> Result<T, git2::Error>
+where
+    F: FnMut() -> Result<T, git2::Error>,
+{
+    let mut delay: Duration = retry.delay;
+    let mut attempt: u32 = 0;
+    loop {
+        match operation() {
+            Err(e) if attempt < retry.retries && is_transient(&e) => {
+                attempt += 1;
+                warn!(
+                    "Could not {} ({}). Retrying in {} s ({}/{}).",
+                    action,
+                    e,
+                    delay.as_secs_f64(),
+                    attempt,
+                    retry.retries
+                );
+                thread::sleep(delay);
+                delay = delay.saturating_mul(2);
+            }
+            result => return result,
+        }
+    }
+}
+
+/// Returns the options of a fetch of only the upstream branch, which
+/// doesn't follow tags or write `FETCH_HEAD`. The remote then only sends the
+/// commits that the local references, e.g., the remote-tracking branch, don't
+/// have, so a run on a metered link downloads little more than the new marks.
+fn upstream_fetch_options<'a>(repo: &Repository) -> Result<FetchOptions<'a>, git2::Error> {
+    let mut fetch_options = FetchOptions::new();
+    fetch_options
+        .remote_callbacks(authenticating_callbacks(repo).map_err(|e| git2::Error::from_str(&e))?)
+        .download_tags(AutotagOption::None)
+        .update_fetchhead(false);
+    Ok(fetch_options)
+}
+
+/// Fetches the upstream branch into its remote-tracking reference, see
+/// [`upstream_fetch_options`].
+///
+/// # Arguments
+///
+/// * `repo` - The wallet repository.
+/// * `upstream` - The branch to fetch.
+/// * `retry` - How to retry a fetch that failed because of the network.
+///
+/// # Returns
+///
+/// The ID of the upstream branch's tip, or `None` if the remote doesn't have
+/// the branch.
+fn fetch_upstream(
+    repo: &Repository,
+    upstream: &Upstream,
+    retry: RetryPolicy,
+) -> Result<Option<Oid>, String> {
+    let mut remote = repo
+        .find_remote(&upstream.remote)
+        .map_err(|e| format!("Could not find the remote {}: {}", upstream.remote, e))?;
+    let refspec = format!("+{}:{}", upstream.merge_ref, upstream.tracking_ref());
+    with_retries(retry, &format!("fetch from {}", upstream.remote), || {
+        remote.fetch(
+            &[refspec.as_str()],
+            Some(&mut upstream_fetch_options(repo)?),
+            None,
+        )
+    })
+    .map_err(|e| remote_error("fetch from", &upstream.remote, e))?;
+    match repo.refname_to_id(&upstream.tracking_ref()) {
+        Ok(upstream_id) => Ok(Some(upstream_id)),
+        Err(e) if e.code() == ErrorCode::NotFound => Ok(None),
+        Err(e) => Err(format!(
+            "Could not resolve {}: {}",
+            upstream.tracking_ref(),
+            e
+        )),
+    }
+}
+
+/// Rewrites the first-parent chain of commits from `base` (exclusive) to `head`
+/// as signed commits and returns the new head.
+///
+/// An in-memory rebase can't sign the commits it creates.
+fn sign_commits(
+    repo: &Repository,
+    base: Oid,
+    head: Oid,
+    signer: &dyn CommitSigner,
+) -> Result<Oid, String> {
+    let mut chain: Vec<Commit> = Vec::new();
+    let mut commit_id: Oid = head;
+    while commit_id != base {
+        let commit = repo
+            .find_commit(commit_id)
+            .map_err(|e| format!("Could not find the rebased commit {}: {}", commit_id, e))?;
+        commit_id = commit
+            .parent_id(0)
+            .map_err(|e| format!("Could not find the parent of {}: {}", commit.id(), e))?;
+        chain.push(commit);
+    }
+    let mut parent = repo
+        .find_commit(base)
+        .map_err(|e| format!("Could not find the upstream commit {}: {}", base, e))?;
+    for commit in chain.iter().rev() {
+        let tree = commit
+            .tree()
+            .map_err(|e| format!("Could not find the tree of {}: {}", commit.id(), e))?;
+        let message: &str = commit
+            .message()
+            .ok_or_else(|| format!("The message of {} isn't UTF-8.", commit.id()))?;
+        let signed_id: Oid = write_commit(
+            repo,
+            &commit.author(),
+            &commit.committer(),
+            message,
+            &tree,
+            &[&parent],
+            Some(signer),
+        )?;
+        parent = repo
+            .find_commit(signed_id)
+            .map_err(|e| format!("Could not find the signed commit {}: {}", signed_id, e))?;
+    }
+    Ok(parent.id())
+}
+
+/// How far HEAD's branch and its upstream have diverged.
+struct Divergence {
+    /// The upstream commit.
+    upstream_id: Oid,
+    /// The number of commits that only the local branch has.
+    local: usize,
+    /// The number of commits that only the upstream has.
+    remote: usize,
+}
+
+/// Finds out whether HEAD's branch has diverged from its upstream.
+///
+/// # Arguments
+///
+/// * `repo` - The wallet repository.
+/// * `upstream` - HEAD's upstream.
+/// * `retry` - How to retry a fetch that failed because of the network.
+/// * `fetch` - Whether to fetch the upstream first instead of comparing with
+///   the last fetched state, e.g., not for dry runs.
+///
+/// # Returns
+///
+/// How far they diverged, or `None` if one contains the other or the branch
+/// doesn't exist on the remote.
+fn find_divergence(
+    repo: &Repository,
+    upstream: &Upstream,
+    retry: RetryPolicy,
+    fetch: bool,
+) -> Result<Option<Divergence>, String> {
+    let upstream_id: Option<Oid> = if fetch {
+        fetch_upstream(repo, upstream, retry)?
+    } else {
+        match repo.refname_to_id(&upstream.tracking_ref()) {
+            Ok(upstream_id) => Some(upstream_id),
+            Err(e) if e.code() == ErrorCode::NotFound => None,
+            Err(e) => {
+                return Err(format!(
+                    "Could not resolve {}: {}",
+                    upstream.tracking_ref(),
+                    e
+                ))
+            }
+        }
+    };
+    let Some(upstream_id) = upstream_id else {
+        return Ok(None);
+    };
+    let head_id: Oid = match repo.refname_to_id(&upstream.branch_ref) {
+        Ok(head_id) => head_id,
+        // An unborn branch has no local commits to diverge with.
+        Err(e) if e.code() == ErrorCode::NotFound => return Ok(None),
+        Err(e) => return Err(format!("Could not resolve {}: {}", upstream.branch_ref, e)),
+    };
+    let (local, remote) = repo.graph_ahead_behind(head_id, upstream_id).map_err(|e| {
+        format!(
+            "Could not compare {} with its upstream: {}",
+            upstream.branch_ref, e
+        )
+    })?;
+    Ok((local > 0 && remote > 0).then_some(Divergence {
+        upstream_id,
+        local,
+        remote,
+    }))
+}
+
+/// Rebases the current branch onto its upstream if the upstream has moved.
+///
+/// The rebase happens in memory, so it doesn't touch the working tree. A
+/// conflict aborts it, because the marks were then also changed elsewhere.
+///
+/// # Arguments
+///
+/// * `repo` - The wallet repository.
+/// * `target` - Where to push.
+/// * `retry` - How to retry a fetch that failed because of the network.
+/// * `signer` - Signs the rebased commits if given.
+/// * `clock` - Gives the rebased commits their time.
+///
+/// # Returns
+///
+/// The new tip of the branch, or `None` if no rebase was needed.
+fn rebase_onto_upstream(
+    repo: &Repository,
+    target: &PushTarget,
+    retry: RetryPolicy,
+    signer: Option<&dyn CommitSigner>,
+    clock: &CommitClock,
+) -> Result<Option<Oid>, String> {
+    let upstream = Upstream::of_head(repo, target)?;
+    // A branch that doesn't exist on the remote yet has nothing to rebase onto.
+    let Some(upstream_id) = fetch_upstream(repo, &upstream, retry)? else {
+        return Ok(None);
+    };
+    let head_id: Oid = repo
+        .refname_to_id(&upstream.branch_ref)
+        .map_err(|e| format!("Could not resolve {}: {}", upstream.branch_ref, e))?;
+    let up_to_date: bool = head_id == upstream_id
+        || repo
+            .graph_descendant_of(head_id, upstream_id)
+            .map_err(|e| {
+                format!(
+                    "Could not compare {} with its upstream: {}",
+                    upstream.branch_ref, e
+                )
+            })?;
+    if up_to_date {
+        return Ok(None);
+    }
+
+    let onto = repo
+        .find_annotated_commit(upstream_id)
+        .map_err(|e| format!("Could not find the upstream commit {}: {}", upstream_id, e))?;
+    let mut rebase_options = RebaseOptions::new();
+    rebase_options.inmemory(true);
+    let mut rebase = repo
+        .rebase(None, Some(&onto), None, Some(&mut rebase_options))
+        .map_err(|e| {
+            format!(
+                "Could not start rebasing onto {}: {}",
+                upstream.tracking_ref(),
+                e
+            )
+        })?;
+    let signature: Signature = clock.committer(repo)?;
+    let mut new_head_id: Oid = upstream_id;
+    while let Some(operation) = rebase.next() {
+        let result: Result<(), String> = operation
+            .map_err(|e| format!("Could not rebase onto {}: {}", upstream.tracking_ref(), e))
+            .and_then(|_| {
+                let index = rebase
+                    .inmemory_index()
+                    .map_err(|e| format!("Could not read the rebase index: {}", e))?;
+                if index.has_conflicts() {
+                    return Err(format!(
+                        "The changes on {} conflict with the local commits. Pull them manually.",
+                        upstream.tracking_ref()
+                    ));
+                }
+                match rebase.commit(None, &signature, None) {
+                    Ok(commit_id) => new_head_id = commit_id,
+                    // The upstream already has this change.
+                    Err(e) if e.code() == ErrorCode::Applied => {}
+                    Err(e) => return Err(format!("Could not commit the rebased changes: {}", e)),
+                }
+                Ok(())
+            });
+        if let Err(e) = result {
+            // Aborting an in-memory rebase can't leave anything behind.
+            let _ = rebase.abort();
+            return Err(e);
+        }
+    }
+    rebase
+        .finish(Some(&signature))
+        .map_err(|e| format!("Could not finish the rebase: {}", e))?;
+    if let Some(signer) = signer {
+        new_head_id = sign_commits(repo, upstream_id, new_head_id, signer)?;
+    }
+    repo.reference_matching(
+        &upstream.branch_ref,
+        new_head_id,
+        true,
+        head_id,
+        "push-wallet-marks: rebase onto the upstream",
+    )
+    .map_err(|e| format!("Could not update {}: {}", upstream.branch_ref, e))?;
+    info!(
+        "Rebased {} onto {} {}.",
+        upstream.branch_ref, upstream.remote, upstream.merge_ref
+    );
+    Ok(Some(new_head_id))
+}
+
+/// Returns the commit that the upstream branch was at when it was last
+/// fetched, or the zero ID if it wasn't fetched yet.
+fn last_fetched_upstream(repo: &Repository, target: &PushTarget) -> Result<Oid, String> {
+    let upstream = Upstream::of_head(repo, target)?;
+    match repo.refname_to_id(&upstream.tracking_ref()) {
+        Ok(upstream_id) => Ok(upstream_id),
+        Err(e) if e.code() == ErrorCode::NotFound => Ok(Oid::zero()),
+        Err(e) => Err(format!(
+            "Could not resolve {}: {}",
+            upstream.tracking_ref(),
+            e
+        )),
+    }
+}
+
+/// Pushes the current branch to its upstream.
+///
+/// Nothing is pushed if the upstream already has the branch's commits, e.g.,
+/// because the rebase dropped an auto commit whose changes were upstream
+/// already. Without a lease, the upstream was just fetched by the rebase, so
+/// the remote-tracking branch tells without connecting again.
+///
+/// # Arguments
+///
+/// * `repo` - The wallet repository.
+/// * `target` - Where to push.
+/// * `retry` - How to retry a push that failed because of the network.
+/// * `lease` - Forces the push if the remote branch is at this commit, or
+///   doesn't exist for the zero ID. The commit that the remote advertises for
+///   the push is checked, which also works for a remote without branches.
+///
+/// # Returns
+///
+/// The remote and the branch that were pushed to.
+fn push_head(
+    repo: &Repository,
+    target: &PushTarget,
+    retry: RetryPolicy,
+    lease: Option<Oid>,
+) -> Result<Upstream, String> {
+    let upstream = Upstream::of_head(repo, target)?;
+    let head_id: Oid = repo
+        .refname_to_id(&upstream.branch_ref)
+        .map_err(|e| format!("Could not resolve {}: {}", upstream.branch_ref, e))?;
+    let up_to_date = || {
+        info!(
+            "{} {} is at {} already, so there is nothing to push.",
+            upstream.remote, upstream.merge_ref, head_id
+        );
+    };
+    if lease.is_none() {
+        if let Ok(upstream_id) = repo.refname_to_id(&upstream.tracking_ref()) {
+            let (ahead, _) = repo.graph_ahead_behind(head_id, upstream_id).map_err(|e| {
+                format!(
+                    "Could not compare {} with its upstream: {}",
+                    upstream.branch_ref, e
+                )
+            })?;
+            if ahead == 0 {
+                up_to_date();
+                return Ok(upstream);
+            }
+        }
+    }
+    let mut remote = repo
+        .find_remote(&upstream.remote)
+        .map_err(|e| format!("Could not find the remote {}: {}", upstream.remote, e))?;
+    let force: &str = if lease.is_some() { "+" } else { "" };
+    let refspec = format!("{}{}:{}", force, upstream.branch_ref, upstream.merge_ref);
+    let mut pushed: bool = true;
+    with_retries(retry, &format!("push to {}", upstream.remote), || {
+        let Some(expected_id) = lease else {
+            return remote.push(&[refspec.as_str()], Some(&mut push_options(repo)?));
+        };
+        // The negotiation sees the remote branch even if the remote has no
+        // refs at all, for which listing them fails. Returning an error
+        // aborts the push, but its message is lost, so it's recreated below.
+        let remote_id: Cell<Option<Oid>> = Cell::new(None);
+        let mut callbacks: RemoteCallbacks = push_callbacks(repo)?;
+        callbacks.push_negotiation(|updates| {
+            let Some(update) = updates
+                .iter()
+                .find(|update| update.dst_refname() == Some(&upstream.merge_ref))
+            else {
+                return Ok(());
+            };
+            remote_id.set(Some(update.src()));
+            if update.src() != expected_id || update.src() == head_id {
+                return Err(git2::Error::from_str("The push was aborted."));
+            }
+            Ok(())
+        });
+        let mut push_options = PushOptions::new();
+        push_options.remote_callbacks(callbacks);
+        let result: Result<(), git2::Error> =
+            remote.push(&[refspec.as_str()], Some(&mut push_options));
+        match remote_id.get() {
+            Some(remote_id) if remote_id != expected_id => Err(git2::Error::from_str(&format!(
+                "{} is at {} instead of {}, where it was last fetched. Check the remote changes before forcing the push.",
+                upstream.merge_ref, remote_id, expected_id
+            ))),
+            Some(remote_id) if remote_id == head_id => {
+                pushed = false;
+                Ok(())
+            }
+            _ => result,
+        }
+    })
+    .map_err(|e| remote_error("push to", &upstream.remote, e))?;
+    if pushed {
+        info!(
+            "Pushed {} to {} {}.",
+            upstream.branch_ref, upstream.remote, upstream.merge_ref
+        );
+    } else {
+        up_to_date();
+    }
+    Ok(upstream)
+}
+
+/// Returns push options that authenticate like Git and fail the push if the
+/// remote rejects the ref.
+fn push_options<'a>(repo: &Repository) -> Result<PushOptions<'a>, git2::Error> {
+    let mut push_options = PushOptions::new();
+    push_options.remote_callbacks(push_callbacks(repo)?);
+    Ok(push_options)
+}
+
+/// Returns the callbacks of [`push_options`], to which more can be added.
+fn push_callbacks<'a>(repo: &Repository) -> Result<RemoteCallbacks<'a>, git2::Error> {
+    let mut callbacks = authenticating_callbacks(repo).map_err(|e| git2::Error::from_str(&e))?;
+    callbacks.push_update_reference(|refname, status| match status {
+        Some(message) => Err(git2::Error::from_str(&format!(
+            "The remote rejected {}: {}",
+            refname, message
+        ))),
+        None => Ok(()),
+    });
+    Ok(callbacks)
+}
+
+/// Pushes the pushed branch, and its tag if any, to the mirrors as well.
+///
+/// # Arguments
+///
+/// * `repo` - The wallet repository.
+/// * `upstream` - Where the branch was pushed.
+/// * `tag` - The name of the pushed tag, if any.
+/// * `mirrors` - The remotes of the mirrors.
+/// * `retry` - How to retry a push that failed because of the network.
+///
+/// # Returns
+///
+/// The result of each push. A failure is also logged as a warning.
+fn push_mirrors(
+    repo: &Repository,
+    upstream: &Upstream,
+    tag: Option<&str>,
+    mirrors: &[String],
+    retry: RetryPolicy,
+) -> Vec<MirrorPush> {
+    let mut refspecs: Vec<String> = vec![format!("{}:{}", upstream.branch_ref, upstream.merge_ref)];
+    refspecs.extend(tag.map(|tag| format!("refs/tags/{0}:refs/tags/{0}", tag)));
+    mirrors
+        .iter()
+        .map(|mirror| {
+            let result: Result<(), String> = repo
+                .find_remote(mirror)
+                .map_err(|e| format!("Could not find the remote {}: {}", mirror, e))
+                .and_then(|mut remote| {
+                    with_retries(retry, &format!("push to {}", mirror), || {
+                        remote.push(&refspecs, Some(&mut push_options(repo)?))
+                    })
+                    .map_err(|e| remote_error("push to", mirror, e))
+                });
+            match &result {
+                Ok(()) => info!(
+                    "Pushed {} to {} {}.",
+                    upstream.branch_ref, mirror, upstream.merge_ref
+                ),
+                Err(e) => warn!("{}", e),
+            }
+            MirrorPush {
+                remote: mirror.clone(),
+                result,
+            }
+        })
+        .collect()
+}
+
+/// Brings the original repository up to date with a pushed auto commit.
+///
+/// The remote-tracking branch is moved to the auto commit, as `git push` does,
+/// and the local branch is fast-forwarded to it. The repository must have the
+/// auto commit's objects, e.g., fetched from the copy, so that no network is
+/// needed. The working tree already has the committed content, so
+/// only the index entries of the committed files are updated. Any edits made
+/// in the meantime remain visible as modifications.
+///
+/// If the auto commit was rebased onto upstream changes, those changes are
+/// checked out as well, unless they'd overwrite local edits.
+///
+/// If the branch has moved since the copy was made, it's left alone.
+///
+/// # Arguments
+///
+/// * `repo` - The original repository.
+/// * `target` - Where the auto commit was pushed.
+/// * `commit_id` - The pushed auto commit.
+/// * `rebased` - Whether the auto commit was rebased onto upstream changes.
+/// * `replaced` - The unpushed auto commit that the auto commit amended.
+fn sync_original(
+    repo: &Repository,
+    target: &PushTarget,
+    commit_id: Oid,
+    rebased: bool,
+    replaced: Option<Oid>,
+) -> Result<(), String> {
+    let upstream = Upstream::of_head(repo, target)?;
+    repo.reference(
+        &upstream.tracking_ref(),
+        commit_id,
+        true,
+        "push-wallet-marks: update by push",
+    )
+    .map_err(|e| format!("Could not update {}: {}", upstream.tracking_ref(), e))?;
+    fast_forward_original(repo, &upstream.branch_ref, commit_id, rebased, replaced)
+}
+
+/// Moves the branch of the original repository to an auto commit, see
+/// [`sync_original`].
+///
+/// # Arguments
+///
+/// * `repo` - The original repository.
+/// * `branch_ref` - The full name of HEAD's branch.
+/// * `commit_id` - The auto commit, whose objects the repository must have.
+/// * `rebased` - Whether the auto commit was rebased onto upstream changes.
+/// * `replaced` - The unpushed auto commit that the auto commit amended.
+fn fast_forward_original(
+    repo: &Repository,
+    branch_ref: &str,
+    commit_id: Oid,
+    rebased: bool,
+    replaced: Option<Oid>,
+) -> Result<(), String> {
+    let commit = repo
+        .find_commit(commit_id)
+        .map_err(|e| format!("Could not find the auto commit {}: {}", commit_id, e))?;
+    // A root commit was made on an unborn branch.
+    let parent_id: Option<Oid> = commit.parent_ids().next();
+    let head_id: Option<Oid> = head_commit(repo)?.map(|head| head.id());
+    let can_fast_forward: bool = match head_id {
+        Some(head_id) => {
+            Some(head_id) == parent_id
+                || Some(head_id) == replaced
+                || repo
+                    .graph_descendant_of(commit_id, head_id)
+                    .unwrap_or(false)
+        }
+        None => parent_id.is_none(),
+    };
+    let update_branch = |log_message: &str| {
+        match head_id {
+            Some(head_id) => {
+                repo.reference_matching(branch_ref, commit_id, true, head_id, log_message)
+            }
+            None => repo.reference(branch_ref, commit_id, false, log_message),
+        }
+        .map_err(|e| format!("Could not fast-forward {}: {}", branch_ref, e))
+    };
+    if !can_fast_forward {
+        warn!(
+            "{} has moved since the copy was made, so it’s not fast-forwarded. Pull the auto commit manually.",
+            branch_ref
+        );
+        return Ok(());
+    };
+    if rebased {
+        // A safe checkout only updates files that are unmodified or already
+        // have the new content, and it fails before writing anything if a
+        // local edit is in the way.
+        repo.checkout_tree(commit.as_object(), Some(CheckoutBuilder::new().safe()))
+            .map_err(|e| {
+                format!(
+                    "Could not check out the upstream changes under the auto commit ({}). Pull them manually.",
+                    e
+                )
+            })?;
+        update_branch("push-wallet-marks: fast-forward to the rebased auto commit")?;
+        info!("Fast-forwarded {} in the original repository.", branch_ref);
+        return Ok(());
+    }
+
+    let tree = commit
+        .tree()
+        .map_err(|e| format!("Could not read the tree of {}: {}", commit_id, e))?;
+    let head_tree: Option<Tree> = head_id
+        .map(|head_id| {
+            repo.find_commit(head_id)
+                .and_then(|head| head.tree())
+                .map_err(|e| format!("Could not read the tree of {}: {}", head_id, e))
+        })
+        .transpose()?;
+    let diff = repo
+        .diff_tree_to_tree(head_tree.as_ref(), Some(&tree), None)
+        .map_err(|e| format!("Could not diff the auto commit: {}", e))?;
+    let mut index: Index = repo
+        .index()
+        .map_err(|e| format!("Could not fetch the index: {}", e))?;
+    for delta in diff.deltas() {
+        let Some(path) = delta.new_file().path() else {
+            continue;
+        };
+        if delta.status() == Delta::Deleted {
+            index.remove_path(path).map_err(|e| {
+                format!("Could not remove {} from the index: {}", path.display(), e)
+            })?;
+            continue;
+        }
+        let mut entry: IndexEntry = match index.get_path(path, 0) {
+            Some(entry) => entry,
+            // A new file. Without stat data, Git compares its content the next
+            // time, which it has to for a file it hasn't seen anyway.
+            None if delta.status() == Delta::Added => IndexEntry {
+                ctime: IndexTime::new(0, 0),
+                mtime: IndexTime::new(0, 0),
+                dev: 0,
+                ino: 0,
+                mode: u32::from(delta.new_file().mode()),
+                uid: 0,
+                gid: 0,
+                file_size: 0,
+                id: delta.new_file().id(),
+                flags: 0,
+                flags_extended: 0,
+                path: delta.new_file().path_bytes().unwrap_or_default().to_vec(),
+            },
+            None => continue,
+        };
+        entry.id = delta.new_file().id();
+        entry.file_size = delta.new_file().size() as u32;
+        index
+            .add(&entry)
+            .map_err(|e| format!("Could not update {} in the index: {}", path.display(), e))?;
+    }
+
+    update_branch("push-wallet-marks: fast-forward to the auto commit")?;
+    index
+        .write()
+        .map_err(|e| format!("Could not write the index: {}", e))?;
+    info!("Fast-forwarded {} in the original repository.", branch_ref);
+    Ok(())
+}
+
+/// Fetches the upstream and fast-forwards the original repository over the
+/// auto commits that other machines pushed, see [`PushRequest::follow_peers`].
+/// As after a rebase, the changes are only checked out if no local edit is in
+/// the way.
+///
+/// # Arguments
+///
+/// * `repo` - The original repository.
+/// * `request` - The options of the run.
+///
+/// # Returns
+///
+/// How many auto commits the branch was fast-forwarded over.
+fn follow_peers(repo: &Repository, request: &PushRequest) -> Result<usize, String> {
+    let upstream = Upstream::of_head(repo, &request.push_target)?;
+    let Some(upstream_id) = fetch_upstream(repo, &upstream, request.retry)? else {
+        return Ok(0);
+    };
+    let Some(head) = head_commit(repo)? else {
+        return Ok(0);
+    };
+    let behind: bool = head.id() != upstream_id
+        && repo
+            .graph_descendant_of(upstream_id, head.id())
+            .map_err(|e| {
+                format!(
+                    "Could not compare HEAD with {}: {}",
+                    upstream.tracking_ref(),
+                    e
+                )
+            })?;
+    if !behind {
+        return Ok(0);
+    }
+    let mut revwalk = repo
+        .revwalk()
+        .map_err(|e| format!("Could not walk the history: {}", e))?;
+    revwalk
+        .push(upstream_id)
+        .and_then(|()| revwalk.hide(head.id()))
+        .map_err(|e| format!("Could not walk the history: {}", e))?;
+    let mut hosts: Vec<String> = Vec::new();
+    let mut count: usize = 0;
+    for commit_id in revwalk {
+        let commit = commit_id
+            .and_then(|commit_id| repo.find_commit(commit_id))
+            .map_err(|e| format!("Could not walk the history: {}", e))?;
+        if !is_auto_commit(&commit) {
+            info!(
+                "{} has commits besides auto commits, such as {}, so it's not followed.",
+                upstream.tracking_ref(),
+                commit.id()
+            );
+            return Ok(0);
+        }
+        let host: String = auto_commit_host(&commit).unwrap_or_else(|| "unknown".to_string());
+        if !hosts.contains(&host) {
+            hosts.push(host);
+        }
+        count += 1;
+    }
+    fast_forward_original(repo, &upstream.branch_ref, upstream_id, true, None)?;
+    info!(
+        "Followed {} auto commit(s) from {}.",
+        count,
+        hosts.join(", ")
+    );
+    Ok(count)
+}
+
+/// Applies the [`DivergencePolicy`] of the run to a branch that diverged from
+/// its upstream. A forced push replaces the upstream anyway, and rebasing
+/// happens after committing.
+///
+/// # Arguments
+///
+/// * `repo` - The wallet repository.
+/// * `request` - The options of the run.
+/// * `target` - Where to push.
+/// * `timings` - Where to record the duration of each phase.
+///
+/// # Returns
+///
+/// Whether the branch was reset to its upstream, or why the run skips.
+fn handle_divergence(
+    repo: &Repository,
+    request: &PushRequest,
+    target: &PushTarget,
+    timings: &mut Timings,
+) -> Result<Result<bool, SkipReason>, String> {
+    if request.force_with_lease || request.on_diverged == DivergencePolicy::Rebase {
+        return Ok(Ok(false));
+    }
+    let upstream = Upstream::of_head(repo, target)?;
+    let divergence: Option<Divergence> = timings.measure("divergence", || {
+        find_divergence(repo, &upstream, request.retry, !request.dry_run)
+    })?;
+    let Some(divergence) = divergence else {
+        return Ok(Ok(false));
+    };
+    let description: String = format!(
+        "{} and {} {} have diverged, with {} local and {} upstream commit(s).",
+        upstream.branch_ref,
+        upstream.remote,
+        upstream.merge_ref,
+        divergence.local,
+        divergence.remote
+    );
+    match request.on_diverged {
+        DivergencePolicy::Abort => {
+            info!("{} Not committing.", description);
+            Ok(Err(SkipReason::Diverged))
+        }
+        DivergencePolicy::ResetToRemote if request.dry_run => {
+            info!("{} Would drop the local commits.", description);
+            Ok(Ok(false))
+        }
+        DivergencePolicy::ResetToRemote => {
+            // Only the branch and the index move, so the mark files keep
+            // their changes and are compared with the upstream.
+            repo.find_commit(divergence.upstream_id)
+                .and_then(|commit| repo.reset(commit.as_object(), ResetType::Mixed, None))
+                .map_err(|e| {
+                    format!(
+                        "Could not reset {} to {}: {}",
+                        upstream.branch_ref,
+                        upstream.tracking_ref(),
+                        e
+                    )
+                })?;
+            warn!("{} Dropped the local commits.", description);
+            Ok(Ok(true))
+        }
+        DivergencePolicy::Rebase => Ok(Ok(false)),
+    }
+}
+
+/// The commits that a run makes, see [`plan_commits`].
+struct CommitPlan<'s, 'r> {
+    /// The mark files of each commit, in the order they're committed.
+    groups: Vec<&'s [StatusEntryBetter]>,
+    /// The unpushed auto commit that the first commit replaces, if any.
+    amended: Option<Commit<'r>>,
+    /// The name of the tag of the pushed commit, if any.
+    tag: Option<String>,
+}
+
+impl CommitPlan<'_, '_> {
+    /// Builds the message of a planned commit. An amended commit also lists
+    /// the mark files of the commit it replaces, so it's built once the
+    /// commit's mark files are staged.
+    ///
+    /// # Arguments
+    ///
+    /// * `repo` - The wallet repository.
+    /// * `index` - The index with the commit's mark files staged.
+    /// * `position` - The position of the commit in [`CommitPlan::groups`].
+    /// * `request` - The options of the run.
+    fn message(
+        &self,
+        repo: &Repository,
+        index: &Index,
+        position: usize,
+        request: &PushRequest,
+    ) -> Result<String, String> {
+        let committed_files: Option<Vec<StatusEntryBetter>> = self
+            .amended
+            .as_ref()
+            .filter(|_| position == 0)
+            .map(|amended| amended_mark_files(repo, amended, index))
+            .transpose()?;
+        commit_message(
+            committed_files.as_deref().unwrap_or(self.groups[position]),
+            request.message.as_deref(),
+            request.commit_strategy,
+            committer(repo)?.when(),
+        )
+    }
+}
+
+/// Splits the changed mark files into commits and decides which commit
+/// amends an unpushed auto commit. Only the first commit can amend HEAD, and
+/// a per-file commit only amends a commit of the same file.
+///
+/// # Arguments
+///
+/// * `repo` - The wallet repository.
+/// * `request` - The options of the run.
+/// * `target` - Where to push.
+/// * `mark_file_statuses` - The changed mark files.
+/// * `flushing` - Whether the run only pushes a pending batch, which commits
+///   nothing.
+fn plan_commits<'s, 'r>(
+    repo: &'r Repository,
+    request: &PushRequest,
+    target: &PushTarget,
+    mark_file_statuses: &'s [StatusEntryBetter],
+    flushing: bool,
+) -> Result<CommitPlan<'s, 'r>, String> {
+    let mut groups: Vec<&[StatusEntryBetter]> = match request.commit_strategy {
+        _ if flushing => Vec::new(),
+        CommitStrategy::Combined => vec![mark_file_statuses],
+        CommitStrategy::PerFile => mark_file_statuses.chunks(1).collect(),
+    };
+    let mut amended: Option<Commit> = if request.amend || !request.batch_window.is_zero() {
+        unpushed_auto_commit(repo, target)?
+    } else {
+        None
+    };
+    if let (Some(head), CommitStrategy::PerFile) = (&amended, request.commit_strategy) {
+        let head_files: Vec<PathBuf> = changed_files(repo, head)?;
+        match groups
+            .iter()
+            .position(|group| head_files == [group[0].path.clone()])
+        {
+            Some(position) => groups[..=position].rotate_right(1),
+            None => amended = None,
+        }
+    }
+    // An invalid tag template fails before anything is committed.
+    let tag: Option<String> = request
+        .tag_template
+        .as_deref()
+        .map(|template| tag_name(template, mark_file_statuses, committer(repo)?.when()))
+        .transpose()?;
+    Ok(CommitPlan {
+        groups,
+        amended,
+        tag,
+    })
+}
+
+/// Records the auto commit in the pending batch unless the batch window has
+/// passed, see [`PushRequest::batch_window`].
+///
+/// # Arguments
+///
+/// * `original` - The original repository, which stores the [`BATCH_FILE`].
+/// * `request` - The options of the run.
+/// * `commit_id` - The auto commit.
+/// * `amended` - Whether the auto commit amended the pending one, which
+///   continues its batch.
+///
+/// # Returns
+///
+/// Whether the auto commit waits for the end of the window instead of being
+/// pushed.
+fn hold_batch(
+    original: &Repository,
+    request: &PushRequest,
+    commit_id: Oid,
+    amended: bool,
+) -> Result<bool, String> {
+    let batch_path: PathBuf = original.path().join(BATCH_FILE);
+    let now: SystemTime = SystemTime::now();
+    let started: SystemTime = match amended {
+        true => batch_started(&batch_path).unwrap_or(now),
+        false => now,
+    };
+    if started + request.batch_window <= now {
+        return Ok(false);
+    }
+    let seconds: u64 = started
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .map_or(0, |duration| duration.as_secs());
+    fs::write(&batch_path, format!("{}\n", seconds)).map_err(|e| {
+        format!(
+            "Could not record the batch in {}: {}",
+            batch_path.display(),
+            e
+        )
+    })?;
+    info!(
+        "Batched the changes in {}, which is pushed {} second(s) after the batch started.",
+        commit_id,
+        request.batch_window.as_secs()
+    );
+    Ok(true)
+}
+
+/// An auto commit pushed by [`push_auto_commit`].
+struct PushedCommit {
+    /// The auto commit rebased onto a moved upstream, if it was.
+    rebased: Option<Oid>,
+    /// Where the auto commit was pushed.
+    upstream: Upstream,
+    /// The name of the pushed tag, if one was pushed.
+    tag: Option<String>,
+    /// The pushes to the mirrors.
+    mirrors: Vec<MirrorPush>,
+}
+
+/// Rebases the auto commit at HEAD onto its upstream and pushes it, then
+/// pushes its tag and the mirrors.
+///
+/// # Arguments
+///
+/// * `repo` - The wallet repository.
+/// * `request` - The options of the run.
+/// * `target` - Where to push.
+/// * `tag` - The name of the tag to create for the pushed commit, if any.
+/// * `signer` - Signs rebased commits if given.
+/// * `clock` - Gives rebased commits their time.
+/// * `timings` - Where to record the duration of each phase.
+///
+/// # Returns
+///
+/// The pushed commit, or the error of the push of the marks. Failures to push
+/// the tag or the mirrors are only reported, as the marks are pushed already.
+fn push_auto_commit(
+    repo: &Repository,
+    request: &PushRequest,
+    target: &PushTarget,
+    tag: Option<&str>,
+    signer: Option<&dyn CommitSigner>,
+    clock: &CommitClock,
+    timings: &mut Timings,
+) -> Result<PushedCommit, String> {
+    // A forced push replaces the upstream, so there's nothing to rebase onto.
+    let lease: Option<Oid> = if request.force_with_lease {
+        Some(last_fetched_upstream(repo, target)?)
+    } else {
+        None
+    };
+    let rebased: Option<Oid> = timings.measure("rebase", || match lease {
+        Some(_) => Ok(None),
+        None => rebase_onto_upstream(repo, target, request.retry, signer, clock),
+    })?;
+    let upstream: Upstream =
+        timings.measure("push", || push_head(repo, target, request.retry, lease))?;
+    let head_id: Oid = repo
+        .refname_to_id(&upstream.branch_ref)
+        .map_err(|e| format!("Could not resolve {}: {}", upstream.branch_ref, e))?;
+    let tag: Option<String> = tag
+        .filter(|tag| {
+            timings
+                .measure("tag", || {
+                    push_tag(repo, &upstream, tag, head_id, request.retry)
+                })
+                .map_err(|e| warn!("{}", e))
+                .is_ok()
+        })
+        .map(str::to_string);
+    let mirrors: Vec<MirrorPush> = timings.measure("mirrors", || {
+        Ok::<_, String>(push_mirrors(
+            repo,
+            &upstream,
+            tag.as_deref(),
+            &request.mirrors,
+            request.retry,
+        ))
+    })?;
+    Ok(PushedCommit {
+        rebased,
+        upstream,
+        tag,
+        mirrors,
+    })
+}
+
+/// Stages and pushes mark files in the wallet repository upstream.
+///
+/// # Arguments
+///
+/// * `repo_path` - The wallet repository path.
+/// * `mark_files` - The mark files to potentially push.
+/// * `request` - The options of the push.
+/// * `target` - Where to push, which may differ from the request's target.
+/// * `timings` - Where to record the duration of each phase.
+fn push_wallet_marks<P, A>(
+    repo_path: P,
+    auto_files: &[A],
+    request: &PushRequest,
+    target: &PushTarget,
+    timings: &mut Timings,
+) -> Result<PushOutcome, String>
+where
+    P: AsRef<Path>,
+    A: AsRef<Path>,
+{
+    let repo = Repository::open(repo_path.as_ref()).map_err(|e| {
+        format!(
+            "Failed to open a repository, {}: {}",
+            repo_path.as_ref().display(),
+            e
+        )
+    })?;
+    // Kept alive until the end, as the repository writes the index there.
+    let _private_index: Option<tempfile::TempPath> = if request.workspace == Workspace::InPlace {
+        Some(use_private_index(&repo)?)
+    } else {
+        None
+    };
+    let original: Repository = open_repository(&request.repo)?;
+    let clock = CommitClock::new(original.path(), !request.dry_run);
+    let batching: bool = !request.batch_window.is_zero();
+    let changes: ChangeFilter = request.changes;
+    let excludes: Vec<Pattern> = path_patterns("exclude", &request.excludes)?;
+
+    let reset: bool = match handle_divergence(&repo, request, target, timings)? {
+        Ok(reset) => reset,
+        Err(reason) => return Ok(PushOutcome::Skipped(reason)),
+    };
+
+    let mut index: Index = repo
+        .index()
+        .map_err(|e| format!("Could not fetch the index: {}", e))?;
+
+    let mark_file_statuses: Vec<StatusEntryBetter> = match timings.measure("status", || {
+        collect_mark_file_statuses(&repo, auto_files, &excludes, changes)
+    })? {
+        Ok(statuses) => statuses,
+        // The pending batch is pushed as it is.
+        Err(SkipReason::NoChanges) if batch_due(&original, request)? => Vec::new(),
+        Err(reason) => return Ok(PushOutcome::Skipped(reason)),
+    };
+    let flushing: bool = mark_file_statuses.is_empty();
+
+    // Opaque files, e.g., encrypted ones, only count towards the file limit.
+    let opaque: Vec<Pattern> = path_patterns("opaque", &request.opaque)?;
+    let ignore_case: bool = ignores_case(&repo);
+    let diffed: Vec<StatusEntryBetter> = mark_file_statuses
+        .iter()
+        .filter(|mark_file_status| !matches_patterns(&mark_file_status.path, &opaque, ignore_case))
+        .cloned()
+        .collect();
+    timings.measure("validate", || {
+        check_lfs(&repo, &mark_file_statuses)?;
+        check_change_size(&repo, &index, &mark_file_statuses, &diffed, changes)?;
+        if let Some(require_diff_regex) = &request.require_diff_regex {
+            check_diff_pattern(&repo, &index, &diffed, require_diff_regex)?;
+        }
+        if changes.marks_only {
+            check_marks_only(&repo, &diffed)?;
+        }
+        match &request.validate_cmd {
+            Some(validate_cmd) => validate_mark_files(validate_cmd, &request.repo, &diffed),
+            None => Ok(()),
+        }
+    })?;
+    let diff: Option<String> = if request.show_diff {
+        let diff: String = timings.measure("diff", || mark_file_diff(&repo, &index, &diffed))?;
+        info!("The changes of the mark files:\n{}", diff);
+        Some(diff)
+    } else {
+        None
+    };
+    let staged_files: Vec<PathBuf> = mark_file_statuses
+        .iter()
+        .map(|mark_file_status| mark_file_status.path.clone())
+        .collect();
+    let confirming: bool = !request.dry_run && !flushing;
+    if let Some(confirmer) = request.confirmer.as_deref().filter(|_| confirming) {
+        if !confirmer.confirm(&staged_files)? {
+            info!("The push was declined.");
+            return Ok(PushOutcome::Skipped(SkipReason::Declined));
+        }
+    }
+    let signer: Option<Arc<dyn CommitSigner>> = commit_signer(&repo, request)?;
+    let author: Signature = author(&repo, &request.author)?;
+    let plan: CommitPlan = plan_commits(&repo, request, target, &mark_file_statuses, flushing)?;
+    let mut commits: Vec<(&[StatusEntryBetter], String)> = Vec::new();
+    let mut commit_id: Option<Oid> = None;
+    for (position, &group) in plan.groups.iter().enumerate() {
+        let amended_here: Option<&Commit> = plan.amended.as_ref().filter(|_| position == 0);
+        timings.measure("stage", || stage_mark_files(&mut index, group, changes))?;
+        let message: String = plan.message(&repo, &index, position, request)?;
+        if request.dry_run {
+            commits.push((group, message));
+            continue;
+        }
+        if request.run_hooks {
+            timings.measure("hooks", || {
+                index
+                    .write()
+                    .map_err(|e| format!("Could not write the index: {}", e))?;
+                let staged_tree: Oid = index
+                    .write_tree()
+                    .map_err(|e| format!("Could not write the index tree: {}", e))?;
+                // The hook may have staged more changes, e.g., formatting.
+                run_hook(&repo, &request.repo, "pre-commit")
+                    .and_then(|()| {
+                        index
+                            .read(true)
+                            .map_err(|e| format!("Could not read the index: {}", e))
+                    })
+                    .and_then(|()| check_hook_changes(&repo, &mut index, staged_tree, group))
+            })?;
+        }
+        let new_commit_id: Oid = timings.measure("commit", || {
+            commit_mark_files(
+                &repo,
+                &mut index,
+                &author,
+                &message,
+                signer.as_deref(),
+                amended_here,
+                &clock,
+            )
+        })?;
+        check_commit_files(&repo, new_commit_id, auto_files)?;
+        match amended_here {
+            Some(amended) => info!(
+                "Amended {} with {} mark file(s) as {}.",
+                amended.id(),
+                group.len(),
+                new_commit_id
+            ),
+            None => info!(
+                "Committed {} mark file(s) as {}.",
+                group.len(),
+                new_commit_id
+            ),
+        }
+        // As with Git, the commit stands even if the hook fails.
+        if request.run_hooks {
+            if let Err(e) =
+                timings.measure("hooks", || run_hook(&repo, &request.repo, "post-commit"))
+            {
+                warn!("{}", e);
+            }
+        }
+        commit_id = Some(new_commit_id);
+    }
+    let CommitPlan { amended, tag, .. } = plan;
+    if request.dry_run {
+        if let Some(amended) = &amended {
+            info!("Would amend the unpushed auto commit {}.", amended.id());
+        }
+        let upstream = report_dry_run(&repo, target, tag.as_deref(), &request.mirrors, &commits)?;
+        return Ok(PushOutcome::DryRun {
+            staged_files,
+            diff,
+            remote: upstream.remote,
+            branch: upstream.merge_ref,
+        });
+    }
+    // Flushing a batch pushes its commit, which isn't amended then.
+    let (commit_id, staged_files, amended): (Oid, Vec<PathBuf>, Option<Commit>) =
+        match (commit_id, amended) {
+            (Some(commit_id), amended) => (commit_id, staged_files, amended),
+            (None, Some(pending)) if flushing => {
+                info!("Pushing the batch of {}.", pending.id());
+                (pending.id(), changed_files(&repo, &pending)?, None)
+            }
+            _ => return Err("There were no mark files to commit.".to_string()),
+        };
+    if batching && !flushing && hold_batch(&original, request, commit_id, amended.is_some())? {
+        return Ok(PushOutcome::Committed {
+            staged_files,
+            diff,
+            commit: commit_id,
+            rebased: false,
+            amended: amended.is_some(),
+            reset,
+        });
+    }
+
+    let pushed: PushedCommit = match push_auto_commit(
+        &repo,
+        request,
+        target,
+        tag.as_deref(),
+        signer.as_deref(),
+        &clock,
+        timings,
+    ) {
+        Ok(pushed) => pushed,
+        // The next run amends the kept commit, so that the changes made while
+        // offline end up in a single commit.
+        Err(e) if request.amend || batching => {
+            let kept_commit_id: Oid = repo
+                .head()
+                .ok()
+                .and_then(|head| head.target())
+                .unwrap_or(commit_id);
+            warn!(
+                "{}\nKept the auto commit {} to amend and push it in the next run.",
+                e, kept_commit_id
+            );
+            return Ok(PushOutcome::Committed {
+                staged_files,
+                diff,
+                commit: kept_commit_id,
+                rebased: kept_commit_id != commit_id,
+                amended: amended.is_some(),
+                reset,
+            });
+        }
+        Err(e) => return Err(e),
+    };
+    if batching {
+        match fs::remove_file(original.path().join(BATCH_FILE)) {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => {
+                warn!("Could not end the batch: {}", e);
+            }
+            _ => {}
+        }
+    }
+    Ok(PushOutcome::Pushed {
+        staged_files,
+        diff,
+        commit: pushed.rebased.unwrap_or(commit_id),
+        rebased: pushed.rebased.is_some(),
+        amended: amended.is_some(),
+        reset,
+        remote: pushed.upstream.remote,
+        branch: pushed.upstream.merge_ref,
+        mirrors: pushed.mirrors,
+        tag: pushed.tag,
+        sync_error: None,
+    })
+}
+
+/// Fetches the objects of an auto commit from the copy it was made in, so that
+/// they outlive the copy.
+///
+/// # Arguments
+///
+/// * `repo` - The original repository.
+/// * `copy` - The path of the copy.
+fn fetch_from_copy(repo: &Repository, copy: &Path) -> Result<(), String> {
+    let copy_repo: Repository = open_repository(copy)?;
+    let head = copy_repo
+        .head()
+        .map_err(|e| format!("Could not resolve HEAD in the copy: {}", e))?;
+    let branch_ref: &str = head.name().ok_or("The HEAD branch name isn't UTF-8.")?;
+    let url: &str = copy.to_str().ok_or("The copy's path isn't UTF-8.")?;
+    repo.remote_anonymous(url)
+        .and_then(|mut remote| remote.fetch(&[branch_ref], None, None))
+        .map_err(|e| format!("Could not fetch the auto commit from the copy: {}", e))
+}
+
+/// Stages, commits, and pushes the mark files directly in the original
+/// repository.
+///
+/// The mark files are staged in a private copy of the index, so the user's
+/// index is never written. The branch is reset to where it was afterwards, so
+/// that the auto commit is synced like for the other workspaces and a failed
+/// push is tried again by the next run. The working tree keeps the mark files'
+/// changes either way.
+///
+/// # Arguments
+///
+/// * `repo` - The wallet repository.
+/// * `auto_files` - The auto files, relative to the working directory.
+/// * `request` - The options of the push.
+/// * `timings` - Where to record the duration of each phase.
+fn push_in_place(
+    repo: &Repository,
+    auto_files: &[PathBuf],
+    request: &PushRequest,
+    timings: &mut Timings,
+) -> Result<PushOutcome, String> {
+    let undo = InPlaceUndo {
+        repo,
+        head: head_commit(repo)?,
+        armed: true,
+    };
+    let outcome: PushOutcome = push_wallet_marks(
+        &request.repo,
+        auto_files,
+        request,
+        &request.push_target,
+        timings,
+    )?;
+    undo.finish()?;
+    Ok(outcome)
+}
+
+/// Makes the repository stage into a temporary copy of its index, which is
+/// how Git commits only some paths too.
+///
+/// # Returns
+///
+/// The copy, which is deleted when dropped.
+fn use_private_index(repo: &Repository) -> Result<tempfile::TempPath, String> {
+    let copy: tempfile::TempPath = NamedTempFile::new()
+        .map_err(|e| format!("Could not create a temporary index: {}", e))?
+        .into_temp_path();
+    let index_path: PathBuf = repo.path().join("index");
+    if index_path.exists() {
+        fs::copy(&index_path, &copy)
+            .map_err(|e| format!("Could not copy the index, {}: {}", index_path.display(), e))?;
+    } else {
+        // An empty file isn't a valid index, while a missing one is empty.
+        fs::remove_file(&copy).map_err(|e| format!("Could not create a temporary index: {}", e))?;
+    }
+    Index::open(&copy)
+        .and_then(|mut index| repo.set_index(&mut index))
+        .map_err(|e| format!("Could not open the temporary index: {}", e))?;
+    Ok(copy)
+}
+
+/// Resets the branch to the HEAD commit from before an auto commit made in
+/// place. The reset happens on drop unless the guard is
+/// disarmed, so that a failed or panicking push leaves no auto commit behind.
+struct InPlaceUndo<'repo> {
+    /// The original repository.
+    repo: &'repo Repository,
+    /// The HEAD commit from before the auto commit, or `None` if the branch
+    /// was unborn.
+    head: Option<Commit<'repo>>,
+    /// Whether to reset on drop.
+    armed: bool,
+}
+
+impl InPlaceUndo<'_> {
+    /// Resets the branch if HEAD moved.
+    fn reset(&self) -> Result<(), String> {
+        let head_id: Option<Oid> = head_commit(self.repo)?.map(|head| head.id());
+        match &self.head {
+            Some(head) if head_id != Some(head.id()) => self
+                .repo
+                .reset(head.as_object(), ResetType::Soft, None)
+                .map_err(|e| format!("Could not reset the branch to {}: {}", head.id(), e)),
+            // The root commit is undone by making the branch unborn again.
+            None if head_id.is_some() => {
+                let branch_ref: String = head_branch(self.repo)?
+                    .ok_or("HEAD is detached, so there is no branch to reset.")?;
+                self.repo
+                    .find_reference(&branch_ref)
+                    .and_then(|mut branch| branch.delete())
+                    .map_err(|e| format!("Could not delete the branch {}: {}", branch_ref, e))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Disarms the guard and resets right away, so that a failed reset can be
+    /// reported.
+    fn finish(mut self) -> Result<(), String> {
+        self.armed = false;
+        self.reset()
+    }
+}
+
+impl Drop for InPlaceUndo<'_> {
+    fn drop(&mut self) {
+        if self.armed {
+            if let Err(e) = self.reset() {
+                warn!("Could not undo the auto commit: {}", e);
+            }
+        }
+    }
+}
+
+/// Decides what a push would do with a changed auto file.
+///
+/// # Arguments
+///
+/// * `repo` - The wallet repository.
+/// * `status_entry` - The file's status.
+/// * `changes` - Which changes may be pushed.
+fn file_action(
+    repo: &Repository,
+    status_entry: &StatusEntryBetter,
+    changes: ChangeFilter,
+) -> Result<FileAction, String> {
+    Ok(match status_entry.status {
+        status if status != Status::WT_DELETED && is_lfs_tracked(repo, &status_entry.path) => {
+            FileAction::Abort("stored in Git LFS, which isn’t supported".to_string())
+        }
+        Status::WT_MODIFIED
+            if changes.ignore_mode_changes && is_mode_only_change(repo, status_entry)? =>
+        {
+            FileAction::Ignore("only its mode changed".to_string())
+        }
+        Status::WT_MODIFIED => FileAction::Commit,
+        status if status.contains(Status::WT_RENAMED) => FileAction::Commit,
+        Status::WT_NEW if changes.allow_new => FileAction::Commit,
+        Status::WT_NEW => FileAction::Ignore("untracked, see --allow-new".to_string()),
+        Status::WT_DELETED if changes.allow_delete => FileAction::Commit,
+        Status::WT_DELETED => FileAction::Abort("deleted, see --allow-delete".to_string()),
+        status => FileAction::Abort(format!("unexpected status {:?}", status)),
+    })
+}
+
+/// Reports what a push would do, see [`PushRequest::status`].
+///
+/// Unlike a push, this lists every changed auto file, including the ones that
+/// a push would ignore or fail on.
+fn push_status(request: &PushRequest) -> Result<PushStatus, String> {
+    let repo: Repository = open_repository(&request.repo)?;
+    let mut auto_files: Vec<PathBuf> =
+        expand_auto_files(&repo, &request.auto_files, request.changes.allow_new)?;
+    let problems: Vec<String> = auto_file_problems(
+        &repo,
+        &request.auto_files,
+        &auto_files,
+        request.changes.allow_new,
+    )?;
+    auto_files.extend(rename_targets(&repo, &request.auto_files, &auto_files)?);
+    let excludes: Vec<Pattern> = path_patterns("exclude", &request.excludes)?;
+    let ignore_case: bool = ignores_case(&repo);
+
+    let statuses: Statuses = repo
+        .statuses(Some(&mut file_status_options(&auto_files, true)))
+        .map_err(|e| format!("Could not fetch file statuses: {}", e))?;
+    let mut files: Vec<AutoFileStatus> = Vec::new();
+    for status_entry in filter_statuses_by_path(&statuses, &auto_files, &excludes, ignore_case) {
+        let action: FileAction = file_action(&repo, &status_entry, request.changes)?;
+        files.push(AutoFileStatus {
+            path: status_entry.path,
+            status: status_entry.status,
+            action,
+        });
+    }
+
+    let index_statuses: Statuses = repo
+        .statuses(Some(&mut index_status_options()))
+        .map_err(|e| format!("Could not fetch index statuses: {}", e))?;
+    let skip_reason: Option<SkipReason> = if let Some(operation) = ongoing_operation(repo.state()) {
+        Some(SkipReason::OngoingOperation(operation))
+    } else if is_index_empty(&index_statuses) {
+        (!files.iter().any(|file| file.action == FileAction::Commit))
+            .then_some(SkipReason::NoChanges)
+    } else if filter_statuses_by_path(&index_statuses, &auto_files, &excludes, ignore_case)
+        .is_empty()
+    {
+        Some(SkipReason::IndexNotEmpty)
+    } else {
+        Some(SkipReason::StagedMarkFiles)
+    };
+    Ok(PushStatus {
+        files,
+        problems,
+        skip_reason,
+    })
+}
+
+/// The tip of the upstream branch, fetched into a temporary repository.
+struct RemoteTip {
+    /// The temporary repository, which borrows the wallet's objects.
+    probe: Repository,
+    /// The tip.
+    commit: Oid,
+    /// Keeps the temporary repository until the tip isn't needed anymore.
+    _dir: tempfile::TempDir,
+}
+
+/// Fetches the tip of the upstream branch without changing the wallet.
+///
+/// The upstream branch is fetched into a temporary bare repository that
+/// borrows the wallet's objects as alternates. Refs to the wallet's HEAD and
+/// remote-tracking branch tell the remote what needn't be sent.
+///
+/// # Returns
+///
+/// The tip, or `None` if the remote doesn't have the upstream branch.
+fn fetch_remote_tip(repo: &Repository, request: &PushRequest) -> Result<Option<RemoteTip>, String> {
+    let upstream = Upstream::of_head(repo, &request.push_target)?;
+    let url: String = repo
+        .find_remote(&upstream.remote)
+        .map_err(|e| format!("Could not find the remote {}: {}", upstream.remote, e))?
+        .url()
+        .ok_or("The remote's URL isn’t valid UTF-8.")?
+        .to_string();
+
+    let probe_dir: tempfile::TempDir =
+        tempdir().map_err(|e| format!("Could not create a temporary directory: {}", e))?;
+    let probe: Repository = Repository::init_bare(probe_dir.path())
+        .map_err(|e| format!("Could not create a temporary repository: {}", e))?;
+    let objects_dir: PathBuf = common_dir(repo.path()).join("objects");
+    fs::write(
+        probe.path().join("objects/info/alternates"),
+        format!("{}\n", objects_dir.display()),
+    )
+    .map_err(|e| format!("Could not share the objects: {}", e))?;
+    let haves = [
+        repo.refname_to_id(&upstream.branch_ref),
+        repo.refname_to_id(&upstream.tracking_ref()),
+    ];
+    for (position, have) in haves.into_iter().flatten().enumerate() {
+        probe
+            .reference(&format!("refs/haves/{}", position), have, true, "")
+            .map_err(|e| format!("Could not create a temporary reference: {}", e))?;
+    }
+    let tip_ref: &str = "refs/remotes/tip";
+    let refspec = format!("+{}:{}", upstream.merge_ref, tip_ref);
+    with_retries(
+        request.retry,
+        &format!("fetch from {}", upstream.remote),
+        || {
+            probe.remote_anonymous(&url)?.fetch(
+                &[refspec.as_str()],
+                Some(&mut upstream_fetch_options(repo)?),
+                None,
+            )
+        },
+    )
+    .map_err(|e| remote_error("fetch from", &upstream.remote, e))?;
+    let commit: Oid = match probe.find_reference(tip_ref) {
+        Ok(tip) => tip
+            .peel_to_commit()
+            .map_err(|e| format!("Could not read the remote tip: {}", e))?
+            .id(),
+        Err(e) if e.code() == ErrorCode::NotFound => return Ok(None),
+        Err(e) => return Err(format!("Could not read the remote tip: {}", e)),
+    };
+    Ok(Some(RemoteTip {
+        probe,
+        commit,
+        _dir: probe_dir,
+    }))
+}
+
+/// An auto file that differs from the remote tip.
+struct DifferingFile {
+    /// The path, relative to the working directory.
+    path: PathBuf,
+    /// The blob in the working directory, or `None` if the file is missing.
+    local: Option<Oid>,
+    /// The blob at the remote tip, or `None` if the tip lacks the file.
+    remote: Option<Oid>,
+}
+
+/// Finds the auto files that differ from a tree, skipping excluded ones.
+fn differing_auto_files(
+    repo: &Repository,
+    request: &PushRequest,
+    tree: &Tree,
+) -> Result<Vec<DifferingFile>, String> {
+    let workdir: &Path = repo
+        .workdir()
+        .ok_or("The repository has no working directory.")?;
+    let excludes: Vec<Pattern> = path_patterns("exclude", &request.excludes)?;
+    let ignore_case: bool = ignores_case(repo);
+    let mut files: Vec<DifferingFile> = Vec::new();
+    for auto_file in expand_auto_files(repo, &request.auto_files, request.changes.allow_new)? {
+        if matches_patterns(&auto_file, &excludes, ignore_case) {
+            continue;
+        }
+        let full_path: PathBuf = workdir.join(&auto_file);
+        let local_id: Option<Oid> = if full_path.is_file() {
+            Some(
+                Oid::hash_file(ObjectType::Blob, &full_path)
+                    .map_err(|e| format!("Could not hash {}: {}", full_path.display(), e))?,
+            )
+        } else {
+            None
+        };
+        let remote_id: Option<Oid> = tree.get_path(&auto_file).ok().map(|entry| entry.id());
+        if local_id != remote_id {
+            debug!(
+                "{} is {:?} locally and {:?} at the remote tip.",
+                auto_file.display(),
+                local_id,
+                remote_id
+            );
+            files.push(DifferingFile {
+                path: auto_file,
+                local: local_id,
+                remote: remote_id,
+            });
+        }
+    }
+    Ok(files)
+}
+
+/// Reads the tree of the remote tip.
+fn remote_tree(tip: &RemoteTip) -> Result<Tree<'_>, String> {
+    tip.probe
+        .find_commit(tip.commit)
+        .and_then(|commit| commit.tree())
+        .map_err(|e| format!("Could not read the tree of {}: {}", tip.commit, e))
+}
+
+/// Compares the auto files with the remote tip, see [`PushRequest::verify`].
+fn verify(request: &PushRequest) -> Result<Verification, String> {
+    let repo: Repository = open_repository(&request.repo)?;
+    let Some(tip) = fetch_remote_tip(&repo, request)? else {
+        return Ok(Verification::NoRemoteBranch);
+    };
+    let files: Vec<PathBuf> = differing_auto_files(&repo, request, &remote_tree(&tip)?)?
+        .into_iter()
+        .map(|file| file.path)
+        .collect();
+    Ok(if files.is_empty() {
+        Verification::InSync { commit: tip.commit }
+    } else {
+        Verification::OutOfSync {
+            commit: tip.commit,
+            files,
+        }
+    })
+}
+
+/// Summarizes how the auto files differ from the remote tip, see
+/// [`PushRequest::diff`].
+fn diff(request: &PushRequest) -> Result<Option<MarksDiff>, String> {
+    let repo: Repository = open_repository(&request.repo)?;
+    let workdir: &Path = repo
+        .workdir()
+        .ok_or("The repository has no working directory.")?;
+    let Some(tip) = fetch_remote_tip(&repo, request)? else {
+        return Ok(None);
+    };
+    let tree: Tree = remote_tree(&tip)?;
+    let mut files: Vec<FileDiff> = Vec::new();
+    for file in differing_auto_files(&repo, request, &tree)? {
+        let local: Vec<u8> = match file.local {
+            Some(_) => fs::read(workdir.join(&file.path))
+                .map_err(|e| format!("Could not read {}: {}", file.path.display(), e))?,
+            None => Vec::new(),
+        };
+        let remote: Vec<u8> = match file.remote {
+            Some(id) => tip
+                .probe
+                .find_blob(id)
+                .map_err(|e| {
+                    format!(
+                        "Could not read {} at the remote tip: {}",
+                        file.path.display(),
+                        e
+                    )
+                })?
+                .content()
+                .to_vec(),
+            None => Vec::new(),
+        };
+        let (_, added, removed) =
+            git2::Patch::from_buffers(&remote, Some(&file.path), &local, Some(&file.path), None)
+                .and_then(|patch| patch.line_stats())
+                .map_err(|e| format!("Could not diff {}: {}", file.path.display(), e))?;
+        let last_pushed: Option<String> = match file.remote {
+            Some(_) => last_change(&tip, &file.path)?.map(format_time),
+            None => None,
+        };
+        files.push(FileDiff {
+            path: file.path,
+            added,
+            removed,
+            last_pushed,
+        });
+    }
+    Ok(Some(MarksDiff {
+        commit: tip.commit,
+        files,
+    }))
+}
+
+/// Appends the outcome of a run to [`RUN_LOG_FILE`] if it tried to push.
+///
+/// The log only feeds [`PushRequest::stats`], so failing to write it is only
+/// logged.
+///
+/// # Arguments
+///
+/// * `repo` - The original repository.
+/// * `outcome` - The outcome of the run.
+fn log_run(repo: &Path, outcome: &Result<PushOutcome, String>) {
+    let code: &str = match outcome {
+        Ok(PushOutcome::Pushed { .. }) => "pushed",
+        Ok(PushOutcome::Committed { .. }) => "committed",
+        Ok(PushOutcome::Skipped(_) | PushOutcome::DryRun { .. }) => return,
+        Err(_) => "failed",
+    };
+    let Ok(repo) = Repository::open(repo) else {
+        return;
+    };
+    let path: PathBuf = repo.path().join(RUN_LOG_FILE);
+    let seconds: u64 = SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .map_or(0, |elapsed| elapsed.as_secs());
+    let mut lines: Vec<String> = fs::read_to_string(&path)
+        .unwrap_or_default()
+        .lines()
+        .map(str::to_string)
+        .collect();
+    lines.push(format!("{} {}", seconds, code));
+    let start: usize = lines.len().saturating_sub(RUN_LOG_LIMIT);
+    if let Err(e) = fs::write(&path, lines[start..].join("\n") + "\n") {
+        warn!("Could not log the run in {}: {}", path.display(), e);
+    }
+}
+
+/// Analyzes the history of auto commits, see [`PushRequest::stats`].
+///
+/// The auto commits are those reachable from HEAD of the repository that they
+/// are made in, i.e., the marks repository if there is one.
+fn stats(request: &PushRequest) -> Result<HistoryStats, String> {
+    let wallet: Repository = open_repository(&request.repo)?;
+    let repo: Repository = match &request.marks_repo {
+        Some(marks_repo) => open_repository(marks_repo)?,
+        None => open_repository(&request.repo)?,
+    };
+    let mut commits: usize = 0;
+    let mut files: usize = 0;
+    let mut per_day: BTreeMap<String, usize> = BTreeMap::new();
+    if let Ok(head) = repo.head().and_then(|head| head.peel_to_commit()) {
+        let mut walk = repo
+            .revwalk()
+            .map_err(|e| format!("Could not walk the history: {}", e))?;
+        walk.set_sorting(git2::Sort::TIME | git2::Sort::REVERSE)
+            .and_then(|()| walk.push(head.id()))
+            .map_err(|e| format!("Could not walk the history: {}", e))?;
+        for id in walk {
+            let commit: Commit = id
+                .and_then(|id| repo.find_commit(id))
+                .map_err(|e| format!("Could not walk the history: {}", e))?;
+            if !is_auto_commit(&commit) {
+                continue;
+            }
+            let parent_tree: Option<Tree> = commit.parent(0).ok().and_then(|p| p.tree().ok());
+            let diff: Diff = commit
+                .tree()
+                .and_then(|tree| repo.diff_tree_to_tree(parent_tree.as_ref(), Some(&tree), None))
+                .map_err(|e| format!("Could not diff {}: {}", commit.id(), e))?;
+            commits += 1;
+            files += diff.deltas().len();
+            *per_day.entry(format_date(commit.time())).or_default() += 1;
+        }
+    }
+
+    let mut longest_failure_streak: usize = 0;
+    let mut current_failure_streak: usize = 0;
+    let log: String = fs::read_to_string(wallet.path().join(RUN_LOG_FILE)).unwrap_or_default();
+    for line in log.lines() {
+        if line.split_whitespace().nth(1) == Some("failed") {
+            current_failure_streak += 1;
+            longest_failure_streak = longest_failure_streak.max(current_failure_streak);
+        } else {
+            current_failure_streak = 0;
+        }
+    }
+    Ok(HistoryStats {
+        commits,
+        commits_per_day: per_day.into_iter().collect(),
+        average_files: if commits == 0 {
+            0.0
+        } else {
+            files as f64 / commits as f64
+        },
+        longest_failure_streak,
+        current_failure_streak,
+    })
+}
+
+/// Squashes old history into a snapshot commit, see [`PushRequest::compact`].
+///
+/// The commits after the snapshot are recreated on top of it with their
+/// authors, committers, messages, and trees, signed like auto commits. The
+/// push is forced with the branch's old tip as the lease, so that a peer's
+/// push in the meantime isn't lost, and the branch is reset if it fails.
+fn compact(request: &PushRequest, keep: Duration) -> Result<Compaction, String> {
+    let repo: Repository = open_repository(request.marks_repo.as_ref().unwrap_or(&request.repo))?;
+    let Some(_run_lock) = lock_run(&repo)? else {
+        return Err("Another run is in progress.".to_string());
+    };
+    let upstream = Upstream::of_head(&repo, &request.push_target)?;
+    let head: Commit = head_commit(&repo)?.ok_or("HEAD's branch has no commits yet.")?;
+    if fetch_upstream(&repo, &upstream, request.retry)? != Some(head.id()) {
+        return Err(format!(
+            "{} isn't in sync with {} {}. Push or pull first.",
+            upstream.branch_ref, upstream.remote, upstream.merge_ref
+        ));
+    }
+
+    let cutoff: i64 = SystemTime::now()
+        .checked_sub(keep)
+        .and_then(|cutoff| cutoff.duration_since(SystemTime::UNIX_EPOCH).ok())
+        .map_or(0, |cutoff| cutoff.as_secs() as i64);
+    let mut chain: Vec<Commit> = vec![head.clone()];
+    while let Some(parent_id) = chain.last().and_then(|commit| commit.parent_id(0).ok()) {
+        let parent: Commit = repo
+            .find_commit(parent_id)
+            .map_err(|e| format!("Could not find the commit {}: {}", parent_id, e))?;
+        chain.push(parent);
+    }
+    let Some(base) = chain
+        .iter()
+        .position(|commit| commit.committer().when().seconds() < cutoff)
+    else {
+        return Ok(Compaction::NothingToCompact);
+    };
+    if base + 1 == chain.len() {
+        return Ok(Compaction::NothingToCompact);
+    }
+    // The root commit may set up the branch, but everything else must be
+    // reproducible from the marks alone.
+    if let Some(commit) = chain[base..chain.len() - 1]
+        .iter()
+        .find(|commit| !is_auto_commit(commit))
+    {
+        return Err(format!(
+            "{} isn't an auto commit, so {} has history that compaction would lose.",
+            commit.id(),
+            upstream.branch_ref
+        ));
+    }
+
+    snapshot_head(&repo, request.snapshots.max(1))?;
+    let signer: Option<Arc<dyn CommitSigner>> = commit_signer(&repo, request)?;
+    let squashed: &Commit = &chain[base];
+    let tree: Tree = squashed
+        .tree()
+        .map_err(|e| format!("Could not find the tree of {}: {}", squashed.id(), e))?;
+    let message: String = format!(
+        "Compact marks history\n\nSquashes {} commits up to {}.\n\n{}\n",
+        chain.len() - base,
+        squashed.id(),
+        AUTO_COMMIT_TRAILER
+    );
+    let mut tip: Oid = write_commit(
+        &repo,
+        &squashed.committer(),
+        &squashed.committer(),
+        &message,
+        &tree,
+        &[],
+        signer.as_deref(),
+    )?;
+    for commit in chain[..base].iter().rev() {
+        let parent: Commit = repo
+            .find_commit(tip)
+            .map_err(|e| format!("Could not find the commit {}: {}", tip, e))?;
+        let tree: Tree = commit
+            .tree()
+            .map_err(|e| format!("Could not find the tree of {}: {}", commit.id(), e))?;
+        let message: &str = commit
+            .message()
+            .ok_or_else(|| format!("The message of {} isn't UTF-8.", commit.id()))?;
+        tip = write_commit(
+            &repo,
+            &commit.author(),
+            &commit.committer(),
+            message,
+            &tree,
+            &[&parent],
+            signer.as_deref(),
+        )?;
+    }
+
+    repo.reference_matching(
+        &upstream.branch_ref,
+        tip,
+        true,
+        head.id(),
+        "push-wallet-marks: compact the history",
+    )
+    .map_err(|e| format!("Could not update {}: {}", upstream.branch_ref, e))?;
+    if let Err(e) = push_head(&repo, &request.push_target, request.retry, Some(head.id())) {
+        if let Err(reset_error) = repo.reference(
+            &upstream.branch_ref,
+            head.id(),
+            true,
+            "push-wallet-marks: undo the compaction",
+        ) {
+            warn!("Could not reset {}: {}", upstream.branch_ref, reset_error);
+        }
+        return Err(e);
+    }
+    info!(
+        "Squashed {} commits of {} into a snapshot.",
+        chain.len() - base,
+        upstream.branch_ref
+    );
+    Ok(Compaction::Compacted {
+        squashed: chain.len() - base,
+        commit: tip,
+    })
+}
+
+/// Finds when the remote history last changed a file.
+///
+/// # Returns
+///
+/// The time of the newest commit reachable from the remote tip that changed
+/// the file, or `None` if the history isn't available to tell.
+fn last_change(tip: &RemoteTip, path: &Path) -> Result<Option<git2::Time>, String> {
+    let entry_id = |commit: &Commit| -> Option<Oid> {
+        commit
+            .tree()
+            .ok()?
+            .get_path(path)
+            .ok()
+            .map(|entry| entry.id())
+    };
+    let mut commit: Commit = tip
+        .probe
+        .find_commit(tip.commit)
+        .map_err(|e| format!("Could not read the remote tip: {}", e))?;
+    let id: Option<Oid> = entry_id(&commit);
+    loop {
+        let Ok(parent) = commit.parent(0) else {
+            // The root commit, or a shallow boundary, added the file.
+            return Ok(Some(commit.time()).filter(|_| commit.parent_count() == 0));
+        };
+        if entry_id(&parent) != id {
+            return Ok(Some(commit.time()));
+        }
+        commit = parent;
+    }
+}
+
+/// Checks the prerequisites of a push, see [`PushRequest::doctor`].
+///
+/// The checks stop at the first one that the others depend on, e.g., a
+/// repository that can't be opened.
+fn doctor(request: &PushRequest) -> Vec<Check> {
+    let mut checks: Vec<Check> = Vec::new();
+    let mut check = |name: &'static str, result: Result<String, String>| {
+        let passed: bool = result.is_ok();
+        checks.push(Check { name, result });
+        passed
+    };
+
+    let repo: Result<Repository, String> = open_repository(&request.repo);
+    let workdir: Result<String, String> = repo.as_ref().map_err(Clone::clone).and_then(|repo| {
+        repo.workdir()
+            .map(|workdir| workdir.display().to_string())
+            .ok_or_else(|| "The repository has no working directory.".to_string())
+    });
+    let (Ok(repo), true) = (repo, check("repository", workdir)) else {
+        return checks;
+    };
+    check(
+        "operation",
+        match ongoing_operation(repo.state()) {
+            Some(operation) => Err(format!("A {} is in progress.", operation)),
+            None => Ok("none in progress".to_string()),
+        },
+    );
+    check(
+        "auto files",
+        path_patterns("exclude", &request.excludes)
+            .and_then(|_| expand_auto_files(&repo, &request.auto_files, request.changes.allow_new))
+            .and_then(|auto_files| {
+                let problems: Vec<String> = auto_file_problems(
+                    &repo,
+                    &request.auto_files,
+                    &auto_files,
+                    request.changes.allow_new,
+                )?;
+                match auto_files.len() {
+                    0 => Err("No file matches the auto files.".to_string()),
+                    _ if !problems.is_empty() => Err(problems.join(" ")),
+                    count => Ok(format!("{} file(s)", count)),
+                }
+            }),
+    );
+    check(
+        "committer",
+        committer(&repo).map(|signature| signature.to_string()),
+    );
+    check(
+        "author",
+        author(&repo, &request.author).map(|signature| signature.to_string()),
+    );
+    check(
+        "signing",
+        commit_signer(&repo, request).map(|signer| match signer {
+            Some(signer) => format!("{:?}", signer),
+            None => "off".to_string(),
+        }),
+    );
+    if !request.allow_non_default_branch {
+        check(
+            "default branch",
+            check_default_branch(&repo, request.push_target.remote.as_deref())
+                .map(|()| "HEAD is on it".to_string()),
+        );
+    }
+    let upstream: Result<Upstream, String> = Upstream::of_head(&repo, &request.push_target);
+    let described: Result<String, String> = upstream
+        .as_ref()
+        .map(|upstream| format!("{} {}", upstream.remote, upstream.merge_ref))
+        .map_err(Clone::clone);
+    let (Ok(upstream), true) = (upstream, check("upstream", described)) else {
+        return checks;
+    };
+    let reachable = |name: &str| {
+        repo.find_remote(name)
+            .map_err(|e| format!("Could not find the remote {}: {}", name, e))
+            .and_then(|mut remote| {
+                let callbacks: RemoteCallbacks = authenticating_callbacks(&repo)?;
+                remote
+                    .connect_auth(Direction::Push, Some(callbacks), None)
+                    .map_err(|e| remote_error("connect to", name, e))?;
+                Ok(format!("{} is reachable", remote.url().unwrap_or(name)))
+            })
+    };
+    check("remote", reachable(&upstream.remote));
+    for mirror in &request.mirrors {
+        check("mirror", reachable(mirror));
+    }
+    checks
+}
+
+/// Copies the auto files from the wallet's working tree into the marks
+/// repository's, see [`PushRequest::marks_repo`]. A dry run only reports what
+/// it would copy.
+///
+/// # Arguments
+///
+/// * `wallet` - The wallet repository.
+/// * `marks_repo` - The path of the marks repository.
+/// * `request` - The options of the run.
+///
+/// # Returns
+///
+/// The expanded auto files, which the marks repository then pushes.
+fn copy_into_marks_repo(
+    wallet: &Repository,
+    marks_repo: &Path,
+    request: &PushRequest,
+) -> Result<Vec<PathBuf>, String> {
+    let workdir: &Path = wallet
+        .workdir()
+        .ok_or("The wallet repository has no working directory.")?;
+    let excludes: Vec<Pattern> = path_patterns("exclude", &request.excludes)?;
+    let ignore_case: bool = ignores_case(wallet);
+    let mut auto_files: Vec<PathBuf> =
+        expand_auto_files(wallet, &request.auto_files, request.changes.allow_new)?;
+    auto_files.retain(|auto_file| !matches_patterns(auto_file, &excludes, ignore_case));
+    for auto_file in &auto_files {
+        let from: PathBuf = workdir.join(auto_file);
+        let to: PathBuf = marks_repo.join(auto_file);
+        let content: Option<Vec<u8>> = match fs::read(&from) {
+            Ok(content) => Some(content),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => None,
+            Err(e) => return Err(format!("Could not read {}: {}", from.display(), e)),
+        };
+        if (content.is_none() && !request.changes.allow_delete) || content == fs::read(&to).ok() {
+            continue;
+        }
+        if request.dry_run {
+            info!(
+                "Would copy {} into {}.",
+                auto_file.display(),
+                marks_repo.display()
+            );
+            continue;
+        }
+        match content {
+            Some(content) => to
+                .parent()
+                .map_or(Ok(()), fs::create_dir_all)
+                .and_then(|()| fs::write(&to, content)),
+            None => fs::remove_file(&to).or_else(|e| match e.kind() {
+                io::ErrorKind::NotFound => Ok(()),
+                _ => Err(e),
+            }),
+        }
+        .map_err(|e| {
+            format!(
+                "Could not copy {} into {}: {}",
+                auto_file.display(),
+                marks_repo.display(),
+                e
+            )
+        })?;
+        debug!(
+            "Copied {} into {}.",
+            auto_file.display(),
+            marks_repo.display()
+        );
+    }
+    Ok(auto_files)
+}
+
+/// Opens the wallet repository.
+fn open_repository(path: &Path) -> Result<Repository, String> {
+    Repository::open(path)
+        .map_err(|_| format!("The path `{}` is not a valid repository.", path.display()))
+}
+
+fn run(request: &PushRequest, timings: &mut Timings) -> Result<PushOutcome, String> {
+    if let Some(marks_repo) = &request.marks_repo {
+        let wallet: Repository = open_repository(&request.repo)?;
+        let auto_files: Vec<PathBuf> = timings.measure("marks-repo", || {
+            copy_into_marks_repo(&wallet, marks_repo, request)
+        })?;
+        let request = PushRequest {
+            repo: marks_repo.clone(),
+            marks_repo: None,
+            auto_files,
+            ..request.clone()
+        };
+        return run(&request, timings);
+    }
+    let repo: Repository = open_repository(&request.repo)?;
+    let copy_request: PushRequest;
+    let request: &PushRequest = if request.workspace != Workspace::Copy
+        && NamedTempFile::new_in(common_dir(repo.path())).is_err()
+    {
+        // E.g., a backup snapshot or a network share. A copy only reads the
+        // original until the auto commit is pushed.
+        info!(
+            "{} can't be written, so the auto commit is made in a copy instead.",
+            repo.path().display()
+        );
+        copy_request = PushRequest {
+            workspace: Workspace::Copy,
+            ..request.clone()
+        };
+        &copy_request
+    } else {
+        request
+    };
+    let Some(_run_lock) = lock_run(&repo)? else {
+        info!("Another run is in progress.");
+        return Ok(PushOutcome::Skipped(SkipReason::AnotherRunInProgress));
+    };
+
+    wait_for_index_lock(&repo, request.lock_timeout)?;
+    let mut auto_files: Vec<PathBuf> =
+        expand_auto_files(&repo, &request.auto_files, request.changes.allow_new)?;
+    for problem in auto_file_problems(
+        &repo,
+        &request.auto_files,
+        &auto_files,
+        request.changes.allow_new,
+    )? {
+        warn!("{}", problem);
+    }
+    auto_files.extend(rename_targets(&repo, &request.auto_files, &auto_files)?);
+    debug!("Auto files: {:?}", auto_files);
+    let excludes: Vec<Pattern> = path_patterns("exclude", &request.excludes)?;
+    // A dry run mustn't touch the working tree, so it only reports conflicts.
+    let on_sync_conflict: SyncConflictPolicy = if request.dry_run {
+        SyncConflictPolicy::Abort
+    } else {
+        request.on_sync_conflict
+    };
+    // Resolving rewrites auto files, which mustn't interfere with a manual
+    // change ongoing in the repository.
+    if let Some(reason) = untouchable_state(&repo, &auto_files, &excludes)? {
+        return Ok(PushOutcome::Skipped(reason));
+    }
+    resolve_sync_conflicts(&repo, &auto_files, on_sync_conflict)?;
+
+    // A batch whose window has passed is pushed even without new changes.
+    let flush_batch: bool = batch_due(&repo, request)?;
+    let state: String =
+        timings.measure("state", || describe_run_state(&repo, request, &auto_files))?;
+    let state_path: PathBuf = run_state_path(&repo);
+    // The state doesn't tell whether peers pushed.
+    if !request.ignore_state
+        && !flush_batch
+        && !request.follow_peers
+        && fs::read_to_string(&state_path).ok().as_ref() == Some(&state)
+    {
+        info!("Nothing has changed since the last run.");
+        return Ok(PushOutcome::Skipped(SkipReason::Unchanged));
+    }
+
+    // Check the original repository first, so that the common case of having
+    // nothing to commit doesn't pay for a copy.
+    let precheck: Result<Vec<StatusEntryBetter>, SkipReason> = timings
+        .measure("precheck", || {
+            collect_mark_file_statuses(&repo, &auto_files, &excludes, request.changes)
+        })?;
+    match precheck {
+        Ok(_) => {}
+        Err(SkipReason::NoChanges) if flush_batch => {}
+        Err(SkipReason::NoChanges) => {
+            // The same HEAD and mark files will give the same answer, so the
+            // next run can stop at the state comparison. The state is only an
+            // optimization, so e.g. a read-only mount mustn't fail the run.
+            if request.dry_run {
+                return Ok(PushOutcome::Skipped(SkipReason::NoChanges));
+            }
+            // Being offline mustn't fail a run without changes.
+            if request.follow_peers {
+                if let Err(e) = timings.measure("follow", || follow_peers(&repo, request)) {
+                    warn!("{}", e);
+                }
+            }
+            if let Err(e) = fs::write(&state_path, state) {
+                warn!(
+                    "Could not record the run state in {} ({}). The next run will check the repository again.",
+                    state_path.display(),
+                    e
+                );
+            }
+            return Ok(PushOutcome::Skipped(SkipReason::NoChanges));
+        }
+        Err(reason) => return Ok(PushOutcome::Skipped(reason)),
+    }
+
+    if !request.allow_non_default_branch {
+        check_default_branch(&repo, request.push_target.remote.as_deref())?;
+    }
+
+    if request.snapshots > 0 && !request.dry_run {
+        snapshot_head(&repo, request.snapshots)?;
+    }
+
+    let head_id: Option<Oid> = repo.head().ok().and_then(|head| head.target());
+    let mut outcome: PushOutcome = match request.workspace {
+        Workspace::Copy => {
+            let temp_dir: tempfile::TempDir = timings.measure("copy", || {
+                copy_repository(&request.repo, &auto_files, request.temp_dir.as_deref())
+            })?;
+            let outcome: Result<PushOutcome, String> = push_wallet_marks(
+                temp_dir.path(),
+                &auto_files,
+                request,
+                &request.push_target,
+                timings,
+            )
+            .and_then(|mut outcome| {
+                match &mut outcome {
+                    PushOutcome::Committed { .. } => fetch_from_copy(&repo, temp_dir.path())?,
+                    // The auto commit is on the remote already, so a failed
+                    // fetch only keeps the original from being synced.
+                    PushOutcome::Pushed { sync_error, .. } => {
+                        *sync_error = fetch_from_copy(&repo, temp_dir.path()).err();
+                    }
+                    PushOutcome::Skipped(_) | PushOutcome::DryRun { .. } => {}
+                }
+                Ok(outcome)
+            });
+            match outcome {
+                Err(e) if request.keep_temp => {
+                    let copy: PathBuf = temp_dir.into_path();
+                    return Err(format!("{}\nKept the copy at {}.", e, copy.display()));
+                }
+                outcome => outcome?,
+            }
+        }
+        Workspace::Worktree => {
+            // The worktree's branch is temporary, so the target must not
+            // depend on its name or upstream.
+            let upstream = Upstream::of_head(&repo, &request.push_target)?;
+            let target = PushTarget {
+                remote: Some(upstream.remote),
+                branch: Some(upstream.merge_ref),
+            };
+            let worktree: TemporaryWorktree = timings.measure("worktree", || {
+                add_temporary_worktree(&repo, &auto_files, request.temp_dir.as_deref())
+            })?;
+            push_wallet_marks(worktree.path(), &auto_files, request, &target, timings)?
+        }
+        Workspace::InPlace => {
+            if let Err(reason) = check_clean_worktree(&repo, &auto_files)? {
+                return Ok(PushOutcome::Skipped(reason));
+            }
+            push_in_place(&repo, &auto_files, request, timings)?
+        }
+    };
+    match &mut outcome {
+        PushOutcome::Pushed {
+            commit,
+            rebased,
+            amended,
+            reset,
+            tag,
+            sync_error,
+            ..
+        } => {
+            // After a reset, HEAD is replaced like an amended commit and the
+            // upstream changes are checked out like after a rebase.
+            let (commit, rebased, amended, reset) = (*commit, *rebased, *amended, *reset);
+            if sync_error.is_none() {
+                *sync_error = timings
+                    .measure("sync", || {
+                        sync_original(
+                            &repo,
+                            &request.push_target,
+                            commit,
+                            rebased || reset,
+                            head_id.filter(|_| amended || reset),
+                        )
+                    })
+                    .err();
+            }
+            match sync_error {
+                // The push succeeded, so the run doesn't fail.
+                Some(e) => warn!(
+                    "{}\nThe auto commit was pushed, but the original repository wasn’t updated. Pull it manually.",
+                    e
+                ),
+                // The original has the auto commit now, so the tag can point
+                // to it.
+                None => {
+                    if let Some(tag) = tag {
+                        if let Err(e) = create_tag(&repo, tag, commit) {
+                            warn!("{}", e);
+                        }
+                    }
+                }
+            }
+        }
+        PushOutcome::Committed {
+            commit,
+            rebased,
+            amended,
+            reset,
+            ..
+        } => timings.measure("sync", || {
+            let head = repo
+                .head()
+                .map_err(|e| format!("Could not resolve HEAD: {}", e))?;
+            let branch_ref: &str = head.name().ok_or("The HEAD branch name isn't UTF-8.")?;
+            fast_forward_original(
+                &repo,
+                branch_ref,
+                *commit,
+                *rebased || *reset,
+                head_id.filter(|_| *amended || *reset),
+            )
+        })?,
+        PushOutcome::Skipped(_) | PushOutcome::DryRun { .. } => {}
+    }
+    if let PushOutcome::Pushed { staged_files, .. } | PushOutcome::Committed { staged_files, .. } =
+        &outcome
+    {
+        let commits: usize = match request.commit_strategy {
+            CommitStrategy::Combined => 1,
+            CommitStrategy::PerFile => staged_files.len(),
+        };
+        // Maintenance only keeps the repository fast, so it doesn't fail the
+        // run.
+        if request.maintenance_every > 0 {
+            if let Err(e) =
+                count_auto_commits(&repo, commits, request.maintenance_every).and_then(|due| {
+                    match due {
+                        true => timings.measure("maintenance", || run_maintenance(&repo)),
+                        false => Ok(()),
+                    }
+                })
+            {
+                warn!("{}", e);
+            }
+        }
+    }
+    Ok(outcome)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Returns a modified mark file entry.
+    fn modified(path: &str) -> StatusEntryBetter {
+        StatusEntryBetter {
+            path: PathBuf::from(path),
+            status: Status::WT_MODIFIED,
+            old_path: None,
+        }
+    }
+
+    #[test]
+    fn git_path_joins_components_with_slashes() {
+        assert_eq!(git_path(Path::new("a.journal")), Path::new("a.journal"));
+        assert_eq!(
+            git_path(Path::new("./marks/./2024/a.journal")),
+            Path::new("marks/2024/a.journal")
+        );
+        assert_eq!(git_path(Path::new("marks/")), Path::new("marks"));
+        // `..` isn't resolved, since that would need the file system.
+        assert_eq!(
+            git_path(Path::new("marks/../other/a.journal")),
+            Path::new("marks/../other/a.journal")
+        );
+    }
+
+    #[cfg(windows)]
+    #[test]
+    fn git_path_converts_backslashes() {
+        assert_eq!(
+            git_path(Path::new(r".\marks\2024\a.journal")),
+            Path::new("marks/2024/a.journal")
+        );
+    }
+
+    #[test]
+    fn path_key_normalizes_unicode_and_case() {
+        let decomposed: &Path = Path::new("marks/Cafe\u{301}/a.journal");
+        assert_eq!(
+            path_key(decomposed, false),
+            Path::new("marks/Caf\u{e9}/a.journal")
+        );
+        assert_eq!(
+            path_key(decomposed, true),
+            Path::new("marks/caf\u{e9}/a.journal")
+        );
+        assert_eq!(
+            path_key(Path::new("Marks/A.journal"), false),
+            Path::new("Marks/A.journal")
+        );
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn paths_that_arent_unicode_are_kept() {
+        use std::os::unix::ffi::OsStrExt;
+
+        let path: &Path = Path::new(OsStr::from_bytes(b"./Marks/\xff.journal"));
+        let git: PathBuf = git_path(path);
+        assert_eq!(git.as_os_str().as_bytes(), b"Marks/\xff.journal");
+        assert_eq!(path_key(&git, true), git);
+    }
+
+    #[test]
+    fn fallback_lock_path_is_per_repository() {
+        let dir: tempfile::TempDir = tempdir().unwrap();
+        let wallet: PathBuf = dir.path().join("wallet/.git");
+        let other: PathBuf = dir.path().join("other/.git");
+        fs::create_dir_all(&wallet).unwrap();
+        fs::create_dir_all(&other).unwrap();
+
+        let lock_path: PathBuf = fallback_lock_path(&wallet).unwrap();
+
+        assert!(
+            !lock_path.starts_with(dir.path()),
+            "{}",
+            lock_path.display()
+        );
+        assert_eq!(
+            fallback_lock_path(&wallet.join("../.git")),
+            Ok(lock_path.clone())
+        );
+        assert_ne!(fallback_lock_path(&other), Ok(lock_path));
+    }
+
+    #[test]
+    fn format_date_uses_the_time_zone() {
+        // 2024-01-05T23:30:00Z
+        let seconds: i64 = 1_704_497_400;
+        assert_eq!(format_date(git2::Time::new(seconds, 0)), "2024-01-05");
+        assert_eq!(format_date(git2::Time::new(seconds, 60)), "2024-01-06");
+        assert_eq!(format_date(git2::Time::new(seconds, -600)), "2024-01-05");
+    }
+
+    #[test]
+    fn format_date_handles_leap_days_and_the_epoch() {
+        assert_eq!(format_date(git2::Time::new(0, 0)), "1970-01-01");
+        assert_eq!(format_date(git2::Time::new(-1, 0)), "1969-12-31");
+        assert_eq!(format_date(git2::Time::new(1_709_164_800, 0)), "2024-02-29");
+        assert_eq!(format_date(git2::Time::new(951_782_400, 0)), "2000-02-29");
+    }
+
+    #[test]
+    fn format_time_gives_the_offset() {
+        let seconds: i64 = 1_704_458_700;
+        assert_eq!(
+            format_time(git2::Time::new(seconds, 0)),
+            "2024-01-05T12:45:00Z"
+        );
+        assert_eq!(
+            format_time(git2::Time::new(seconds, 60)),
+            "2024-01-05T13:45:00+01:00"
+        );
+        assert_eq!(
+            format_time(git2::Time::new(seconds, -570)),
+            "2024-01-05T03:15:00-09:30"
+        );
+    }
+
+    #[test]
+    fn render_template_fills_in_placeholders() {
+        let files: Vec<StatusEntryBetter> =
+            vec![modified("marks/a.journal"), modified("b.journal")];
+        let time = git2::Time::new(1_704_458_700, 60);
+
+        let message: String = render_template(
+            "commit message",
+            "{count} in {files} on {date} at {local-time} ({utc-time}) {{x}}",
+            &files,
+            time,
+        )
+        .unwrap();
+
+        assert_eq!(
+            message,
+            "2 in marks/a.journal, b.journal on 2024-01-05 at 2024-01-05T13:45:00+01:00 (2024-01-05T12:45:00Z) {x}"
+        );
+    }
+
+    #[test]
+    fn render_template_rejects_bad_templates() {
+        let time = git2::Time::new(0, 0);
+
+        let unknown: String = render_template("tag", "{when}", &[], time).unwrap_err();
+        assert!(
+            unknown.contains("unknown placeholder {when}"),
+            "{}",
+            unknown
+        );
+        assert!(unknown.contains("{utc-time}"), "{}", unknown);
+        let unmatched: String = render_template("tag", "marks/{date", &[], time).unwrap_err();
+        assert!(unmatched.contains("unmatched brace"), "{}", unmatched);
+        let closing: String = render_template("tag", "marks}", &[], time).unwrap_err();
+        assert!(closing.contains("unmatched brace"), "{}", closing);
+    }
+}
@@ -1,228 +1,2224 @@
-use std::collections::HashSet;
+use std::collections::BTreeMap;
+use std::env;
+use std::fs;
+use std::io;
+use std::io::BufRead;
+use std::io::BufReader;
+use std::io::IsTerminal;
+use std::io::Write;
+use std::net::SocketAddr;
+use std::net::TcpListener;
+use std::net::TcpStream;
 use std::path::Path;
 use std::path::PathBuf;
+use std::process::ExitCode;
+use std::sync::atomic::AtomicUsize;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use std::sync::Mutex;
+use std::sync::PoisonError;
+use std::thread;
+use std::time::Duration;
+use std::time::SystemTime;
+use std::time::UNIX_EPOCH;
 
+use clap::builder::BoolishValueParser;
+use clap::ArgAction;
+use clap::CommandFactory;
 use clap::Parser;
-use git2::Index;
-use git2::Repository;
+use clap::Subcommand;
+use clap::ValueEnum;
+use clap::ValueHint;
+use clap_complete::Shell;
 use git2::Status;
-use git2::StatusEntry;
-use git2::Statuses;
-use tempfile::tempdir;
+use git_auto_commit::CommitStrategy;
+use git_auto_commit::Compaction;
+use git_auto_commit::Confirmer;
+use git_auto_commit::DivergencePolicy;
+use git_auto_commit::FileAction;
+use git_auto_commit::HistoryStats;
+use git_auto_commit::MarksDiff;
+use git_auto_commit::PushOutcome;
+use git_auto_commit::PushRequest;
+use git_auto_commit::SyncConflictPolicy;
+use git_auto_commit::SyncEvent;
+use git_auto_commit::SyncService;
+use git_auto_commit::Timings;
+use git_auto_commit::Verification;
+use git_auto_commit::Workspace;
+use log::error;
+use log::info;
+use log::warn;
+use log::LevelFilter;
+use serde::Deserialize;
+use serde::Serialize;
 
 const ABOUT: &str = "Commits tracked files if changed.";
 
+/// The name of the executable, which completion scripts complete.
+const BIN_NAME: &str = env!("CARGO_BIN_NAME");
+
 /// The command-line interface parameters.
+///
+/// Options that are also available in the configuration file are optional
+/// here, so that we can tell whether they were given and should take
+/// precedence.
 #[derive(Debug, Parser)]
-#[command(author, version, about, long_about = ABOUT)]
+#[command(author, version, about = ABOUT, long_about = ABOUT)]
 struct Cli {
-    /// The repository path.
-    #[arg(short, long, value_name = "DIR")]
-    repo: PathBuf,
+    #[command(subcommand)]
+    command: Option<Action>,
+
+    /// The configuration file [default: ~/.config/push-wallet-marks/config.toml].
+    #[arg(
+        short,
+        long,
+        global = true,
+        value_name = "FILE",
+        value_hint = ValueHint::FilePath,
+        env = "PUSH_WALLET_MARKS_CONFIG"
+    )]
+    config: Option<PathBuf>,
+
+    /// The repository path. Repeat to process several repositories with the
+    /// same auto files [env: PUSH_WALLET_MARKS_REPO, a list like PATH].
+    #[arg(short, long, global = true, value_name = "DIR", value_hint = ValueHint::DirPath)]
+    repo: Vec<PathBuf>,
 
-    /// Relative paths of files to be automatically committed.
-    #[arg(short, long, value_name = "FILES...")]
+    /// Relative paths of files to be automatically committed. Glob patterns,
+    /// e.g., `marks/**/*.journal`, are matched against tracked files, and a
+    /// directory stands for the files in it [env: PUSH_WALLET_MARKS_AUTO_FILES,
+    /// a list like PATH].
+    #[arg(
+        short,
+        long,
+        global = true,
+        value_name = "FILES...",
+        value_hint = ValueHint::AnyPath
+    )]
     auto_files: Vec<PathBuf>,
+
+    /// A glob pattern of files never to commit, even if --auto-files covers
+    /// them, e.g., `journals/drafts`. Repeat for several patterns [env:
+    /// PUSH_WALLET_MARKS_EXCLUDE, a list like PATH].
+    #[arg(long, global = true, value_name = "PATTERN")]
+    exclude: Vec<String>,
+
+    /// A glob pattern of auto files to treat as opaque blobs, e.g.,
+    /// `marks/*.gpg` for encrypted ones, which skip the diff-based checks and
+    /// validations. Repeat for several patterns [env:
+    /// PUSH_WALLET_MARKS_OPAQUE, a list like PATH].
+    #[arg(long, global = true, value_name = "PATTERN")]
+    opaque: Vec<String>,
+
+    /// Don't commit changes that only flip a file's mode, e.g., the executable
+    /// bit, and keep the committed mode when staging content changes.
+    #[arg(
+        long,
+        global = true,
+        env = "PUSH_WALLET_MARKS_IGNORE_MODE_CHANGES",
+        value_parser = BoolishValueParser::new()
+    )]
+    ignore_mode_changes: bool,
+
+    /// Also commit auto files that Git doesn't track yet. Glob patterns then
+    /// match untracked files too.
+    #[arg(
+        long,
+        global = true,
+        env = "PUSH_WALLET_MARKS_ALLOW_NEW",
+        value_parser = BoolishValueParser::new()
+    )]
+    allow_new: bool,
+
+    /// Also commit deletions of auto files.
+    #[arg(
+        long,
+        global = true,
+        env = "PUSH_WALLET_MARKS_ALLOW_DELETE",
+        value_parser = BoolishValueParser::new()
+    )]
+    allow_delete: bool,
+
+    /// Refuse to push changes that do more than flip the status marks of
+    /// transactions and postings, e.g., `!` to `*`.
+    #[arg(
+        long,
+        global = true,
+        env = "PUSH_WALLET_MARKS_MARKS_ONLY",
+        value_parser = BoolishValueParser::new()
+    )]
+    marks_only: bool,
+
+    /// Refuse to push changes of more than this many mark files.
+    #[arg(
+        long,
+        global = true,
+        value_name = "COUNT",
+        env = "PUSH_WALLET_MARKS_MAX_FILES"
+    )]
+    max_files: Option<usize>,
+
+    /// Refuse to push changes that add and remove more than this many lines
+    /// in total, e.g., because an editor reformatted a whole journal.
+    #[arg(
+        long,
+        global = true,
+        value_name = "COUNT",
+        env = "PUSH_WALLET_MARKS_MAX_CHANGED_LINES"
+    )]
+    max_changed_lines: Option<usize>,
+
+    /// Refuse to push unless every changed hunk matches this regular
+    /// expression as a whole. A hunk is matched as its removed and added
+    /// lines, each with its - or + prefix and newline.
+    #[arg(
+        long,
+        global = true,
+        value_name = "REGEX",
+        env = "PUSH_WALLET_MARKS_REQUIRE_DIFF_REGEX"
+    )]
+    require_diff_regex: Option<String>,
+
+    /// Commit even if HEAD isn't on the remote's default branch.
+    #[arg(
+        long,
+        global = true,
+        env = "PUSH_WALLET_MARKS_ALLOW_NON_DEFAULT_BRANCH",
+        value_parser = BoolishValueParser::new()
+    )]
+    allow_non_default_branch: bool,
+
+    /// What to do about Syncthing conflict copies of auto files [default: abort].
+    #[arg(
+        long,
+        value_enum,
+        global = true,
+        env = "PUSH_WALLET_MARKS_ON_SYNC_CONFLICT"
+    )]
+    on_sync_conflict: Option<SyncConflictPolicy>,
+
+    /// What to do if HEAD's branch and its upstream have diverged before the
+    /// auto commit is made [default: rebase].
+    #[arg(long, value_enum, global = true, env = "PUSH_WALLET_MARKS_ON_DIVERGED")]
+    on_diverged: Option<DivergencePolicy>,
+
+    /// Where to make the auto commit [default: copy].
+    #[arg(long, value_enum, global = true, env = "PUSH_WALLET_MARKS_WORKSPACE")]
+    workspace: Option<Workspace>,
+
+    /// Commit directly in the repository instead of a temporary copy. Only
+    /// runs if no tracked file but the auto files has uncommitted changes.
+    /// Short for --workspace in-place.
+    #[arg(long, global = true, conflicts_with = "workspace")]
+    in_place: bool,
+
+    /// The directory to create the temporary copy or worktree in, e.g., if
+    /// the system's temporary directory is too small [default: the system's
+    /// temporary directory].
+    #[arg(
+        long,
+        global = true,
+        value_name = "DIR",
+        value_hint = ValueHint::DirPath,
+        env = "PUSH_WALLET_MARKS_TEMP_DIR"
+    )]
+    temp_dir: Option<PathBuf>,
+
+    /// A separate repository to copy the auto files into and commit and push
+    /// them from, e.g., a shared one while the wallet stays private.
+    #[arg(
+        long,
+        global = true,
+        value_name = "DIR",
+        value_hint = ValueHint::DirPath,
+        env = "PUSH_WALLET_MARKS_MARKS_REPO"
+    )]
+    marks_repo: Option<PathBuf>,
+
+    /// Keep the temporary copy if the run fails, and print where it is, to
+    /// debug the failure.
+    #[arg(
+        long,
+        global = true,
+        env = "PUSH_WALLET_MARKS_KEEP_TEMP",
+        value_parser = BoolishValueParser::new()
+    )]
+    keep_temp: bool,
+
+    /// Run even if HEAD and the auto files are unchanged since the last run.
+    #[arg(long, global = true)]
+    ignore_state: bool,
+
+    /// How many seconds to wait for another Git process to release the index
+    /// [default: 0].
+    #[arg(
+        long,
+        global = true,
+        value_name = "SECONDS",
+        env = "PUSH_WALLET_MARKS_LOCK_TIMEOUT"
+    )]
+    lock_timeout: Option<u64>,
+
+    /// How many seconds to keep amending an unpushed auto commit before
+    /// pushing it [default: 0].
+    #[arg(
+        long,
+        global = true,
+        value_name = "SECONDS",
+        env = "PUSH_WALLET_MARKS_BATCH_WINDOW"
+    )]
+    batch_window: Option<u64>,
+
+    /// How many times to retry a fetch or push that failed because of the
+    /// network [default: 0].
+    #[arg(
+        long,
+        global = true,
+        value_name = "COUNT",
+        env = "PUSH_WALLET_MARKS_RETRIES"
+    )]
+    retries: Option<u32>,
+
+    /// How many seconds to wait before the first retry. Each further retry
+    /// waits twice as long [default: 5].
+    #[arg(
+        long,
+        global = true,
+        value_name = "SECONDS",
+        env = "PUSH_WALLET_MARKS_RETRY_DELAY"
+    )]
+    retry_delay: Option<u64>,
+
+    /// How many repositories to push at the same time. --interactive pushes
+    /// one at a time [default: 4].
+    #[arg(
+        short,
+        long,
+        global = true,
+        value_name = "COUNT",
+        value_parser = clap::value_parser!(u32).range(1..),
+        env = "PUSH_WALLET_MARKS_JOBS"
+    )]
+    jobs: Option<u32>,
+
+    /// Print more details; repeat for even more.
+    #[arg(short, long, global = true, action = ArgAction::Count, conflicts_with = "quiet")]
+    verbose: u8,
+
+    /// Print only warnings and errors; repeat to print only errors.
+    #[arg(short, long, global = true, action = ArgAction::Count)]
+    quiet: u8,
+
+    /// How to report the outcome on stdout. JSON prints one object per run.
+    #[arg(long, value_enum, global = true, default_value_t = OutputFormat::Text)]
+    output: OutputFormat,
+
+    /// Print how long each phase of the run took.
+    #[arg(long, global = true)]
+    timings: bool,
+
+    /// Show a desktop notification when marks are pushed or a run is aborted
+    /// or fails.
+    #[cfg(feature = "desktop-notifications")]
+    #[arg(long, global = true)]
+    notify: bool,
+
+    /// POST a JSON report of each run to this URL, e.g., a Slack, Discord, or
+    /// ntfy.sh webhook.
+    #[arg(
+        long,
+        global = true,
+        value_name = "URL",
+        env = "PUSH_WALLET_MARKS_NOTIFY_URL"
+    )]
+    notify_url: Option<String>,
+
+    /// The remote to push to [default: the remote of HEAD's upstream].
+    #[arg(
+        long,
+        global = true,
+        value_name = "REMOTE",
+        env = "PUSH_WALLET_MARKS_REMOTE"
+    )]
+    remote: Option<String>,
+
+    /// The branch on the remote to push to [default: HEAD's upstream branch].
+    #[arg(
+        long,
+        global = true,
+        value_name = "BRANCH",
+        env = "PUSH_WALLET_MARKS_BRANCH"
+    )]
+    branch: Option<String>,
+
+    /// Another remote to push the auto commit to after the upstream, e.g., a
+    /// mirror. A failed push to it doesn't fail the run. Repeat for several
+    /// remotes [env: PUSH_WALLET_MARKS_MIRROR, a list like PATH].
+    #[arg(long, global = true, value_name = "REMOTE")]
+    mirror: Vec<String>,
+
+    /// The author name of auto commits, e.g., "wallet-bot" [default: user.name
+    /// from the Git config].
+    #[arg(
+        long,
+        global = true,
+        value_name = "NAME",
+        env = "PUSH_WALLET_MARKS_AUTHOR_NAME"
+    )]
+    author_name: Option<String>,
+
+    /// The author email of auto commits [default: user.email from the Git
+    /// config].
+    #[arg(
+        long,
+        global = true,
+        value_name = "EMAIL",
+        env = "PUSH_WALLET_MARKS_AUTHOR_EMAIL"
+    )]
+    author_email: Option<String>,
+
+    /// The commit message template, e.g., "auto: update marks in {files}".
+    /// Placeholders: {files}, {count}, {date}, {local-time}, {utc-time}, and
+    /// {hostname} [default:
+    /// "Update marks" and a list of the files].
+    #[arg(
+        short,
+        long,
+        global = true,
+        value_name = "TEMPLATE",
+        env = "PUSH_WALLET_MARKS_MESSAGE"
+    )]
+    message: Option<String>,
+
+    /// Create a lightweight tag on the pushed auto commit and push it, e.g.,
+    /// "marks/{date}". Takes the placeholders of --message. An existing tag
+    /// isn't moved.
+    #[arg(
+        long,
+        global = true,
+        value_name = "TEMPLATE",
+        env = "PUSH_WALLET_MARKS_TAG_TEMPLATE"
+    )]
+    tag_template: Option<String>,
+
+    /// Keep this many snapshots of HEAD from before auto commits as
+    /// refs/pwm/snapshots/<seconds since the epoch>, deleting the oldest.
+    #[arg(
+        long,
+        global = true,
+        value_name = "COUNT",
+        env = "PUSH_WALLET_MARKS_SNAPSHOTS"
+    )]
+    snapshots: Option<usize>,
+
+    /// Run `git maintenance run` after every this many auto commits.
+    #[arg(
+        long,
+        global = true,
+        value_name = "COUNT",
+        env = "PUSH_WALLET_MARKS_MAINTENANCE_EVERY"
+    )]
+    maintenance_every: Option<usize>,
+
+    /// A shell command that validates each staged mark file before the
+    /// commit, e.g., "hledger check -f". The file's path is appended, and a
+    /// failure aborts the push.
+    #[arg(
+        long,
+        global = true,
+        value_name = "COMMAND",
+        env = "PUSH_WALLET_MARKS_VALIDATE_CMD"
+    )]
+    validate_cmd: Option<String>,
+
+    /// Run the repository's pre-commit and post-commit hooks around each auto
+    /// commit, respecting core.hooksPath. A failing pre-commit hook aborts the
+    /// push.
+    #[arg(
+        long,
+        global = true,
+        overrides_with = "no_verify",
+        env = "PUSH_WALLET_MARKS_RUN_HOOKS",
+        value_parser = BoolishValueParser::new()
+    )]
+    run_hooks: bool,
+
+    /// Don't run the hooks, even if run-hooks is set in the configuration
+    /// file.
+    #[arg(
+        long,
+        global = true,
+        env = "PUSH_WALLET_MARKS_NO_VERIFY",
+        value_parser = BoolishValueParser::new()
+    )]
+    no_verify: bool,
+
+    /// Whether to commit all mark files together or each in its own commit,
+    /// whose message names the file [default: combined].
+    #[arg(
+        long,
+        value_enum,
+        global = true,
+        env = "PUSH_WALLET_MARKS_COMMIT_STRATEGY"
+    )]
+    commit_strategy: Option<CommitStrategy>,
+
+    /// Print the diff of the mark files before committing them. With
+    /// `--output json`, it's in the `diff` field, too.
+    #[arg(
+        long,
+        global = true,
+        env = "PUSH_WALLET_MARKS_SHOW_DIFF",
+        value_parser = BoolishValueParser::new()
+    )]
+    show_diff: bool,
+
+    /// List the changed mark files and ask before committing and pushing
+    /// them. Needs a terminal.
+    #[arg(long, global = true)]
+    interactive: bool,
+
+    /// Amend HEAD instead of adding a commit if it's an auto commit that
+    /// hasn't been pushed yet. A failed push then keeps the auto commit, so
+    /// that the next run amends it, e.g., on a laptop that's offline.
+    #[arg(
+        long,
+        global = true,
+        env = "PUSH_WALLET_MARKS_AMEND",
+        value_parser = BoolishValueParser::new()
+    )]
+    amend: bool,
+
+    /// On a run without changes, fetch the upstream and fast-forward over
+    /// the auto commits that other machines pushed.
+    #[arg(
+        long,
+        global = true,
+        env = "PUSH_WALLET_MARKS_FOLLOW_PEERS",
+        value_parser = BoolishValueParser::new()
+    )]
+    follow_peers: bool,
+
+    /// Force the push instead of rebasing onto the upstream, e.g., after the
+    /// upstream was rewritten. Fails if the remote branch has moved since it
+    /// was last fetched.
+    #[arg(
+        long,
+        global = true,
+        env = "PUSH_WALLET_MARKS_FORCE_WITH_LEASE",
+        value_parser = BoolishValueParser::new()
+    )]
+    force_with_lease: bool,
+
+    /// Sign the auto commit as Git would [default: commit.gpgsign from the Git
+    /// config].
+    #[arg(
+        long,
+        global = true,
+        overrides_with = "no_sign",
+        env = "PUSH_WALLET_MARKS_SIGN",
+        value_parser = BoolishValueParser::new()
+    )]
+    sign: bool,
+
+    /// Don't sign the auto commit, even if commit.gpgsign is set.
+    #[arg(
+        long,
+        global = true,
+        env = "PUSH_WALLET_MARKS_NO_SIGN",
+        value_parser = BoolishValueParser::new()
+    )]
+    no_sign: bool,
+
+    /// Stage the mark files in the copy and report the commit that would be
+    /// pushed, without committing, pushing, or changing the repository.
+    #[arg(long, global = true)]
+    dry_run: bool,
 }
 
-/// A modification of git2::StatusEntry that owns its path.
+/// The subcommands. Without one, the tool pushes once.
+#[derive(Debug, Subcommand)]
+enum Action {
+    /// Push the changed mark files once.
+    Push,
+    /// Print which auto files changed and what a push would do with them,
+    /// without changing anything.
+    Status,
+    /// Keep running and push marks whenever an auto file changes.
+    Watch {
+        /// How many seconds of quiet to wait for after a change before
+        /// pushing, so that a burst of writes results in one commit.
+        #[arg(long, value_name = "SECONDS", default_value_t = 2)]
+        debounce: u64,
+        /// Serve Prometheus metrics at http://ADDRESS/metrics, e.g.,
+        /// 127.0.0.1:9184.
+        #[arg(long, value_name = "ADDRESS")]
+        metrics_address: Option<SocketAddr>,
+    },
+    /// Print the configuration file in use and the settings that each
+    /// repository is processed with.
+    Config,
+    /// Check whether pushing could work, e.g., whether the remote is
+    /// reachable, without changing anything.
+    Doctor,
+    /// Check whether the auto files match the tip of the upstream branch on
+    /// the remote, without changing anything, e.g., as a monitoring probe.
+    /// Fails unless every repository is in sync.
+    Verify,
+    /// Print how the auto files differ from the tip of the upstream branch on
+    /// the remote, i.e., the lines added and removed per file and when the
+    /// remote last changed it, without changing anything.
+    Diff,
+    /// Print how many auto commits were made per day, how many files they
+    /// change on average, and how many pushes failed in a row, e.g., for
+    /// tuning how often to push.
+    Stats,
+    /// Squash the history of a branch of only auto commits, e.g., of the
+    /// marks repository, that is older than a retention window into one
+    /// snapshot commit and force-push it, e.g., from a weekly timer.
+    Compact {
+        /// How many days of history to keep as it is.
+        #[arg(long, value_name = "DAYS", default_value_t = 90)]
+        keep_days: u64,
+    },
+    /// Print a completion script for a shell, e.g., `git-auto-commit
+    /// completions bash > ~/.local/share/bash-completion/completions/git-auto-commit`.
+    Completions {
+        /// The shell.
+        shell: Shell,
+    },
+    /// Print the JSON Schema of the reports of `--output json` and
+    /// `--notify-url`, or of the configuration file, to validate them against.
+    Schema {
+        /// What to print the schema of.
+        #[arg(value_enum)]
+        kind: SchemaKind,
+    },
+    /// Merge two versions of a mark file by transaction, as a Git merge
+    /// driver, so that marks changed on two machines don't conflict. Register
+    /// it with `git config merge.wallet-marks.driver "git-auto-commit
+    /// merge-driver %O %A %B"` and `*.journal merge=wallet-marks` in
+    /// .gitattributes.
+    MergeDriver {
+        /// The common ancestor (%O).
+        base: PathBuf,
+        /// Our version (%A), which is replaced with the merge.
+        ours: PathBuf,
+        /// Their version (%B).
+        theirs: PathBuf,
+    },
+    /// Print the man page, or write the man pages of the command and its
+    /// subcommands to a directory, e.g., when packaging.
+    #[command(hide = true)]
+    GenerateMan {
+        /// The directory to write the man pages to.
+        #[arg(value_hint = ValueHint::DirPath)]
+        out_dir: Option<PathBuf>,
+    },
+}
+
+/// A machine-readable format with a versioned JSON Schema.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+enum SchemaKind {
+    /// The report of a run, see [`RunReport`].
+    RunReport,
+    /// The configuration file, see [`ConfigFile`].
+    Config,
+    /// The report of `verify`, see [`VerifyReport`].
+    VerifyReport,
+    /// The report of `diff`, see [`DiffReport`].
+    DiffReport,
+    /// The report of `stats`, see [`StatsReport`].
+    StatsReport,
+}
+
+impl SchemaKind {
+    /// Returns the schema, which is embedded from `schema/`. A new version of
+    /// a schema goes into a new directory, e.g., `schema/v2`, when a change
+    /// would break a consumer of the old one.
+    fn schema(self) -> &'static str {
+        match self {
+            SchemaKind::RunReport => include_str!("../schema/v1/run-report.json"),
+            SchemaKind::Config => include_str!("../schema/v1/config.json"),
+            SchemaKind::VerifyReport => include_str!("../schema/v1/verify-report.json"),
+            SchemaKind::DiffReport => include_str!("../schema/v1/diff-report.json"),
+            SchemaKind::StatsReport => include_str!("../schema/v1/stats-report.json"),
+        }
+    }
+}
+
+/// The content of the configuration file.
 ///
-/// Owning the path gives us a saner interface for working with the path without
-/// checking the Option every time.
-struct StatusEntryBetter {
-    pub path: PathBuf,
-    pub status: Status,
+/// Every field but `repos` has a command-line counterpart, which takes
+/// precedence. Keep `schema/v1/config.json` in sync.
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+struct ConfigFile {
+    repo: Option<PathBuf>,
+    repos: Vec<RepoConfig>,
+    auto_files: Vec<PathBuf>,
+    exclude: Vec<String>,
+    opaque: Vec<String>,
+    ignore_mode_changes: bool,
+    allow_new: bool,
+    allow_delete: bool,
+    marks_only: bool,
+    max_files: Option<usize>,
+    max_changed_lines: Option<usize>,
+    require_diff_regex: Option<String>,
+    allow_non_default_branch: bool,
+    on_sync_conflict: Option<SyncConflictPolicy>,
+    on_diverged: Option<DivergencePolicy>,
+    workspace: Option<Workspace>,
+    temp_dir: Option<PathBuf>,
+    marks_repo: Option<PathBuf>,
+    keep_temp: bool,
+    lock_timeout: Option<u64>,
+    batch_window: Option<u64>,
+    retries: Option<u32>,
+    retry_delay: Option<u64>,
+    jobs: Option<u32>,
+    remote: Option<String>,
+    branch: Option<String>,
+    mirror: Vec<String>,
+    author_name: Option<String>,
+    author_email: Option<String>,
+    message: Option<String>,
+    tag_template: Option<String>,
+    snapshots: Option<usize>,
+    maintenance_every: Option<usize>,
+    validate_cmd: Option<String>,
+    run_hooks: bool,
+    commit_strategy: Option<CommitStrategy>,
+    show_diff: bool,
+    amend: bool,
+    follow_peers: bool,
+    force_with_lease: bool,
+    sign: Option<bool>,
+    notify_url: Option<String>,
+}
+
+/// A repository in the `repos` list of the configuration file.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields, rename_all = "kebab-case")]
+struct RepoConfig {
+    path: PathBuf,
+    /// The auto files of this repository [default: the top-level auto files].
+    #[serde(default)]
+    auto_files: Vec<PathBuf>,
 }
 
-impl StatusEntryBetter {
-    fn from_status_entry(status_entry: &StatusEntry) -> Option<Self> {
-        let path: &str = status_entry.path()?;
-        Some(StatusEntryBetter {
-            path: PathBuf::from(path),
-            status: status_entry.status(),
+impl ConfigFile {
+    /// Returns the default location of the configuration file.
+    fn default_path() -> Option<PathBuf> {
+        env::var_os("XDG_CONFIG_HOME")
+            .map(PathBuf::from)
+            .or_else(|| env::var_os("HOME").map(|home| PathBuf::from(home).join(".config")))
+            .map(|config_dir| config_dir.join("push-wallet-marks").join("config.toml"))
+    }
+
+    /// Loads the configuration file.
+    ///
+    /// # Arguments
+    ///
+    /// * `path` - An explicitly requested file, which must exist. Without it,
+    ///   the default file is read if it exists.
+    fn load(path: Option<&Path>) -> Result<Self, String> {
+        let (path, required): (PathBuf, bool) = match path {
+            Some(path) => (path.to_path_buf(), true),
+            None => match Self::default_path() {
+                Some(path) => (path, false),
+                None => return Ok(ConfigFile::default()),
+            },
+        };
+        let content: String = match fs::read_to_string(&path) {
+            Ok(content) => content,
+            Err(e) if e.kind() == io::ErrorKind::NotFound && !required => {
+                return Ok(ConfigFile::default())
+            }
+            Err(e) => {
+                return Err(format!(
+                    "Could not read the configuration file {}: {}",
+                    path.display(),
+                    e
+                ))
+            }
+        };
+        toml::from_str(&content).map_err(|e| {
+            format!(
+                "Could not parse the configuration file {}:\n{}",
+                path.display(),
+                e
+            )
         })
     }
 }
 
-/// Copies the content of one directory P to another.
+/// Expands a leading `~` in a path to the home directory.
+fn expand_home(path: PathBuf) -> PathBuf {
+    let Ok(rest) = path.strip_prefix("~") else {
+        return path;
+    };
+    match env::var_os("HOME") {
+        Some(home) => PathBuf::from(home).join(rest),
+        None => path,
+    }
+}
+
+/// Fills a list option that wasn't given on the command line from an
+/// environment variable, which separates the items like `PATH`.
+///
+/// Clap reads the environment for the other options, but it would split list
+/// options given on the command line too.
+fn list_from_env<T: From<String>>(list: &mut Vec<T>, name: &str) {
+    if !list.is_empty() {
+        return;
+    }
+    if let Some(value) = env::var_os(name) {
+        list.extend(
+            env::split_paths(&value)
+                .filter(|item| !item.as_os_str().is_empty())
+                .map(|item| T::from(item.to_string_lossy().into_owned())),
+        );
+    }
+}
+
+/// Merges command-line arguments and environment variables over the
+/// configuration file.
+///
+/// # Returns
+///
+/// A request per repository. Repositories given on the command line replace
+/// the ones in the configuration file.
+fn push_requests(mut cli: Cli, config: ConfigFile) -> Result<Vec<PushRequest>, String> {
+    list_from_env(&mut cli.repo, "PUSH_WALLET_MARKS_REPO");
+    list_from_env(&mut cli.auto_files, "PUSH_WALLET_MARKS_AUTO_FILES");
+    list_from_env(&mut cli.exclude, "PUSH_WALLET_MARKS_EXCLUDE");
+    list_from_env(&mut cli.opaque, "PUSH_WALLET_MARKS_OPAQUE");
+    list_from_env(&mut cli.mirror, "PUSH_WALLET_MARKS_MIRROR");
+    let cli_auto_files: bool = !cli.auto_files.is_empty();
+    let default_auto_files: Vec<PathBuf> = if cli_auto_files {
+        cli.auto_files
+    } else {
+        config.auto_files
+    };
+    let repos: Vec<(PathBuf, Vec<PathBuf>)> = if cli.repo.is_empty() {
+        config
+            .repo
+            .map(|repo| (expand_home(repo), default_auto_files.clone()))
+            .into_iter()
+            .chain(config.repos.into_iter().map(|repo| {
+                let auto_files: Vec<PathBuf> = if cli_auto_files || repo.auto_files.is_empty() {
+                    default_auto_files.clone()
+                } else {
+                    repo.auto_files
+                };
+                (expand_home(repo.path), auto_files)
+            }))
+            .collect()
+    } else {
+        cli.repo
+            .into_iter()
+            .map(|repo| (repo, default_auto_files.clone()))
+            .collect()
+    };
+    if repos.is_empty() {
+        return Err(
+            "No repository given. Pass --repo or set `repo` or `repos` in the configuration file."
+                .to_string(),
+        );
+    }
+
+    let excludes: Vec<String> = if cli.exclude.is_empty() {
+        config.exclude
+    } else {
+        cli.exclude
+    };
+    let opaque: Vec<String> = if cli.opaque.is_empty() {
+        config.opaque
+    } else {
+        cli.opaque
+    };
+    let mirrors: Vec<String> = if cli.mirror.is_empty() {
+        config.mirror
+    } else {
+        cli.mirror
+    };
+    let remote: Option<String> = cli.remote.or(config.remote);
+    let branch: Option<String> = cli.branch.or(config.branch);
+    let author_name: Option<String> = cli.author_name.or(config.author_name);
+    let author_email: Option<String> = cli.author_email.or(config.author_email);
+    let message: Option<String> = cli.message.or(config.message);
+    let tag_template: Option<String> = cli.tag_template.or(config.tag_template);
+    let snapshots: Option<usize> = cli.snapshots.or(config.snapshots);
+    let maintenance_every: Option<usize> = cli.maintenance_every.or(config.maintenance_every);
+    let validate_cmd: Option<String> = cli.validate_cmd.or(config.validate_cmd);
+    let max_files: Option<usize> = cli.max_files.or(config.max_files);
+    let max_changed_lines: Option<usize> = cli.max_changed_lines.or(config.max_changed_lines);
+    let require_diff_regex: Option<String> = cli.require_diff_regex.or(config.require_diff_regex);
+    let sign: Option<bool> = if cli.sign {
+        Some(true)
+    } else if cli.no_sign {
+        Some(false)
+    } else {
+        config.sign
+    };
+    let on_sync_conflict: SyncConflictPolicy = cli
+        .on_sync_conflict
+        .or(config.on_sync_conflict)
+        .unwrap_or(SyncConflictPolicy::Abort);
+    let on_diverged: DivergencePolicy = cli
+        .on_diverged
+        .or(config.on_diverged)
+        .unwrap_or(DivergencePolicy::Rebase);
+    let workspace: Workspace = cli
+        .workspace
+        .or(cli.in_place.then_some(Workspace::InPlace))
+        .or(config.workspace)
+        .unwrap_or(Workspace::Copy);
+    let temp_dir: Option<PathBuf> = cli.temp_dir.or(config.temp_dir.map(expand_home));
+    let marks_repo: Option<PathBuf> = cli.marks_repo.or(config.marks_repo.map(expand_home));
+    let commit_strategy: CommitStrategy = cli
+        .commit_strategy
+        .or(config.commit_strategy)
+        .unwrap_or(CommitStrategy::Combined);
+    let lock_timeout = Duration::from_secs(cli.lock_timeout.or(config.lock_timeout).unwrap_or(0));
+    let batch_window = Duration::from_secs(cli.batch_window.or(config.batch_window).unwrap_or(0));
+    let retries: u32 = cli.retries.or(config.retries).unwrap_or(0);
+    let retry_delay = Duration::from_secs(cli.retry_delay.or(config.retry_delay).unwrap_or(5));
+    Ok(repos
+        .into_iter()
+        .map(|(repo, auto_files)| {
+            let mut request = PushRequest::new(repo)
+                .auto_files(auto_files)
+                .excludes(excludes.clone())
+                .opaques(opaque.clone())
+                .mirrors(mirrors.clone())
+                .ignore_mode_changes(cli.ignore_mode_changes || config.ignore_mode_changes)
+                .allow_new(cli.allow_new || config.allow_new)
+                .allow_delete(cli.allow_delete || config.allow_delete)
+                .marks_only(cli.marks_only || config.marks_only)
+                .allow_non_default_branch(
+                    cli.allow_non_default_branch || config.allow_non_default_branch,
+                )
+                .on_sync_conflict(on_sync_conflict)
+                .on_diverged(on_diverged)
+                .workspace(workspace)
+                .keep_temp(cli.keep_temp || config.keep_temp)
+                .ignore_state(cli.ignore_state)
+                .lock_timeout(lock_timeout)
+                .batch_window(batch_window)
+                .retries(retries)
+                .retry_delay(retry_delay)
+                .run_hooks((cli.run_hooks || config.run_hooks) && !cli.no_verify)
+                .commit_strategy(commit_strategy)
+                .show_diff(cli.show_diff || config.show_diff)
+                .amend(cli.amend || config.amend)
+                .follow_peers(cli.follow_peers || config.follow_peers)
+                .force_with_lease(cli.force_with_lease || config.force_with_lease)
+                .dry_run(cli.dry_run);
+            if let Some(temp_dir) = &temp_dir {
+                request = request.temp_dir(temp_dir.clone());
+            }
+            if let Some(marks_repo) = &marks_repo {
+                request = request.marks_repo(marks_repo.clone());
+            }
+            if let Some(remote) = &remote {
+                request = request.remote(remote.clone());
+            }
+            if let Some(branch) = &branch {
+                request = request.branch(branch.clone());
+            }
+            if let Some(author_name) = &author_name {
+                request = request.author_name(author_name.clone());
+            }
+            if let Some(author_email) = &author_email {
+                request = request.author_email(author_email.clone());
+            }
+            if let Some(message) = &message {
+                request = request.message(message.clone());
+            }
+            if let Some(tag_template) = &tag_template {
+                request = request.tag_template(tag_template.clone());
+            }
+            if let Some(snapshots) = snapshots {
+                request = request.snapshots(snapshots);
+            }
+            if let Some(maintenance_every) = maintenance_every {
+                request = request.maintenance_every(maintenance_every);
+            }
+            if let Some(validate_cmd) = &validate_cmd {
+                request = request.validate_cmd(validate_cmd.clone());
+            }
+            if let Some(sign) = sign {
+                request = request.sign(sign);
+            }
+            if let Some(max_files) = max_files {
+                request = request.max_files(max_files);
+            }
+            if let Some(max_changed_lines) = max_changed_lines {
+                request = request.max_changed_lines(max_changed_lines);
+            }
+            if let Some(require_diff_regex) = &require_diff_regex {
+                request = request.require_diff_regex(require_diff_regex.clone());
+            }
+            if cli.interactive {
+                request = request.confirmer(TerminalConfirmer);
+            }
+            request
+        })
+        .collect())
+}
+
+/// Asks on the terminal whether to push.
+#[derive(Debug)]
+struct TerminalConfirmer;
+
+impl Confirmer for TerminalConfirmer {
+    fn confirm(&self, files: &[PathBuf]) -> Result<bool, String> {
+        let stdin = io::stdin();
+        if !stdin.is_terminal() {
+            return Err("--interactive needs a terminal to ask on.".to_string());
+        }
+        eprintln!("Changed mark files:");
+        for file in files {
+            eprintln!("  {}", file.display());
+        }
+        loop {
+            eprint!("Commit and push them? [y/n] ");
+            let mut answer = String::new();
+            let read: usize = stdin
+                .lock()
+                .read_line(&mut answer)
+                .map_err(|e| format!("Could not read the answer: {}", e))?;
+            match answer.trim().to_lowercase().as_str() {
+                // The end of input declines.
+                _ if read == 0 => return Ok(false),
+                "y" | "yes" => return Ok(true),
+                "n" | "no" => return Ok(false),
+                _ => {}
+            }
+        }
+    }
+}
+
+/// How to report the outcome of a run.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+enum OutputFormat {
+    /// Human-readable log messages only.
+    Text,
+    /// A JSON object per run on stdout, in addition to the log messages on
+    /// stderr.
+    Json,
+}
+
+/// How to report the outcome of runs.
+#[derive(Clone, Debug)]
+struct Reporting {
+    /// Whether to print the timings of each run.
+    print_timings: bool,
+    /// How to report the outcome on stdout.
+    output: OutputFormat,
+    /// Whether to show desktop notifications.
+    #[cfg(feature = "desktop-notifications")]
+    notify: bool,
+    /// The webhook to post the report of each run to.
+    notify_url: Option<String>,
+}
+
+/// How many repositories are pushed at the same time without --jobs.
+const DEFAULT_JOBS: u32 = 4;
+
+/// How long to wait for a webhook to respond.
+const WEBHOOK_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// The body of a webhook request: the run report and its status.
+#[derive(Debug, Serialize)]
+struct WebhookPayload<'a> {
+    /// `pushed`, `committed`, `skipped`, `dry-run`, or `failed`.
+    status: &'static str,
+    #[serde(flatten)]
+    report: &'a RunReport,
+}
+
+/// The outcome of a run, as reported by `--output json`. Keep
+/// `schema/v1/run-report.json` in sync.
+#[derive(Debug, Default, Serialize)]
+struct RunReport {
+    /// The repository, unless the run failed before one was known.
+    repo: Option<PathBuf>,
+    /// The mark files that were staged for the auto commit.
+    staged_files: Vec<PathBuf>,
+    /// The diff of the mark files, with `--show-diff`.
+    diff: Option<String>,
+    /// The ID of the auto commit, which wasn't pushed if `pushed` is unset.
+    commit: Option<String>,
+    /// Whether the auto commit was rebased onto a moved upstream.
+    rebased: bool,
+    /// Whether the auto commit replaced an unpushed one.
+    amended: bool,
+    /// Whether the auto commit was made on the upstream instead of local
+    /// commits that diverged from it.
+    reset: bool,
+    /// Where the auto commit was pushed.
+    pushed: Option<PushedRef>,
+    /// The pushes to mirrors after the upstream.
+    mirrors: Vec<MirrorReport>,
+    /// The tag that was pushed with the auto commit.
+    tag: Option<String>,
+    /// Why the original repository wasn't updated after the push, if it
+    /// wasn't.
+    sync_error: Option<String>,
+    /// Why the run didn't push anything, e.g., `unchanged` or `rebase`.
+    skip_reason: Option<&'static str>,
+    /// Whether this was a dry run.
+    dry_run: bool,
+    /// The error that stopped the run.
+    error: Option<String>,
+}
+
+/// The result of `verify` for a repository, as reported by `--output json`.
+/// Keep `schema/v1/verify-report.json` in sync.
+#[derive(Debug, Serialize)]
+struct VerifyReport {
+    repo: PathBuf,
+    /// `in-sync`, `out-of-sync`, `no-remote-branch`, or `failed`.
+    status: &'static str,
+    /// The remote tip that the auto files were compared with.
+    commit: Option<String>,
+    /// The auto files that differ from the remote tip.
+    files: Vec<PathBuf>,
+    /// Why the verification failed.
+    error: Option<String>,
+}
+
+/// The result of `diff` for a repository, as reported by `--output json`.
+/// Keep `schema/v1/diff-report.json` in sync.
+#[derive(Debug, Serialize)]
+struct DiffReport {
+    repo: PathBuf,
+    /// The remote tip that the auto files were compared with, or `None` if
+    /// the remote doesn't have the upstream branch.
+    commit: Option<String>,
+    /// The auto files that differ from the remote tip.
+    files: Vec<FileDiffReport>,
+    /// Why the comparison failed.
+    error: Option<String>,
+}
+
+/// How an auto file differs from the remote tip.
+#[derive(Debug, Serialize)]
+struct FileDiffReport {
+    path: PathBuf,
+    added: usize,
+    removed: usize,
+    last_pushed: Option<String>,
+}
+
+/// The result of `stats` for a repository, as reported by `--output json`.
+/// Keep `schema/v1/stats-report.json` in sync.
+#[derive(Debug, Serialize)]
+struct StatsReport {
+    repo: PathBuf,
+    commits: usize,
+    /// The number of auto commits per date.
+    commits_per_day: BTreeMap<String, usize>,
+    average_files: f64,
+    longest_failure_streak: usize,
+    current_failure_streak: usize,
+    /// Why the analysis failed.
+    error: Option<String>,
+}
+
+/// A branch that an auto commit was pushed to.
+#[derive(Debug, Serialize)]
+struct PushedRef {
+    remote: String,
+    branch: String,
+}
+
+/// The push of an auto commit to a mirror.
+#[derive(Debug, Serialize)]
+struct MirrorReport {
+    remote: String,
+    /// Why the push failed, if it did.
+    error: Option<String>,
+}
+
+impl RunReport {
+    /// Records the outcome of a run.
+    fn record(&mut self, outcome: PushOutcome) {
+        match outcome {
+            PushOutcome::Skipped(reason) => self.skip_reason = Some(reason.code()),
+            PushOutcome::DryRun {
+                staged_files, diff, ..
+            } => {
+                self.staged_files = staged_files;
+                self.diff = diff;
+            }
+            PushOutcome::Pushed {
+                staged_files,
+                diff,
+                commit,
+                rebased,
+                amended,
+                reset,
+                remote,
+                branch,
+                mirrors,
+                tag,
+                sync_error,
+            } => {
+                self.staged_files = staged_files;
+                self.diff = diff;
+                self.commit = Some(commit.to_string());
+                self.rebased = rebased;
+                self.amended = amended;
+                self.reset = reset;
+                self.pushed = Some(PushedRef { remote, branch });
+                self.tag = tag;
+                self.sync_error = sync_error;
+                self.mirrors = mirrors
+                    .into_iter()
+                    .map(|mirror| MirrorReport {
+                        remote: mirror.remote,
+                        error: mirror.result.err(),
+                    })
+                    .collect();
+            }
+            PushOutcome::Committed {
+                staged_files,
+                diff,
+                commit,
+                rebased,
+                amended,
+                reset,
+            } => {
+                self.staged_files = staged_files;
+                self.diff = diff;
+                self.commit = Some(commit.to_string());
+                self.rebased = rebased;
+                self.amended = amended;
+                self.reset = reset;
+            }
+        }
+    }
+
+    /// Summarizes the report in a line of text.
+    fn summary(&self) -> String {
+        if let Some(error) = &self.error {
+            format!("failed: {}", error.lines().next().unwrap_or_default())
+        } else if let (Some(commit), Some(pushed)) = (&self.commit, &self.pushed) {
+            let failed_mirrors: Vec<&str> = self
+                .mirrors
+                .iter()
+                .filter(|mirror| mirror.error.is_some())
+                .map(|mirror| mirror.remote.as_str())
+                .collect();
+            let mut caveats = Vec::new();
+            if !failed_mirrors.is_empty() {
+                caveats.push(format!("not to {}", failed_mirrors.join(", ")));
+            }
+            if self.sync_error.is_some() {
+                caveats.push("the original repository wasn't updated".to_string());
+            }
+            if caveats.is_empty() {
+                format!("pushed {} to {} {}", commit, pushed.remote, pushed.branch)
+            } else {
+                format!(
+                    "pushed {} to {} {}, but {}",
+                    commit,
+                    pushed.remote,
+                    pushed.branch,
+                    caveats.join(", and ")
+                )
+            }
+        } else if let Some(commit) = &self.commit {
+            format!("committed {} without pushing it", commit)
+        } else if let Some(reason) = self.skip_reason {
+            format!("skipped ({})", reason)
+        } else {
+            format!("would push {} mark file(s)", self.staged_files.len())
+        }
+    }
+
+    /// Returns whether the run was aborted for a reason that needs the user's
+    /// attention, e.g., an ongoing rebase, as opposed to having nothing to do.
+    fn is_aborted(&self) -> bool {
+        self.skip_reason.is_some_and(|reason| {
+            !matches!(
+                reason,
+                "unchanged" | "no-changes" | "another-run-in-progress" | "declined"
+            )
+        })
+    }
+
+    /// Shows the report as a desktop notification if marks were committed or
+    /// the run was aborted or failed.
+    #[cfg(feature = "desktop-notifications")]
+    fn notify(&self) {
+        if self.commit.is_none() && self.error.is_none() && !self.is_aborted() {
+            return;
+        }
+        let repo: String = self
+            .repo
+            .as_deref()
+            .map(|repo| repo.display().to_string())
+            .unwrap_or_else(|| "git-auto-commit".to_string());
+        let result = notify_rust::Notification::new()
+            .appname("git-auto-commit")
+            .summary(&repo)
+            .body(&self.summary())
+            .show();
+        if let Err(e) = result {
+            warn!("Could not show a desktop notification: {}", e);
+        }
+    }
+
+    /// Returns the status of the run, e.g., `pushed` or `failed`.
+    fn status(&self) -> &'static str {
+        if self.error.is_some() {
+            "failed"
+        } else if self.pushed.is_some() {
+            "pushed"
+        } else if self.commit.is_some() {
+            "committed"
+        } else if self.skip_reason.is_some() {
+            "skipped"
+        } else {
+            "dry-run"
+        }
+    }
+
+    /// Posts the report as JSON to a webhook. A failure is only logged, so
+    /// that it doesn't affect the outcome of the run.
+    fn post(&self, url: &str) {
+        let payload = WebhookPayload {
+            status: self.status(),
+            report: self,
+        };
+        let body: String = match serde_json::to_string(&payload) {
+            Ok(body) => body,
+            Err(e) => {
+                error!("Could not serialize the run report: {}", e);
+                return;
+            }
+        };
+        let result = ureq::post(url)
+            .config()
+            .timeout_global(Some(WEBHOOK_TIMEOUT))
+            .build()
+            .content_type("application/json")
+            .send(body);
+        if let Err(e) = result {
+            warn!("Could not post the run report to {}: {}", url, e);
+        }
+    }
+
+    /// Prints the report as a single line of JSON.
+    fn print_json(&self) {
+        match serde_json::to_string(self) {
+            Ok(json) => println!("{}", json),
+            Err(e) => error!("Could not serialize the run report: {}", e),
+        }
+    }
+}
+
+/// Pushes the mark files of a repository and reports the outcome.
 ///
 /// # Arguments
 ///
-/// * `from` - The source directory
-/// * `to` - The target directory.
-fn copy_content<P, Q>(from: P, to: Q) -> Result<(), fs_extra::error::Error>
-where
-    P: AsRef<Path>,
-    Q: AsRef<Path>,
-{
-    let options = fs_extra::dir::CopyOptions::new().content_only(true);
-    fs_extra::dir::copy(from, to, &options).map(|_| ())
+/// * `request` - What to push.
+/// * `reporting` - How to report the outcome.
+fn run_request(request: &PushRequest, reporting: &Reporting) -> RunReport {
+    let mut timings = Timings::default();
+    let outcome: Result<PushOutcome, String> = request.push_timed(&mut timings);
+    report_run(request, outcome, &timings, reporting)
 }
 
-/// Copies a repository from the given path to a temporary directory.
+/// Reports the outcome of a run.
 ///
 /// # Arguments
 ///
-/// * `repo_path` — The original repository path.
+/// * `request` - What was pushed.
+/// * `outcome` - The outcome of the run.
+/// * `timings` - How long the phases of the run took.
+/// * `reporting` - How to report the outcome.
+fn report_run(
+    request: &PushRequest,
+    outcome: Result<PushOutcome, String>,
+    timings: &Timings,
+    reporting: &Reporting,
+) -> RunReport {
+    let mut report = RunReport {
+        repo: Some(request.repo().to_path_buf()),
+        dry_run: request.is_dry_run(),
+        ..RunReport::default()
+    };
+    match outcome {
+        Ok(outcome) => report.record(outcome),
+        Err(e) => {
+            error!("{}", e);
+            report.error = Some(e);
+        }
+    }
+    if reporting.print_timings {
+        eprintln!("{}", timings);
+    }
+    if reporting.output == OutputFormat::Json {
+        report.print_json();
+    }
+    #[cfg(feature = "desktop-notifications")]
+    if reporting.notify {
+        report.notify();
+    }
+    if let Some(url) = &reporting.notify_url {
+        report.post(url);
+    }
+    report
+}
+
+/// What the watcher has done so far, per repository, for `--metrics-address`.
+#[derive(Debug, Default)]
+struct Metrics {
+    repos: BTreeMap<PathBuf, RepoMetrics>,
+}
+
+/// What the watcher has done so far in a repository.
+#[derive(Debug, Default)]
+struct RepoMetrics {
+    /// How many runs pushed an auto commit.
+    pushes: u64,
+    /// How many runs failed.
+    failures: u64,
+    /// When a run last ended without failing or being aborted.
+    last_success: Option<SystemTime>,
+    /// How many changed auto files are waiting for a push.
+    pending_files: usize,
+}
+
+impl Metrics {
+    /// Records a run.
+    ///
+    /// # Arguments
+    ///
+    /// * `request` - What the run pushed.
+    /// * `report` - The outcome of the run.
+    fn record(&mut self, request: &PushRequest, report: &RunReport) {
+        let repo: &mut RepoMetrics = self.repos.entry(request.repo().to_path_buf()).or_default();
+        if report.pushed.is_some() {
+            repo.pushes += 1;
+        }
+        if report.error.is_some() {
+            repo.failures += 1;
+        } else if !report.is_aborted() {
+            repo.last_success = Some(SystemTime::now());
+        }
+        match request.status() {
+            Ok(status) => {
+                repo.pending_files = status
+                    .files
+                    .iter()
+                    .filter(|file| !matches!(file.action, FileAction::Ignore(_)))
+                    .count();
+            }
+            Err(e) => warn!("Could not count the pending auto files: {}", e),
+        }
+    }
+
+    /// Renders the metrics in the Prometheus text format.
+    fn render(&self) -> String {
+        let mut text = String::new();
+        let mut family =
+            |name: &str, kind: &str, help: &str, value: &dyn Fn(&RepoMetrics) -> Option<f64>| {
+                text.push_str(&format!(
+                    "# HELP {} {}\n# TYPE {} {}\n",
+                    name, help, name, kind
+                ));
+                for (repo, metrics) in &self.repos {
+                    if let Some(value) = value(metrics) {
+                        let repo: String = repo
+                            .display()
+                            .to_string()
+                            .replace('\\', "\\\\")
+                            .replace('"', "\\\"")
+                            .replace('\n', "\\n");
+                        text.push_str(&format!("{}{{repo=\"{}\"}} {}\n", name, repo, value));
+                    }
+                }
+            };
+        family(
+            "git_auto_commit_pushes_total",
+            "counter",
+            "Runs that pushed an auto commit.",
+            &|metrics| Some(metrics.pushes as f64),
+        );
+        family(
+            "git_auto_commit_failures_total",
+            "counter",
+            "Runs that failed.",
+            &|metrics| Some(metrics.failures as f64),
+        );
+        family(
+            "git_auto_commit_last_success_timestamp_seconds",
+            "gauge",
+            "When a run last ended without failing or being aborted.",
+            &|metrics| {
+                metrics
+                    .last_success
+                    .and_then(|time| time.duration_since(UNIX_EPOCH).ok())
+                    .map(|time| time.as_secs_f64())
+            },
+        );
+        family(
+            "git_auto_commit_pending_files",
+            "gauge",
+            "Changed auto files that are waiting for a push.",
+            &|metrics| Some(metrics.pending_files as f64),
+        );
+        text
+    }
+}
+
+/// Serves the metrics over HTTP in a background thread.
 ///
-/// # Returns
+/// # Arguments
 ///
-/// A temporary directory with the copied repository.
-fn copy_repository<P>(repo_path: P) -> Result<tempfile::TempDir, String>
-where
-    P: AsRef<Path>,
-{
-    let temp_dir: tempfile::TempDir = tempdir().map_err(|io_err| {
-        format!(
-            "Could not create a temporary directory:\n{}",
-            io_err.to_string()
-        )
-    })?;
-    println!("Created a temporary directory at {:?}", temp_dir.path());
-    copy_content(repo_path.as_ref(), temp_dir.path()).map_err(|fs_err| {
-        format!(
-            "Could not copy the repository {} to {}:\n{}",
-            repo_path.as_ref().display(),
-            temp_dir.path().display(),
-            fs_err.to_string()
-        )
-    })?;
-    println!(
-        "Copied the repo at {} to the temporary directory.",
-        repo_path.as_ref().display()
-    );
-    return Ok(temp_dir);
-}
-
-fn is_index_status(s: &Status) -> bool {
-    let index_status: Status = Status::INDEX_NEW
-        | Status::INDEX_DELETED
-        | Status::INDEX_MODIFIED
-        | Status::INDEX_RENAMED
-        | Status::INDEX_TYPECHANGE;
-    s.intersects(index_status)
-}
-
-fn is_index_empty(statuses: &Statuses) -> Result<bool, String> {
-    for status in statuses.into_iter() {
-        if is_index_status(&status.status()) {
-            return Ok(false);
-        }
-    }
-    return Ok(true);
-}
-
-fn filter_statuses_by_path<'a, P>(statuses: &'a Statuses<'a>, paths: &[P]) -> Vec<StatusEntry<'a>>
-where
-    P: AsRef<Path>,
-{
-    let path_strings: HashSet<String> = paths
-        .iter()
-        .filter_map(|p| p.as_ref().to_str())
-        .map(|s| s.to_string())
-        .collect();
+/// * `address` - The address to listen on.
+/// * `metrics` - The metrics to serve at `/metrics`.
+fn serve_metrics(address: SocketAddr, metrics: Arc<Mutex<Metrics>>) -> Result<(), String> {
+    let listener = TcpListener::bind(address)
+        .map_err(|e| format!("Could not serve the metrics on {}: {}", address, e))?;
+    info!("Serving metrics at http://{}/metrics.", address);
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            let result: io::Result<()> =
+                stream.and_then(|stream| answer_metrics_request(stream, &metrics));
+            if let Err(e) = result {
+                warn!("Could not answer a metrics request: {}", e);
+            }
+        }
+    });
+    Ok(())
+}
 
-    statuses
-        .into_iter()
-        .filter(|status_entry: &StatusEntry| -> bool {
-            path_strings.contains(status_entry.path().unwrap_or(""))
+/// Answers an HTTP request with the metrics, or 404 for a path other than
+/// `/metrics`.
+fn answer_metrics_request(mut stream: TcpStream, metrics: &Mutex<Metrics>) -> io::Result<()> {
+    stream.set_read_timeout(Some(Duration::from_secs(5)))?;
+    let mut reader = BufReader::new(&stream);
+    let mut request_line = String::new();
+    reader.read_line(&mut request_line)?;
+    // Read the headers, so that closing the connection doesn't reset it.
+    let mut header = String::new();
+    while reader.read_line(&mut header)? > 0 && !header.trim().is_empty() {
+        header.clear();
+    }
+    let (status, body): (&str, String) = match request_line.split_whitespace().nth(1) {
+        Some("/metrics") => (
+            "200 OK",
+            metrics
+                .lock()
+                .unwrap_or_else(PoisonError::into_inner)
+                .render(),
+        ),
+        _ => ("404 Not Found", "Not found. Try /metrics.\n".to_string()),
+    };
+    write!(
+        stream,
+        "HTTP/1.1 {}\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        body.len(),
+        body
+    )
+}
+
+/// Runs whenever an auto file changes, until the watcher fails, and reports
+/// each run of the [`SyncService`].
+///
+/// # Arguments
+///
+/// * `requests` - What each run pushes, per repository.
+/// * `debounce` - How long to wait for further changes before running.
+/// * `metrics_address` - Where to serve metrics, if at all.
+/// * `reporting` - How to report the outcome of each run.
+fn watch(
+    requests: &[PushRequest],
+    debounce: Duration,
+    metrics_address: Option<SocketAddr>,
+    reporting: &Reporting,
+) -> Result<(), String> {
+    let metrics: Option<Arc<Mutex<Metrics>>> = match metrics_address {
+        Some(address) => {
+            let metrics: Arc<Mutex<Metrics>> = Arc::default();
+            serve_metrics(address, Arc::clone(&metrics))?;
+            Some(metrics)
+        }
+        None => None,
+    };
+    let mut service = SyncService::new(requests.to_vec()).debounce(debounce);
+    let events = service.subscribe();
+    service.start()?;
+    for event in events {
+        match event {
+            SyncEvent::Ran {
+                request,
+                outcome,
+                timings,
+            } => {
+                let request: &PushRequest = &requests[request];
+                let report: RunReport = report_run(request, outcome, &timings, reporting);
+                if let Some(metrics) = &metrics {
+                    metrics
+                        .lock()
+                        .unwrap_or_else(PoisonError::into_inner)
+                        .record(request, &report);
+                }
+            }
+            SyncEvent::Failed(e) => return Err(e),
+        }
+    }
+    Err("The sync service stopped unexpectedly.".to_string())
+}
+
+/// Sets up logging to stderr.
+///
+/// Informational messages are printed as they are, the others get their level
+/// as a prefix. `RUST_LOG` overrides the level.
+///
+/// # Arguments
+///
+/// * `verbose` - How many times -v was given.
+/// * `quiet` - How many times -q was given.
+fn init_logging(verbose: u8, quiet: u8) {
+    let level: LevelFilter = match (verbose, quiet) {
+        (0, 0) => LevelFilter::Info,
+        (1, _) => LevelFilter::Debug,
+        (_, 0) => LevelFilter::Trace,
+        (_, 1) => LevelFilter::Warn,
+        (_, _) => LevelFilter::Error,
+    };
+    env_logger::Builder::new()
+        .filter_level(level)
+        .parse_default_env()
+        .format(|buf, record| match record.level() {
+            log::Level::Info => writeln!(buf, "{}", record.args()),
+            log::Level::Warn => writeln!(buf, "Warning: {}", record.args()),
+            log::Level::Error => writeln!(buf, "Error: {}", record.args()),
+            level => writeln!(buf, "[{}] {}", level, record.args()),
         })
-        .collect()
+        .init();
 }
 
-fn is_repo_path(repo_path: &Path) -> bool {
-    match Repository::open(repo_path) {
-        Ok(_) => true,
-        Err(_) => false,
+fn main() -> ExitCode {
+    let mut cli = Cli::parse();
+    init_logging(cli.verbose, cli.quiet);
+    let mut reporting = Reporting {
+        print_timings: cli.timings,
+        output: cli.output,
+        #[cfg(feature = "desktop-notifications")]
+        notify: cli.notify,
+        notify_url: cli.notify_url.take(),
+    };
+    let output: OutputFormat = cli.output;
+    let mut jobs: Option<u32> = if cli.interactive { Some(1) } else { cli.jobs };
+    let command: Action = cli.command.take().unwrap_or(Action::Push);
+    // Completions, schemas, merges, and man pages don't need a repository or
+    // the configuration file.
+    match &command {
+        Action::Completions { shell } => {
+            clap_complete::generate(*shell, &mut Cli::command(), BIN_NAME, &mut io::stdout());
+            return ExitCode::SUCCESS;
+        }
+        Action::Schema { kind } => {
+            print!("{}", kind.schema());
+            return ExitCode::SUCCESS;
+        }
+        Action::MergeDriver { base, ours, theirs } => {
+            return match merge_driver(base, ours, theirs) {
+                Ok(()) => ExitCode::SUCCESS,
+                Err(e) => {
+                    error!("{}", e);
+                    ExitCode::FAILURE
+                }
+            };
+        }
+        Action::GenerateMan { out_dir } => {
+            let written: io::Result<()> = match out_dir {
+                Some(out_dir) => clap_mangen::generate_to(Cli::command(), out_dir),
+                None => clap_mangen::Man::new(Cli::command()).render(&mut io::stdout()),
+            };
+            return match written {
+                Ok(()) => ExitCode::SUCCESS,
+                Err(e) => {
+                    error!("Could not write the man page: {}", e);
+                    ExitCode::FAILURE
+                }
+            };
+        }
+        _ => {}
+    }
+    let config_path: Option<PathBuf> = cli.config.clone().or_else(ConfigFile::default_path);
+
+    // Returning errors from main would print them with Debug, which escapes
+    // newlines and combining characters, e.g., in decomposed umlauts.
+    let requests: Vec<PushRequest> =
+        match ConfigFile::load(cli.config.as_deref()).and_then(|config| {
+            if reporting.notify_url.is_none() {
+                reporting.notify_url = config.notify_url.clone();
+            }
+            jobs = jobs.or(config.jobs);
+            push_requests(cli, config)
+        }) {
+            Ok(requests) => requests,
+            Err(e) => {
+                error!("{}", e);
+                if output == OutputFormat::Json {
+                    RunReport {
+                        error: Some(e),
+                        ..RunReport::default()
+                    }
+                    .print_json();
+                }
+                return ExitCode::FAILURE;
+            }
+        };
+
+    match command {
+        Action::Push => push(
+            &requests,
+            &reporting,
+            jobs.unwrap_or(DEFAULT_JOBS).max(1) as usize,
+        ),
+        Action::Watch {
+            debounce,
+            metrics_address,
+        } => {
+            match watch(
+                &requests,
+                Duration::from_secs(debounce),
+                metrics_address,
+                &reporting,
+            ) {
+                Ok(()) => ExitCode::SUCCESS,
+                Err(e) => {
+                    error!("{}", e);
+                    ExitCode::FAILURE
+                }
+            }
+        }
+        Action::Config => {
+            print_config(config_path.as_deref(), &requests);
+            ExitCode::SUCCESS
+        }
+        Action::Status => status(&requests),
+        Action::Doctor => doctor(&requests),
+        Action::Verify => verify(&requests, output),
+        Action::Diff => diff(&requests, output),
+        Action::Stats => stats(&requests, output),
+        Action::Compact { keep_days } => {
+            compact(&requests, Duration::from_secs(keep_days * 24 * 60 * 60))
+        }
+        Action::Completions { .. }
+        | Action::Schema { .. }
+        | Action::MergeDriver { .. }
+        | Action::GenerateMan { .. } => {
+            unreachable!(
+                "Completions, schemas, merges, and man pages are handled before loading the configuration."
+            )
+        }
     }
 }
 
-/// Stages and pushes mark files in the wallet repository upstream.
+/// Merges the versions of a mark file that Git passes to a merge driver, see
+/// [`git_auto_commit::merge_mark_files`].
 ///
 /// # Arguments
 ///
-/// * `repo_path` - The wallet repository path.
-/// * `mark_files` - The mark files to potentially push.
-fn push_wallet_marks<P, A>(repo_path: P, auto_files: &[A]) -> Result<(), String>
-where
-    P: AsRef<Path>,
-    A: AsRef<Path>,
-{
-    let repo = Repository::open(repo_path.as_ref()).map_err(|e| {
-        format!(
-            "Failed to open a repository, {}: {}",
-            repo_path.as_ref().display(),
-            e
-        )
-    })?;
-
-    let statuses: Statuses = repo
-        .statuses(None)
-        .map_err(|e| format!("Could not fetch file statuses: {}", e))?;
-
-    let mut index: Index = repo
-        .index()
-        .map_err(|e| format!("Could not fetch the index: {}", e))?;
-
-    if !is_index_empty(&statuses)? {
-        println!("The repository’s index is not empty. There’s possibly a manual change ongoing so we’re aborting the push.");
-        return Ok(());
-    }
-
-    let mark_file_statuses: Vec<StatusEntry> = filter_statuses_by_path(&statuses, auto_files);
-    let mark_file_statuses: Vec<StatusEntryBetter> = mark_file_statuses
-        .iter()
-        .map(StatusEntryBetter::from_status_entry)
-        .collect::<Option<Vec<StatusEntryBetter>>>()
-        .map_or(Err("Could not convert all mark files to a path."), Ok)?;
-
-    if mark_file_statuses.is_empty() {
-        println!("No mark files to push.");
-        return Ok(());
-    }
-
-    for mark_file_status in &mark_file_statuses {
-        if mark_file_status.status == Status::WT_MODIFIED {
-            index
-                .add_path(mark_file_status.path.as_path())
-                .map_err(|e| {
-                    format!(
-                        "Could not add {} to the index: {}",
-                        mark_file_status.path.display(),
-                        e
-                    )
-                })?;
-        } else {
-            return Err(format!(
-                "The mark file {} has an unexpected status: {:?}.",
-                mark_file_status.path.display(),
-                mark_file_status.status
-            ));
+/// * `base` - The common ancestor.
+/// * `ours` - Our version, which is replaced with the merge.
+/// * `theirs` - Their version.
+fn merge_driver(base: &Path, ours: &Path, theirs: &Path) -> Result<(), String> {
+    let read = |path: &Path| {
+        fs::read_to_string(path).map_err(|e| format!("Could not read {}: {}", path.display(), e))
+    };
+    let merged: String =
+        git_auto_commit::merge_mark_files(&read(base)?, &read(ours)?, &read(theirs)?);
+    fs::write(ours, merged).map_err(|e| format!("Could not write {}: {}", ours.display(), e))
+}
+
+/// Prints where the configuration comes from and the resulting requests.
+///
+/// # Arguments
+///
+/// * `config_path` - The configuration file that was looked for.
+/// * `requests` - The requests merged from it and the command line.
+fn print_config(config_path: Option<&Path>, requests: &[PushRequest]) {
+    match config_path {
+        Some(path) if path.is_file() => println!("Configuration file: {}", path.display()),
+        Some(path) => println!("Configuration file: {} (not found)", path.display()),
+        None => println!("Configuration file: none"),
+    }
+    for request in requests {
+        println!("{:#?}", request);
+    }
+}
+
+/// Describes a Git status in a word, e.g., `modified`.
+fn describe_status(status: Status) -> &'static str {
+    if status.intersects(Status::WT_RENAMED | Status::INDEX_RENAMED) {
+        "renamed"
+    } else if status.intersects(Status::WT_NEW | Status::INDEX_NEW) {
+        "new"
+    } else if status.intersects(Status::WT_DELETED | Status::INDEX_DELETED) {
+        "deleted"
+    } else if status.intersects(Status::WT_MODIFIED | Status::INDEX_MODIFIED) {
+        "modified"
+    } else if status.intersects(Status::WT_TYPECHANGE | Status::INDEX_TYPECHANGE) {
+        "typechange"
+    } else if status.is_conflicted() {
+        "conflicted"
+    } else {
+        "changed"
+    }
+}
+
+/// Prints the changed auto files of each request and what a push would do.
+///
+/// # Returns
+///
+/// Failure if the status of a repository couldn't be determined.
+fn status(requests: &[PushRequest]) -> ExitCode {
+    let mut failed = false;
+    for request in requests {
+        println!("{}:", request.repo().display());
+        let push_status = match request.status() {
+            Ok(push_status) => push_status,
+            Err(e) => {
+                failed = true;
+                error!("{}", e);
+                continue;
+            }
+        };
+        for problem in &push_status.problems {
+            println!("  {}", problem);
+        }
+        for file in &push_status.files {
+            let action: String = match &file.action {
+                FileAction::Commit => "commit".to_string(),
+                FileAction::Ignore(reason) => format!("ignore ({})", reason),
+                FileAction::Abort(reason) => format!("abort ({})", reason),
+            };
+            println!(
+                "  {:<10} {}: {}",
+                describe_status(file.status),
+                file.path.display(),
+                action
+            );
+        }
+        let aborts: bool = push_status
+            .files
+            .iter()
+            .any(|file| matches!(file.action, FileAction::Abort(_)));
+        match push_status.skip_reason {
+            Some(reason) => println!("  A push would skip this repository ({}).", reason.code()),
+            None if aborts => println!("  A push would fail."),
+            None => {
+                let count: usize = push_status
+                    .files
+                    .iter()
+                    .filter(|file| file.action == FileAction::Commit)
+                    .count();
+                println!("  A push would commit {} mark file(s).", count);
+            }
+        }
+    }
+    if failed {
+        ExitCode::FAILURE
+    } else {
+        ExitCode::SUCCESS
+    }
+}
+
+/// Runs the checks of each request and prints their results.
+///
+/// # Returns
+///
+/// Failure if any check failed.
+fn doctor(requests: &[PushRequest]) -> ExitCode {
+    let mut failed = false;
+    for request in requests {
+        println!("{}:", request.repo().display());
+        for check in request.doctor() {
+            match &check.result {
+                Ok(found) => println!("  ok    {}: {}", check.name, found),
+                Err(e) => {
+                    failed = true;
+                    println!("  FAIL  {}: {}", check.name, e);
+                }
+            }
         }
     }
-    // NOTE: Let’s see.
+    if failed {
+        ExitCode::FAILURE
+    } else {
+        ExitCode::SUCCESS
+    }
+}
 
-    // TODO: If we commit & push, what happens to the original repository?
-    // Ideally, I shouldn’t have to pull and resolve conflicts manually.
+/// Compares the auto files of each repository with the remote tip.
+///
+/// # Arguments
+///
+/// * `requests` - The repositories to verify.
+/// * `output` - Whether to print a JSON object per repository instead of text.
+///
+/// # Returns
+///
+/// Failure unless every repository is in sync.
+fn verify(requests: &[PushRequest], output: OutputFormat) -> ExitCode {
+    let mut in_sync = true;
+    for request in requests {
+        let verification: Result<Verification, String> = request.verify();
+        let mut report = VerifyReport {
+            repo: request.repo().to_path_buf(),
+            status: "failed",
+            commit: None,
+            files: Vec::new(),
+            error: None,
+        };
+        match verification {
+            Ok(verification) => {
+                report.status = verification.code();
+                match verification {
+                    Verification::InSync { commit } => report.commit = Some(commit.to_string()),
+                    Verification::OutOfSync { commit, files } => {
+                        report.commit = Some(commit.to_string());
+                        report.files = files;
+                    }
+                    Verification::NoRemoteBranch => {}
+                }
+            }
+            Err(e) => report.error = Some(e),
+        }
+        in_sync &= report.status == "in-sync";
+        match output {
+            OutputFormat::Json => match serde_json::to_string(&report) {
+                Ok(json) => println!("{}", json),
+                Err(e) => error!("Could not serialize the verify report: {}", e),
+            },
+            OutputFormat::Text => {
+                println!("{}: {}", report.repo.display(), report.status);
+                for file in &report.files {
+                    println!("  {}", file.display());
+                }
+                if let Some(e) = &report.error {
+                    println!("  {}", e);
+                }
+            }
+        }
+    }
+    if in_sync {
+        ExitCode::SUCCESS
+    } else {
+        ExitCode::FAILURE
+    }
+}
+
+/// Prints how the auto files of each repository differ from the remote tip.
+///
+/// # Arguments
+///
+/// * `requests` - The repositories to compare.
+/// * `output` - Whether to print a JSON object per repository instead of text.
+///
+/// # Returns
+///
+/// Failure if a comparison failed.
+fn diff(requests: &[PushRequest], output: OutputFormat) -> ExitCode {
+    let mut failed = false;
+    for request in requests {
+        let mut report = DiffReport {
+            repo: request.repo().to_path_buf(),
+            commit: None,
+            files: Vec::new(),
+            error: None,
+        };
+        match request.diff() {
+            Ok(Some(MarksDiff { commit, files })) => {
+                report.commit = Some(commit.to_string());
+                report.files = files
+                    .into_iter()
+                    .map(|file| FileDiffReport {
+                        path: file.path,
+                        added: file.added,
+                        removed: file.removed,
+                        last_pushed: file.last_pushed,
+                    })
+                    .collect();
+            }
+            Ok(None) => {}
+            Err(e) => report.error = Some(e),
+        }
+        failed |= report.error.is_some();
+        match output {
+            OutputFormat::Json => match serde_json::to_string(&report) {
+                Ok(json) => println!("{}", json),
+                Err(e) => error!("Could not serialize the diff report: {}", e),
+            },
+            OutputFormat::Text => {
+                let state: &str = match (&report.commit, &report.error) {
+                    (_, Some(_)) => "failed",
+                    (None, None) => "no-remote-branch",
+                    (Some(_), None) if report.files.is_empty() => "in-sync",
+                    (Some(_), None) => "differs",
+                };
+                println!("{}: {}", report.repo.display(), state);
+                for file in &report.files {
+                    let last_pushed: &str = file.last_pushed.as_deref().unwrap_or("never");
+                    println!(
+                        "  {}: +{} -{}, last pushed {}",
+                        file.path.display(),
+                        file.added,
+                        file.removed,
+                        last_pushed
+                    );
+                }
+                if let Some(e) = &report.error {
+                    println!("  {}", e);
+                }
+            }
+        }
+    }
+    if failed {
+        ExitCode::FAILURE
+    } else {
+        ExitCode::SUCCESS
+    }
+}
+
+/// Compacts the history of each repository.
+///
+/// # Arguments
+///
+/// * `requests` - The repositories to compact.
+/// * `keep` - How much of the history to keep as it is.
+///
+/// # Returns
+///
+/// Failure if a compaction failed.
+fn compact(requests: &[PushRequest], keep: Duration) -> ExitCode {
+    let mut failed = false;
+    for request in requests {
+        match request.compact(keep) {
+            Ok(Compaction::NothingToCompact) => {
+                println!("{}: nothing to compact", request.repo().display());
+            }
+            Ok(Compaction::Compacted { squashed, commit }) => println!(
+                "{}: squashed {} commits, now at {}",
+                request.repo().display(),
+                squashed,
+                commit
+            ),
+            Err(e) => {
+                failed = true;
+                error!("{}: {}", request.repo().display(), e);
+            }
+        }
+    }
+    if failed {
+        ExitCode::FAILURE
+    } else {
+        ExitCode::SUCCESS
+    }
+}
 
-    mark_file_statuses
+/// Prints statistics of the auto commits and runs of each repository.
+///
+/// # Arguments
+///
+/// * `requests` - The repositories to analyze.
+/// * `output` - Whether to print a JSON object per repository instead of text.
+///
+/// # Returns
+///
+/// Failure if an analysis failed.
+fn stats(requests: &[PushRequest], output: OutputFormat) -> ExitCode {
+    let mut failed = false;
+    for request in requests {
+        let mut report = StatsReport {
+            repo: request.repo().to_path_buf(),
+            commits: 0,
+            commits_per_day: BTreeMap::new(),
+            average_files: 0.0,
+            longest_failure_streak: 0,
+            current_failure_streak: 0,
+            error: None,
+        };
+        match request.stats() {
+            Ok(HistoryStats {
+                commits,
+                commits_per_day,
+                average_files,
+                longest_failure_streak,
+                current_failure_streak,
+            }) => {
+                report.commits = commits;
+                report.commits_per_day = commits_per_day.into_iter().collect();
+                report.average_files = average_files;
+                report.longest_failure_streak = longest_failure_streak;
+                report.current_failure_streak = current_failure_streak;
+            }
+            Err(e) => report.error = Some(e),
+        }
+        failed |= report.error.is_some();
+        match output {
+            OutputFormat::Json => match serde_json::to_string(&report) {
+                Ok(json) => println!("{}", json),
+                Err(e) => error!("Could not serialize the stats report: {}", e),
+            },
+            OutputFormat::Text => {
+                println!("{}:", report.repo.display());
+                if let Some(e) = &report.error {
+                    println!("  {}", e);
+                    continue;
+                }
+                println!(
+                    "  {} auto commits, {:.1} files each on average",
+                    report.commits, report.average_files
+                );
+                println!(
+                    "  {} failed pushes in a row at most, {} now",
+                    report.longest_failure_streak, report.current_failure_streak
+                );
+                for (date, count) in &report.commits_per_day {
+                    println!("  {}: {}", date, count);
+                }
+            }
+        }
+    }
+    if failed {
+        ExitCode::FAILURE
+    } else {
+        ExitCode::SUCCESS
+    }
+}
+
+/// Pushes the mark files of each repository once.
+///
+/// The repositories are independent, so several are pushed at the same time,
+/// and a failure doesn't stop the others.
+///
+/// # Arguments
+///
+/// * `requests` - What to push, per repository.
+/// * `reporting` - How to report the outcome of each run.
+/// * `jobs` - How many repositories to push at the same time.
+///
+/// # Returns
+///
+/// Failure if any run failed.
+fn push(requests: &[PushRequest], reporting: &Reporting, jobs: usize) -> ExitCode {
+    let next: AtomicUsize = AtomicUsize::new(0);
+    let reports: Mutex<Vec<Option<RunReport>>> =
+        Mutex::new(requests.iter().map(|_| None).collect());
+    thread::scope(|scope| {
+        for _ in 0..jobs.min(requests.len()) {
+            scope.spawn(|| loop {
+                let index: usize = next.fetch_add(1, Ordering::Relaxed);
+                let Some(request) = requests.get(index) else {
+                    break;
+                };
+                if requests.len() > 1 {
+                    info!("Processing {}.", request.repo().display());
+                }
+                let report: RunReport = run_request(request, reporting);
+                reports.lock().unwrap_or_else(PoisonError::into_inner)[index] = Some(report);
+            });
+        }
+    });
+    // The summary keeps the order of the requests.
+    let reports: Vec<RunReport> = reports
+        .into_inner()
+        .unwrap_or_else(PoisonError::into_inner)
         .into_iter()
-        .for_each(|s| println!("{:?}, {:?}", s.path, s.status));
-    println!("Hello, world!");
-    return Ok(());
+        .flatten()
+        .collect();
+    if reports.len() > 1 && reporting.output == OutputFormat::Text {
+        for report in &reports {
+            info!(
+                "{}: {}",
+                report.repo.as_deref().unwrap_or(Path::new("")).display(),
+                report.summary()
+            );
+        }
+    }
+    if reports.iter().any(|report| report.error.is_some()) {
+        ExitCode::FAILURE
+    } else {
+        ExitCode::SUCCESS
+    }
 }
 
-fn main() -> Result<(), String> {
-    let cli = Cli::parse();
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Parses a configuration file.
+    fn config(content: &str) -> Result<ConfigFile, toml::de::Error> {
+        toml::from_str(content)
+    }
+
+    /// Returns the settings of the single repository of a command line.
+    fn settings(args: &[&str], config: ConfigFile) -> String {
+        let cli = Cli::try_parse_from([&["git-auto-commit"], args].concat()).unwrap();
+        let requests: Vec<PushRequest> = push_requests(cli, config).unwrap();
+        assert_eq!(requests.len(), 1);
+        format!("{:?}", requests[0])
+    }
+
+    // The only test that sets environment variables, which the other tests
+    // leave alone, so that they don't race.
+    #[test]
+    fn command_line_overrides_environment_overrides_file() {
+        let file = || config("repo = \"/wallet\"\nmessage = \"file\"\n").unwrap();
+        env::remove_var("PUSH_WALLET_MARKS_MESSAGE");
+        assert!(settings(&[], file()).contains("\"file\""));
+
+        env::set_var("PUSH_WALLET_MARKS_MESSAGE", "environment");
+        let from_env: String = settings(&[], file());
+        let from_cli: String = settings(&["--message", "command line"], file());
+        env::remove_var("PUSH_WALLET_MARKS_MESSAGE");
 
-    if !is_repo_path(&cli.repo) {
-        return Err(format!(
-            "The path `{}` is not a valid repository.",
-            cli.repo.display()
-        ));
+        assert!(from_env.contains("\"environment\""), "{}", from_env);
+        assert!(!from_env.contains("\"file\""), "{}", from_env);
+        assert!(from_cli.contains("\"command line\""), "{}", from_cli);
+        assert!(!from_cli.contains("\"environment\""), "{}", from_cli);
     }
 
-    let temp_dir: tempfile::TempDir = copy_repository(cli.repo)?;
-    push_wallet_marks(temp_dir.path(), &cli.auto_files)?;
-    return Ok(());
+    #[test]
+    fn command_line_lists_replace_the_file_ones() {
+        let file = config("repo = \"/wallet\"\nauto-files = [\"marks/file.journal\"]\n").unwrap();
+
+        let settings: String = settings(&["--auto-files", "marks/cli.journal"], file);
+
+        assert!(settings.contains("marks/cli.journal"), "{}", settings);
+        assert!(!settings.contains("marks/file.journal"), "{}", settings);
+    }
+
+    #[test]
+    fn unknown_keys_are_rejected() {
+        let error: String = config("repo = \"/wallet\"\nauto-file = []\n")
+            .err()
+            .unwrap()
+            .to_string();
+        assert!(error.contains("unknown field `auto-file`"), "{}", error);
+
+        let error: String = config("[[repos]]\npath = \"/wallet\"\nexclude = []\n")
+            .err()
+            .unwrap()
+            .to_string();
+        assert!(error.contains("unknown field `exclude`"), "{}", error);
+    }
 }
@@ -0,0 +1,65 @@
+//! Tests of batching auto commits across runs.
+
+mod common;
+
+use std::fs;
+use std::path::PathBuf;
+use std::time::Duration;
+
+use common::Fixture;
+use git2::Oid;
+use git_auto_commit::PushOutcome;
+use git_auto_commit::PushRequest;
+
+const MARKS: &str = "2024-01-05 * Rent\n2024-01-06 ! Food\n";
+
+/// Runs within the batch window amend one unpushed commit.
+#[test]
+fn runs_within_the_window_amend_one_commit() {
+    let fixture = Fixture::new();
+    let base: Oid = fixture.head();
+    let request = PushRequest::new(fixture.wallet.clone())
+        .auto_file("marks/a.journal")
+        .batch_window(Duration::from_secs(3600));
+
+    for content in ["2024-01-05 * Rent\n", MARKS] {
+        fixture.write("marks/a.journal", content);
+        let outcome: PushOutcome = request.clone().push().unwrap();
+        assert!(
+            matches!(outcome, PushOutcome::Committed { .. }),
+            "{:?}",
+            outcome
+        );
+    }
+
+    let repo = fixture.repo();
+    let head = repo.head().unwrap().peel_to_commit().unwrap();
+    assert_eq!(head.parent_id(0).unwrap(), base);
+    assert_eq!(
+        fixture.remote_file("marks/a.journal").unwrap(),
+        "2024-01-05 ! Rent\n"
+    );
+}
+
+/// The first run after the window pushes the batch, even without changes.
+#[test]
+fn run_after_the_window_pushes_the_batch() {
+    let fixture = Fixture::new();
+    let request = PushRequest::new(fixture.wallet.clone())
+        .auto_file("marks/a.journal")
+        .batch_window(Duration::from_secs(3600));
+    fixture.write("marks/a.journal", MARKS);
+    request.clone().push().unwrap();
+    let batch: PathBuf = fixture.wallet.join(".git/push-wallet-marks-batch");
+    fs::write(&batch, "0\n").unwrap();
+
+    let outcome: PushOutcome = request.push().unwrap();
+
+    assert!(
+        matches!(outcome, PushOutcome::Pushed { .. }),
+        "{:?}",
+        outcome
+    );
+    assert_eq!(fixture.remote_file("marks/a.journal").unwrap(), MARKS);
+    assert!(!batch.exists());
+}
@@ -0,0 +1,85 @@
+//! Tests that a run which panics leaves nothing behind that affects the next
+//! one.
+
+mod common;
+
+use std::fs;
+use std::panic;
+use std::panic::AssertUnwindSafe;
+use std::path::PathBuf;
+use std::sync::atomic::AtomicUsize;
+use std::sync::atomic::Ordering;
+
+use common::Fixture;
+use git2::Repository;
+use git_auto_commit::CommitSigner;
+use git_auto_commit::CommitStrategy;
+use git_auto_commit::PushOutcome;
+use git_auto_commit::PushRequest;
+use git_auto_commit::Workspace;
+
+/// A signer that panics on its second commit, as a bug in the middle of a
+/// run would, after the first auto commit was made.
+#[derive(Debug, Default)]
+struct PanickingSigner {
+    signed: AtomicUsize,
+}
+
+impl CommitSigner for PanickingSigner {
+    fn sign(&self, _content: &str) -> Result<String, String> {
+        if self.signed.fetch_add(1, Ordering::SeqCst) > 0 {
+            panic!("The signer crashed.");
+        }
+        Ok("-----BEGIN SSH SIGNATURE-----\n-----END SSH SIGNATURE-----".to_string())
+    }
+}
+
+/// Panics in the middle of a run in a workspace and checks that the
+/// temporary directory, the worktree, the run lock, and HEAD are cleaned up.
+fn assert_cleaned_up_after_a_panic(workspace: Workspace) {
+    let fixture = Fixture::new();
+    let head = fixture.head();
+    let index: Vec<u8> = fs::read(fixture.wallet.join(".git/index")).unwrap();
+    let temp_dir: PathBuf = fixture.wallet.join("../tmp");
+    fs::create_dir(&temp_dir).unwrap();
+    fixture.write("marks/a.journal", "2024-01-05 * Rent\n");
+    fixture.write("marks/b.journal", "2024-01-06 * Gas\n");
+    let request = PushRequest::new(fixture.wallet.clone())
+        .auto_file("marks/*.journal")
+        .allow_new(true)
+        .commit_strategy(CommitStrategy::PerFile)
+        .workspace(workspace)
+        .temp_dir(&temp_dir);
+
+    let crashed = panic::catch_unwind(AssertUnwindSafe(|| {
+        request.clone().signer(PanickingSigner::default()).push()
+    }));
+
+    assert!(crashed.is_err());
+    assert_eq!(fs::read_dir(&temp_dir).unwrap().count(), 0);
+    let repo: Repository = fixture.repo();
+    assert_eq!(repo.worktrees().unwrap().len(), 0);
+    assert_eq!(fixture.head(), head);
+    assert_eq!(fs::read(fixture.wallet.join(".git/index")).unwrap(), index);
+    let outcome: PushOutcome = request.push().unwrap();
+    assert!(
+        matches!(outcome, PushOutcome::Pushed { .. }),
+        "{:?}",
+        outcome
+    );
+}
+
+#[test]
+fn copy_is_cleaned_up_after_a_panic() {
+    assert_cleaned_up_after_a_panic(Workspace::Copy);
+}
+
+#[test]
+fn worktree_is_cleaned_up_after_a_panic() {
+    assert_cleaned_up_after_a_panic(Workspace::Worktree);
+}
+
+#[test]
+fn in_place_is_cleaned_up_after_a_panic() {
+    assert_cleaned_up_after_a_panic(Workspace::InPlace);
+}
@@ -0,0 +1,142 @@
+//! A wallet repository with a bare upstream for the integration tests.
+
+#![allow(dead_code)]
+
+use std::fs;
+use std::path::Path;
+use std::path::PathBuf;
+
+use git2::Oid;
+use git2::Repository;
+use git2::RepositoryInitOptions;
+use tempfile::TempDir;
+
+/// A wallet repository whose `main` branch tracks `main` of a bare remote.
+pub struct Fixture {
+    /// Holds the repositories until the fixture is dropped.
+    _dir: TempDir,
+    /// The working directory of the wallet.
+    pub wallet: PathBuf,
+    /// The bare remote of the wallet, `origin`.
+    pub remote: PathBuf,
+}
+
+impl Fixture {
+    /// Creates the remote and the wallet with a pushed `marks/a.journal`.
+    pub fn new() -> Self {
+        let fixture = Fixture::unborn();
+        fixture.write("marks/a.journal", "2024-01-05 ! Rent\n");
+        fixture.commit(&["marks/a.journal"], "Add marks");
+        fixture.push();
+        fixture
+    }
+
+    /// Creates an empty remote and a wallet whose `main` has no commit yet.
+    pub fn unborn() -> Self {
+        let dir: TempDir = tempfile::tempdir().unwrap();
+        let remote: PathBuf = dir.path().join("remote.git");
+        let wallet: PathBuf = dir.path().join("wallet");
+        Repository::init_opts(
+            &remote,
+            RepositoryInitOptions::new().bare(true).initial_head("main"),
+        )
+        .unwrap();
+        let repo: Repository =
+            Repository::init_opts(&wallet, RepositoryInitOptions::new().initial_head("main"))
+                .unwrap();
+        let mut config = repo.config().unwrap();
+        config.set_str("user.name", "Tester").unwrap();
+        config.set_str("user.email", "tester@example.com").unwrap();
+        // Keep the user's Git config from signing the commits.
+        config.set_bool("commit.gpgsign", false).unwrap();
+        config.set_str("branch.main.remote", "origin").unwrap();
+        config
+            .set_str("branch.main.merge", "refs/heads/main")
+            .unwrap();
+        repo.remote("origin", remote.to_str().unwrap()).unwrap();
+
+        Fixture {
+            _dir: dir,
+            wallet,
+            remote,
+        }
+    }
+
+    /// Clones the remote as another machine would, with `main` checked out.
+    pub fn peer(&self, name: &str) -> Repository {
+        let peer: Repository =
+            Repository::clone(self.remote.to_str().unwrap(), self._dir.path().join(name)).unwrap();
+        let mut config = peer.config().unwrap();
+        config.set_str("user.name", "Peer").unwrap();
+        config.set_str("user.email", "peer@example.com").unwrap();
+        config.set_bool("commit.gpgsign", false).unwrap();
+        peer
+    }
+
+    /// Opens the wallet.
+    pub fn repo(&self) -> Repository {
+        Repository::open(&self.wallet).unwrap()
+    }
+
+    /// Writes a file of the wallet's working tree.
+    pub fn write(&self, path: &str, content: &str) {
+        let full_path: PathBuf = self.wallet.join(path);
+        fs::create_dir_all(full_path.parent().unwrap()).unwrap();
+        fs::write(full_path, content).unwrap();
+    }
+
+    /// Commits files of the working tree on HEAD.
+    pub fn commit(&self, paths: &[&str], message: &str) -> Oid {
+        let repo: Repository = self.repo();
+        let mut index = repo.index().unwrap();
+        for path in paths {
+            index.add_path(Path::new(path)).unwrap();
+        }
+        index.write().unwrap();
+        let tree = repo.find_tree(index.write_tree().unwrap()).unwrap();
+        let signature = repo.signature().unwrap();
+        let parent = repo.head().ok().map(|head| head.peel_to_commit().unwrap());
+        let parents: Vec<&git2::Commit> = parent.iter().collect();
+        repo.commit(
+            Some("HEAD"),
+            &signature,
+            &signature,
+            message,
+            &tree,
+            &parents,
+        )
+        .unwrap()
+    }
+
+    /// Pushes `main` to the remote, which updates `origin/main`, too.
+    pub fn push(&self) {
+        self.repo()
+            .find_remote("origin")
+            .unwrap()
+            .push(&["refs/heads/main:refs/heads/main"], None)
+            .unwrap();
+    }
+
+    /// Returns the ID of HEAD's commit in the wallet.
+    pub fn head(&self) -> Oid {
+        self.repo().head().unwrap().target().unwrap()
+    }
+
+    /// Returns the content of a file at `main` of the remote, if it's there.
+    pub fn remote_file(&self, path: &str) -> Option<String> {
+        let remote: Repository = Repository::open_bare(&self.remote).unwrap();
+        let tree = remote
+            .find_reference("refs/heads/main")
+            .ok()?
+            .peel_to_tree()
+            .unwrap();
+        let blob = tree
+            .get_path(Path::new(path))
+            .ok()?
+            .to_object(&remote)
+            .unwrap()
+            .peel_to_blob()
+            .unwrap();
+        Some(String::from_utf8(blob.content().to_vec()).unwrap())
+    }
+}
@@ -0,0 +1,109 @@
+//! Tests of squashing old history of a branch of auto commits.
+
+mod common;
+
+use std::thread;
+use std::time::Duration;
+use std::time::SystemTime;
+
+use common::Fixture;
+use git2::Commit;
+use git2::Oid;
+use git2::Repository;
+use git_auto_commit::Compaction;
+use git_auto_commit::PushRequest;
+
+/// Returns the commit at `main` of the remote.
+fn remote_main(fixture: &Fixture) -> Oid {
+    Repository::open_bare(&fixture.remote)
+        .unwrap()
+        .refname_to_id("refs/heads/main")
+        .unwrap()
+}
+
+/// Commits older than the window are squashed into a root commit with their
+/// content, and the newer ones are kept on top of it.
+#[test]
+fn squashes_old_auto_commits() {
+    let fixture = Fixture::new();
+    let request = PushRequest::new(fixture.wallet.clone()).auto_file("marks/a.journal");
+    fixture.write("marks/a.journal", "2024-01-05 * Rent\n");
+    request.push().unwrap();
+    fixture.write("marks/a.journal", "2024-01-05 * Rent\n2024-01-06 ! Gas\n");
+    request.push().unwrap();
+    let old_tree: Oid = fixture.repo().head().unwrap().peel_to_tree().unwrap().id();
+    // Commit times have a resolution of seconds, and runs in the same second
+    // get increasing times.
+    thread::sleep(Duration::from_millis(2100));
+    fixture.write("marks/a.journal", "2024-01-05 * Rent\n2024-01-06 * Gas\n");
+    request.push().unwrap();
+    let old_head: Oid = fixture.head();
+    // Keep exactly the last auto commit.
+    let commit_time: u64 = fixture
+        .repo()
+        .find_commit(old_head)
+        .unwrap()
+        .time()
+        .seconds() as u64;
+    let now: u64 = SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap()
+        .as_secs();
+    let keep: Duration = Duration::from_secs(now - commit_time);
+
+    let compaction: Compaction = request.compact(keep).unwrap();
+
+    let repo = fixture.repo();
+    let head: Commit = repo.head().unwrap().peel_to_commit().unwrap();
+    assert_eq!(
+        compaction,
+        Compaction::Compacted {
+            squashed: 3,
+            commit: head.id(),
+        }
+    );
+    assert_eq!(remote_main(&fixture), head.id());
+    let old: Commit = repo.find_commit(old_head).unwrap();
+    assert_eq!(head.tree_id(), old.tree_id());
+    assert_eq!(head.message(), old.message());
+    let snapshot: Commit = head.parent(0).unwrap();
+    assert_eq!(snapshot.parent_count(), 0);
+    assert_eq!(snapshot.tree_id(), old_tree);
+    assert_eq!(request.compact(keep), Ok(Compaction::NothingToCompact));
+}
+
+/// History with commits other than auto commits isn't rewritten.
+#[test]
+fn refuses_to_squash_manual_commits() {
+    let fixture = Fixture::new();
+    fixture.write("marks/b.journal", "2024-01-06 ! Gas\n");
+    fixture.commit(&["marks/b.journal"], "Add gas");
+    fixture.push();
+    let request = PushRequest::new(fixture.wallet.clone()).auto_file("marks/a.journal");
+    fixture.write("marks/a.journal", "2024-01-05 * Rent\n");
+    request.push().unwrap();
+    let head: Oid = fixture.head();
+    thread::sleep(Duration::from_millis(1100));
+
+    let error: String = request.compact(Duration::ZERO).unwrap_err();
+
+    assert!(error.contains("isn't an auto commit"), "{}", error);
+    assert_eq!(fixture.head(), head);
+    assert_eq!(remote_main(&fixture), head);
+}
+
+/// Nothing happens while all history is within the window.
+#[test]
+fn keeps_recent_history() {
+    let fixture = Fixture::new();
+    let request = PushRequest::new(fixture.wallet.clone()).auto_file("marks/a.journal");
+    fixture.write("marks/a.journal", "2024-01-05 * Rent\n");
+    request.push().unwrap();
+    let head: Oid = fixture.head();
+
+    assert_eq!(
+        request.compact(Duration::from_secs(24 * 60 * 60)),
+        Ok(Compaction::NothingToCompact)
+    );
+    assert_eq!(fixture.head(), head);
+}
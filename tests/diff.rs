@@ -0,0 +1,57 @@
+//! Tests of summarizing how the auto files differ from the remote tip.
+
+mod common;
+
+use common::Fixture;
+use git_auto_commit::FileDiff;
+use git_auto_commit::MarksDiff;
+use git_auto_commit::PushRequest;
+
+/// A changed auto file is summarized with its line counts and when it was
+/// last pushed, and nothing is left after it's pushed.
+#[test]
+fn summarizes_the_changed_lines() {
+    let fixture = Fixture::new();
+    let request = PushRequest::new(fixture.wallet.clone()).auto_file("marks/a.journal");
+
+    fixture.write("marks/a.journal", "2024-01-05 * Rent\n2024-01-06 ! Gas\n");
+    let diff: MarksDiff = request.diff().unwrap().unwrap();
+
+    assert_eq!(diff.commit, fixture.head());
+    assert_eq!(diff.files.len(), 1);
+    let FileDiff {
+        path,
+        added,
+        removed,
+        last_pushed,
+    } = &diff.files[0];
+    assert_eq!(path.to_str(), Some("marks/a.journal"));
+    assert_eq!((*added, *removed), (2, 1));
+    let last_pushed: &str = last_pushed.as_deref().unwrap();
+    assert!(last_pushed.contains('T'), "{}", last_pushed);
+
+    request.push().unwrap();
+    assert_eq!(request.diff().unwrap().unwrap().files, Vec::new());
+}
+
+/// A file that the remote tip lacks counts as added and was never pushed.
+#[test]
+fn new_files_were_never_pushed() {
+    let fixture = Fixture::new();
+    fixture.write("marks/b.journal", "2024-01-07 * Food\n");
+    let request = PushRequest::new(fixture.wallet.clone())
+        .auto_file("marks/*.journal")
+        .allow_new(true);
+
+    let diff: MarksDiff = request.diff().unwrap().unwrap();
+
+    assert_eq!(
+        diff.files,
+        vec![FileDiff {
+            path: "marks/b.journal".into(),
+            added: 1,
+            removed: 0,
+            last_pushed: None,
+        }]
+    );
+}
@@ -0,0 +1,41 @@
+//! Tests of reading the options from environment variables.
+
+mod common;
+
+use std::process::Command;
+use std::process::Output;
+
+use common::Fixture;
+
+/// Returns the effective settings that `config` prints with an environment
+/// variable set.
+fn config_with(fixture: &Fixture, name: &str, value: &str) -> String {
+    let output: Output = Command::new(env!("CARGO_BIN_EXE_git-auto-commit"))
+        .args(["config", "--repo", fixture.wallet.to_str().unwrap()])
+        .args(["--auto-files", "marks/a.journal"])
+        .env_remove("PUSH_WALLET_MARKS_CONFIG")
+        .env(name, value)
+        .output()
+        .unwrap();
+    assert!(
+        output.status.success(),
+        "{}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+    String::from_utf8(output.stdout).unwrap()
+}
+
+/// Switches take the usual spellings of true and false, as in systemd units.
+#[test]
+fn switches_take_numbers_and_words() {
+    let fixture = Fixture::new();
+    for (value, expected) in [("1", true), ("0", false), ("true", true), ("false", false)] {
+        let config: String = config_with(&fixture, "PUSH_WALLET_MARKS_ALLOW_NEW", value);
+        assert!(
+            config.contains(&format!("allow_new: {},", expected)),
+            "{}: {}",
+            value,
+            config
+        );
+    }
+}
@@ -0,0 +1,70 @@
+//! Tests of running the repository's Git hooks around auto commits.
+
+#![cfg(unix)]
+
+mod common;
+
+use std::fs;
+use std::os::unix::fs::PermissionsExt;
+
+use common::Fixture;
+use git_auto_commit::PushRequest;
+
+/// Writes an executable hook of the wallet.
+fn write_hook(fixture: &Fixture, name: &str, script: &str) {
+    fixture.write(&format!(".git/hooks/{}", name), script);
+    let hook = fixture.wallet.join(".git/hooks").join(name);
+    fs::set_permissions(hook, fs::Permissions::from_mode(0o755)).unwrap();
+}
+
+/// A pre-commit hook that stages an unrelated change of the working tree
+/// aborts the push instead of committing the change.
+#[test]
+fn pre_commit_hook_cannot_stage_other_files() {
+    let fixture = Fixture::new();
+    fixture.write("notes.txt", "Buy milk\n");
+    fixture.commit(&["notes.txt"], "Add notes");
+    fixture.push();
+    fixture.write("notes.txt", "Buy milk and eggs\n");
+    fixture.write("marks/a.journal", "2024-01-05 * Rent\n");
+    write_hook(&fixture, "pre-commit", "#!/bin/sh\ngit add -u\n");
+
+    let error: String = PushRequest::new(fixture.wallet.clone())
+        .auto_file("marks/a.journal")
+        .run_hooks(true)
+        .push()
+        .unwrap_err();
+
+    assert!(error.contains("notes.txt"), "{}", error);
+    assert_eq!(
+        fixture.remote_file("notes.txt").as_deref(),
+        Some("Buy milk\n")
+    );
+    assert_eq!(
+        fixture.remote_file("marks/a.journal").as_deref(),
+        Some("2024-01-05 ! Rent\n")
+    );
+}
+
+/// A pre-commit hook may restage the mark files, e.g., after formatting them.
+#[test]
+fn pre_commit_hook_can_restage_mark_files() {
+    let fixture = Fixture::new();
+    fixture.write("marks/a.journal", "2024-01-05 * Rent\n");
+    write_hook(
+        &fixture,
+        "pre-commit",
+        "#!/bin/sh\necho '2024-01-06 * Food' >> marks/a.journal\ngit add marks/a.journal\n",
+    );
+
+    PushRequest::new(fixture.wallet.clone())
+        .auto_file("marks/a.journal")
+        .run_hooks(true)
+        .push()
+        .unwrap();
+
+    assert_eq!(
+        fixture.remote_file("marks/a.journal").as_deref(),
+        Some("2024-01-05 * Rent\n2024-01-06 * Food\n")
+    );
+}
@@ -0,0 +1,121 @@
+//! Tests of committing directly in the original repository.
+
+mod common;
+
+use std::fs;
+
+use common::Fixture;
+use git2::Status;
+use git_auto_commit::DivergencePolicy;
+use git_auto_commit::PushOutcome;
+use git_auto_commit::PushRequest;
+use git_auto_commit::Workspace;
+
+/// A push that fails after the auto commit was made leaves HEAD and the index
+/// as they were, with the changes still in the working tree.
+#[test]
+fn failed_push_leaves_head_and_index_unchanged() {
+    let fixture = Fixture::new();
+    let head = fixture.head();
+    let reflog_len = fixture.repo().reflog("HEAD").unwrap().len();
+    fixture.write("marks/a.journal", "2024-01-05 * Rent\n");
+    // Rebasing skips the divergence check, so the remote is first needed
+    // after the commit.
+    fixture
+        .repo()
+        .remote_set_url("origin", "/nonexistent/remote.git")
+        .unwrap();
+    let request = PushRequest::new(fixture.wallet.clone())
+        .auto_file("marks/a.journal")
+        .on_diverged(DivergencePolicy::Rebase)
+        .workspace(Workspace::InPlace);
+
+    assert!(request.push().is_err());
+
+    let repo = fixture.repo();
+    // The auto commit was made and undone.
+    assert_eq!(repo.reflog("HEAD").unwrap().len(), reflog_len + 2);
+    assert_eq!(fixture.head(), head);
+    let head_tree = repo.head().unwrap().peel_to_tree().unwrap().id();
+    assert_eq!(repo.index().unwrap().write_tree().unwrap(), head_tree);
+    assert_eq!(
+        repo.status_file("marks/a.journal".as_ref()).unwrap(),
+        Status::WT_MODIFIED
+    );
+}
+
+/// Working in place stages into a private index, so the repository's index
+/// file isn't written even while the auto commit exists.
+#[test]
+fn index_file_is_not_written() {
+    let fixture = Fixture::new();
+    fixture.write("marks/a.journal", "2024-01-05 * Rent\n");
+    let index_path = fixture.wallet.join(".git/index");
+    let index: Vec<u8> = fs::read(&index_path).unwrap();
+    fixture
+        .repo()
+        .remote_set_url("origin", "/nonexistent/remote.git")
+        .unwrap();
+
+    let result = PushRequest::new(fixture.wallet.clone())
+        .auto_file("marks/a.journal")
+        .on_diverged(DivergencePolicy::Rebase)
+        .workspace(Workspace::InPlace)
+        .push();
+
+    assert!(result.is_err());
+    assert_eq!(fs::read(&index_path).unwrap(), index);
+}
+
+/// A repository that can't be written, e.g., on a read-only mount, is pushed
+/// from a copy instead of failing in place.
+#[cfg(unix)]
+#[test]
+fn read_only_repository_falls_back_to_a_copy() {
+    use std::os::unix::fs::PermissionsExt;
+
+    let fixture = Fixture::new();
+    fixture.write("marks/a.journal", "2024-01-05 * Rent\n");
+    let request = PushRequest::new(fixture.wallet.clone())
+        .auto_file("marks/a.journal")
+        .workspace(Workspace::InPlace);
+    let git_dir = fixture.wallet.join(".git");
+    let read_only = |read_only: bool| {
+        let mode: u32 = if read_only { 0o555 } else { 0o755 };
+        for entry in walk(&git_dir) {
+            if entry.is_dir() {
+                fs::set_permissions(&entry, fs::Permissions::from_mode(mode)).unwrap();
+            }
+        }
+    };
+    read_only(true);
+    if fs::write(git_dir.join("probe"), "").is_ok() {
+        read_only(false);
+        // Permissions don't bind, e.g., root.
+        eprintln!("Skipped: the Git directory is still writable.");
+        return;
+    }
+
+    let outcome = request.push();
+
+    read_only(false);
+    match outcome {
+        Ok(PushOutcome::Pushed { sync_error, .. }) => assert!(sync_error.is_some()),
+        outcome => panic!("{:?}", outcome),
+    }
+    assert_eq!(
+        fixture.remote_file("marks/a.journal").as_deref(),
+        Some("2024-01-05 * Rent\n")
+    );
+}
+
+/// Returns a directory and everything below it.
+fn walk(dir: &std::path::Path) -> Vec<std::path::PathBuf> {
+    let mut entries = vec![dir.to_path_buf()];
+    if dir.is_dir() {
+        for entry in fs::read_dir(dir).unwrap() {
+            entries.extend(walk(&entry.unwrap().path()));
+        }
+    }
+    entries
+}
@@ -0,0 +1,38 @@
+//! Tests of running maintenance after auto commits.
+
+mod common;
+
+use std::fs;
+use std::path::PathBuf;
+
+use common::Fixture;
+use git_auto_commit::PushOutcome;
+use git_auto_commit::PushRequest;
+
+/// Maintenance runs after every given number of auto commits, which writes a
+/// commit graph among others.
+#[test]
+fn maintenance_runs_after_every_count_of_auto_commits() {
+    let fixture = Fixture::new();
+    let request = PushRequest::new(fixture.wallet.clone())
+        .auto_file("marks/a.journal")
+        .maintenance_every(2);
+    let git_dir: PathBuf = fixture.wallet.join(".git");
+    let commit_graph: PathBuf = git_dir.join("objects/info/commit-graph");
+    let count = || fs::read_to_string(git_dir.join("push-wallet-marks-commit-count")).unwrap();
+
+    fixture.write("marks/a.journal", "2024-01-05 * Rent\n");
+    let outcome: PushOutcome = request.clone().push().unwrap();
+    assert!(
+        matches!(outcome, PushOutcome::Pushed { .. }),
+        "{:?}",
+        outcome
+    );
+    assert_eq!(count(), "1\n");
+    assert!(!commit_graph.exists());
+
+    fixture.write("marks/a.journal", "2024-01-05 * Rent\n2024-01-06 ! Food\n");
+    request.push().unwrap();
+    assert_eq!(count(), "0\n");
+    assert!(commit_graph.exists());
+}
@@ -0,0 +1,93 @@
+//! Tests of pushing the marks from a separate marks repository.
+
+mod common;
+
+use std::fs;
+use std::path::Path;
+use std::path::PathBuf;
+
+use common::Fixture;
+use git2::Repository;
+use git2::RepositoryInitOptions;
+use git2::Signature;
+use git_auto_commit::PushOutcome;
+use git_auto_commit::PushRequest;
+use tempfile::TempDir;
+
+/// Creates a private wallet with the marks and other files committed.
+fn private_wallet(dir: &Path) -> PathBuf {
+    let wallet: PathBuf = dir.join("wallet");
+    let repo: Repository =
+        Repository::init_opts(&wallet, RepositoryInitOptions::new().initial_head("main")).unwrap();
+    fs::create_dir_all(wallet.join("marks")).unwrap();
+    fs::write(wallet.join("marks/a.journal"), "2024-01-05 ! Rent\n").unwrap();
+    fs::write(wallet.join("salary.journal"), "2024-01-01 Salary\n").unwrap();
+    let mut index = repo.index().unwrap();
+    index.add_path(Path::new("marks/a.journal")).unwrap();
+    index.add_path(Path::new("salary.journal")).unwrap();
+    index.write().unwrap();
+    let tree = repo.find_tree(index.write_tree().unwrap()).unwrap();
+    let signature = Signature::now("Tester", "tester@example.com").unwrap();
+    repo.commit(Some("HEAD"), &signature, &signature, "Add", &tree, &[])
+        .unwrap();
+    wallet
+}
+
+/// The marks of the wallet are committed and pushed in the marks repository,
+/// and nothing else of the wallet is.
+#[test]
+fn pushes_the_wallet_marks_from_the_marks_repository() {
+    let dir: TempDir = tempfile::tempdir().unwrap();
+    let wallet: PathBuf = private_wallet(dir.path());
+    let marks = Fixture::new();
+    fs::write(wallet.join("marks/a.journal"), "2024-01-05 * Rent\n").unwrap();
+
+    let outcome: PushOutcome = PushRequest::new(wallet.clone())
+        .auto_file("marks/*.journal")
+        .marks_repo(marks.wallet.clone())
+        .push()
+        .unwrap();
+
+    assert!(
+        matches!(outcome, PushOutcome::Pushed { .. }),
+        "{:?}",
+        outcome
+    );
+    assert_eq!(
+        marks.remote_file("marks/a.journal").as_deref(),
+        Some("2024-01-05 * Rent\n")
+    );
+    assert_eq!(marks.remote_file("salary.journal"), None);
+    // The wallet itself is left alone.
+    let wallet_repo: Repository = Repository::open(&wallet).unwrap();
+    assert_eq!(
+        wallet_repo
+            .head()
+            .unwrap()
+            .peel_to_commit()
+            .unwrap()
+            .message(),
+        Some("Add")
+    );
+}
+
+/// A dry run doesn't copy the marks.
+#[test]
+fn dry_run_copies_nothing() {
+    let dir: TempDir = tempfile::tempdir().unwrap();
+    let wallet: PathBuf = private_wallet(dir.path());
+    let marks = Fixture::new();
+    fs::write(wallet.join("marks/a.journal"), "2024-01-05 * Rent\n").unwrap();
+
+    PushRequest::new(wallet)
+        .auto_file("marks/a.journal")
+        .marks_repo(marks.wallet.clone())
+        .dry_run(true)
+        .push()
+        .unwrap();
+
+    assert_eq!(
+        fs::read_to_string(marks.wallet.join("marks/a.journal")).unwrap(),
+        "2024-01-05 ! Rent\n"
+    );
+}
@@ -0,0 +1,100 @@
+//! Tests of merging mark files by transaction.
+
+use std::fs;
+use std::process::Command;
+use std::process::Output;
+
+use git_auto_commit::merge_mark_files;
+
+const BASE: &str = "\
+2024-01-05 ! Rent
+    expenses:rent  500
+    assets:bank
+2024-01-06 Food
+    expenses:food  20
+    assets:cash
+";
+
+/// Marks that each side changed on different transactions are both kept.
+#[test]
+fn merges_marks_of_different_transactions() {
+    let ours: String = BASE.replace("2024-01-05 ! Rent", "2024-01-05 * Rent");
+    let theirs: String = BASE.replace("2024-01-06 Food", "2024-01-06 ! Food");
+
+    assert_eq!(
+        merge_mark_files(BASE, &ours, &theirs),
+        BASE.replace("2024-01-05 ! Rent", "2024-01-05 * Rent")
+            .replace("2024-01-06 Food", "2024-01-06 ! Food")
+    );
+}
+
+/// A transaction whose mark both sides changed gets the more settled mark.
+#[test]
+fn prefers_the_settled_mark() {
+    let ours: String = BASE.replace("2024-01-06 Food", "2024-01-06 ! Food");
+    let theirs: String = BASE.replace("2024-01-06 Food", "2024-01-06 * Food");
+
+    let expected: String = theirs.clone();
+    assert_eq!(merge_mark_files(BASE, &ours, &theirs), expected);
+    assert_eq!(merge_mark_files(BASE, &theirs, &ours), expected);
+}
+
+/// Transactions that either side added are kept in place, and one that a
+/// side removed stays removed.
+#[test]
+fn merges_added_and_removed_transactions() {
+    let ours: String = format!("{}2024-01-07 Salary\n    assets:bank  1000\n", BASE);
+    let theirs: String = format!(
+        "2024-01-04 Coffee\n    expenses:food  3\n{}",
+        BASE.replace(
+            "2024-01-06 Food\n    expenses:food  20\n    assets:cash\n",
+            ""
+        )
+    );
+
+    assert_eq!(
+        merge_mark_files(BASE, &ours, &theirs),
+        "\
+2024-01-04 Coffee
+    expenses:food  3
+2024-01-05 ! Rent
+    expenses:rent  500
+    assets:bank
+2024-01-07 Salary
+    assets:bank  1000
+"
+    );
+}
+
+/// The binary merges into our version's file, as Git expects of a merge
+/// driver.
+#[test]
+fn merge_driver_replaces_our_file() {
+    let dir = tempfile::tempdir().unwrap();
+    let [base, ours, theirs] = ["base", "ours", "theirs"].map(|name| dir.path().join(name));
+    fs::write(&base, BASE).unwrap();
+    fs::write(
+        &ours,
+        BASE.replace("2024-01-05 ! Rent", "2024-01-05 * Rent"),
+    )
+    .unwrap();
+    fs::write(
+        &theirs,
+        BASE.replace("2024-01-06 Food", "2024-01-06 * Food"),
+    )
+    .unwrap();
+
+    let output: Output = Command::new(env!("CARGO_BIN_EXE_git-auto-commit"))
+        .arg("merge-driver")
+        .args([&base, &ours, &theirs])
+        .env_remove("PUSH_WALLET_MARKS_CONFIG")
+        .output()
+        .unwrap();
+
+    assert!(output.status.success(), "{:?}", output);
+    assert_eq!(
+        fs::read_to_string(&ours).unwrap(),
+        BASE.replace("2024-01-05 ! Rent", "2024-01-05 * Rent")
+            .replace("2024-01-06 Food", "2024-01-06 * Food")
+    );
+}
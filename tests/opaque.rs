@@ -0,0 +1,77 @@
+//! Tests of auto files that are treated as opaque blobs.
+
+mod common;
+
+use common::Fixture;
+use git_auto_commit::PushOutcome;
+use git_auto_commit::PushRequest;
+
+/// Returns a fixture with a pushed, "encrypted" `marks/b.gpg`.
+fn encrypted_fixture() -> Fixture {
+    let fixture = Fixture::new();
+    fixture.write("marks/b.gpg", "-----BEGIN PGP MESSAGE-----\nhQEMA1\n");
+    fixture.commit(&["marks/b.gpg"], "Add encrypted marks");
+    fixture.push();
+    fixture.write("marks/b.gpg", "-----BEGIN PGP MESSAGE-----\nhQEMA2\n");
+    fixture
+}
+
+/// Without the opaque pattern, the diff-based checks refuse the change.
+#[test]
+fn diff_checks_refuse_encrypted_changes() {
+    let fixture = encrypted_fixture();
+
+    let result = PushRequest::new(fixture.wallet.clone())
+        .auto_file("marks")
+        .marks_only(true)
+        .max_changed_lines(1)
+        .push();
+
+    assert!(result.is_err(), "{:?}", result);
+    assert_eq!(
+        fixture.remote_file("marks/b.gpg").as_deref(),
+        Some("-----BEGIN PGP MESSAGE-----\nhQEMA1\n")
+    );
+}
+
+/// Opaque files skip the diff-based checks and validations, but are pushed.
+#[test]
+fn opaque_files_skip_diff_checks() {
+    let fixture = encrypted_fixture();
+
+    let outcome: PushOutcome = PushRequest::new(fixture.wallet.clone())
+        .auto_file("marks")
+        .opaque("marks/*.gpg")
+        .marks_only(true)
+        .max_changed_lines(1)
+        .require_diff_regex("never")
+        .validate_cmd("false")
+        .push()
+        .unwrap();
+
+    assert!(
+        matches!(outcome, PushOutcome::Pushed { .. }),
+        "{:?}",
+        outcome
+    );
+    assert_eq!(
+        fixture.remote_file("marks/b.gpg").as_deref(),
+        Some("-----BEGIN PGP MESSAGE-----\nhQEMA2\n")
+    );
+}
+
+/// Opaque files still count towards the file limit.
+#[test]
+fn opaque_files_count_towards_the_file_limit() {
+    let fixture = encrypted_fixture();
+    fixture.write("marks/a.journal", "2024-01-05 * Rent\n");
+
+    let result = PushRequest::new(fixture.wallet.clone())
+        .auto_file("marks")
+        .opaque("marks/*.gpg")
+        .max_files(1)
+        .push();
+
+    let error: String = result.unwrap_err();
+    assert!(error.contains("more than the limit of 1"), "{}", error);
+}
@@ -0,0 +1,256 @@
+//! Tests of several machines pushing the marks of the same wallet.
+
+mod common;
+
+use std::fs;
+use std::path::Path;
+
+use common::Fixture;
+use git2::Oid;
+use git2::Repository;
+use git2::Signature;
+use git_auto_commit::DivergencePolicy;
+use git_auto_commit::PushOutcome;
+use git_auto_commit::PushRequest;
+use git_auto_commit::SkipReason;
+
+/// Commits the marks in a clone of the remote and pushes them.
+fn push_from(peer: &Repository, content: &str, message: &str) -> Oid {
+    let workdir: &Path = peer.workdir().unwrap();
+    fs::write(workdir.join("marks/a.journal"), content).unwrap();
+    let mut index = peer.index().unwrap();
+    index.add_path(Path::new("marks/a.journal")).unwrap();
+    index.write().unwrap();
+    let tree = peer.find_tree(index.write_tree().unwrap()).unwrap();
+    let signature: Signature = peer.signature().unwrap();
+    let parent = peer.head().unwrap().peel_to_commit().unwrap();
+    let commit: Oid = peer
+        .commit(
+            Some("HEAD"),
+            &signature,
+            &signature,
+            message,
+            &tree,
+            &[&parent],
+        )
+        .unwrap();
+    peer.find_remote("origin")
+        .unwrap()
+        .push(&["refs/heads/main:refs/heads/main"], None)
+        .unwrap();
+    commit
+}
+
+/// Auto commits name the machine that made them.
+#[test]
+fn auto_commits_name_the_host() {
+    let fixture = Fixture::new();
+    fixture.write("marks/a.journal", "2024-01-05 * Rent\n");
+
+    PushRequest::new(fixture.wallet.clone())
+        .auto_file("marks/a.journal")
+        .push()
+        .unwrap();
+
+    let repo = fixture.repo();
+    let head = repo.head().unwrap().peel_to_commit().unwrap();
+    let hostname: String = gethostname::gethostname().to_string_lossy().into_owned();
+    assert!(
+        head.message()
+            .unwrap()
+            .ends_with(&format!("Auto-Commit-Host: {}\n", hostname)),
+        "{:?}",
+        head.message()
+    );
+}
+
+/// A run without changes fast-forwards over the auto commits of a peer and
+/// checks out their marks.
+#[test]
+fn follows_auto_commits_of_peers() {
+    let fixture = Fixture::new();
+    let peer: Repository = fixture.peer("laptop");
+    let pushed: Oid = push_from(
+        &peer,
+        "2024-01-05 * Rent\n",
+        "Update marks\n\nAuto-Commit: push-wallet-marks\nAuto-Commit-Host: laptop\n",
+    );
+
+    let outcome: PushOutcome = PushRequest::new(fixture.wallet.clone())
+        .auto_file("marks/a.journal")
+        .follow_peers(true)
+        .push()
+        .unwrap();
+
+    assert!(
+        matches!(outcome, PushOutcome::Skipped(SkipReason::NoChanges)),
+        "{:?}",
+        outcome
+    );
+    assert_eq!(fixture.head(), pushed);
+    assert_eq!(
+        fs::read_to_string(fixture.wallet.join("marks/a.journal")).unwrap(),
+        "2024-01-05 * Rent\n"
+    );
+    let repo: Repository = fixture.repo();
+    assert!(repo.statuses(None).unwrap().is_empty());
+}
+
+/// Commits that aren't auto commits are left for the user to pull.
+#[test]
+fn does_not_follow_other_commits() {
+    let fixture = Fixture::new();
+    let base: Oid = fixture.head();
+    let peer: Repository = fixture.peer("laptop");
+    push_from(&peer, "2024-01-05 * Rent\n", "Clear the rent by hand");
+
+    PushRequest::new(fixture.wallet.clone())
+        .auto_file("marks/a.journal")
+        .follow_peers(true)
+        .push()
+        .unwrap();
+
+    assert_eq!(fixture.head(), base);
+    assert_eq!(
+        fs::read_to_string(fixture.wallet.join("marks/a.journal")).unwrap(),
+        "2024-01-05 ! Rent\n"
+    );
+}
+
+/// Makes the wallet diverge from the remote: a peer pushes `notes/peer.txt`
+/// and the wallet commits `notes/local.txt`, and then the marks change.
+///
+/// # Returns
+///
+/// The local commit.
+fn diverge(fixture: &Fixture) -> Oid {
+    let peer: Repository = fixture.peer("peer");
+    let workdir: &Path = peer.workdir().unwrap();
+    fs::create_dir_all(workdir.join("notes")).unwrap();
+    fs::write(workdir.join("notes/peer.txt"), "peer\n").unwrap();
+    let mut index = peer.index().unwrap();
+    index.add_path(Path::new("notes/peer.txt")).unwrap();
+    index.write().unwrap();
+    let tree = peer.find_tree(index.write_tree().unwrap()).unwrap();
+    let signature: Signature = peer.signature().unwrap();
+    let parent = peer.head().unwrap().peel_to_commit().unwrap();
+    peer.commit(
+        Some("HEAD"),
+        &signature,
+        &signature,
+        "Add peer notes",
+        &tree,
+        &[&parent],
+    )
+    .unwrap();
+    peer.find_remote("origin")
+        .unwrap()
+        .push(&["refs/heads/main:refs/heads/main"], None)
+        .unwrap();
+    fixture.write("notes/local.txt", "local\n");
+    let local: Oid = fixture.commit(&["notes/local.txt"], "Add local notes");
+    fixture.write("marks/a.journal", "2024-01-05 * Rent\n");
+    local
+}
+
+/// The local commits of a diverged branch are rebased onto the upstream and
+/// pushed with the auto commit.
+#[test]
+fn diverged_branch_is_rebased() {
+    let fixture = Fixture::new();
+    let local: Oid = diverge(&fixture);
+
+    let outcome: PushOutcome = PushRequest::new(fixture.wallet.clone())
+        .auto_file("marks/a.journal")
+        .on_diverged(DivergencePolicy::Rebase)
+        .push()
+        .unwrap();
+
+    let PushOutcome::Pushed {
+        commit,
+        rebased,
+        reset,
+        ..
+    } = outcome
+    else {
+        panic!("{:?}", outcome);
+    };
+    assert!(rebased);
+    assert!(!reset);
+    let remote: Repository = Repository::open_bare(&fixture.remote).unwrap();
+    assert_eq!(remote.refname_to_id("refs/heads/main").unwrap(), commit);
+    // The rebased commits don't descend from the local ones, so the original
+    // branch isn't fast-forwarded.
+    assert_eq!(fixture.head(), local);
+    assert_eq!(
+        fixture.remote_file("notes/peer.txt").as_deref(),
+        Some("peer\n")
+    );
+    assert_eq!(
+        fixture.remote_file("notes/local.txt").as_deref(),
+        Some("local\n")
+    );
+    assert_eq!(
+        fixture.remote_file("marks/a.journal").as_deref(),
+        Some("2024-01-05 * Rent\n")
+    );
+}
+
+/// A diverged branch skips the run and leaves both sides alone.
+#[test]
+fn diverged_branch_aborts() {
+    let fixture = Fixture::new();
+    let local: Oid = diverge(&fixture);
+
+    let outcome: PushOutcome = PushRequest::new(fixture.wallet.clone())
+        .auto_file("marks/a.journal")
+        .on_diverged(DivergencePolicy::Abort)
+        .push()
+        .unwrap();
+
+    assert!(
+        matches!(outcome, PushOutcome::Skipped(SkipReason::Diverged)),
+        "{:?}",
+        outcome
+    );
+    assert_eq!(fixture.head(), local);
+    assert_eq!(fixture.remote_file("notes/local.txt"), None);
+    assert_eq!(
+        fixture.remote_file("marks/a.journal").as_deref(),
+        Some("2024-01-05 ! Rent\n")
+    );
+    assert_eq!(
+        fs::read_to_string(fixture.wallet.join("marks/a.journal")).unwrap(),
+        "2024-01-05 * Rent\n"
+    );
+}
+
+/// A diverged branch drops its local commits and gets the auto commit on
+/// the upstream.
+#[test]
+fn diverged_branch_is_reset_to_the_remote() {
+    let fixture = Fixture::new();
+    let local: Oid = diverge(&fixture);
+
+    let outcome: PushOutcome = PushRequest::new(fixture.wallet.clone())
+        .auto_file("marks/a.journal")
+        .on_diverged(DivergencePolicy::ResetToRemote)
+        .push()
+        .unwrap();
+
+    let PushOutcome::Pushed { commit, reset, .. } = outcome else {
+        panic!("{:?}", outcome);
+    };
+    assert!(reset);
+    assert_eq!(fixture.head(), commit);
+    assert!(!fixture.repo().graph_descendant_of(commit, local).unwrap());
+    assert_eq!(
+        fixture.remote_file("notes/peer.txt").as_deref(),
+        Some("peer\n")
+    );
+    assert_eq!(fixture.remote_file("notes/local.txt"), None);
+    assert_eq!(
+        fixture.remote_file("marks/a.journal").as_deref(),
+        Some("2024-01-05 * Rent\n")
+    );
+}
@@ -0,0 +1,514 @@
+//! Tests of pushing auto commits upstream.
+
+mod common;
+
+use std::fs;
+use std::path::Path;
+use std::path::PathBuf;
+
+use common::Fixture;
+use git2::Oid;
+use git2::Repository;
+use git2::ResetType;
+use git2::Signature;
+use git_auto_commit::PushOutcome;
+use git_auto_commit::PushRequest;
+use git_auto_commit::Workspace;
+
+/// Commits a file in a clone of the remote.
+fn commit_in(peer: &Repository, path: &str, content: &str, message: &str) -> Oid {
+    let workdir: &Path = peer.workdir().unwrap();
+    fs::create_dir_all(workdir.join(path).parent().unwrap()).unwrap();
+    fs::write(workdir.join(path), content).unwrap();
+    let mut index = peer.index().unwrap();
+    index.add_path(Path::new(path)).unwrap();
+    index.write().unwrap();
+    let tree = peer.find_tree(index.write_tree().unwrap()).unwrap();
+    let signature: Signature = peer.signature().unwrap();
+    let parent = peer.head().unwrap().peel_to_commit().unwrap();
+    peer.commit(
+        Some("HEAD"),
+        &signature,
+        &signature,
+        message,
+        &tree,
+        &[&parent],
+    )
+    .unwrap()
+}
+
+/// An auto commit whose changes the upstream already has is dropped by the
+/// rebase, and the push is skipped instead of connecting to the remote.
+#[test]
+fn no_push_when_upstream_has_the_changes() {
+    let fixture = Fixture::new();
+    let base = fixture.head();
+    fixture.write("marks/a.journal", "2024-01-05 * Rent\n");
+    let upstream = fixture.commit(&["marks/a.journal"], "Clear the rent");
+    fixture.push();
+    // The wallet is back at the base, with the upstream's change uncommitted.
+    let repo = fixture.repo();
+    repo.reset(
+        &repo.find_object(base, None).unwrap(),
+        ResetType::Mixed,
+        None,
+    )
+    .unwrap();
+    // Pushing would fail, so a skipped push is the only way to succeed.
+    repo.remote_set_pushurl("origin", Some("/nonexistent/remote.git"))
+        .unwrap();
+
+    let outcome: PushOutcome = PushRequest::new(fixture.wallet.clone())
+        .auto_file("marks/a.journal")
+        .push()
+        .unwrap();
+
+    let PushOutcome::Pushed { commit, .. } = outcome else {
+        panic!("{:?}", outcome);
+    };
+    assert_eq!(commit, upstream);
+    assert_eq!(fixture.head(), upstream);
+}
+
+/// A forced push fails if the remote moved since it was last fetched.
+#[test]
+fn forced_push_checks_the_lease() {
+    let fixture = Fixture::new();
+    let base = fixture.head();
+    fixture.write("marks/a.journal", "2024-01-05 * Rent\n");
+    fixture.commit(&["marks/a.journal"], "Clear the rent");
+    fixture.push();
+    // The wallet still thinks that the upstream is at the base.
+    let repo = fixture.repo();
+    repo.reference("refs/remotes/origin/main", base, true, "test")
+        .unwrap();
+    fixture.write("marks/a.journal", "2024-01-06 ! Rent\n");
+
+    let result = PushRequest::new(fixture.wallet.clone())
+        .auto_file("marks/a.journal")
+        .force_with_lease(true)
+        .push();
+
+    let error: String = result.unwrap_err();
+    assert!(error.contains("where it was last fetched"), "{}", error);
+    assert_eq!(
+        fixture.remote_file("marks/a.journal").as_deref(),
+        Some("2024-01-05 * Rent\n")
+    );
+}
+
+/// An auto commit that would change other files, here by amending a commit
+/// that only looks like an auto commit, isn't pushed.
+#[test]
+fn auto_commit_with_other_files_is_not_pushed() {
+    let fixture = Fixture::new();
+    fixture.write("marks/a.journal", "2024-01-05 * Rent\n");
+    fixture.write("notes.txt", "Private\n");
+    fixture.commit(
+        &["marks/a.journal", "notes.txt"],
+        "Update marks\n\nAuto-Commit: push-wallet-marks\n",
+    );
+    fixture.write("marks/a.journal", "2024-01-05 * Rent\n2024-01-06 ! Food\n");
+
+    let result = PushRequest::new(fixture.wallet.clone())
+        .auto_file("marks/a.journal")
+        .amend(true)
+        .push();
+
+    let error: String = result.unwrap_err();
+    assert!(error.contains("besides the auto files"), "{}", error);
+    assert!(error.contains("notes.txt"), "{}", error);
+    assert_eq!(fixture.remote_file("notes.txt"), None);
+}
+
+/// The fetch before the rebase only updates the upstream branch, not other
+/// branches or tags of the remote. A local remote sends all its objects
+/// anyway, so only the references tell.
+#[test]
+fn fetch_only_downloads_the_upstream_branch() {
+    let fixture = Fixture::new();
+    let peer: Repository = fixture.peer("peer");
+    let upstream: Oid = commit_in(&peer, "marks/b.journal", "2024-01-06 ! Food\n", "Add b");
+    let signature: Signature = peer.signature().unwrap();
+    peer.tag(
+        "v1",
+        &peer.find_object(upstream, None).unwrap(),
+        &signature,
+        "Release",
+        false,
+    )
+    .unwrap();
+    peer.branch("other", &peer.find_commit(upstream).unwrap(), false)
+        .unwrap();
+    peer.set_head("refs/heads/other").unwrap();
+    commit_in(&peer, "notes.txt", "Private\n", "Add notes");
+    peer.find_remote("origin")
+        .unwrap()
+        .push(
+            &[
+                "refs/heads/main:refs/heads/main",
+                "refs/heads/other:refs/heads/other",
+                "refs/tags/v1:refs/tags/v1",
+            ],
+            None,
+        )
+        .unwrap();
+    fixture.write("marks/a.journal", "2024-01-05 * Rent\n");
+
+    let outcome: PushOutcome = PushRequest::new(fixture.wallet.clone())
+        .auto_file("marks/a.journal")
+        .workspace(Workspace::InPlace)
+        .push()
+        .unwrap();
+
+    let PushOutcome::Pushed { rebased, .. } = outcome else {
+        panic!("{:?}", outcome);
+    };
+    assert!(rebased);
+    assert_eq!(
+        fixture.remote_file("marks/b.journal").as_deref(),
+        Some("2024-01-06 ! Food\n")
+    );
+    let repo = fixture.repo();
+    assert!(repo.find_reference("refs/tags/v1").is_err());
+    assert!(repo.find_reference("refs/remotes/origin/other").is_err());
+}
+
+/// Returns the files that the auto commit of an outcome staged.
+fn staged_files(outcome: &PushOutcome) -> Vec<&str> {
+    match outcome {
+        PushOutcome::Pushed { staged_files, .. } => staged_files
+            .iter()
+            .map(|path| path.to_str().unwrap())
+            .collect(),
+        outcome => panic!("{:?}", outcome),
+    }
+}
+
+/// A recursive glob expands to the tracked files that it matches, in
+/// subdirectories too, and leaves the others alone.
+#[test]
+fn glob_expands_to_tracked_files() {
+    let fixture = Fixture::new();
+    fixture.write("marks/2024/b.journal", "2024-01-06 ! Food\n");
+    fixture.write("marks/notes.txt", "Rent is due\n");
+    fixture.commit(&["marks/2024/b.journal", "marks/notes.txt"], "Add b");
+    fixture.push();
+    fixture.write("marks/a.journal", "2024-01-05 * Rent\n");
+    fixture.write("marks/2024/b.journal", "2024-01-06 * Food\n");
+    fixture.write("marks/notes.txt", "Rent is paid\n");
+
+    let outcome: PushOutcome = PushRequest::new(fixture.wallet.clone())
+        .auto_file("marks/**/*.journal")
+        .push()
+        .unwrap();
+
+    assert_eq!(
+        staged_files(&outcome),
+        vec!["marks/2024/b.journal", "marks/a.journal"]
+    );
+    assert_eq!(
+        fixture.remote_file("marks/2024/b.journal").as_deref(),
+        Some("2024-01-06 * Food\n")
+    );
+    assert_eq!(
+        fixture.remote_file("marks/notes.txt").as_deref(),
+        Some("Rent is due\n")
+    );
+}
+
+/// A glob only picks up untracked files with `allow_new`.
+#[test]
+fn glob_matches_untracked_files_only_with_allow_new() {
+    let fixture = Fixture::new();
+    fixture.write("marks/a.journal", "2024-01-05 * Rent\n");
+    fixture.write("marks/b.journal", "2024-01-06 ! Food\n");
+    let request = PushRequest::new(fixture.wallet.clone()).auto_file("marks/*.journal");
+
+    let outcome: PushOutcome = request.clone().push().unwrap();
+
+    assert_eq!(staged_files(&outcome), vec!["marks/a.journal"]);
+    assert_eq!(fixture.remote_file("marks/b.journal"), None);
+
+    let outcome: PushOutcome = request.allow_new(true).push().unwrap();
+
+    assert_eq!(staged_files(&outcome), vec!["marks/b.journal"]);
+    assert_eq!(
+        fixture.remote_file("marks/b.journal").as_deref(),
+        Some("2024-01-06 ! Food\n")
+    );
+}
+
+/// An excluded file that an auto glob matches is left uncommitted.
+#[test]
+fn excluded_files_are_not_committed() {
+    let fixture = Fixture::new();
+    fixture.write("marks/drafts/b.journal", "2024-01-06 ! Food\n");
+    fixture.commit(&["marks/drafts/b.journal"], "Add a draft");
+    fixture.push();
+    fixture.write("marks/a.journal", "2024-01-05 * Rent\n");
+    fixture.write("marks/drafts/b.journal", "2024-01-06 * Food\n");
+
+    let outcome: PushOutcome = PushRequest::new(fixture.wallet.clone())
+        .auto_file("marks/**/*.journal")
+        .exclude("marks/drafts/**")
+        .push()
+        .unwrap();
+
+    assert_eq!(staged_files(&outcome), vec!["marks/a.journal"]);
+    assert_eq!(
+        fixture.remote_file("marks/drafts/b.journal").as_deref(),
+        Some("2024-01-06 ! Food\n")
+    );
+    assert_eq!(
+        fixture
+            .repo()
+            .status_file(Path::new("marks/drafts/b.journal"))
+            .unwrap(),
+        git2::Status::WT_MODIFIED
+    );
+}
+
+/// With `marks_only`, a change of anything but a transaction's mark fails the
+/// push, and the remote keeps the old file.
+#[test]
+fn marks_only_refuses_other_changes() {
+    let fixture = Fixture::new();
+    let request = PushRequest::new(fixture.wallet.clone())
+        .auto_file("marks/a.journal")
+        .marks_only(true);
+
+    for (content, reason) in [
+        ("2024-01-05 ! Rent and gas\n", "changed more than its mark"),
+        (
+            "2024-01-05 ! Rent\n2024-01-06 ! Food\n",
+            "gained or lost lines",
+        ),
+    ] {
+        fixture.write("marks/a.journal", content);
+
+        let error: String = request.push().unwrap_err();
+
+        assert!(error.contains(reason), "{}", error);
+        assert_eq!(
+            fixture.remote_file("marks/a.journal").as_deref(),
+            Some("2024-01-05 ! Rent\n")
+        );
+    }
+
+    fixture.write("marks/a.journal", "2024-01-05 * Rent\n");
+    let outcome: PushOutcome = request.push().unwrap();
+    assert_eq!(staged_files(&outcome), vec!["marks/a.journal"]);
+}
+
+/// More changed mark files than `max_files` fail the push, and as many pass.
+#[test]
+fn max_files_limits_the_changed_files() {
+    let fixture = Fixture::new();
+    fixture.write("marks/a.journal", "2024-01-05 * Rent\n");
+    fixture.write("marks/b.journal", "2024-01-06 ! Food\n");
+    let request = PushRequest::new(fixture.wallet.clone())
+        .auto_file("marks/*.journal")
+        .allow_new(true);
+
+    let error: String = request.clone().max_files(1).push().unwrap_err();
+
+    assert!(error.contains("2 mark files changed"), "{}", error);
+    assert_eq!(fixture.remote_file("marks/b.journal"), None);
+    let outcome: PushOutcome = request.max_files(2).push().unwrap();
+    assert_eq!(
+        staged_files(&outcome),
+        vec!["marks/a.journal", "marks/b.journal"]
+    );
+}
+
+/// More added and removed lines than `max_changed_lines` fail the push, and
+/// as many pass.
+#[test]
+fn max_changed_lines_limits_the_diff() {
+    let fixture = Fixture::new();
+    // One line removed and two added.
+    fixture.write("marks/a.journal", "2024-01-05 * Rent\n2024-01-06 ! Food\n");
+    let request = PushRequest::new(fixture.wallet.clone()).auto_file("marks/a.journal");
+
+    let error: String = request.clone().max_changed_lines(2).push().unwrap_err();
+
+    assert!(error.contains("3 lines of mark files changed"), "{}", error);
+    assert_eq!(
+        fixture.remote_file("marks/a.journal").as_deref(),
+        Some("2024-01-05 ! Rent\n")
+    );
+    let outcome: PushOutcome = request.max_changed_lines(3).push().unwrap();
+    assert_eq!(staged_files(&outcome), vec!["marks/a.journal"]);
+}
+
+/// An auto file that was moved within the auto file patterns is committed as
+/// a rename.
+#[test]
+fn renamed_auto_file_is_committed() {
+    let fixture = Fixture::new();
+    fs::rename(
+        fixture.wallet.join("marks/a.journal"),
+        fixture.wallet.join("marks/rent.journal"),
+    )
+    .unwrap();
+
+    let outcome: PushOutcome = PushRequest::new(fixture.wallet.clone())
+        .auto_file("marks/*.journal")
+        .push()
+        .unwrap();
+
+    let PushOutcome::Pushed { commit, .. } = &outcome else {
+        panic!("{:?}", outcome);
+    };
+    assert_eq!(fixture.remote_file("marks/a.journal"), None);
+    assert_eq!(
+        fixture.remote_file("marks/rent.journal").as_deref(),
+        Some("2024-01-05 ! Rent\n")
+    );
+    let repo = fixture.repo();
+    let commit = repo.find_commit(*commit).unwrap();
+    let mut diff = repo
+        .diff_tree_to_tree(
+            Some(&commit.parent(0).unwrap().tree().unwrap()),
+            Some(&commit.tree().unwrap()),
+            None,
+        )
+        .unwrap();
+    diff.find_similar(None).unwrap();
+    let deltas: Vec<git2::Delta> = diff.deltas().map(|delta| delta.status()).collect();
+    assert_eq!(deltas, vec![git2::Delta::Renamed]);
+}
+
+/// A forced push is rejected if a peer pushed since the wallet last fetched,
+/// and replaces the peer's commit only once the wallet has seen it.
+#[test]
+fn forced_push_is_rejected_after_a_peer_push() {
+    let fixture = Fixture::new();
+    let peer: Repository = fixture.peer("peer");
+    let peer_commit: Oid = commit_in(&peer, "marks/b.journal", "2024-01-06 ! Food\n", "Add b");
+    peer.find_remote("origin")
+        .unwrap()
+        .push(&["refs/heads/main:refs/heads/main"], None)
+        .unwrap();
+    fixture.write("marks/a.journal", "2024-01-05 * Rent\n");
+    let request = PushRequest::new(fixture.wallet.clone())
+        .auto_file("marks/a.journal")
+        .force_with_lease(true);
+
+    let error: String = request.push().unwrap_err();
+
+    assert!(error.contains("where it was last fetched"), "{}", error);
+    assert!(error.contains(&peer_commit.to_string()), "{}", error);
+    assert_eq!(
+        fixture.remote_file("marks/b.journal").as_deref(),
+        Some("2024-01-06 ! Food\n")
+    );
+
+    fixture
+        .repo()
+        .find_remote("origin")
+        .unwrap()
+        .fetch(&["+refs/heads/main:refs/remotes/origin/main"], None, None)
+        .unwrap();
+    let outcome: PushOutcome = request.push().unwrap();
+
+    assert_eq!(staged_files(&outcome), vec!["marks/a.journal"]);
+    assert_eq!(fixture.remote_file("marks/b.journal"), None);
+    assert_eq!(
+        fixture.remote_file("marks/a.journal").as_deref(),
+        Some("2024-01-05 * Rent\n")
+    );
+}
+
+/// The auto commit is pushed to the mirrors too, and a failing mirror is
+/// reported without failing the run.
+#[test]
+fn mirrors_get_the_auto_commit() {
+    let fixture = Fixture::new();
+    let mirror_path: PathBuf = fixture.remote.with_file_name("mirror.git");
+    let mirror: Repository = Repository::init_bare(&mirror_path).unwrap();
+    let repo = fixture.repo();
+    repo.remote("mirror", mirror_path.to_str().unwrap())
+        .unwrap();
+    repo.remote("broken", "/nonexistent/mirror.git").unwrap();
+    fixture.write("marks/a.journal", "2024-01-05 * Rent\n");
+
+    let outcome: PushOutcome = PushRequest::new(fixture.wallet.clone())
+        .auto_file("marks/a.journal")
+        .mirrors(["mirror", "broken"])
+        .push()
+        .unwrap();
+
+    let PushOutcome::Pushed {
+        commit, mirrors, ..
+    } = outcome
+    else {
+        panic!("{:?}", outcome);
+    };
+    assert_eq!(mirrors.len(), 2);
+    assert_eq!(mirrors[0].remote, "mirror");
+    assert_eq!(mirrors[0].result, Ok(()));
+    assert_eq!(mirrors[1].remote, "broken");
+    assert!(mirrors[1].result.is_err(), "{:?}", mirrors[1]);
+    assert_eq!(mirror.refname_to_id("refs/heads/main").unwrap(), commit);
+    assert_eq!(
+        fixture.remote_file("marks/a.journal").as_deref(),
+        Some("2024-01-05 * Rent\n")
+    );
+}
+
+/// The tag is created on the auto commit and pushed with it.
+#[test]
+fn tag_is_pushed_with_the_auto_commit() {
+    let fixture = Fixture::new();
+    fixture.write("marks/a.journal", "2024-01-05 * Rent\n");
+
+    let outcome: PushOutcome = PushRequest::new(fixture.wallet.clone())
+        .auto_file("marks/a.journal")
+        .tag_template("marks/{date}")
+        .push()
+        .unwrap();
+
+    let PushOutcome::Pushed { commit, tag, .. } = outcome else {
+        panic!("{:?}", outcome);
+    };
+    let tag_ref: String = format!("refs/tags/{}", tag.unwrap());
+    assert_eq!(fixture.repo().refname_to_id(&tag_ref).unwrap(), commit);
+    let remote: Repository = Repository::open_bare(&fixture.remote).unwrap();
+    assert_eq!(remote.refname_to_id(&tag_ref).unwrap(), commit);
+}
+
+/// An existing tag isn't moved to the auto commit, and the run still
+/// succeeds.
+#[test]
+fn existing_tag_is_not_moved() {
+    let fixture = Fixture::new();
+    let tagged: Oid = fixture.head();
+    fixture
+        .repo()
+        .reference("refs/tags/marks/rent", tagged, false, "tag")
+        .unwrap();
+    fixture.write("marks/a.journal", "2024-01-05 * Rent\n");
+
+    let outcome: PushOutcome = PushRequest::new(fixture.wallet.clone())
+        .auto_file("marks/a.journal")
+        .tag_template("marks/rent")
+        .push()
+        .unwrap();
+
+    let PushOutcome::Pushed { commit, tag, .. } = outcome else {
+        panic!("{:?}", outcome);
+    };
+    assert_eq!(tag, None);
+    assert_eq!(
+        fixture
+            .repo()
+            .refname_to_id("refs/tags/marks/rent")
+            .unwrap(),
+        tagged
+    );
+    let remote: Repository = Repository::open_bare(&fixture.remote).unwrap();
+    assert_eq!(remote.refname_to_id("refs/heads/main").unwrap(), commit);
+    assert!(remote.refname_to_id("refs/tags/marks/rent").is_err());
+}
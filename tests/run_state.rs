@@ -0,0 +1,93 @@
+//! Tests of skipping runs whose outcome can't have changed since the last one.
+
+mod common;
+
+use std::fs;
+
+use common::Fixture;
+use git_auto_commit::PushOutcome;
+use git_auto_commit::PushRequest;
+use git_auto_commit::SkipReason;
+
+/// An untracked auto file that a run skipped is pushed once `allow_new` is
+/// set, although neither HEAD nor the file changed in between.
+#[test]
+fn allow_new_after_a_skipped_run() {
+    let fixture = Fixture::new();
+    fixture.write("marks/new.journal", "2024-01-06 * Salary\n");
+    let request = PushRequest::new(fixture.wallet.clone()).auto_file("marks/new.journal");
+
+    assert_eq!(
+        request.clone().push(),
+        Ok(PushOutcome::Skipped(SkipReason::NoChanges))
+    );
+    let outcome: PushOutcome = request.allow_new(true).push().unwrap();
+
+    assert!(
+        matches!(outcome, PushOutcome::Pushed { .. }),
+        "{:?}",
+        outcome
+    );
+    assert_eq!(
+        fixture.remote_file("marks/new.journal").as_deref(),
+        Some("2024-01-06 * Salary\n")
+    );
+}
+
+/// Auto commits get increasing committer times, even if the clock is behind
+/// the last auto commit.
+#[test]
+fn commit_times_increase() {
+    let fixture = Fixture::new();
+    // As if the last auto commit came from a clock an hour ahead.
+    let ahead: i64 = fixture.repo().signature().unwrap().when().seconds() + 3600;
+    fs::write(
+        fixture.wallet.join(".git/push-wallet-marks-commit-time"),
+        format!("{}\n", ahead),
+    )
+    .unwrap();
+    let request = PushRequest::new(fixture.wallet.clone()).auto_file("marks/a.journal");
+    let committer_time = |outcome: PushOutcome| {
+        let PushOutcome::Pushed { commit, .. } = outcome else {
+            panic!("{:?}", outcome);
+        };
+        fixture
+            .repo()
+            .find_commit(commit)
+            .unwrap()
+            .committer()
+            .when()
+            .seconds()
+    };
+
+    fixture.write("marks/a.journal", "2024-01-05 * Rent\n");
+    let first: i64 = committer_time(request.clone().push().unwrap());
+    fixture.write("marks/a.journal", "2024-01-05 * Rent\n2024-01-06 ! Food\n");
+    let second: i64 = committer_time(request.push().unwrap());
+
+    assert_eq!(first, ahead + 1);
+    assert_eq!(second, ahead + 2);
+}
+
+/// A Git directory that can't be written, e.g., on a read-only mount, still
+/// gets unchanged repositories skipped instead of failing on the run lock.
+#[cfg(unix)]
+#[test]
+fn read_only_git_dir() {
+    use std::os::unix::fs::PermissionsExt;
+
+    let fixture = Fixture::new();
+    let request = PushRequest::new(fixture.wallet.clone()).auto_file("marks/a.journal");
+    let git_dir = fixture.wallet.join(".git");
+    fs::set_permissions(&git_dir, fs::Permissions::from_mode(0o555)).unwrap();
+    if fs::write(git_dir.join("probe"), "").is_ok() {
+        // Permissions don't bind, e.g., root.
+        eprintln!("Skipped: the Git directory is still writable.");
+        return;
+    }
+
+    let outcome = request.push();
+
+    fs::set_permissions(&git_dir, fs::Permissions::from_mode(0o755)).unwrap();
+    assert_eq!(outcome, Ok(PushOutcome::Skipped(SkipReason::NoChanges)));
+}
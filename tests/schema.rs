@@ -0,0 +1,178 @@
+//! Tests that the JSON Schemas match what the binary reads and writes.
+
+mod common;
+
+use std::collections::BTreeSet;
+use std::fs;
+use std::process::Command;
+use std::process::Output;
+
+use common::Fixture;
+use serde_json::Value;
+
+/// Runs the binary and returns its output.
+fn run(args: &[&str]) -> Output {
+    Command::new(env!("CARGO_BIN_EXE_git-auto-commit"))
+        .args(args)
+        .env_remove("PUSH_WALLET_MARKS_CONFIG")
+        .output()
+        .unwrap()
+}
+
+/// Returns the schema that `schema <kind>` prints.
+fn schema(kind: &str) -> Value {
+    let output: Output = run(&["schema", kind]);
+    assert!(output.status.success());
+    serde_json::from_slice(&output.stdout).unwrap()
+}
+
+/// Returns the names of a schema's properties.
+fn property_names(schema: &Value) -> BTreeSet<String> {
+    schema["properties"]
+        .as_object()
+        .unwrap()
+        .keys()
+        .cloned()
+        .collect()
+}
+
+/// A run report has the required fields and no undocumented ones.
+#[test]
+fn run_report_matches_its_schema() {
+    let fixture = Fixture::new();
+    fixture.write("marks/a.journal", "2024-01-05 * Rent\n");
+    let schema: Value = schema("run-report");
+    let required: BTreeSet<String> = schema["required"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|name| name.as_str().unwrap().to_string())
+        .collect();
+
+    let output: Output = run(&[
+        "--repo",
+        fixture.wallet.to_str().unwrap(),
+        "--auto-files",
+        "marks/a.journal",
+        "--output",
+        "json",
+    ]);
+
+    assert!(output.status.success());
+    let report: Value = serde_json::from_slice(&output.stdout).unwrap();
+    let fields: BTreeSet<String> = report.as_object().unwrap().keys().cloned().collect();
+    assert!(fields.is_subset(&property_names(&schema)), "{:?}", fields);
+    assert!(required.is_subset(&fields), "{:?}", fields);
+    assert!(report["pushed"].is_object());
+}
+
+/// A verify report has the fields of its schema, and an out-of-sync
+/// repository fails the command.
+#[test]
+fn verify_report_matches_its_schema() {
+    let fixture = Fixture::new();
+    fixture.write("marks/a.journal", "2024-01-05 * Rent\n");
+    let schema: Value = schema("verify-report");
+
+    let output: Output = run(&[
+        "verify",
+        "--repo",
+        fixture.wallet.to_str().unwrap(),
+        "--auto-files",
+        "marks/a.journal",
+        "--output",
+        "json",
+    ]);
+
+    assert!(!output.status.success());
+    let report: Value = serde_json::from_slice(&output.stdout).unwrap();
+    let fields: BTreeSet<String> = report.as_object().unwrap().keys().cloned().collect();
+    assert_eq!(fields, property_names(&schema));
+    assert_eq!(report["status"], "out-of-sync");
+    assert_eq!(report["files"], serde_json::json!(["marks/a.journal"]));
+}
+
+/// A diff report has the fields of its schema, and so do its files.
+#[test]
+fn diff_report_matches_its_schema() {
+    let fixture = Fixture::new();
+    fixture.write("marks/a.journal", "2024-01-05 * Rent\n");
+    let schema: Value = schema("diff-report");
+
+    let output: Output = run(&[
+        "diff",
+        "--repo",
+        fixture.wallet.to_str().unwrap(),
+        "--auto-files",
+        "marks/a.journal",
+        "--output",
+        "json",
+    ]);
+
+    assert!(output.status.success());
+    let report: Value = serde_json::from_slice(&output.stdout).unwrap();
+    let fields: BTreeSet<String> = report.as_object().unwrap().keys().cloned().collect();
+    assert_eq!(fields, property_names(&schema));
+    let file: &Value = &report["files"][0];
+    let file_fields: BTreeSet<String> = file.as_object().unwrap().keys().cloned().collect();
+    assert_eq!(
+        file_fields,
+        property_names(&schema["properties"]["files"]["items"])
+    );
+    assert_eq!(file["path"], "marks/a.journal");
+    assert_eq!((&file["added"], &file["removed"]), (&1.into(), &1.into()));
+}
+
+/// A stats report has the fields of its schema.
+#[test]
+fn stats_report_matches_its_schema() {
+    let fixture = Fixture::new();
+    let schema: Value = schema("stats-report");
+
+    let output: Output = run(&[
+        "stats",
+        "--repo",
+        fixture.wallet.to_str().unwrap(),
+        "--auto-files",
+        "marks/a.journal",
+        "--output",
+        "json",
+    ]);
+
+    assert!(output.status.success());
+    let report: Value = serde_json::from_slice(&output.stdout).unwrap();
+    let fields: BTreeSet<String> = report.as_object().unwrap().keys().cloned().collect();
+    assert_eq!(fields, property_names(&schema));
+    assert_eq!(report["commits"], 0);
+}
+
+/// A configuration file that sets every property of the schema is accepted.
+#[test]
+fn config_schema_covers_the_config_file() {
+    let fixture = Fixture::new();
+    let schema: Value = schema("config");
+    let mut config = String::new();
+    for (name, property) in schema["properties"].as_object().unwrap() {
+        let value: String = match (&property["type"], &property["enum"]) {
+            (_, Value::Array(values)) => values[0].to_string(),
+            (Value::String(kind), _) if kind == "boolean" => "true".to_string(),
+            (Value::String(kind), _) if kind == "integer" => "1".to_string(),
+            (Value::String(kind), _) if kind == "string" => {
+                format!("{:?}", fixture.wallet.to_str().unwrap())
+            }
+            (Value::String(kind), _) if kind == "array" => "[]".to_string(),
+            _ => panic!("{}: {}", name, property),
+        };
+        config.push_str(&format!("{} = {}\n", name, value));
+    }
+    let config_path = fixture.wallet.join("config.toml");
+    fs::write(&config_path, config).unwrap();
+
+    let output: Output = run(&["config", "--config", config_path.to_str().unwrap()]);
+
+    assert!(
+        output.status.success(),
+        "{}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+}
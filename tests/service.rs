@@ -0,0 +1,92 @@
+//! Tests of hosting the sync in a background service.
+
+mod common;
+
+use std::fs;
+use std::sync::mpsc::Receiver;
+use std::thread;
+use std::time::Duration;
+
+use common::Fixture;
+use git_auto_commit::PushOutcome;
+use git_auto_commit::PushRequest;
+use git_auto_commit::SkipReason;
+use git_auto_commit::SyncEvent;
+use git_auto_commit::SyncService;
+
+/// How long to wait for an event before failing.
+const TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Returns the outcome of the next run.
+fn next_outcome(events: &Receiver<SyncEvent>) -> Result<PushOutcome, String> {
+    match events.recv_timeout(TIMEOUT).unwrap() {
+        SyncEvent::Ran {
+            request, outcome, ..
+        } => {
+            assert_eq!(request, 0);
+            outcome
+        }
+        SyncEvent::Failed(e) => panic!("{}", e),
+    }
+}
+
+/// The service catches up when it starts, pushes a change of an auto file,
+/// and stops on request.
+#[test]
+fn pushes_changes_until_stopped() {
+    let fixture = Fixture::new();
+    let mut service = SyncService::new(vec![
+        PushRequest::new(fixture.wallet.clone()).auto_file("marks/a.journal")
+    ])
+    .debounce(Duration::from_millis(100));
+    let events: Receiver<SyncEvent> = service.subscribe();
+
+    service.start().unwrap();
+    assert!(service.is_running());
+    assert_eq!(
+        next_outcome(&events),
+        Ok(PushOutcome::Skipped(SkipReason::NoChanges))
+    );
+    fixture.write("marks/a.journal", "2024-01-05 * Rent\n");
+    let outcome: PushOutcome = next_outcome(&events).unwrap();
+    service.stop();
+
+    assert!(
+        matches!(outcome, PushOutcome::Pushed { .. }),
+        "{:?}",
+        outcome
+    );
+    assert_eq!(
+        fixture.remote_file("marks/a.journal").as_deref(),
+        Some("2024-01-05 * Rent\n")
+    );
+    assert!(!service.is_running());
+}
+
+/// Reading an auto file doesn't run the request again, because its
+/// modification time and size are the same as at the last run.
+#[test]
+fn skips_wake_ups_without_changes() {
+    let fixture = Fixture::new();
+    let mut service = SyncService::new(vec![
+        PushRequest::new(fixture.wallet.clone()).auto_file("marks/a.journal")
+    ])
+    .debounce(Duration::from_millis(100));
+    let events: Receiver<SyncEvent> = service.subscribe();
+    service.start().unwrap();
+    assert_eq!(
+        next_outcome(&events),
+        Ok(PushOutcome::Skipped(SkipReason::NoChanges))
+    );
+
+    fs::read(fixture.wallet.join("marks/a.journal")).unwrap();
+    thread::sleep(Duration::from_millis(500));
+    fixture.write("marks/a.journal", "2024-01-05 * Rent\n");
+    let outcome: PushOutcome = next_outcome(&events).unwrap();
+
+    assert!(
+        matches!(outcome, PushOutcome::Pushed { .. }),
+        "{:?}",
+        outcome
+    );
+}
@@ -0,0 +1,68 @@
+//! Tests of snapshotting HEAD before auto commits.
+
+mod common;
+
+use common::Fixture;
+use git2::Oid;
+use git_auto_commit::PushOutcome;
+use git_auto_commit::PushRequest;
+use git_auto_commit::SNAPSHOT_REFS;
+
+/// Lists the snapshots of the wallet from the oldest to the newest.
+fn snapshots(fixture: &Fixture) -> Vec<Oid> {
+    let repo = fixture.repo();
+    let mut snapshots: Vec<(u64, Oid)> = repo
+        .references_glob(&format!("{}*", SNAPSHOT_REFS))
+        .unwrap()
+        .map(|reference| {
+            let reference = reference.unwrap();
+            let seconds: u64 = reference.name().unwrap()[SNAPSHOT_REFS.len()..]
+                .parse()
+                .unwrap();
+            (seconds, reference.target().unwrap())
+        })
+        .collect();
+    snapshots.sort();
+    snapshots.into_iter().map(|(_, target)| target).collect()
+}
+
+/// Each auto commit snapshots the commit before it, and only the newest
+/// snapshots are kept.
+#[test]
+fn keeps_the_newest_snapshots() {
+    let fixture = Fixture::new();
+    let request = PushRequest::new(fixture.wallet.clone())
+        .auto_file("marks/a.journal")
+        .snapshots(2);
+    let mut heads: Vec<Oid> = vec![fixture.head()];
+    for content in [
+        "2024-01-05 * Rent\n",
+        "2024-01-05 * Rent\n2024-01-06 ! Food\n",
+        "2024-01-05 * Rent\n2024-01-06 * Food\n",
+    ] {
+        fixture.write("marks/a.journal", content);
+        let outcome: PushOutcome = request.clone().push().unwrap();
+        assert!(
+            matches!(outcome, PushOutcome::Pushed { .. }),
+            "{:?}",
+            outcome
+        );
+        heads.push(fixture.head());
+    }
+
+    assert_eq!(snapshots(&fixture), heads[1..3]);
+}
+
+/// A run that commits nothing takes no snapshot.
+#[test]
+fn no_snapshot_without_changes() {
+    let fixture = Fixture::new();
+
+    PushRequest::new(fixture.wallet.clone())
+        .auto_file("marks/a.journal")
+        .snapshots(2)
+        .push()
+        .unwrap();
+
+    assert_eq!(snapshots(&fixture), Vec::<Oid>::new());
+}
@@ -0,0 +1,58 @@
+//! Tests of analyzing the history of auto commits and runs.
+
+mod common;
+
+use common::Fixture;
+use git_auto_commit::HistoryStats;
+use git_auto_commit::PushRequest;
+
+/// Auto commits are counted per day with the files they change, and failed
+/// pushes are counted in a row until one succeeds.
+#[test]
+fn counts_commits_and_failure_streaks() {
+    let fixture = Fixture::new();
+    fixture.write("marks/b.journal", "2024-01-06 ! Gas\n");
+    fixture.commit(&["marks/b.journal"], "Add gas");
+    fixture.push();
+    let request = PushRequest::new(fixture.wallet.clone())
+        .auto_file("marks/*.journal")
+        .ignore_state(true);
+
+    fixture.write("marks/a.journal", "2024-01-05 * Rent\n");
+    fixture.write("marks/b.journal", "2024-01-06 * Gas\n");
+    request.push().unwrap();
+    let remote_url: String = fixture
+        .repo()
+        .find_remote("origin")
+        .unwrap()
+        .url()
+        .unwrap()
+        .to_string();
+    fixture
+        .repo()
+        .remote_set_url("origin", "/nonexistent/remote.git")
+        .unwrap();
+    fixture.write("marks/a.journal", "2024-01-05 ! Rent\n");
+    assert!(request.push().is_err());
+    assert!(request.push().is_err());
+
+    let stats: HistoryStats = request.stats().unwrap();
+    assert_eq!(stats.commits, 1);
+    assert_eq!(stats.commits_per_day.len(), 1);
+    assert_eq!(stats.commits_per_day[0].1, 1);
+    assert_eq!(stats.average_files, 2.0);
+    assert_eq!(stats.longest_failure_streak, 2);
+    assert_eq!(stats.current_failure_streak, 2);
+
+    fixture
+        .repo()
+        .remote_set_url("origin", &remote_url)
+        .unwrap();
+    request.push().unwrap();
+
+    let stats: HistoryStats = request.stats().unwrap();
+    assert_eq!(stats.commits, 2);
+    assert_eq!(stats.average_files, 1.5);
+    assert_eq!(stats.longest_failure_streak, 2);
+    assert_eq!(stats.current_failure_streak, 0);
+}
@@ -0,0 +1,102 @@
+//! Tests of resolving Syncthing conflict copies of auto files.
+
+mod common;
+
+use std::fs;
+use std::fs::File;
+use std::path::PathBuf;
+use std::time::Duration;
+use std::time::SystemTime;
+
+use common::Fixture;
+use git_auto_commit::PushOutcome;
+use git_auto_commit::PushRequest;
+use git_auto_commit::SkipReason;
+use git_auto_commit::SyncConflictPolicy;
+
+/// The conflict copy of `marks/a.journal`.
+const CONFLICT: &str = "marks/a.sync-conflict-20240105-101010-ABCDEFG.journal";
+
+/// Writes a conflict copy that is newer than the auto file.
+fn write_conflict(fixture: &Fixture) -> PathBuf {
+    fixture.write(CONFLICT, "2024-01-05 * Rent\n");
+    let path: PathBuf = fixture.wallet.join(CONFLICT);
+    File::options()
+        .write(true)
+        .open(&path)
+        .unwrap()
+        .set_modified(SystemTime::now() + Duration::from_secs(60))
+        .unwrap();
+    path
+}
+
+/// Returns a request that resolves conflicts by taking the newest copy.
+fn request(fixture: &Fixture) -> PushRequest {
+    PushRequest::new(fixture.wallet.clone())
+        .auto_file("marks/a.journal")
+        .on_sync_conflict(SyncConflictPolicy::PreferNewest)
+}
+
+/// The newer conflict copy replaces the auto file and is pushed.
+#[test]
+fn newest_copy_is_pushed() {
+    let fixture = Fixture::new();
+    let conflict: PathBuf = write_conflict(&fixture);
+
+    let outcome: PushOutcome = request(&fixture).push().unwrap();
+
+    assert!(
+        matches!(outcome, PushOutcome::Pushed { .. }),
+        "{:?}",
+        outcome
+    );
+    assert!(!conflict.exists());
+    assert_eq!(
+        fixture.remote_file("marks/a.journal").as_deref(),
+        Some("2024-01-05 * Rent\n")
+    );
+}
+
+/// Conflicts aren't resolved in the middle of a merge.
+#[test]
+fn ongoing_operation_keeps_the_copies() {
+    let fixture = Fixture::new();
+    let conflict: PathBuf = write_conflict(&fixture);
+    fs::write(
+        fixture.wallet.join(".git/MERGE_HEAD"),
+        format!("{}\n", fixture.head()),
+    )
+    .unwrap();
+
+    assert_eq!(
+        request(&fixture).push(),
+        Ok(PushOutcome::Skipped(SkipReason::OngoingOperation("merge")))
+    );
+    assert!(conflict.exists());
+    assert_eq!(
+        fs::read_to_string(fixture.wallet.join("marks/a.journal")).unwrap(),
+        "2024-01-05 ! Rent\n"
+    );
+}
+
+/// Conflicts aren't resolved while a manual change is staged.
+#[test]
+fn staged_changes_keep_the_copies() {
+    let fixture = Fixture::new();
+    let conflict: PathBuf = write_conflict(&fixture);
+    fixture.write("notes.txt", "Call the landlord\n");
+    let repo = fixture.repo();
+    let mut index = repo.index().unwrap();
+    index.add_path("notes.txt".as_ref()).unwrap();
+    index.write().unwrap();
+
+    assert_eq!(
+        request(&fixture).push(),
+        Ok(PushOutcome::Skipped(SkipReason::IndexNotEmpty))
+    );
+    assert!(conflict.exists());
+    assert_eq!(
+        fs::read_to_string(fixture.wallet.join("marks/a.journal")).unwrap(),
+        "2024-01-05 ! Rent\n"
+    );
+}
@@ -0,0 +1,137 @@
+//! Tests of wallets whose branch has no commit yet.
+
+mod common;
+
+use common::Fixture;
+use git_auto_commit::DivergencePolicy;
+use git_auto_commit::PushOutcome;
+use git_auto_commit::PushRequest;
+use git_auto_commit::Workspace;
+
+/// Pushes a new mark file of an unborn wallet from a workspace and checks
+/// that it became the root commit on both sides.
+fn assert_root_commit_pushed(workspace: Workspace) {
+    let fixture = Fixture::unborn();
+    fixture.write("marks/a.journal", "2024-01-05 ! Rent\n");
+
+    let outcome: PushOutcome = PushRequest::new(fixture.wallet.clone())
+        .auto_file("marks/a.journal")
+        .allow_new(true)
+        .workspace(workspace)
+        .push()
+        .unwrap();
+
+    let PushOutcome::Pushed { commit, .. } = outcome else {
+        panic!("{:?}", outcome);
+    };
+    assert_eq!(fixture.head(), commit);
+    assert_eq!(
+        fixture.repo().find_commit(commit).unwrap().parent_count(),
+        0
+    );
+    assert_eq!(
+        fixture.remote_file("marks/a.journal").as_deref(),
+        Some("2024-01-05 ! Rent\n")
+    );
+    assert!(fixture.repo().statuses(None).unwrap().is_empty());
+}
+
+#[test]
+fn root_commit_from_a_copy() {
+    assert_root_commit_pushed(Workspace::Copy);
+}
+
+#[test]
+fn root_commit_in_place() {
+    assert_root_commit_pushed(Workspace::InPlace);
+}
+
+/// A failed push of a root commit made in place leaves the branch unborn.
+#[test]
+fn failed_root_commit_in_place_is_undone() {
+    let fixture = Fixture::unborn();
+    fixture.write("marks/a.journal", "2024-01-05 ! Rent\n");
+    fixture
+        .repo()
+        .remote_set_url("origin", "/nonexistent/remote.git")
+        .unwrap();
+
+    let result = PushRequest::new(fixture.wallet.clone())
+        .auto_file("marks/a.journal")
+        .allow_new(true)
+        .on_diverged(DivergencePolicy::Rebase)
+        .workspace(Workspace::InPlace)
+        .push();
+
+    assert!(result.is_err());
+    let repo = fixture.repo();
+    assert!(repo
+        .head()
+        .is_err_and(|e| e.code() == git2::ErrorCode::UnbornBranch));
+    assert!(repo.index().unwrap().is_empty());
+}
+
+/// A wallet with commits creates the branch of a remote that has none yet,
+/// whatever the divergence policy.
+#[test]
+fn first_push_to_an_empty_remote() {
+    for policy in [
+        DivergencePolicy::Abort,
+        DivergencePolicy::Rebase,
+        DivergencePolicy::ResetToRemote,
+    ] {
+        let fixture = Fixture::unborn();
+        fixture.write("marks/a.journal", "2024-01-05 ! Rent\n");
+        let base = fixture.commit(&["marks/a.journal"], "Add marks");
+        fixture.write("marks/a.journal", "2024-01-05 * Rent\n");
+
+        let outcome: PushOutcome = PushRequest::new(fixture.wallet.clone())
+            .auto_file("marks/a.journal")
+            .on_diverged(policy)
+            .push()
+            .unwrap();
+
+        let PushOutcome::Pushed { commit, .. } = outcome else {
+            panic!("{:?}: {:?}", policy, outcome);
+        };
+        assert_eq!(fixture.head(), commit);
+        assert_eq!(
+            fixture
+                .repo()
+                .find_commit(commit)
+                .unwrap()
+                .parent_id(0)
+                .unwrap(),
+            base
+        );
+        assert_eq!(
+            fixture.remote_file("marks/a.journal").as_deref(),
+            Some("2024-01-05 * Rent\n")
+        );
+    }
+}
+
+/// A forced push checks its lease against a remote without branches, too.
+#[test]
+fn first_forced_push_to_an_empty_remote() {
+    let fixture = Fixture::unborn();
+    fixture.write("marks/a.journal", "2024-01-05 ! Rent\n");
+    fixture.commit(&["marks/a.journal"], "Add marks");
+    fixture.write("marks/a.journal", "2024-01-05 * Rent\n");
+
+    let outcome: PushOutcome = PushRequest::new(fixture.wallet.clone())
+        .auto_file("marks/a.journal")
+        .force_with_lease(true)
+        .workspace(Workspace::InPlace)
+        .push()
+        .unwrap();
+
+    let PushOutcome::Pushed { commit, .. } = outcome else {
+        panic!("{:?}", outcome);
+    };
+    assert_eq!(fixture.head(), commit);
+    assert_eq!(
+        fixture.remote_file("marks/a.journal").as_deref(),
+        Some("2024-01-05 * Rent\n")
+    );
+}
@@ -0,0 +1,88 @@
+//! Tests of comparing the auto files with the remote tip.
+
+mod common;
+
+use common::Fixture;
+use git_auto_commit::PushRequest;
+use git_auto_commit::Verification;
+
+/// Pushed auto files are in sync, and changed ones aren't until they're
+/// pushed.
+#[test]
+fn verifies_against_the_remote_tip() {
+    let fixture = Fixture::new();
+    let request = PushRequest::new(fixture.wallet.clone()).auto_file("marks/a.journal");
+
+    assert_eq!(
+        request.verify(),
+        Ok(Verification::InSync {
+            commit: fixture.head()
+        })
+    );
+
+    fixture.write("marks/a.journal", "2024-01-05 * Rent\n");
+    assert_eq!(
+        request.verify(),
+        Ok(Verification::OutOfSync {
+            commit: fixture.head(),
+            files: vec!["marks/a.journal".into()],
+        })
+    );
+
+    request.push().unwrap();
+    assert_eq!(
+        request.verify(),
+        Ok(Verification::InSync {
+            commit: fixture.head()
+        })
+    );
+}
+
+/// The remote is fetched, but nothing in the wallet changes.
+#[test]
+fn sees_remote_changes_without_fetching_them() {
+    let fixture = Fixture::new();
+    let base = fixture.head();
+    fixture.write("marks/a.journal", "2024-01-05 * Rent\n");
+    let upstream = fixture.commit(&["marks/a.journal"], "Clear the rent");
+    fixture.push();
+    // The wallet is back at the base, as if another machine pushed.
+    let repo = fixture.repo();
+    repo.reset(
+        &repo.find_object(base, None).unwrap(),
+        git2::ResetType::Hard,
+        None,
+    )
+    .unwrap();
+    repo.reference("refs/remotes/origin/main", base, true, "test")
+        .unwrap();
+
+    assert_eq!(
+        PushRequest::new(fixture.wallet.clone())
+            .auto_file("marks/a.journal")
+            .verify(),
+        Ok(Verification::OutOfSync {
+            commit: upstream,
+            files: vec!["marks/a.journal".into()],
+        })
+    );
+    assert_eq!(
+        repo.refname_to_id("refs/remotes/origin/main").unwrap(),
+        base
+    );
+}
+
+/// A remote without the upstream branch is reported as such.
+#[test]
+fn no_remote_branch() {
+    let fixture = Fixture::unborn();
+    fixture.write("marks/a.journal", "2024-01-05 ! Rent\n");
+    fixture.commit(&["marks/a.journal"], "Add marks");
+
+    assert_eq!(
+        PushRequest::new(fixture.wallet.clone())
+            .auto_file("marks/a.journal")
+            .verify(),
+        Ok(Verification::NoRemoteBranch)
+    );
+}